@@ -5,6 +5,9 @@ import "@openzeppelin/contracts/access/AccessControl.sol";
 import "@openzeppelin/contracts/security/ReentrancyGuard.sol";
 import "@openzeppelin/contracts/security/Pausable.sol";
 import "@openzeppelin/contracts/utils/cryptography/ECDSA.sol";
+import "@openzeppelin/contracts/token/ERC20/IERC20.sol";
+import "@openzeppelin/contracts/token/ERC20/utils/SafeERC20.sol";
+import "./StateEncoding.sol";
 
 /**
  * @title BridgeCore
@@ -12,6 +15,7 @@ import "@openzeppelin/contracts/utils/cryptography/ECDSA.sol";
  */
 contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
     using ECDSA for bytes32;
+    using SafeERC20 for IERC20;
 
     bytes32 public constant BRIDGE_ADMIN_ROLE = keccak256("BRIDGE_ADMIN_ROLE");
     bytes32 public constant VALIDATOR_ROLE = keccak256("VALIDATOR_ROLE");
@@ -24,6 +28,8 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
     event DisputeResolved(bytes32 indexed channelId, bytes32 finalStateHash);
     event FundsLocked(bytes32 indexed channelId, uint256 amount);
     event FundsReleased(bytes32 indexed channelId, uint256 amount);
+    event TokenDeposited(bytes32 indexed channelId, address indexed token, address depositor, uint256 amount);
+    event TokenReleased(bytes32 indexed channelId, address indexed token, address recipient, uint256 amount);
     event ValidatorAdded(address indexed validator);
     event ValidatorRemoved(address indexed validator);
 
@@ -33,6 +39,7 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
         uint256 capacity;
         uint256 lockedFunds;
         bytes32 latestStateHash;
+        uint256 latestSequence;
         uint256 disputePeriodEnd;
         bool isActive;
         mapping(address => bool) hasConsented;
@@ -66,6 +73,9 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
     mapping(bytes32 => Channel) public channels;
     mapping(bytes32 => ChannelState) public channelStates;
     mapping(address => uint256) public validatorStakes;
+    mapping(bytes32 => mapping(address => uint256)) public tokenDeposits;
+    // Token releases already paid, by the digest the participants signed
+    mapping(bytes32 => bool) public tokenReleases;
     
     uint256 public constant MINIMUM_STAKE = 1000 ether;
     uint256 public constant DISPUTE_PERIOD = 7 days;
@@ -77,13 +87,17 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
     }
 
     /**
-     * @dev Registers a new channel
+     * @dev Registers a new channel. Its id follows from the arguments, so participants
+     * can sign states for it before it is registered.
      * @param participants Array of channel participants
      * @param capacity Total channel capacity
+     * @param salt Distinguishes channels between the same participants, such as the
+     * id the participants know the channel by
      */
     function registerChannel(
         address[] calldata participants,
-        uint256 capacity
+        uint256 capacity,
+        bytes32 salt
     ) 
         external 
         nonReentrant 
@@ -94,9 +108,9 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
         require(capacity > 0, "Capacity must be positive");
 
         bytes32 channelId = keccak256(abi.encodePacked(
-            block.timestamp,
             participants,
-            capacity
+            capacity,
+            salt
         ));
 
         require(!channels[channelId].isActive, "Channel already exists");
@@ -113,11 +127,13 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
     /**
      * @dev Updates channel state with signatures from all participants
      * @param channelId Channel identifier
+     * @param sequence Sequence number of the new state, above the latest one stored
      * @param stateHash Hash of the new state
-     * @param signatures Array of signatures from participants
+     * @param signatures Signatures of every participant, in participant order
      */
     function updateChannelState(
         bytes32 channelId,
+        uint256 sequence,
         bytes32 stateHash,
         bytes[] calldata signatures
     ) 
@@ -127,16 +143,61 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
     {
         Channel storage channel = channels[channelId];
         require(channel.isActive, "Channel not active");
-        require(signatures.length == channel.participants.length, "Invalid signature count");
+        require(channel.disputeStatus == DisputeStatus.None, "Channel in dispute");
+        // No state is stored before the first update, which may be the opening state
+        require(
+            channel.latestStateHash == bytes32(0) || sequence > channel.latestSequence,
+            "State not newer than the latest"
+        );
 
-        // Verify all signatures
-        bytes32 messageHash = keccak256(abi.encodePacked(channelId, stateHash));
-        for (uint i = 0; i < signatures.length; i++) {
-            address signer = messageHash.toEthSignedMessageHash().recover(signatures[i]);
-            require(_isParticipant(channelId, signer), "Invalid signature");
-        }
+        // Every participant signs the sequence along with the hash, in participant order
+        bytes32 digest = keccak256(abi.encodePacked(channelId, sequence, stateHash)).toEthSignedMessageHash();
+        _verifyParticipantSignatures(channel.participants, digest, signatures);
+
+        channel.latestStateHash = stateHash;
+        channel.latestSequence = sequence;
+        emit ChannelStateUpdated(channelId, stateHash);
+    }
+
+    /**
+     * @dev Updates channel state from an EIP-712 typed state, as signed with eth_signTypedData_v4
+     * @param channelId Channel identifier
+     * @param sequence Sequence number of the state
+     * @param balances Balances of the state, in canonical order
+     * @param htlcs HTLCs of the state, in canonical order
+     * @param escrows Escrows of the state, in canonical order
+     * @param signatures Typed-data signatures of every participant, in participant order
+     */
+    function updateChannelStateTyped(
+        bytes32 channelId,
+        uint256 sequence,
+        StateEncoding.Balance[] calldata balances,
+        StateEncoding.Htlc[] calldata htlcs,
+        StateEncoding.Escrow[] calldata escrows,
+        bytes[] calldata signatures
+    )
+        external
+        nonReentrant
+        whenNotPaused
+    {
+        Channel storage channel = channels[channelId];
+        require(channel.isActive, "Channel not active");
+        require(channel.disputeStatus == DisputeStatus.None, "Channel in dispute");
+        // No state is stored before the first update, which may be the opening state
+        require(
+            channel.latestStateHash == bytes32(0) || sequence > channel.latestSequence,
+            "State not newer than the latest"
+        );
+
+        bytes32 digest = StateEncoding.typedDataHash(
+            StateEncoding.domainSeparator(address(this)),
+            StateEncoding.hashTypedState(channelId, sequence, balances, htlcs, escrows)
+        );
+        _verifyParticipantSignatures(channel.participants, digest, signatures);
 
+        bytes32 stateHash = StateEncoding.hashState(sequence, balances, htlcs, escrows);
         channel.latestStateHash = stateHash;
+        channel.latestSequence = sequence;
         emit ChannelStateUpdated(channelId, stateHash);
     }
 
@@ -245,6 +306,71 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
         emit FundsReleased(channelId, amount);
     }
 
+    /**
+     * @dev Deposits ERC-20 tokens into the channel; the bridge must be approved first
+     * @param channelId Channel identifier
+     * @param token Token contract address
+     * @param amount Amount to deposit
+     */
+    function depositToken(
+        bytes32 channelId,
+        address token,
+        uint256 amount
+    ) 
+        external 
+        nonReentrant 
+        whenNotPaused 
+    {
+        Channel storage channel = channels[channelId];
+        require(channel.isActive, "Channel not active");
+        require(_isParticipant(channelId, msg.sender), "Not a participant");
+        require(token != address(0), "Invalid token");
+        require(amount > 0, "Amount must be positive");
+
+        IERC20(token).safeTransferFrom(msg.sender, address(this), amount);
+        tokenDeposits[channelId][token] += amount;
+
+        emit TokenDeposited(channelId, token, msg.sender, amount);
+    }
+
+    /**
+     * @dev Pays ERC-20 tokens out of the channel's deposit. Every participant signs
+     * the release, so no single participant can move tokens on their own.
+     * @param channelId Channel identifier
+     * @param token Token contract address
+     * @param amount Amount to release
+     * @param recipient Recipient address
+     * @param nonce Distinguishes releases with the same terms, such as the sequence
+     * of the state the channel settled to
+     * @param signatures Signatures of all participants, in participant order
+     */
+    function releaseToken(
+        bytes32 channelId,
+        address token,
+        uint256 amount,
+        address recipient,
+        uint256 nonce,
+        bytes[] calldata signatures
+    ) 
+        external 
+        nonReentrant 
+    {
+        Channel storage channel = channels[channelId];
+        require(channel.isActive, "Channel not active");
+        require(tokenDeposits[channelId][token] >= amount, "Insufficient tokens");
+
+        bytes32 digest = keccak256(abi.encodePacked(channelId, token, amount, recipient, nonce))
+            .toEthSignedMessageHash();
+        require(!tokenReleases[digest], "Release already paid");
+        _verifyParticipantSignatures(channel.participants, digest, signatures);
+
+        tokenReleases[digest] = true;
+        tokenDeposits[channelId][token] -= amount;
+        IERC20(token).safeTransfer(recipient, amount);
+
+        emit TokenReleased(channelId, token, recipient, amount);
+    }
+
     /**
      * @dev Adds a new validator
      * @param validator Address of the validator
@@ -272,6 +398,20 @@ contract BridgeCore is AccessControl, ReentrancyGuard, Pausable {
 
     // Internal functions
 
+    function _verifyParticipantSignatures(
+        address[] storage participants,
+        bytes32 digest,
+        bytes[] calldata signatures
+    )
+        internal
+        view
+    {
+        require(signatures.length == participants.length, "Invalid signature count");
+        for (uint i = 0; i < signatures.length; i++) {
+            require(digest.recover(signatures[i]) == participants[i], "Invalid signature");
+        }
+    }
+
     function _isParticipant(bytes32 channelId, address participant) 
         internal 
         view 
@@ -151,7 +151,7 @@ contract ChannelManager is AccessControl, ReentrancyGuard, Pausable {
         }
 
         // Register with bridge
-        bridgeCore.registerChannel(participants, capacity);
+        bridgeCore.registerChannel(participants, capacity, channelId);
 
         emit ChannelOpened(channelId, participants, capacity);
         return channelId;
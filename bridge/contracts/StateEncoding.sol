@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.19;
+
+/**
+ * @title StateEncoding
+ * @dev Canonical encoding of a channel state, as signed off-chain.
+ * Balances are sorted by (token, participant) and omit zero amounts;
+ * HTLCs and escrows are sorted by id, escrow signers by address.
+ * The native asset is token address(0).
+ */
+library StateEncoding {
+    struct Balance {
+        address token;
+        address participant;
+        uint256 amount;
+    }
+
+    struct Htlc {
+        bytes32 id;
+        address token;
+        address sender;
+        address receiver;
+        uint256 amount;
+        bytes32 hashLock;
+        uint256 expiration;
+    }
+
+    struct Escrow {
+        bytes32 id;
+        address token;
+        address sender;
+        address recipient;
+        uint256 amount;
+        uint256 released;
+        uint256 threshold;
+        uint256 timeout;
+        address[] signers;
+    }
+
+    function encodeState(
+        uint256 sequence,
+        Balance[] memory balances,
+        Htlc[] memory htlcs,
+        Escrow[] memory escrows
+    )
+        internal
+        pure
+        returns (bytes memory)
+    {
+        return abi.encode(sequence, balances, htlcs, escrows);
+    }
+
+    function hashState(
+        uint256 sequence,
+        Balance[] memory balances,
+        Htlc[] memory htlcs,
+        Escrow[] memory escrows
+    )
+        internal
+        pure
+        returns (bytes32)
+    {
+        return keccak256(encodeState(sequence, balances, htlcs, escrows));
+    }
+
+    // EIP-712 typed data, as signed with eth_signTypedData_v4
+
+    bytes32 internal constant DOMAIN_TYPEHASH = keccak256(
+        "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)"
+    );
+    bytes32 internal constant BALANCE_TYPEHASH = keccak256(
+        "Balance(address token,address participant,uint256 amount)"
+    );
+    bytes32 internal constant HTLC_TYPEHASH = keccak256(
+        "Htlc(bytes32 id,address token,address sender,address receiver,uint256 amount,bytes32 hashLock,uint256 expiration)"
+    );
+    bytes32 internal constant ESCROW_TYPEHASH = keccak256(
+        "Escrow(bytes32 id,address token,address sender,address recipient,uint256 amount,uint256 released,uint256 threshold,uint256 timeout,address[] signers)"
+    );
+    bytes32 internal constant CHANNEL_STATE_TYPEHASH = keccak256(
+        "ChannelState(bytes32 channelId,uint256 sequence,Balance[] balances,Htlc[] htlcs,Escrow[] escrows)"
+        "Balance(address token,address participant,uint256 amount)"
+        "Escrow(bytes32 id,address token,address sender,address recipient,uint256 amount,uint256 released,uint256 threshold,uint256 timeout,address[] signers)"
+        "Htlc(bytes32 id,address token,address sender,address receiver,uint256 amount,bytes32 hashLock,uint256 expiration)"
+    );
+
+    function domainSeparator(address verifyingContract) internal view returns (bytes32) {
+        return keccak256(abi.encode(
+            DOMAIN_TYPEHASH,
+            keccak256("FlashChain Channel"),
+            keccak256("1"),
+            block.chainid,
+            verifyingContract
+        ));
+    }
+
+    function hashTypedState(
+        bytes32 channelId,
+        uint256 sequence,
+        Balance[] memory balances,
+        Htlc[] memory htlcs,
+        Escrow[] memory escrows
+    )
+        internal
+        pure
+        returns (bytes32)
+    {
+        bytes32[] memory balanceHashes = new bytes32[](balances.length);
+        for (uint256 i = 0; i < balances.length; i++) {
+            balanceHashes[i] = keccak256(abi.encode(
+                BALANCE_TYPEHASH,
+                balances[i].token,
+                balances[i].participant,
+                balances[i].amount
+            ));
+        }
+
+        bytes32[] memory htlcHashes = new bytes32[](htlcs.length);
+        for (uint256 i = 0; i < htlcs.length; i++) {
+            htlcHashes[i] = hashHtlc(htlcs[i]);
+        }
+
+        bytes32[] memory escrowHashes = new bytes32[](escrows.length);
+        for (uint256 i = 0; i < escrows.length; i++) {
+            escrowHashes[i] = hashEscrow(escrows[i]);
+        }
+
+        return keccak256(abi.encode(
+            CHANNEL_STATE_TYPEHASH,
+            channelId,
+            sequence,
+            keccak256(abi.encodePacked(balanceHashes)),
+            keccak256(abi.encodePacked(htlcHashes)),
+            keccak256(abi.encodePacked(escrowHashes))
+        ));
+    }
+
+    function hashHtlc(Htlc memory htlc) internal pure returns (bytes32) {
+        return keccak256(abi.encode(
+            HTLC_TYPEHASH,
+            htlc.id,
+            htlc.token,
+            htlc.sender,
+            htlc.receiver,
+            htlc.amount,
+            htlc.hashLock,
+            htlc.expiration
+        ));
+    }
+
+    function hashEscrow(Escrow memory escrow) internal pure returns (bytes32) {
+        return keccak256(abi.encode(
+            ESCROW_TYPEHASH,
+            escrow.id,
+            escrow.token,
+            escrow.sender,
+            escrow.recipient,
+            escrow.amount,
+            escrow.released,
+            escrow.threshold,
+            escrow.timeout,
+            keccak256(abi.encodePacked(escrow.signers))
+        ));
+    }
+
+    /// @dev The digest a participant signed for `structHash` in the domain
+    function typedDataHash(bytes32 separator, bytes32 structHash) internal pure returns (bytes32) {
+        return keccak256(abi.encodePacked("\x19\x01", separator, structHash));
+    }
+}
@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.19;
+
+import "../StateEncoding.sol";
+
+/**
+ * @title StateEncodingHarness
+ * @dev Exposes StateEncoding for generating and checking the shared test vectors
+ */
+contract StateEncodingHarness {
+    function encodeState(
+        uint256 sequence,
+        StateEncoding.Balance[] calldata balances,
+        StateEncoding.Htlc[] calldata htlcs,
+        StateEncoding.Escrow[] calldata escrows
+    )
+        external
+        pure
+        returns (bytes memory)
+    {
+        return StateEncoding.encodeState(sequence, balances, htlcs, escrows);
+    }
+
+    function hashState(
+        uint256 sequence,
+        StateEncoding.Balance[] calldata balances,
+        StateEncoding.Htlc[] calldata htlcs,
+        StateEncoding.Escrow[] calldata escrows
+    )
+        external
+        pure
+        returns (bytes32)
+    {
+        return StateEncoding.hashState(sequence, balances, htlcs, escrows);
+    }
+}
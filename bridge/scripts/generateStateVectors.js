@@ -0,0 +1,27 @@
+const StateEncodingHarness = artifacts.require("StateEncodingHarness");
+const { toCallArgs, readVectors, writeVectors } = require("./stateVectors");
+
+// Recomputes the encoding and hash of every vector from the Solidity side.
+// Run with `truffle exec bridge/scripts/generateStateVectors.js` after adding a vector.
+async function main(callback) {
+    try {
+        const harness = await StateEncodingHarness.new();
+        const vectors = readVectors();
+
+        for (const vector of vectors) {
+            const args = toCallArgs(vector.state);
+            vector.encoded = await harness.encodeState(...args);
+            vector.hash = await harness.hashState(...args);
+            console.log(`${vector.name}: ${vector.hash}`);
+        }
+
+        writeVectors(vectors);
+        console.log(`Wrote ${vectors.length} state vectors`);
+        callback();
+    } catch (error) {
+        console.error("Generating state vectors failed:", error);
+        callback(error);
+    }
+}
+
+module.exports = main;
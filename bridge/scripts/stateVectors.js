@@ -0,0 +1,44 @@
+const fs = require("fs");
+const path = require("path");
+
+const VECTORS_PATH = path.join(__dirname, "../../common/rust/src/test_vectors/channel_state.json");
+
+// Hex quantities in the vectors file, as serialized by the Rust side
+const uint = (value) => BigInt(value).toString();
+
+function toCallArgs(state) {
+    return [
+        state.sequence.toString(),
+        state.balances.map((balance) => [balance.asset, balance.participant, uint(balance.amount)]),
+        state.htlcs.map((htlc) => [
+            htlc.id,
+            htlc.asset,
+            htlc.sender,
+            htlc.receiver,
+            uint(htlc.amount),
+            htlc.hash_lock,
+            htlc.expiration.toString(),
+        ]),
+        state.escrows.map((escrow) => [
+            escrow.id,
+            escrow.asset,
+            escrow.sender,
+            escrow.recipient,
+            uint(escrow.amount),
+            uint(escrow.released),
+            escrow.threshold.toString(),
+            escrow.timeout.toString(),
+            escrow.signers,
+        ]),
+    ];
+}
+
+function readVectors() {
+    return JSON.parse(fs.readFileSync(VECTORS_PATH, "utf8"));
+}
+
+function writeVectors(vectors) {
+    fs.writeFileSync(VECTORS_PATH, JSON.stringify(vectors, null, 2) + "\n");
+}
+
+module.exports = { toCallArgs, readVectors, writeVectors };
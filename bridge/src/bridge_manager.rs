@@ -1,18 +1,47 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use ethers::prelude::*;
-use ethers::types::{Address, H256, U256};
+use async_trait::async_trait;
+use ethers::contract::{parse_log, ContractCall};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Address, Bytes, Signature, H256, U256};
+use ethers::utils::keccak256;
 use anyhow::Result;
 
-use crate::contract_bindings::{BridgeCore, ChannelManager};
+use crate::contract_bindings::{BridgeCore, ChannelManager, ChannelRegisteredFilter};
 use crate::state_sync::StateSync;
 use crate::types::*;
+use flashchain_common::encoding::u256_bytes;
+use flashchain_common::types::AssetId;
+
+/// Signs the transactions the bridge submits
+#[async_trait]
+pub trait TransactionSigner: Send + Sync {
+    /// Account the transactions are sent from
+    fn address(&self) -> Address;
+
+    /// Signature over the transaction's sighash, with `v` as the transaction type expects it
+    async fn sign_transaction(&self, transaction: &TypedTransaction) -> Result<Signature>;
+}
+
+#[async_trait]
+impl TransactionSigner for LocalWallet {
+    fn address(&self) -> Address {
+        Signer::address(self)
+    }
+
+    async fn sign_transaction(&self, transaction: &TypedTransaction) -> Result<Signature> {
+        Ok(Signer::sign_transaction(self, transaction).await?)
+    }
+}
 
 pub struct BridgeManager {
     bridge_contract: BridgeCore<Provider<Http>>,
     channel_manager: ChannelManager<Provider<Http>>,
     state_sync: Arc<RwLock<StateSync>>,
-    wallet: LocalWallet,
+    signer: Arc<dyn TransactionSigner>,
     pending_transactions: Arc<RwLock<HashMap<H256, PendingTransaction>>>,
 }
 
@@ -31,19 +60,39 @@ impl BridgeManager {
             bridge_contract,
             channel_manager,
             state_sync,
-            wallet,
+            signer: Arc::new(wallet),
             pending_transactions: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 
+    /// Signs transactions with `signer` instead of the wallet the manager was created with
+    pub fn with_signer(mut self, signer: Arc<dyn TransactionSigner>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    /// Id `BridgeCore.registerChannel` gives the channel registered with these arguments
+    pub fn channel_id_for(participants: &[Address], capacity: U256, salt: H256) -> H256 {
+        // abi.encodePacked pads each address of an array to a full word
+        let mut data = Vec::new();
+        for participant in participants {
+            data.extend_from_slice(H256::from(*participant).as_bytes());
+        }
+        data.extend_from_slice(&u256_bytes(capacity));
+        data.extend_from_slice(salt.as_bytes());
+        H256::from(keccak256(&data))
+    }
+
+    /// Registers the channel under `channel_id_for(participants, capacity, salt)`
     pub async fn register_channel(
         &self,
         participants: Vec<Address>,
         capacity: U256,
+        salt: H256,
     ) -> Result<H256> {
         let tx = self.bridge_contract
-            .register_channel(participants.clone(), capacity)
-            .from(self.wallet.address())
+            .register_channel(participants.clone(), capacity, salt.into())
+            .from(self.signer.address())
             .gas(500_000);
 
         let pending_tx = self.submit_transaction(tx).await?;
@@ -56,6 +105,7 @@ impl BridgeManager {
             data: Some(serde_json::to_value(&ChannelRegistrationData {
                 participants,
                 capacity,
+                salt,
             })?),
         });
 
@@ -71,8 +121,8 @@ impl BridgeManager {
         let state_hash = state.hash();
         
         let tx = self.bridge_contract
-            .update_channel_state(channel_id, state_hash, signatures)
-            .from(self.wallet.address())
+            .update_channel_state(channel_id.into(), state.sequence.into(), state_hash.into(), encode_signatures(&signatures))
+            .from(self.signer.address())
             .gas(300_000);
 
         let pending_tx = self.submit_transaction(tx).await?;
@@ -99,8 +149,8 @@ impl BridgeManager {
         proof: Vec<u8>,
     ) -> Result<H256> {
         let tx = self.bridge_contract
-            .initiate_dispute(channel_id, proof)
-            .from(self.wallet.address())
+            .initiate_dispute(channel_id.into(), Bytes::from(proof.clone()))
+            .from(self.signer.address())
             .gas(500_000);
 
         let pending_tx = self.submit_transaction(tx).await?;
@@ -129,8 +179,8 @@ impl BridgeManager {
         let state_hash = final_state.hash();
         
         let tx = self.bridge_contract
-            .resolve_dispute(channel_id, state_hash, validator_signatures)
-            .from(self.wallet.address())
+            .resolve_dispute(channel_id.into(), state_hash.into(), encode_signatures(&validator_signatures))
+            .from(self.signer.address())
             .gas(500_000);
 
         let pending_tx = self.submit_transaction(tx).await?;
@@ -150,29 +200,164 @@ impl BridgeManager {
         Ok(pending_tx.tx_hash)
     }
 
+    /// Deposits ERC-20 tokens into a channel; the bridge must already be approved to spend them
+    pub async fn deposit_token(
+        &self,
+        channel_id: H256,
+        token: AssetId,
+        amount: U256,
+    ) -> Result<H256> {
+        if token.is_native() {
+            return Err(anyhow::anyhow!("Native deposits go through lock_funds"));
+        }
+
+        let tx = self.bridge_contract
+            .deposit_token(channel_id.into(), token.0, amount)
+            .from(self.signer.address())
+            .gas(200_000);
+
+        let pending_tx = self.submit_transaction(tx).await?;
+
+        let mut pending = self.pending_transactions.write().await;
+        pending.insert(pending_tx.tx_hash, PendingTransaction {
+            tx_type: TransactionType::TokenDeposit,
+            status: TransactionStatus::Pending,
+            timestamp: chrono::Utc::now().timestamp(),
+            data: Some(serde_json::to_value(&TokenDepositData {
+                channel_id,
+                token,
+                depositor: self.signer.address(),
+                amount,
+            })?),
+        });
+
+        Ok(pending_tx.tx_hash)
+    }
+
+    /// Pays a participant's token balance out of the channel's deposit when it settles.
+    /// `signatures` are every participant's, in participant order, over the release
+    /// terms and `nonce`.
+    pub async fn release_token(
+        &self,
+        channel_id: H256,
+        token: AssetId,
+        amount: U256,
+        recipient: Address,
+        nonce: U256,
+        signatures: Vec<Signature>,
+    ) -> Result<H256> {
+        if token.is_native() {
+            return Err(anyhow::anyhow!("Native payouts go through releaseFunds"));
+        }
+
+        let tx = self.bridge_contract
+            .release_token(channel_id.into(), token.0, amount, recipient, nonce, encode_signatures(&signatures))
+            .from(self.signer.address())
+            .gas(200_000);
+
+        let pending_tx = self.submit_transaction(tx).await?;
+
+        let mut pending = self.pending_transactions.write().await;
+        pending.insert(pending_tx.tx_hash, PendingTransaction {
+            tx_type: TransactionType::TokenRelease,
+            status: TransactionStatus::Pending,
+            timestamp: chrono::Utc::now().timestamp(),
+            data: Some(serde_json::to_value(&TokenReleaseData {
+                channel_id,
+                token,
+                recipient,
+                amount,
+                nonce,
+            })?),
+        });
+
+        Ok(pending_tx.tx_hash)
+    }
+
+    /// Tokens of one kind the bridge holds for a channel
+    pub async fn token_deposit(&self, channel_id: H256, token: AssetId) -> Result<U256> {
+        Ok(self.bridge_contract.token_deposits(channel_id.into(), token.0).call().await?)
+    }
+
     pub async fn get_channel(&self, channel_id: H256) -> Result<Channel> {
-        let channel = self.bridge_contract.get_channel(channel_id).call().await?;
+        let channel = self.bridge_contract.get_channel(channel_id.into()).call().await?;
         Ok(Channel {
             participants: channel.0,
             capacity: channel.1,
             locked_funds: channel.2,
-            latest_state_hash: channel.3,
+            latest_state_hash: H256::from(channel.3),
             is_active: channel.4,
             dispute_status: channel.5.into(),
         })
     }
 
+    pub fn bridge_address(&self) -> Address {
+        self.bridge_contract.address()
+    }
+
+    /// Provider the bridge contracts are bound to, shared with the lightning chain clock
+    pub fn provider(&self) -> Arc<Provider<Http>> {
+        self.bridge_contract.client()
+    }
+
+    /// Block a transaction was mined in, or `None` while it is still pending
+    pub async fn transaction_block(&self, tx_hash: H256) -> Result<Option<u64>> {
+        let receipt = match self.bridge_contract.client().get_transaction_receipt(tx_hash).await? {
+            Some(receipt) => receipt,
+            None => return Ok(None),
+        };
+
+        if receipt.status.unwrap_or_default().as_u64() != 1 {
+            return Err(anyhow::anyhow!("Transaction {:?} reverted", tx_hash));
+        }
+
+        Ok(receipt.block_number.map(|block| block.as_u64()))
+    }
+
+    /// Id `BridgeCore` assigned to the channel registered by `tx_hash`, and the block
+    /// it was mined in, or `None` while the transaction is still pending
+    pub async fn registered_channel(&self, tx_hash: H256) -> Result<Option<(H256, u64)>> {
+        let receipt = match self.bridge_contract.client().get_transaction_receipt(tx_hash).await? {
+            Some(receipt) => receipt,
+            None => return Ok(None),
+        };
+
+        if receipt.status.unwrap_or_default().as_u64() != 1 {
+            return Err(anyhow::anyhow!("Transaction {:?} reverted", tx_hash));
+        }
+        let block = match receipt.block_number {
+            Some(block) => block.as_u64(),
+            None => return Ok(None),
+        };
+
+        let registered = receipt.logs.into_iter()
+            .filter(|log| log.address == self.bridge_contract.address())
+            .find_map(|log| parse_log::<ChannelRegisteredFilter>(log).ok())
+            .ok_or_else(|| anyhow::anyhow!("Transaction {:?} registered no channel", tx_hash))?;
+
+        Ok(Some((H256::from(registered.channel_id), block)))
+    }
+
     pub async fn get_pending_transaction(&self, tx_hash: H256) -> Option<PendingTransaction> {
         self.pending_transactions.read().await.get(&tx_hash).cloned()
     }
 
-    async fn submit_transaction<T: Send + Sync + ethers::abi::Tokenize>(
+    async fn submit_transaction<T: ethers::abi::Detokenize>(
         &self,
-        tx: ContractCall<Provider<Http>, T>,
+        call: ContractCall<Provider<Http>, T>,
     ) -> Result<PendingTransactionReceipt> {
-        let tx = tx.send().await?;
+        let client = self.bridge_contract.client();
+        let mut tx = call.tx;
+        client.fill_transaction(&mut tx, None).await?;
+        if tx.chain_id().is_none() {
+            tx.set_chain_id(client.get_chainid().await?.as_u64());
+        }
+
+        // Signed here rather than by the RPC node, so the signer can check every transaction
+        let signature = self.signer.sign_transaction(&tx).await?;
+        let pending = client.send_raw_transaction(tx.rlp_signed(&signature)).await?;
         Ok(PendingTransactionReceipt {
-            tx_hash: tx.tx_hash(),
+            tx_hash: pending.tx_hash(),
             block_number: None,
         })
     }
@@ -219,13 +404,17 @@ impl BridgeManager {
     }
 }
 
+fn encode_signatures(signatures: &[Signature]) -> Vec<Bytes> {
+    signatures.iter().map(|signature| Bytes::from(signature.to_vec())).collect()
+}
+
 impl Clone for BridgeManager {
     fn clone(&self) -> Self {
         Self {
             bridge_contract: self.bridge_contract.clone(),
             channel_manager: self.channel_manager.clone(),
             state_sync: Arc::clone(&self.state_sync),
-            wallet: self.wallet.clone(),
+            signer: Arc::clone(&self.signer),
             pending_transactions: Arc::clone(&self.pending_transactions),
         }
     }
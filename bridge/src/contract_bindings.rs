@@ -0,0 +1,47 @@
+//! Bindings for the bridge contracts in `bridge/contracts`. Keep the signatures in
+//! step with the Solidity sources.
+#![allow(clippy::all)]
+
+use ethers::contract::abigen;
+
+abigen!(
+    BridgeCore,
+    r#"[
+        event ChannelRegistered(bytes32 indexed channelId, address[] participants)
+        event ChannelStateUpdated(bytes32 indexed channelId, bytes32 stateHash)
+        event DisputeInitiated(bytes32 indexed channelId, address initiator)
+        event DisputeResolved(bytes32 indexed channelId, bytes32 finalStateHash)
+        event FundsLocked(bytes32 indexed channelId, uint256 amount)
+        event FundsReleased(bytes32 indexed channelId, uint256 amount)
+        event TokenDeposited(bytes32 indexed channelId, address indexed token, address depositor, uint256 amount)
+        event TokenReleased(bytes32 indexed channelId, address indexed token, address recipient, uint256 amount)
+        function registerChannel(address[] participants, uint256 capacity, bytes32 salt) external returns (bytes32)
+        function updateChannelState(bytes32 channelId, uint256 sequence, bytes32 stateHash, bytes[] signatures) external
+        struct Balance { address token; address participant; uint256 amount; }
+        struct Htlc { bytes32 id; address token; address sender; address receiver; uint256 amount; bytes32 hashLock; uint256 expiration; }
+        struct Escrow { bytes32 id; address token; address sender; address recipient; uint256 amount; uint256 released; uint256 threshold; uint256 timeout; address[] signers; }
+        function updateChannelStateTyped(bytes32 channelId, uint256 sequence, Balance[] balances, Htlc[] htlcs, Escrow[] escrows, bytes[] signatures) external
+        function initiateDispute(bytes32 channelId, bytes stateProof) external
+        function resolveDispute(bytes32 channelId, bytes32 finalStateHash, bytes[] validatorSignatures) external
+        function lockFunds(bytes32 channelId) external payable
+        function releaseFunds(bytes32 channelId, uint256 amount, address recipient) external
+        function depositToken(bytes32 channelId, address token, uint256 amount) external
+        function releaseToken(bytes32 channelId, address token, uint256 amount, address recipient, uint256 nonce, bytes[] signatures) external
+        function tokenDeposits(bytes32 channelId, address token) external view returns (uint256)
+        function tokenReleases(bytes32 digest) external view returns (bool)
+        function getChannel(bytes32 channelId) external view returns (address[] participants, uint256 capacity, uint256 lockedFunds, bytes32 latestStateHash, bool isActive, uint8 disputeStatus)
+        function getParticipantChannels(address participant) external view returns (bytes32[])
+    ]"#
+);
+
+abigen!(
+    ChannelManager,
+    r#"[
+        function openChannel(address[] participants, uint256 capacity) external payable returns (bytes32)
+        function initiateClose(bytes32 channelId, bytes32 stateHash) external
+        function confirmClose(bytes32 channelId) external
+        function raiseDispute(bytes32 channelId, bytes32 disputedStateHash) external
+        function getBalance(bytes32 channelId, address participant) external view returns (uint256)
+        function getUserChannels(address user) external view returns (bytes32[])
+    ]"#
+);
@@ -4,5 +4,5 @@ pub mod state_sync;
 pub mod types;
 pub mod utils;
 
-pub use bridge_manager::BridgeManager;
+pub use bridge_manager::{BridgeManager, TransactionSigner};
 pub use state_sync::StateSync;
\ No newline at end of file
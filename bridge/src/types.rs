@@ -1,6 +1,8 @@
 use serde::{Serialize, Deserialize};
 use ethers::types::{Address, H256, U256};
 use std::collections::HashMap;
+use flashchain_common::encoding::{CanonicalBalance, CanonicalEscrow, CanonicalHtlc, CanonicalState};
+use flashchain_common::types::AssetId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Channel {
@@ -18,6 +20,72 @@ pub struct ChannelState {
     pub balances: HashMap<Address, U256>,
     pub htlcs: HashMap<H256, HTLC>,
     pub timestamp: i64,
+    /// ERC-20 balances, keyed by token; `balances` holds the native asset
+    #[serde(default)]
+    pub token_balances: HashMap<AssetId, HashMap<Address, U256>>,
+    #[serde(default)]
+    pub escrows: Vec<CanonicalEscrow>,
+}
+
+impl ChannelState {
+    pub fn from_canonical(state: CanonicalState, timestamp: i64) -> Self {
+        let mut balances = HashMap::new();
+        let mut token_balances: HashMap<AssetId, HashMap<Address, U256>> = HashMap::new();
+        for balance in state.balances {
+            if balance.asset.is_native() {
+                balances.insert(balance.participant, balance.amount);
+            } else {
+                token_balances.entry(balance.asset).or_default().insert(balance.participant, balance.amount);
+            }
+        }
+
+        let htlcs = state.htlcs.into_iter()
+            .map(|htlc| (htlc.id, HTLC {
+                amount: htlc.amount,
+                hash_lock: htlc.hash_lock,
+                expiration: htlc.expiration,
+                sender: htlc.sender,
+                receiver: htlc.receiver,
+                token: htlc.asset,
+            }))
+            .collect();
+
+        Self {
+            sequence: state.sequence,
+            balances,
+            htlcs,
+            timestamp,
+            token_balances,
+            escrows: state.escrows,
+        }
+    }
+
+    /// `StateEncoding.hashState` of this state, as submitted to `BridgeCore`
+    pub fn hash(&self) -> H256 {
+        CanonicalState::from(self).hash()
+    }
+}
+
+impl From<&ChannelState> for CanonicalState {
+    fn from(state: &ChannelState) -> Self {
+        let native = state.balances.iter().map(|(&participant, &amount)| (AssetId::NATIVE, participant, amount));
+        let tokens = state.token_balances.iter()
+            .flat_map(|(&asset, balances)| balances.iter().map(move |(&participant, &amount)| (asset, participant, amount)));
+        let balances = native.chain(tokens)
+            .map(|(asset, participant, amount)| CanonicalBalance { asset, participant, amount });
+
+        let htlcs = state.htlcs.iter().map(|(&id, htlc)| CanonicalHtlc {
+            id,
+            asset: htlc.token,
+            sender: htlc.sender,
+            receiver: htlc.receiver,
+            amount: htlc.amount,
+            hash_lock: htlc.hash_lock,
+            expiration: htlc.expiration,
+        });
+
+        CanonicalState::new(state.sequence, balances, htlcs, state.escrows.clone())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +95,8 @@ pub struct HTLC {
     pub expiration: u64,
     pub sender: Address,
     pub receiver: Address,
+    #[serde(default)]
+    pub token: AssetId,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,6 +115,16 @@ pub enum DisputeStatus {
     Resolved,
 }
 
+impl From<u8> for DisputeStatus {
+    fn from(status: u8) -> Self {
+        match status {
+            1 => DisputeStatus::Initiated,
+            2 => DisputeStatus::Resolved,
+            _ => DisputeStatus::None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PendingTransaction {
     pub tx_type: TransactionType,
@@ -59,6 +139,8 @@ pub enum TransactionType {
     StateUpdate,
     DisputeInitiation,
     DisputeResolution,
+    TokenDeposit,
+    TokenRelease,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -79,6 +161,7 @@ pub struct PendingTransactionReceipt {
 pub struct ChannelRegistrationData {
     pub participants: Vec<Address>,
     pub capacity: U256,
+    pub salt: H256,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -100,4 +183,21 @@ pub struct DisputeResolutionData {
     pub channel_id: H256,
     pub final_state: ChannelState,
     pub state_hash: H256,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenDepositData {
+    pub channel_id: H256,
+    pub token: AssetId,
+    pub depositor: Address,
+    pub amount: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenReleaseData {
+    pub channel_id: H256,
+    pub token: AssetId,
+    pub recipient: Address,
+    pub amount: U256,
+    pub nonce: U256,
+}
@@ -1,35 +1,7 @@
 use ethers::types::{H256, U256};
-use sha3::{Keccak256, Digest};
 
 pub fn hash_state(state: &crate::types::ChannelState) -> H256 {
-    let mut hasher = Keccak256::new();
-    
-    // Hash sequence
-    hasher.update(&state.sequence.to_be_bytes());
-    
-    // Hash balances (sorted by address)
-    let mut balances: Vec<_> = state.balances.iter().collect();
-    balances.sort_by_key(|&(k, _)| *k);
-    
-    for (addr, balance) in balances {
-        hasher.update(addr.as_bytes());
-        hasher.update(&balance.to_be_bytes());
-    }
-    
-    // Hash HTLCs (sorted by hash)
-    let mut htlcs: Vec<_> = state.htlcs.iter().collect();
-    htlcs.sort_by_key(|&(k, _)| *k);
-    
-    for (hash, htlc) in htlcs {
-        hasher.update(hash.as_bytes());
-        hasher.update(&htlc.amount.to_be_bytes());
-        hasher.update(htlc.hash_lock.as_bytes());
-        hasher.update(&htlc.expiration.to_be_bytes());
-        hasher.update(htlc.sender.as_bytes());
-        hasher.update(htlc.receiver.as_bytes());
-    }
-    
-    H256::from_slice(&hasher.finalize())
+    state.hash()
 }
 
 pub fn verify_signature(
@@ -37,8 +9,10 @@ pub fn verify_signature(
     signature: &[u8],
     expected_signer: ethers::types::Address,
 ) -> bool {
-    // Implement signature verification
-    true // Placeholder
+    // Signed as `eth_sign` does, like the signatures BridgeCore checks
+    ethers::types::Signature::try_from(signature)
+        .and_then(|signature| signature.recover(ethers::utils::hash_message(message_hash)))
+        .is_ok_and(|signer| signer == expected_signer)
 }
 
 pub fn format_amount(amount: U256) -> String {
@@ -57,6 +31,8 @@ mod tests {
             balances: HashMap::new(),
             htlcs: HashMap::new(),
             timestamp: 12345,
+            token_balances: HashMap::new(),
+            escrows: Vec::new(),
         };
 
         let hash1 = hash_state(&state);
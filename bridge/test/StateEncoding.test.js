@@ -0,0 +1,20 @@
+const { expect } = require("chai");
+const StateEncodingHarness = artifacts.require("StateEncodingHarness");
+const { toCallArgs, readVectors } = require("../scripts/stateVectors");
+
+// The Rust crates check the same vectors, so the two sides can't drift apart
+contract("StateEncoding", () => {
+    let harness;
+
+    before(async () => {
+        harness = await StateEncodingHarness.new();
+    });
+
+    for (const vector of readVectors()) {
+        it(`matches the ${vector.name} vector`, async () => {
+            const args = toCallArgs(vector.state);
+            expect(await harness.encodeState(...args)).to.equal(vector.encoded);
+            expect(await harness.hashState(...args)).to.equal(vector.hash);
+        });
+    }
+});
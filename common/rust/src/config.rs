@@ -61,10 +61,8 @@ impl Config {
         }
 
         // Validate metrics configuration
-        if self.metrics.enabled {
-            if self.metrics.port == 0 {
-                return Err("Invalid metrics port".into());
-            }
+        if self.metrics.enabled && self.metrics.port == 0 {
+            return Err("Invalid metrics port".into());
         }
 
         Ok(())
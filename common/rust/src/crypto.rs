@@ -1,7 +1,9 @@
+use ethers::core::k256::ecdsa::signature::{Signer, Verifier};
 use ethers::core::k256::ecdsa::{SigningKey, VerifyingKey, Signature};
+use ethers::core::k256::elliptic_curve::sec1::ToEncodedPoint;
+use ethers::core::k256::ProjectivePoint;
 use ethers::types::{H256, Address};
 use sha3::{Keccak256, Digest};
-use rand::Rng;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -20,6 +22,12 @@ pub struct CryptoUtils {
     signing_key: Option<SigningKey>,
 }
 
+impl Default for CryptoUtils {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CryptoUtils {
     pub fn new() -> Self {
         Self {
@@ -30,7 +38,7 @@ impl CryptoUtils {
     pub fn generate_keypair(&mut self) -> Result<(SigningKey, VerifyingKey), CryptoError> {
         let mut rng = rand::thread_rng();
         let signing_key = SigningKey::random(&mut rng);
-        let verifying_key = signing_key.verifying_key();
+        let verifying_key = *signing_key.verifying_key();
         self.signing_key = Some(signing_key.clone());
         Ok((signing_key, verifying_key))
     }
@@ -43,7 +51,7 @@ impl CryptoUtils {
         let signing_key = self.signing_key.as_ref()
             .ok_or_else(|| CryptoError::InvalidKey("No signing key set".into()))?;
 
-        signing_key.sign(message)
+        signing_key.try_sign(message)
             .map_err(|e| CryptoError::SigningError(e.to_string()))
     }
 
@@ -76,9 +84,9 @@ impl CryptoUtils {
         public_key: &VerifyingKey,
     ) -> Result<H256, CryptoError> {
         // Implement ECDH
-        let shared_point = public_key
-            .as_affine()
-            .mul_by_scalar(private_key.as_nonzero_scalar());
+        let shared_point = (ProjectivePoint::from(*public_key.as_affine())
+            * **private_key.as_nonzero_scalar())
+            .to_affine();
 
         let shared_key = H256::from_slice(
             &Keccak256::new()
@@ -97,7 +105,7 @@ mod tests {
     #[test]
     fn test_key_generation() {
         let mut crypto = CryptoUtils::new();
-        let (signing_key, verifying_key) = crypto.generate_keypair().unwrap();
+        let (_, verifying_key) = crypto.generate_keypair().unwrap();
         
         let message = b"test message";
         let signature = crypto.sign_message(message).unwrap();
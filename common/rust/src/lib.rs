@@ -3,4 +3,5 @@ pub mod types;
 pub mod utils;
 pub mod errors;
 pub mod config;
-pub mod metrics;
\ No newline at end of file
+pub mod metrics;
+pub mod encoding;
\ No newline at end of file
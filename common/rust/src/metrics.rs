@@ -57,6 +57,12 @@ pub struct MetricsCollector {
     registry: Arc<Registry>,
 }
 
+impl Default for MetricsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MetricsCollector {
     pub fn new() -> Self {
         Self {
@@ -64,6 +70,10 @@ impl MetricsCollector {
         }
     }
 
+    pub fn registry(&self) -> Arc<Registry> {
+        Arc::clone(&self.registry)
+    }
+
     pub fn record_transaction_latency(&self, duration_secs: f64) {
         TRANSACTION_LATENCY.observe(duration_secs);
     }
@@ -1,6 +1,5 @@
 use serde::{Serialize, Deserialize};
-use ethers::types::{Address, H256, U256};
-use std::collections::HashMap;
+use ethers::types::{Address, H160, H256, U256};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkConfig {
@@ -12,14 +11,14 @@ pub struct NetworkConfig {
     pub gas_settings: GasSettings,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ContractAddresses {
     pub bridge_core: Address,
     pub channel_manager: Address,
     pub validator_set: Address,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GasSettings {
     pub max_gas_price: U256,
     pub gas_multiplier: f64,
@@ -59,4 +58,38 @@ pub struct ValidationResult {
     pub is_valid: bool,
     pub errors: Vec<String>,
     pub warnings: Vec<String>,
-}
\ No newline at end of file
+}
+
+/// Asset an amount is denominated in: an ERC-20 token contract, or the zero
+/// address for the chain's native currency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AssetId(pub Address);
+
+impl AssetId {
+    pub const NATIVE: AssetId = AssetId(H160([0u8; 20]));
+
+    pub fn token(contract: Address) -> Self {
+        Self(contract)
+    }
+
+    pub fn is_native(&self) -> bool {
+        *self == Self::NATIVE
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetInfo {
+    pub id: AssetId,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl AssetInfo {
+    pub fn native() -> Self {
+        Self {
+            id: AssetId::NATIVE,
+            symbol: "ETH".to_string(),
+            decimals: 18,
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use chrono::{DateTime, Utc};
 use ethers::types::{U256, Address};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use hex::{FromHex, ToHex};
+use hex::FromHex;
+
+use crate::types::AssetInfo;
 
 pub fn current_timestamp() -> u64 {
     SystemTime::now()
@@ -25,16 +27,24 @@ pub fn parse_address(address: &str) -> Result<Address, hex::FromHexError> {
 }
 
 pub fn format_amount(amount: U256) -> String {
-    let ether = amount / U256::from(10).pow(U256::from(18));
-    let remainder = amount % U256::from(10).pow(U256::from(18));
-    format!("{}.{:018} ETH", ether, remainder)
+    format_asset_amount(amount, &AssetInfo::native())
+}
+
+/// Formats `amount` in whole units of `asset`, using its decimals and symbol
+pub fn format_asset_amount(amount: U256, asset: &AssetInfo) -> String {
+    let unit = U256::from(10).pow(U256::from(asset.decimals));
+    let whole = amount / unit;
+    let fraction = amount % unit;
+
+    if asset.decimals == 0 {
+        format!("{} {}", whole, asset.symbol)
+    } else {
+        format!("{}.{:0width$} {}", whole, fraction, asset.symbol, width = asset.decimals as usize)
+    }
 }
 
 pub fn timestamp_to_datetime(timestamp: u64) -> DateTime<Utc> {
-    DateTime::from_utc(
-        chrono::NaiveDateTime::from_timestamp_opt(timestamp as i64, 0).unwrap(),
-        Utc,
-    )
+    DateTime::from_timestamp(timestamp as i64, 0).unwrap()
 }
 
 pub fn calculate_timeout(base_timeout: Duration, retry_count: u32) -> Duration {
@@ -67,4 +77,14 @@ mod tests {
         let formatted = format_amount(amount);
         assert!(formatted.contains("ETH"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_token_amount_formatting() {
+        let usdc = AssetInfo {
+            id: crate::types::AssetId::token(Address::random()),
+            symbol: "USDC".to_string(),
+            decimals: 6,
+        };
+        assert_eq!(format_asset_amount(U256::from(12_500_000u64), &usdc), "12.500000 USDC");
+    }
+}
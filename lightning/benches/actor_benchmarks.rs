@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use criterion::{Criterion, BenchmarkId, Throughput};
+use flashchain_lightning::channel::{
+    actor::{ActorConfig, ChannelDirectory, ChannelHandle},
+    clock::ManualClock,
+    operations::{ChannelOperation, OperationExecutor},
+    state::{ChannelState as LocalChannelState, ChannelStatus},
+};
+use flashchain_lightning::crypto::signature::SignatureVerifier;
+use tokio::sync::oneshot;
+use super::*;
+
+const CHANNELS: usize = 256;
+const TRANSFERS_PER_CHANNEL: usize = 64;
+
+fn setup_actor_channel(a: Address, b: Address) -> Channel {
+    let mut balances = HashMap::new();
+    balances.insert(a, U256::from(1_000_000));
+    balances.insert(b, U256::from(1_000_000));
+
+    Channel {
+        channel_id: H256::random(),
+        shard_id: 0,
+        participants: vec![a, b],
+        capacity: U256::from(2_000_000),
+        balance: U256::from(2_000_000),
+        state: LocalChannelState::new(balances).unwrap(),
+        status: ChannelStatus::Active,
+        nonce: 0,
+        timeout_height: u64::MAX,
+        dispute_period: 100,
+        last_update: 0,
+        bridge_channel_id: None,
+    }
+}
+
+async fn run_transfers(directory: Arc<ChannelDirectory>, handles: Vec<(ChannelHandle, Address, Address)>) {
+    let tasks: Vec<_> = handles.into_iter()
+        .map(|(handle, from, to)| {
+            tokio::spawn(async move {
+                let mut results = Vec::with_capacity(TRANSFERS_PER_CHANNEL);
+                for _ in 0..TRANSFERS_PER_CHANNEL {
+                    let (response, result) = oneshot::channel();
+                    handle.send(ChannelOperation::Transfer {
+                        channel_id: handle.channel_id(),
+                        from,
+                        to,
+                        amount: U256::from(1),
+                        idempotency_key: None,
+                        response,
+                    }).await.unwrap();
+                    results.push(result);
+                }
+                for result in results {
+                    result.await.unwrap().unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+    drop(directory);
+}
+
+/// Transfers spread over many channels should scale with the number of worker threads,
+/// since every channel is processed on its own task
+pub fn bench_actor_scaling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Channel Actors");
+    group.throughput(Throughput::Elements((CHANNELS * TRANSFERS_PER_CHANNEL) as u64));
+
+    for workers in [1, 2, 4, 8] {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::new("parallel_transfers", workers),
+            &workers,
+            |b, _| {
+                b.to_async(&runtime).iter_batched(
+                    || {
+                        let executor = OperationExecutor::new(
+                            Arc::new(SignatureVerifier::new(2)),
+                            Arc::new(ManualClock::new(0)),
+                        );
+                        let directory = Arc::new(ChannelDirectory::new(
+                            ActorConfig::default(),
+                            Arc::new(executor),
+                        ));
+                        // Actors are spawned onto the runtime being measured
+                        let _guard = runtime.enter();
+                        let handles = (0..CHANNELS)
+                            .map(|_| {
+                                let (a, b) = (Address::random(), Address::random());
+                                let handle = directory.spawn(setup_actor_channel(a, b)).unwrap();
+                                (handle, a, b)
+                            })
+                            .collect::<Vec<_>>();
+                        (directory, handles)
+                    },
+                    |(directory, handles)| run_transfers(directory, handles),
+                    criterion::BatchSize::PerIteration,
+                );
+            },
+        );
+    }
+
+    group.finish();
+}
@@ -1,13 +1,15 @@
-use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId};
+use std::collections::HashMap;
+use criterion::{criterion_group, criterion_main};
 use ethers::types::{Address, H256, U256};
 use flashchain_lightning::{
-    channel::{Channel, ChannelManager},
-    network::{NetworkManager, NetworkMessage},
-    state::{StateManager, ChannelState},
-    routing::{RoutingManager, Route},
+    channel::{state::{ChannelState, ChannelStatus}, Channel},
+    network::{peer::{PeerCapability, PeerInfo}, NetworkConfig, NetworkManager},
+    routing::{ChannelHop, Route},
 };
-use rand::Rng;
+use flashchain_common::types::AssetId;
 
+mod actor_benchmarks;
+#[path = "channel_benchmark.rs"]
 mod channel_benchmarks;
 mod network_benchmarks;
 mod routing_benchmarks;
@@ -15,6 +17,7 @@ mod state_benchmarks;
 
 criterion_group!(
     benches,
+    actor_benchmarks::bench_actor_scaling,
     channel_benchmarks::bench_channel_operations,
     network_benchmarks::bench_network_operations,
     routing_benchmarks::bench_routing_operations,
@@ -24,17 +27,31 @@ criterion_main!(benches);
 
 // Utility functions for benchmarks
 pub fn setup_test_channel() -> Channel {
-    let channel_id = H256::random();
     let participants = vec![Address::random(), Address::random()];
-    let capacity = U256::from(1_000_000);
-    
-    Channel::new(channel_id, participants, capacity).unwrap()
+    let balances = participants.iter()
+        .map(|participant| (*participant, U256::from(500_000)))
+        .collect::<HashMap<_, _>>();
+
+    Channel {
+        channel_id: H256::random(),
+        shard_id: 0,
+        participants,
+        capacity: U256::from(1_000_000),
+        balance: U256::from(1_000_000),
+        state: ChannelState::new(balances).unwrap(),
+        status: ChannelStatus::Active,
+        nonce: 0,
+        timeout_height: u64::MAX,
+        dispute_period: 100,
+        last_update: 0,
+        bridge_channel_id: None,
+    }
 }
 
 pub fn generate_random_route(num_hops: usize) -> Route {
     let mut path = Vec::with_capacity(num_hops);
     let mut channels = Vec::with_capacity(num_hops);
-    
+
     for _ in 0..num_hops {
         path.push(H256::random());
         channels.push(generate_random_channel_hop());
@@ -46,6 +63,7 @@ pub fn generate_random_route(num_hops: usize) -> Route {
         total_amount: U256::from(1_000_000),
         total_fees: U256::from(1_000),
         total_timelock: 144,
+        swaps: Vec::new(),
     }
 }
 
@@ -57,11 +75,23 @@ fn generate_random_channel_hop() -> ChannelHop {
         amount: U256::from(100_000),
         fee: U256::from(100),
         timelock: 40,
+        asset: AssetId::NATIVE,
     }
 }
 
-pub fn setup_network_with_nodes(num_nodes: usize) -> NetworkManager {
-    let mut network = NetworkManager::new(NetworkConfig {
+pub fn generate_peer_info() -> PeerInfo {
+    PeerInfo {
+        address: Address::random(),
+        endpoint: format!("127.0.0.1:{}", rand::random::<u16>()),
+        shard_id: 0,
+        version: "1.0.0".to_string(),
+        capabilities: vec![PeerCapability::FullNode],
+        last_seen: chrono::Utc::now().timestamp() as u64,
+    }
+}
+
+pub async fn setup_network_with_nodes(num_nodes: usize) -> NetworkManager {
+    let network = NetworkManager::new(NetworkConfig {
         max_peers: num_nodes * 2,
         heartbeat_interval: 60,
         connection_timeout: 30,
@@ -70,24 +100,16 @@ pub fn setup_network_with_nodes(num_nodes: usize) -> NetworkManager {
     });
 
     for _ in 0..num_nodes {
-        let address = Address::random();
-        network.connect_peer(PeerInfo {
-            address,
-            endpoint: format!("127.0.0.1:{}", rand::random::<u16>()),
-            shard_id: 0,
-            version: "1.0.0".to_string(),
-            capabilities: vec![PeerCapability::FullNode],
-            last_seen: chrono::Utc::now().timestamp() as u64,
-        }).await.unwrap();
+        network.connect_peer(generate_peer_info()).await.unwrap();
     }
 
     network
 }
 
-pub fn generate_random_payment_hash() -> H256 {
+pub fn generate_random_preimage() -> H256 {
     H256::random()
 }
 
-pub fn generate_random_preimage() -> H256 {
-    H256::random()
-}
\ No newline at end of file
+pub fn payment_hash(preimage: H256) -> H256 {
+    H256::from(ethers::utils::keccak256(preimage.as_bytes()))
+}
@@ -1,4 +1,7 @@
-use criterion::{Criterion, BenchmarkId};
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use flashchain_lightning::crypto::signature::{channel_state_message, recover_signer};
+use flashchain_lightning::crypto::signer::SignRequest;
+use flashchain_lightning::crypto::CryptoManager;
 use super::*;
 
 pub fn bench_channel_operations(c: &mut Criterion) {
@@ -6,85 +9,70 @@ pub fn bench_channel_operations(c: &mut Criterion) {
 
     // Benchmark channel creation
     group.bench_function("channel_creation", |b| {
-        b.iter(|| {
-            setup_test_channel();
-        });
+        b.iter(setup_test_channel);
     });
 
-    // Benchmark state updates
-    group.bench_function("state_update", |b| {
-        let mut channel = setup_test_channel();
-        let update = generate_random_state_update();
-        b.iter(|| {
-            channel.update_state(update.clone()).unwrap();
-        });
+    // Benchmark transfers
+    group.bench_function("transfer", |b| {
+        b.iter_batched(
+            setup_test_channel,
+            |mut channel| {
+                let (from, to) = (channel.participants[0], channel.participants[1]);
+                channel.state.transfer(from, to, U256::from(1)).unwrap();
+            },
+            BatchSize::SmallInput,
+        );
     });
 
-    // Benchmark HTLC creation with different amounts
+    // Benchmark lock creation with different amounts
     let amounts = vec![1000, 10000, 100000];
     for amount in amounts {
         group.bench_with_input(
-            BenchmarkId::new("htlc_creation", amount),
+            BenchmarkId::new("lock_creation", amount),
             &amount,
             |b, &amount| {
-                let mut channel = setup_test_channel();
-                let payment_hash = generate_random_payment_hash();
-                b.iter(|| {
-                    channel.create_htlc(
-                        channel.participants[0],
-                        channel.participants[1],
-                        U256::from(amount),
-                        payment_hash,
-                        100,
-                    ).unwrap();
-                });
+                let secret_hash = payment_hash(generate_random_preimage());
+                b.iter_batched(
+                    setup_test_channel,
+                    |mut channel| {
+                        let (sender, recipient) = (channel.participants[0], channel.participants[1]);
+                        channel.state.create_lock(sender, recipient, U256::from(amount), 100, secret_hash).unwrap();
+                    },
+                    BatchSize::SmallInput,
+                );
             },
         );
     }
 
-    // Benchmark HTLC fulfillment
-    group.bench_function("htlc_fulfillment", |b| {
-        let mut channel = setup_test_channel();
-        let payment_hash = generate_random_payment_hash();
-        let preimage = generate_random_preimage();
-        let htlc_id = channel.create_htlc(
-            channel.participants[0],
-            channel.participants[1],
-            U256::from(1000),
-            payment_hash,
-            100,
-        ).unwrap();
-        
-        b.iter(|| {
-            channel.fulfill_htlc(htlc_id, preimage).unwrap();
-        });
+    // Benchmark unlocking with the secret
+    group.bench_function("unlock", |b| {
+        let secret = generate_random_preimage();
+        b.iter_batched(
+            || {
+                let mut channel = setup_test_channel();
+                let (sender, recipient) = (channel.participants[0], channel.participants[1]);
+                let lock_id = channel.state
+                    .create_lock(sender, recipient, U256::from(1000), 100, payment_hash(secret))
+                    .unwrap();
+                (channel, lock_id)
+            },
+            |(mut channel, lock_id)| channel.state.unlock(lock_id, secret).unwrap(),
+            BatchSize::SmallInput,
+        );
     });
 
-    // Benchmark signature verification
-    group.bench_function("signature_verification", |b| {
+    // Benchmark recovering the signer of a channel state
+    group.bench_function("signature_recovery", |b| {
         let channel = setup_test_channel();
-        let state_hash = H256::random();
-        let signature = vec![0u8; 65]; // Mock signature
-        
+        let mut keys = CryptoManager::new();
+        let signer = keys.generate_keypair().unwrap();
+        let message = channel_state_message(channel.channel_id, channel.state.sequence_number, channel.state.state_hash());
+        let signature = keys.sign_locally(&signer, &SignRequest::Message(message.as_bytes().to_vec())).unwrap();
+
         b.iter(|| {
-            channel.verify_signature(
-                &channel.participants[0],
-                &state_hash,
-                &signature,
-            );
+            assert_eq!(recover_signer(message.as_bytes(), &signature).unwrap(), signer);
         });
     });
 
     group.finish();
 }
-
-fn generate_random_state_update() -> StateUpdate {
-    StateUpdate {
-        channel_id: H256::random(),
-        sequence: rand::random::<u64>(),
-        timestamp: chrono::Utc::now().timestamp() as u64,
-        previous_state: H256::random(),
-        new_state: H256::random(),
-        signatures: HashMap::new(),
-    }
-}
\ No newline at end of file
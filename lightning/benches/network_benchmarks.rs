@@ -1,8 +1,10 @@
-use criterion::{Criterion, BenchmarkId};
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use flashchain_lightning::network::topology::NetworkTopology;
 use super::*;
 
 pub fn bench_network_operations(c: &mut Criterion) {
     let mut group = c.benchmark_group("Network Operations");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
 
     // Benchmark peer connection
     let node_counts = vec![10, 100, 1000];
@@ -11,44 +13,26 @@ pub fn bench_network_operations(c: &mut Criterion) {
             BenchmarkId::new("peer_connection", count),
             &count,
             |b, &count| {
-                let network = setup_network_with_nodes(count);
-                let peer_info = PeerInfo {
-                    address: Address::random(),
-                    endpoint: "127.0.0.1:8545".to_string(),
-                    shard_id: 0,
-                    version: "1.0.0".to_string(),
-                    capabilities: vec![PeerCapability::FullNode],
-                    last_seen: chrono::Utc::now().timestamp() as u64,
-                };
-                
-                b.iter(|| {
-                    network.connect_peer(peer_info.clone());
-                });
+                b.to_async(&runtime).iter_batched(
+                    || (runtime.block_on(setup_network_with_nodes(count)), generate_peer_info()),
+                    |(network, peer_info)| async move {
+                        network.connect_peer(peer_info).await.unwrap();
+                    },
+                    BatchSize::PerIteration,
+                );
             },
         );
     }
 
-    // Benchmark message broadcasting
+    // Benchmark shard route finding
     for &count in &node_counts {
         group.bench_with_input(
-            BenchmarkId::new("message_broadcast", count),
+            BenchmarkId::new("shard_route", count),
             &count,
             |b, &count| {
-                let network = setup_network_with_nodes(count);
-                let message = NetworkMessage::Heartbeat {
-                    peer_address: Address::random(),
-                    timestamp: chrono::Utc::now().timestamp() as u64,
-                    metrics: PeerMetrics {
-                        channels_count: 10,
-                        active_transfers: 5,
-                        pending_messages: 2,
-                        bandwidth_usage: 100.0,
-                        latency_ms: 50,
-                    },
-                };
-                
-                b.iter(|| {
-                    network.broadcast_message(message.clone());
+                let topology = runtime.block_on(setup_shard_line(count as u64));
+                b.to_async(&runtime).iter(|| async {
+                    topology.find_route(0, count as u64 - 1).await.unwrap();
                 });
             },
         );
@@ -56,11 +40,17 @@ pub fn bench_network_operations(c: &mut Criterion) {
 
     // Benchmark topology updates
     group.bench_function("topology_optimization", |b| {
-        let network = setup_network_with_nodes(100);
-        b.iter(|| {
-            network.optimize_topology();
-        });
+        let mut topology = runtime.block_on(setup_shard_line(100));
+        b.iter(|| runtime.block_on(topology.optimize()).unwrap());
     });
 
     group.finish();
-}
\ No newline at end of file
+}
+
+async fn setup_shard_line(shards: u64) -> NetworkTopology {
+    let mut topology = NetworkTopology::new();
+    for shard in 1..shards {
+        topology.establish_connection(shard - 1, shard, 1000.0, 50).await.unwrap();
+    }
+    topology
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use ethers::types::H256;
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use super::batching::{PendingTransfer, TransferBatcher};
+use super::idempotency::{self, IdempotencyConfig, IdempotencyStore};
+use super::operations::{ChannelOperation, OperationError, OperationExecutor};
+use super::Channel;
+
+#[derive(Error, Debug)]
+pub enum ActorError {
+    #[error("No actor for channel {0}")]
+    NotFound(H256),
+    #[error("Mailbox of channel {0} is full")]
+    MailboxFull(H256),
+    #[error("Actor for channel {0} stopped")]
+    Stopped(H256),
+    #[error("Actor for channel {0} already running")]
+    AlreadyRunning(H256),
+}
+
+#[derive(Debug, Clone)]
+pub struct ActorConfig {
+    /// Number of directory shards, rounded up to a power of two
+    pub shards: usize,
+    /// Messages a channel can have queued before senders wait
+    pub mailbox_capacity: usize,
+}
+
+impl Default for ActorConfig {
+    fn default() -> Self {
+        Self {
+            shards: 64,
+            mailbox_capacity: 256,
+        }
+    }
+}
+
+/// Applies an update to the channel, returning whether it succeeded
+type ChannelUpdate = Box<dyn FnOnce(&mut Channel) -> bool + Send>;
+
+#[allow(clippy::large_enum_variant)]
+enum ActorMessage {
+    Operation(ChannelOperation),
+    Update(ChannelUpdate),
+    Snapshot(oneshot::Sender<Channel>),
+    Stop(oneshot::Sender<Channel>),
+}
+
+/// Owns a single channel and applies every message to it in arrival order
+struct ChannelActor {
+    channel: Channel,
+    mailbox: mpsc::Receiver<ActorMessage>,
+    executor: Arc<OperationExecutor>,
+    batcher: Option<Arc<TransferBatcher>>,
+    changes: Option<broadcast::Sender<H256>>,
+    idempotency: Arc<IdempotencyStore>,
+}
+
+impl ChannelActor {
+    async fn run(mut self) {
+        let flush_delay = self.batcher.as_ref()
+            .map(|batcher| batcher.config().max_delay)
+            .unwrap_or(Duration::from_secs(1));
+        let mut flush = tokio::time::interval(flush_delay);
+
+        loop {
+            tokio::select! {
+                message = self.mailbox.recv() => {
+                    let Some(message) = message else { break };
+                    match message {
+                        ActorMessage::Operation(operation) => {
+                            // Retries of an operation that already ran are answered from the table
+                            let Some(operation) = idempotency::guard(&self.idempotency, operation).await else {
+                                continue;
+                            };
+                            let nonce = self.channel.nonce;
+                            self.execute(operation).await;
+                            if self.channel.nonce != nonce {
+                                self.publish_change();
+                            }
+                        }
+                        // Queued transfers go first, so everything applies in arrival order
+                        ActorMessage::Update(update) => {
+                            self.flush().await;
+                            if update(&mut self.channel) {
+                                self.publish_change();
+                            }
+                        }
+                        ActorMessage::Snapshot(reply) => {
+                            self.flush().await;
+                            let _ = reply.send(self.channel.clone());
+                        }
+                        ActorMessage::Stop(reply) => {
+                            self.flush().await;
+                            let _ = reply.send(self.channel);
+                            return;
+                        }
+                    }
+                }
+                _ = flush.tick(), if self.batcher.is_some() => self.flush().await,
+            }
+        }
+
+        self.flush().await;
+    }
+
+    async fn execute(&mut self, operation: ChannelOperation) {
+        let Some(batcher) = self.batcher.clone() else {
+            return self.executor.execute(&mut self.channel, operation).await;
+        };
+
+        match operation {
+            ChannelOperation::Transfer { channel_id, from, to, amount, response, .. } => {
+                let transfer = PendingTransfer { from, to, amount, response };
+                if let Some(batch) = batcher.push(channel_id, transfer).await {
+                    self.apply_batch(&batcher, batch).await;
+                }
+            }
+            operation => {
+                self.flush().await;
+                self.executor.execute(&mut self.channel, operation).await;
+            }
+        }
+    }
+
+    /// Settles the channel's waiting batch, whether or not it filled up
+    async fn flush(&mut self) {
+        let Some(batcher) = self.batcher.clone() else { return };
+        if let Some(batch) = batcher.take(self.channel.channel_id).await {
+            self.apply_batch(&batcher, batch).await;
+        }
+    }
+
+    async fn apply_batch(&mut self, batcher: &TransferBatcher, batch: Vec<PendingTransfer>) {
+        let outcome = batcher.apply(&self.executor, &mut self.channel, batch).await;
+        log::debug!(
+            "Batch on channel {}: {} accepted, {} rejected",
+            outcome.channel_id, outcome.accepted, outcome.rejected
+        );
+        if outcome.state_update_hash.is_some() {
+            self.publish_change();
+        }
+    }
+
+    fn publish_change(&self) {
+        if let Some(changes) = &self.changes {
+            let _ = changes.send(self.channel.channel_id);
+        }
+    }
+}
+
+/// Cheap, cloneable address of a running channel actor
+#[derive(Clone)]
+pub struct ChannelHandle {
+    channel_id: H256,
+    mailbox: mpsc::Sender<ActorMessage>,
+}
+
+impl ChannelHandle {
+    pub fn channel_id(&self) -> H256 {
+        self.channel_id
+    }
+
+    /// Queues an operation, waiting while the mailbox is full
+    pub async fn send(&self, operation: ChannelOperation) -> Result<(), ActorError> {
+        self.mailbox.send(ActorMessage::Operation(operation)).await
+            .map_err(|_| ActorError::Stopped(self.channel_id))
+    }
+
+    /// Queues an operation, rejecting it straight away if the mailbox is full
+    pub fn try_send(&self, operation: ChannelOperation) -> Result<(), ActorError> {
+        match self.mailbox.try_send(ActorMessage::Operation(operation)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(ActorMessage::Operation(operation))) => {
+                operation.reject(OperationError::Rejected("Channel busy".to_string()));
+                Err(ActorError::MailboxFull(self.channel_id))
+            }
+            Err(_) => Err(ActorError::Stopped(self.channel_id)),
+        }
+    }
+
+    /// Runs `update` on the actor's task, serialized with all other messages for the
+    /// channel. The change is only published if `update` succeeds.
+    pub async fn update<T, E, F>(&self, update: F) -> Result<Result<T, E>, ActorError>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(&mut Channel) -> Result<T, E> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let update: ChannelUpdate = Box::new(move |channel| {
+            let result = update(channel);
+            let succeeded = result.is_ok();
+            let _ = reply.send(result);
+            succeeded
+        });
+
+        self.mailbox.send(ActorMessage::Update(update)).await
+            .map_err(|_| ActorError::Stopped(self.channel_id))?;
+        result.await.map_err(|_| ActorError::Stopped(self.channel_id))
+    }
+
+    pub async fn snapshot(&self) -> Result<Channel, ActorError> {
+        let (reply, result) = oneshot::channel();
+        self.mailbox.send(ActorMessage::Snapshot(reply)).await
+            .map_err(|_| ActorError::Stopped(self.channel_id))?;
+        result.await.map_err(|_| ActorError::Stopped(self.channel_id))
+    }
+
+    async fn stop(&self) -> Result<Channel, ActorError> {
+        let (reply, result) = oneshot::channel();
+        self.mailbox.send(ActorMessage::Stop(reply)).await
+            .map_err(|_| ActorError::Stopped(self.channel_id))?;
+        result.await.map_err(|_| ActorError::Stopped(self.channel_id))
+    }
+}
+
+/// Sharded map from channel id to actor handle. Shard locks are only held
+/// for the lookup itself, never while a channel is being worked on.
+pub struct ChannelDirectory {
+    shards: Vec<RwLock<HashMap<H256, ChannelHandle>>>,
+    executor: Arc<OperationExecutor>,
+    batcher: Option<Arc<TransferBatcher>>,
+    changes: Option<broadcast::Sender<H256>>,
+    idempotency: Arc<IdempotencyStore>,
+    mailbox_capacity: usize,
+}
+
+impl ChannelDirectory {
+    pub fn new(config: ActorConfig, executor: Arc<OperationExecutor>) -> Self {
+        let shard_count = config.shards.max(1).next_power_of_two();
+
+        Self {
+            shards: (0..shard_count).map(|_| RwLock::new(HashMap::new())).collect(),
+            executor,
+            batcher: None,
+            changes: None,
+            idempotency: Arc::new(IdempotencyStore::new(IdempotencyConfig::default())),
+            mailbox_capacity: config.mailbox_capacity.max(1),
+        }
+    }
+
+    /// Replaces the default idempotency table, which is kept in memory only
+    pub fn with_idempotency(mut self, store: Arc<IdempotencyStore>) -> Self {
+        self.idempotency = store;
+        self
+    }
+
+    /// Has every actor send its channel's id on `changes` whenever it changes the channel
+    pub fn with_changes(mut self, changes: broadcast::Sender<H256>) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    /// Has every actor collect its transfers into batches that settle as one
+    /// state update signed by all participants
+    pub fn with_batching(mut self, batcher: Arc<TransferBatcher>) -> Self {
+        self.batcher = Some(batcher);
+        self
+    }
+
+    /// Moves `channel` into its own task and returns its handle
+    pub fn spawn(&self, channel: Channel) -> Result<ChannelHandle, ActorError> {
+        let channel_id = channel.channel_id;
+        let mut shard = self.shard(channel_id).write()
+            .expect("channel directory shard poisoned");
+        if shard.contains_key(&channel_id) {
+            return Err(ActorError::AlreadyRunning(channel_id));
+        }
+
+        let (mailbox_tx, mailbox_rx) = mpsc::channel(self.mailbox_capacity);
+        let actor = ChannelActor {
+            channel,
+            mailbox: mailbox_rx,
+            executor: Arc::clone(&self.executor),
+            batcher: self.batcher.clone(),
+            changes: self.changes.clone(),
+            idempotency: Arc::clone(&self.idempotency),
+        };
+        tokio::spawn(actor.run());
+
+        let handle = ChannelHandle {
+            channel_id,
+            mailbox: mailbox_tx,
+        };
+        shard.insert(channel_id, handle.clone());
+
+        Ok(handle)
+    }
+
+    pub fn get(&self, channel_id: H256) -> Option<ChannelHandle> {
+        self.shard(channel_id).read()
+            .expect("channel directory shard poisoned")
+            .get(&channel_id)
+            .cloned()
+    }
+
+    /// Stops the actor and hands back the channel it owned
+    pub async fn remove(&self, channel_id: H256) -> Result<Channel, ActorError> {
+        let handle = self.shard(channel_id).write()
+            .expect("channel directory shard poisoned")
+            .remove(&channel_id)
+            .ok_or(ActorError::NotFound(channel_id))?;
+
+        handle.stop().await
+    }
+
+    pub async fn dispatch(&self, operation: ChannelOperation) -> Result<(), ActorError> {
+        let channel_id = operation.channel_id();
+        match self.get(channel_id) {
+            Some(handle) => handle.send(operation).await,
+            None => {
+                operation.reject(OperationError::ChannelError("Channel not found".to_string()));
+                Err(ActorError::NotFound(channel_id))
+            }
+        }
+    }
+
+    pub fn try_dispatch(&self, operation: ChannelOperation) -> Result<(), ActorError> {
+        let channel_id = operation.channel_id();
+        match self.get(channel_id) {
+            Some(handle) => handle.try_send(operation),
+            None => {
+                operation.reject(OperationError::ChannelError("Channel not found".to_string()));
+                Err(ActorError::NotFound(channel_id))
+            }
+        }
+    }
+
+    pub fn channel_ids(&self) -> Vec<H256> {
+        self.shards.iter()
+            .flat_map(|shard| {
+                shard.read()
+                    .expect("channel directory shard poisoned")
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter()
+            .map(|shard| shard.read().expect("channel directory shard poisoned").len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, channel_id: H256) -> &RwLock<HashMap<H256, ChannelHandle>> {
+        // Channel ids are hashes, so their low bytes are already uniformly distributed
+        let bytes = channel_id.as_bytes();
+        let index = u64::from_be_bytes(bytes[24..32].try_into().expect("slice of 8 bytes"));
+        &self.shards[(index as usize) & (self.shards.len() - 1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::clock::ManualClock;
+    use crate::channel::test_channel;
+    use crate::channel::operations::TransferResult;
+    use crate::channel::batching::BatchConfig;
+    use crate::crypto::signature::SignatureVerifier;
+    use crate::crypto::signer::{LocalSigner, SigningPolicy};
+    use crate::crypto::CryptoManager;
+    use ethers::types::{Address, U256};
+
+    fn test_directory(mailbox_capacity: usize) -> ChannelDirectory {
+        let executor = OperationExecutor::new(
+            Arc::new(SignatureVerifier::new(2)),
+            Arc::new(ManualClock::new(0)),
+        );
+
+        ChannelDirectory::new(
+            ActorConfig { shards: 4, mailbox_capacity },
+            Arc::new(executor),
+        )
+        .with_idempotency(Arc::new(IdempotencyStore::in_memory(16)))
+    }
+
+    async fn transfer(
+        handle: &ChannelHandle,
+        from: Address,
+        to: Address,
+    ) -> oneshot::Receiver<Result<TransferResult, OperationError>> {
+        let (response, result) = oneshot::channel();
+        handle.send(ChannelOperation::Transfer {
+            channel_id: handle.channel_id(),
+            from,
+            to,
+            amount: U256::from(1),
+            idempotency_key: None,
+            response,
+        }).await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn test_operations_applied_in_order() {
+        let directory = test_directory(16);
+        let (a, b) = (Address::random(), Address::random());
+        let handle = directory.spawn(test_channel(a, 1000, b, 1000)).unwrap();
+
+        let mut results = Vec::new();
+        for _ in 0..10 {
+            results.push(transfer(&handle, a, b).await);
+        }
+        for (i, result) in results.into_iter().enumerate() {
+            let new_state = result.await.unwrap().unwrap().new_state;
+            assert_eq!(new_state.get_participant_balance(&b), U256::from(1001 + i));
+        }
+
+        let channel = directory.remove(handle.channel_id()).await.unwrap();
+        assert_eq!(channel.nonce, 10);
+        assert!(directory.get(handle.channel_id()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retried_operation_applied_once() {
+        let directory = test_directory(16);
+        let (a, b) = (Address::random(), Address::random());
+        let handle = directory.spawn(test_channel(a, 1000, b, 1000)).unwrap();
+        let key = H256::random();
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let (response, result) = oneshot::channel();
+            handle.send(ChannelOperation::Transfer {
+                channel_id: handle.channel_id(),
+                from: a,
+                to: b,
+                amount: U256::from(1),
+                idempotency_key: Some(key),
+                response,
+            }).await.unwrap();
+            results.push(result.await.unwrap().unwrap());
+        }
+
+        assert_eq!(results[0].transaction_hash, results[1].transaction_hash);
+        assert_eq!(handle.snapshot().await.unwrap().nonce, 1);
+    }
+
+    #[tokio::test]
+    async fn test_channels_isolated() {
+        let directory = test_directory(16);
+        let (a, b) = (Address::random(), Address::random());
+        let first = directory.spawn(test_channel(a, 1000, b, 1000)).unwrap();
+        let second = directory.spawn(test_channel(a, 1000, b, 1000)).unwrap();
+
+        transfer(&first, a, b).await.await.unwrap().unwrap();
+
+        assert_eq!(first.snapshot().await.unwrap().nonce, 1);
+        assert_eq!(second.snapshot().await.unwrap().nonce, 0);
+        assert_eq!(directory.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_channel_rejected() {
+        let directory = test_directory(16);
+        let (response, result) = oneshot::channel();
+
+        let dispatched = directory.dispatch(ChannelOperation::Transfer {
+            channel_id: H256::random(),
+            from: Address::random(),
+            to: Address::random(),
+            amount: U256::from(1),
+            idempotency_key: None,
+            response,
+        }).await;
+
+        assert!(matches!(dispatched, Err(ActorError::NotFound(_))));
+        assert!(result.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfers_settle_in_countersigned_batches() {
+        let mut crypto = CryptoManager::new();
+        let a = crypto.generate_keypair().unwrap();
+        let mut counterparty = CryptoManager::new();
+        let b = counterparty.generate_keypair().unwrap();
+        let batcher = TransferBatcher::new(
+            BatchConfig { max_batch_size: 2, max_delay: Duration::from_secs(60) },
+            Arc::new(crypto),
+            a,
+            Arc::new(LocalSigner::new(counterparty, SigningPolicy::default())),
+        );
+        let directory = test_directory(16).with_batching(Arc::new(batcher));
+        let handle = directory.spawn(test_channel(a, 1000, b, 1000)).unwrap();
+
+        let first = transfer(&handle, a, b).await;
+        let second = transfer(&handle, a, b).await;
+        let first = first.await.unwrap().unwrap();
+        let second = second.await.unwrap().unwrap();
+
+        assert_eq!(first.transaction_hash, second.transaction_hash);
+        let channel = handle.snapshot().await.unwrap();
+        assert_eq!(channel.state.sequence_number, 1);
+        assert_eq!(channel.state.get_participant_balance(&b), U256::from(1002));
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_published() {
+        let (changes, mut changed) = broadcast::channel(16);
+        let directory = test_directory(16).with_changes(changes);
+        let (a, b) = (Address::random(), Address::random());
+        let handle = directory.spawn(test_channel(a, 1000, b, 1000)).unwrap();
+
+        transfer(&handle, a, b).await.await.unwrap().unwrap();
+        assert_eq!(changed.recv().await.unwrap(), handle.channel_id());
+
+        // A rejected operation leaves the channel as it was
+        let (response, result) = oneshot::channel();
+        handle.send(ChannelOperation::Transfer {
+            channel_id: handle.channel_id(),
+            from: a,
+            to: b,
+            amount: U256::from(1_000_000),
+            idempotency_key: None,
+            response,
+        }).await.unwrap();
+        assert!(result.await.unwrap().is_err());
+        handle.snapshot().await.unwrap();
+        assert!(changed.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_is_serialized() {
+        let directory = test_directory(16);
+        let handle = directory.spawn(test_channel(Address::random(), 1000, Address::random(), 1000)).unwrap();
+
+        let nonce = handle.update(|channel| {
+            channel.nonce += 5;
+            Ok::<_, ()>(channel.nonce)
+        }).await.unwrap();
+
+        assert_eq!(nonce, Ok(5));
+        assert_eq!(handle.snapshot().await.unwrap().nonce, 5);
+    }
+
+    #[tokio::test]
+    async fn test_failed_update_is_not_published() {
+        let (changes, mut changed) = broadcast::channel(16);
+        let directory = test_directory(16).with_changes(changes);
+        let handle = directory.spawn(test_channel(Address::random(), 1000, Address::random(), 1000)).unwrap();
+
+        let result = handle.update(|_| Err::<(), _>("rejected")).await.unwrap();
+        assert_eq!(result, Err("rejected"));
+        handle.snapshot().await.unwrap();
+        assert!(changed.try_recv().is_err());
+
+        handle.update(|channel| {
+            channel.nonce += 1;
+            Ok::<_, ()>(())
+        }).await.unwrap().unwrap();
+        assert_eq!(changed.recv().await.unwrap(), handle.channel_id());
+    }
+}
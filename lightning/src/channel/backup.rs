@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use ethers::types::{Address, Signature, H256, U256};
+use flashchain_bridge::types::ChannelState as BridgeChannelState;
+use flashchain_bridge::BridgeManager;
+use flashchain_common::encoding::CanonicalState;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::sync::{broadcast, RwLock};
+
+use super::state::{ChannelState, ChannelStatus};
+use super::{Channel, ChannelError, ChannelManager};
+use crate::crypto::signature::channel_state_message;
+use crate::crypto::{CryptoError, CryptoManager};
+use crate::network::peer::PeerInfo;
+use crate::network::{MessageHandler, MessageRoute, NetworkError, NetworkManager, NetworkMessage};
+
+const BACKUP_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Channel error: {0}")]
+    Channel(#[from] ChannelError),
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("Bridge error: {0}")]
+    Bridge(String),
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    #[error("Unsupported backup version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Backup belongs to {0}")]
+    WrongNode(Address),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+    #[error("Invalid peer state: {0}")]
+    InvalidPeerState(String),
+}
+
+/// Everything needed to find a channel again and ask the peer to close it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelBackup {
+    pub channel_id: H256,
+    pub shard_id: u64,
+    pub participants: Vec<Address>,
+    pub capacity: U256,
+    pub dispute_period: u64,
+    pub bridge_contract: Address,
+    /// Id the channel is registered under on chain, if it got that far
+    #[serde(default)]
+    pub bridge_channel_id: Option<H256>,
+    /// State the channel was in when the backup was taken. A peer can't close the
+    /// recovered channel on anything older, or on anything that takes our funds.
+    #[serde(default)]
+    pub latest_state: Option<CanonicalState>,
+}
+
+impl ChannelBackup {
+    pub fn bridge_id(&self) -> H256 {
+        self.bridge_channel_id.unwrap_or(self.channel_id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StaticBackup {
+    pub version: u32,
+    pub node_address: Address,
+    pub created_at: u64,
+    pub channels: Vec<ChannelBackup>,
+    pub peers: Vec<PeerInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedBackup {
+    version: u32,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl StaticBackup {
+    pub fn encrypt(&self, key: &[u8; 32]) -> Result<Vec<u8>, BackupError> {
+        let plaintext = serde_json::to_vec(self)
+            .map_err(|e| BackupError::Encoding(e.to_string()))?;
+
+        let nonce: [u8; 12] = rand::random();
+        let cipher = Aes256Gcm::new(key.into());
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|e| BackupError::Encryption(e.to_string()))?;
+
+        serde_json::to_vec(&EncryptedBackup {
+            version: BACKUP_VERSION,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        }).map_err(|e| BackupError::Encoding(e.to_string()))
+    }
+
+    pub fn decrypt(blob: &[u8], key: &[u8; 32]) -> Result<Self, BackupError> {
+        let encrypted: EncryptedBackup = serde_json::from_slice(blob)
+            .map_err(|e| BackupError::Encoding(e.to_string()))?;
+        if encrypted.version != BACKUP_VERSION {
+            return Err(BackupError::UnsupportedVersion(encrypted.version));
+        }
+        if encrypted.nonce.len() != 12 {
+            return Err(BackupError::Encryption("Invalid nonce length".into()));
+        }
+
+        let cipher = Aes256Gcm::new(key.into());
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&encrypted.nonce), encrypted.ciphertext.as_slice())
+            .map_err(|e| BackupError::Encryption(e.to_string()))?;
+
+        serde_json::from_slice(&plaintext).map_err(|e| BackupError::Encoding(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RecoveryStatus {
+    /// Reestablish sent, waiting for the peer's latest signed state
+    AwaitingPeerState,
+    /// Already closed on chain, nothing to do
+    AlreadyClosed,
+    /// Latest state submitted to the bridge, dispute window running
+    CloseSubmitted { tx_hash: H256 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    pub backup: ChannelBackup,
+    pub status: RecoveryStatus,
+    /// Latest state the peers sent, with the signature of each peer that sent it
+    pub state: Option<ChannelState>,
+    pub signatures: HashMap<Address, Vec<u8>>,
+}
+
+impl Recovery {
+    fn new(backup: ChannelBackup, status: RecoveryStatus) -> Self {
+        Self {
+            backup,
+            status,
+            state: None,
+            signatures: HashMap::new(),
+        }
+    }
+}
+
+/// Keeps an encrypted static backup of every channel on disk and drives
+/// channels found in a restored backup to a safe close through the bridge
+pub struct BackupManager {
+    channel_manager: Arc<ChannelManager>,
+    bridge: Arc<BridgeManager>,
+    network: Arc<NetworkManager>,
+    crypto: Arc<CryptoManager>,
+    node_address: Address,
+    path: PathBuf,
+    recoveries: RwLock<HashMap<H256, Recovery>>,
+}
+
+impl BackupManager {
+    pub fn new(
+        channel_manager: Arc<ChannelManager>,
+        bridge: Arc<BridgeManager>,
+        network: Arc<NetworkManager>,
+        crypto: Arc<CryptoManager>,
+        node_address: Address,
+        path: PathBuf,
+    ) -> Self {
+        Self {
+            channel_manager,
+            bridge,
+            network,
+            crypto,
+            node_address,
+            path,
+            recoveries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn snapshot(&self) -> Result<StaticBackup, BackupError> {
+        let bridge_contract = self.bridge.bridge_address();
+        let channels: Vec<Channel> = self.channel_manager.list_channels().await?
+            .into_iter()
+            .filter(|channel| channel.status != ChannelStatus::Closed)
+            .collect();
+
+        let mut peers: HashMap<Address, PeerInfo> = HashMap::new();
+        for channel in &channels {
+            for participant in &channel.participants {
+                if *participant == self.node_address || peers.contains_key(participant) {
+                    continue;
+                }
+                if let Ok(info) = self.network.get_peer_info(*participant).await {
+                    peers.insert(*participant, info);
+                }
+            }
+        }
+
+        Ok(StaticBackup {
+            version: BACKUP_VERSION,
+            node_address: self.node_address,
+            created_at: chrono::Utc::now().timestamp() as u64,
+            channels: channels.iter()
+                .map(|channel| ChannelBackup {
+                    channel_id: channel.channel_id,
+                    shard_id: channel.shard_id,
+                    participants: channel.participants.clone(),
+                    capacity: channel.capacity,
+                    dispute_period: channel.dispute_period,
+                    bridge_contract,
+                    bridge_channel_id: channel.bridge_channel_id,
+                    latest_state: Some(CanonicalState::from(&channel.state)),
+                })
+                .collect(),
+            peers: peers.into_values().collect(),
+        })
+    }
+
+    pub async fn export(&self) -> Result<Vec<u8>, BackupError> {
+        let key = self.crypto.derive_backup_key()?;
+        self.snapshot().await?.encrypt(&key)
+    }
+
+    /// Writes the backup next to its final path first so a crash never leaves a torn file
+    pub async fn write_backup(&self) -> Result<(), BackupError> {
+        let blob = self.export().await?;
+        let tmp_path = self.path.with_extension("tmp");
+
+        tokio::fs::write(&tmp_path, &blob).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+
+        Ok(())
+    }
+
+    /// Takes the reestablish messages the network receives and regenerates the
+    /// backup every time a channel changes
+    pub async fn start(self: Arc<Self>) -> Result<(), BackupError> {
+        self.network.register_handler(MessageRoute::Reestablish, self.clone());
+
+        let mut changes = self.channel_manager.subscribe_changes();
+        self.write_backup().await?;
+
+        tokio::spawn(async move {
+            while let Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) = changes.recv().await {
+                if let Err(e) = self.write_backup().await {
+                    log::error!("Failed to write static channel backup: {:?}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub fn restore(&self, blob: &[u8]) -> Result<StaticBackup, BackupError> {
+        let key = self.crypto.derive_backup_key()?;
+        let backup = StaticBackup::decrypt(blob, &key)?;
+        if backup.node_address != self.node_address {
+            return Err(BackupError::WrongNode(backup.node_address));
+        }
+
+        Ok(backup)
+    }
+
+    /// Reconnects to the peers in the backup and asks each of them for the
+    /// latest state of every channel still open on chain
+    pub async fn recover(&self, backup: StaticBackup) -> Result<(), BackupError> {
+        for peer in backup.peers {
+            let address = peer.address;
+            if let Err(e) = self.network.connect_peer(peer).await {
+                log::warn!("Failed to reconnect to {:?}: {:?}", address, e);
+            }
+        }
+
+        for channel in backup.channels {
+            let on_chain = self.bridge.get_channel(channel.bridge_id()).await
+                .map_err(|e| BackupError::Bridge(e.to_string()))?;
+
+            if !on_chain.is_active {
+                self.recoveries.write().await.insert(
+                    channel.channel_id,
+                    Recovery::new(channel, RecoveryStatus::AlreadyClosed),
+                );
+                continue;
+            }
+
+            self.reestablish(channel).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Asks the other participants of a channel still open on chain for its latest state
+    pub async fn reestablish(&self, channel: ChannelBackup) -> Result<(), BackupError> {
+        let channel_id = channel.channel_id;
+        for participant in channel.participants.iter().filter(|p| **p != self.node_address) {
+            self.network.send_message(*participant, NetworkMessage::ChannelReestablish {
+                channel_id,
+                sender: self.node_address,
+                data_loss: true,
+            }).await?;
+        }
+
+        self.recoveries.write().await.insert(channel_id, Recovery::new(channel, RecoveryStatus::AwaitingPeerState));
+        Ok(())
+    }
+
+    pub async fn get_recovery(&self, channel_id: H256) -> Option<Recovery> {
+        self.recoveries.read().await.get(&channel_id).cloned()
+    }
+
+    pub async fn handle_message(&self, message: NetworkMessage) -> Result<(), BackupError> {
+        match message {
+            NetworkMessage::ChannelReestablish { channel_id, sender, data_loss: true } => {
+                self.send_latest_state(channel_id, sender).await
+            }
+            NetworkMessage::LatestState { channel_id, sender, state, signature } => {
+                self.close_recovered_channel(channel_id, sender, state, signature).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Peer side: hand our latest signed state to a peer that lost its data
+    async fn send_latest_state(&self, channel_id: H256, sender: Address) -> Result<(), BackupError> {
+        let channel = self.channel_manager.get_channel(channel_id).await?;
+        if !channel.participants.contains(&sender) {
+            return Err(BackupError::InvalidPeerState(format!("{:?} is not a participant", sender)));
+        }
+
+        // Re-signs the state we hold, which is its own baseline
+        let state = CanonicalState::from(&channel.state);
+        let signature = self.crypto
+            .sign_channel_state(&self.node_address, channel.bridge_id(), Some(state.clone()), state)
+            .await?;
+        let encoded = serde_json::to_vec(&channel.state)
+            .map_err(|e| BackupError::Encoding(e.to_string()))?;
+
+        self.network.send_message(sender, NetworkMessage::LatestState {
+            channel_id,
+            sender: self.node_address,
+            state: encoded,
+            signature,
+        }).await?;
+
+        Ok(())
+    }
+
+    /// Recovering side: collect every peer's signature on their latest state, then
+    /// countersign it, submit it to the bridge and start the dispute window so the
+    /// channel closes on chain
+    async fn close_recovered_channel(
+        &self,
+        channel_id: H256,
+        sender: Address,
+        state: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<(), BackupError> {
+        let mut recoveries = self.recoveries.write().await;
+        let recovery = match recoveries.get_mut(&channel_id) {
+            Some(recovery) if recovery.status == RecoveryStatus::AwaitingPeerState => recovery,
+            // Not a channel we are recovering
+            _ => return Ok(()),
+        };
+        let backup = recovery.backup.clone();
+
+        if sender == self.node_address || !backup.participants.contains(&sender) {
+            return Err(BackupError::InvalidPeerState(format!("{:?} is not a peer in the channel", sender)));
+        }
+
+        let state: ChannelState = serde_json::from_slice(&state)
+            .map_err(|e| BackupError::Encoding(e.to_string()))?;
+
+        let total = state.balances.values()
+            .fold(U256::zero(), |acc, &val| acc.saturating_add(val))
+            .saturating_add(state.total_locked);
+        if total != backup.capacity {
+            return Err(BackupError::InvalidPeerState("Balances don't match capacity".into()));
+        }
+
+        let bridge_id = backup.bridge_id();
+        let canonical = CanonicalState::from(&state);
+        if let Some(latest) = &backup.latest_state {
+            if canonical.sequence < latest.sequence {
+                return Err(BackupError::InvalidPeerState(format!(
+                    "State {} is older than the backed up state {}", canonical.sequence, latest.sequence,
+                )));
+            }
+        }
+
+        let message = channel_state_message(bridge_id, canonical.sequence, canonical.hash());
+        if !self.crypto.verify_signature(&sender, message.as_bytes(), &signature)? {
+            return Err(BackupError::InvalidPeerState(format!("Invalid signature from {:?}", sender)));
+        }
+
+        // Signatures on another state don't count towards this one
+        let collected = recovery.state.as_ref().map(CanonicalState::from);
+        if collected.as_ref() != Some(&canonical) {
+            if collected.is_some_and(|collected| collected.sequence > canonical.sequence) {
+                return Ok(());
+            }
+            recovery.state = Some(state.clone());
+            recovery.signatures.clear();
+        }
+        recovery.signatures.insert(sender, signature);
+
+        let peers_signed = backup.participants.iter()
+            .filter(|participant| **participant != self.node_address)
+            .all(|participant| recovery.signatures.contains_key(participant));
+        if !peers_signed {
+            return Ok(());
+        }
+
+        // The signer checks the state against the one in the backup, so a peer can't
+        // close on a state that takes our funds
+        let our_signature = self.crypto
+            .sign_channel_state(&self.node_address, bridge_id, backup.latest_state.clone(), canonical.clone())
+            .await?;
+        recovery.signatures.insert(self.node_address, our_signature);
+
+        // The bridge wants every participant's signature, in participant order
+        let ordered: Vec<Vec<u8>> = backup.participants.iter()
+            .map(|participant| recovery.signatures[participant].clone())
+            .collect();
+        drop(recoveries);
+
+        let signatures = ordered.iter()
+            .map(|signature| Signature::try_from(signature.as_slice())
+                .map_err(|e| BackupError::Bridge(e.to_string())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let bridge_state = BridgeChannelState::from_canonical(canonical, chrono::Utc::now().timestamp());
+        self.bridge
+            .update_channel_state(bridge_id, bridge_state.clone(), signatures)
+            .await
+            .map_err(|e| BackupError::Bridge(e.to_string()))?;
+
+        let tx_hash = self.bridge
+            .initiate_dispute(bridge_id, bridge_state, ordered.concat())
+            .await
+            .map_err(|e| BackupError::Bridge(e.to_string()))?;
+
+        log::info!("Submitted recovered channel {} for closing in {:?}", channel_id, tx_hash);
+
+        if let Some(recovery) = self.recoveries.write().await.get_mut(&channel_id) {
+            recovery.status = RecoveryStatus::CloseSubmitted { tx_hash };
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageHandler for BackupManager {
+    async fn handle(&self, message: NetworkMessage) -> Result<(), NetworkError> {
+        self.handle_message(message).await
+            .map_err(|e| NetworkError::ChannelError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_backup() -> StaticBackup {
+        StaticBackup {
+            version: BACKUP_VERSION,
+            node_address: Address::random(),
+            created_at: 0,
+            channels: vec![ChannelBackup {
+                channel_id: H256::random(),
+                shard_id: 3,
+                participants: vec![Address::random(), Address::random()],
+                capacity: U256::from(1000),
+                dispute_period: 100,
+                bridge_contract: Address::random(),
+                bridge_channel_id: Some(H256::random()),
+                latest_state: None,
+            }],
+            peers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_backup_roundtrip() {
+        let backup = test_backup();
+        let key = [7u8; 32];
+
+        let blob = backup.encrypt(&key).unwrap();
+        let restored = StaticBackup::decrypt(&blob, &key).unwrap();
+
+        assert_eq!(restored.node_address, backup.node_address);
+        assert_eq!(restored.channels, backup.channels);
+    }
+
+    #[test]
+    fn test_backup_wrong_key_rejected() {
+        let blob = test_backup().encrypt(&[7u8; 32]).unwrap();
+        assert!(matches!(
+            StaticBackup::decrypt(&blob, &[8u8; 32]),
+            Err(BackupError::Encryption(_))
+        ));
+    }
+}
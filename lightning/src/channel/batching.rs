@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use ethers::types::{Address, H256, U256};
+use serde::{Serialize, Deserialize};
+use flashchain_common::encoding::CanonicalState;
+use tokio::sync::{oneshot, Mutex};
+
+use super::operations::{OperationError, OperationExecutor, OperationResult, TransferResult};
+use super::state::ChannelStatus;
+use super::Channel;
+use crate::crypto::signer::{SignRequest, Signer, SignerError};
+use crate::crypto::CryptoManager;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// A channel's batch is flushed as soon as it holds this many transfers
+    pub max_batch_size: usize,
+    /// Longest a transfer waits for its batch to fill up
+    pub max_delay: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 128,
+            max_delay: Duration::from_millis(20),
+        }
+    }
+}
+
+/// A transfer waiting in a batch, answered once the batch is signed
+pub struct PendingTransfer {
+    pub from: Address,
+    pub to: Address,
+    pub amount: U256,
+    pub response: oneshot::Sender<OperationResult<TransferResult>>,
+}
+
+/// What remains of a batch once transfers in opposite directions cancel out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetTransfer {
+    pub from: Address,
+    pub to: Address,
+    pub amount: U256,
+}
+
+/// Nets transfers per pair of participants, in a deterministic order
+pub fn net_transfers(transfers: &[(Address, Address, U256)]) -> Vec<NetTransfer> {
+    // Flow from the lower to the higher address of each pair, and back
+    let mut flows: BTreeMap<(Address, Address), (U256, U256)> = BTreeMap::new();
+    for &(from, to, amount) in transfers {
+        if from == to {
+            continue;
+        }
+        if from < to {
+            flows.entry((from, to)).or_default().0 += amount;
+        } else {
+            flows.entry((to, from)).or_default().1 += amount;
+        }
+    }
+
+    flows.into_iter()
+        .filter_map(|((low, high), (forward, backward))| {
+            if forward > backward {
+                Some(NetTransfer { from: low, to: high, amount: forward - backward })
+            } else if backward > forward {
+                Some(NetTransfer { from: high, to: low, amount: backward - forward })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchOutcome {
+    pub channel_id: H256,
+    pub accepted: usize,
+    pub rejected: usize,
+    pub net_transfers: Vec<NetTransfer>,
+    pub state_update_hash: Option<H256>,
+}
+
+/// Gets a batch's state update signed by the other participants of the channel
+#[async_trait]
+pub trait Countersigner: Send + Sync {
+    /// Asks `participant` to sign `request`, returning r || s || v
+    async fn countersign(&self, participant: Address, request: SignRequest) -> Result<Vec<u8>, SignerError>;
+}
+
+#[async_trait]
+impl<S: Signer + ?Sized> Countersigner for S {
+    async fn countersign(&self, participant: Address, request: SignRequest) -> Result<Vec<u8>, SignerError> {
+        self.sign(participant, request).await
+    }
+}
+
+/// Collects transfers per channel and settles each batch with a single state update
+/// signed by every participant
+pub struct TransferBatcher {
+    config: BatchConfig,
+    crypto: Arc<CryptoManager>,
+    node_address: Address,
+    countersigner: Arc<dyn Countersigner>,
+    pending: Mutex<HashMap<H256, Vec<PendingTransfer>>>,
+}
+
+impl TransferBatcher {
+    pub fn new(
+        config: BatchConfig,
+        crypto: Arc<CryptoManager>,
+        node_address: Address,
+        countersigner: Arc<dyn Countersigner>,
+    ) -> Self {
+        Self {
+            config,
+            crypto,
+            node_address,
+            countersigner,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &BatchConfig {
+        &self.config
+    }
+
+    /// Queues a transfer and returns the channel's batch once it is full
+    pub async fn push(&self, channel_id: H256, transfer: PendingTransfer) -> Option<Vec<PendingTransfer>> {
+        let mut pending = self.pending.lock().await;
+        let batch = pending.entry(channel_id).or_insert_with(Vec::new);
+        batch.push(transfer);
+
+        if batch.len() >= self.config.max_batch_size {
+            pending.remove(&channel_id)
+        } else {
+            None
+        }
+    }
+
+    /// Takes every batch collected so far, full or not
+    pub async fn drain(&self) -> Vec<(H256, Vec<PendingTransfer>)> {
+        self.pending.lock().await.drain().collect()
+    }
+
+    /// Takes the channel's batch, full or not
+    pub async fn take(&self, channel_id: H256) -> Option<Vec<PendingTransfer>> {
+        self.pending.lock().await.remove(&channel_id)
+    }
+
+    /// Applies the batch transfer by transfer, so one that fails is rejected on its own,
+    /// then commits the netted result as one signed state update
+    pub async fn apply(
+        &self,
+        executor: &OperationExecutor,
+        channel: &mut Channel,
+        batch: Vec<PendingTransfer>,
+    ) -> BatchOutcome {
+        let channel_id = channel.channel_id;
+        let mut outcome = BatchOutcome {
+            channel_id,
+            accepted: 0,
+            rejected: 0,
+            net_transfers: Vec::new(),
+            state_update_hash: None,
+        };
+
+        if channel.status != ChannelStatus::Active {
+            outcome.rejected = batch.len();
+            for transfer in batch {
+                let _ = transfer.response.send(Err(
+                    OperationError::InvalidOperation("Channel not active".to_string())
+                ));
+            }
+            return outcome;
+        }
+
+        let mut new_state = channel.state.clone();
+        let mut accepted = Vec::with_capacity(batch.len());
+        for transfer in batch {
+            match new_state.transfer(transfer.from, transfer.to, transfer.amount) {
+                Ok(()) => accepted.push(transfer),
+                Err(e) => {
+                    outcome.rejected += 1;
+                    let _ = transfer.response.send(Err(e.into()));
+                }
+            }
+        }
+
+        if accepted.is_empty() {
+            return outcome;
+        }
+
+        let flows: Vec<_> = accepted.iter()
+            .map(|transfer| (transfer.from, transfer.to, transfer.amount))
+            .collect();
+        outcome.net_transfers = net_transfers(&flows);
+
+        // The whole batch is a single update for the counterparty to countersign
+        new_state.sequence_number = channel.state.sequence_number + 1;
+
+        let previous = CanonicalState::from(&channel.state);
+        let state = CanonicalState::from(&new_state);
+        let signatures = match self.collect_signatures(channel, previous, state).await {
+            Ok(signatures) => signatures,
+            Err(e) => {
+                outcome.rejected += accepted.len();
+                reject_all(accepted, e.to_string());
+                return outcome;
+            }
+        };
+
+        let before = channel.clone();
+        match executor.handle_update_state(channel, new_state, signatures).await {
+            Ok(update) => {
+                executor.publish_changes(&before, channel);
+                outcome.accepted = accepted.len();
+                outcome.state_update_hash = Some(update.state_update_hash);
+                for transfer in accepted {
+                    let _ = transfer.response.send(Ok(TransferResult {
+                        channel_id,
+                        new_state: update.new_state.clone(),
+                        transaction_hash: update.state_update_hash,
+                    }));
+                }
+            }
+            Err(e) => {
+                log::warn!("Batch update for channel {} failed: {:?}", channel_id, e);
+                outcome.rejected += accepted.len();
+                reject_all(accepted, e.to_string());
+            }
+        }
+
+        outcome
+    }
+
+    /// Every participant's signature on the update, in participant order
+    async fn collect_signatures(
+        &self,
+        channel: &Channel,
+        previous: CanonicalState,
+        state: CanonicalState,
+    ) -> Result<Vec<Vec<u8>>, SignerError> {
+        let channel_id = channel.bridge_id();
+        let mut signatures = Vec::with_capacity(channel.participants.len());
+        for participant in &channel.participants {
+            let signature = if *participant == self.node_address {
+                self.crypto
+                    .sign_channel_state(&self.node_address, channel_id, Some(previous.clone()), state.clone())
+                    .await?
+            } else {
+                let request = SignRequest::ChannelState {
+                    channel_id,
+                    previous: Some(previous.clone()),
+                    state: state.clone(),
+                };
+                self.countersigner.countersign(*participant, request).await?
+            };
+            signatures.push(signature);
+        }
+
+        Ok(signatures)
+    }
+}
+
+fn reject_all(transfers: Vec<PendingTransfer>, reason: String) {
+    for transfer in transfers {
+        let _ = transfer.response.send(Err(OperationError::Rejected(reason.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::clock::ManualClock;
+    use crate::channel::test_channel;
+    use crate::crypto::signature::SignatureVerifier;
+    use crate::crypto::signer::{LocalSigner, SigningPolicy};
+
+    fn pending(
+        from: Address,
+        to: Address,
+        amount: u64,
+    ) -> (PendingTransfer, oneshot::Receiver<OperationResult<TransferResult>>) {
+        let (response, result) = oneshot::channel();
+        (PendingTransfer { from, to, amount: U256::from(amount), response }, result)
+    }
+
+    #[test]
+    fn test_opposite_transfers_net_out() {
+        let (a, b) = (Address::random(), Address::random());
+        let net = net_transfers(&[
+            (a, b, U256::from(30)),
+            (b, a, U256::from(10)),
+            (a, b, U256::from(5)),
+        ]);
+        assert_eq!(net, vec![NetTransfer { from: a, to: b, amount: U256::from(25) }]);
+
+        let balanced = net_transfers(&[(a, b, U256::from(7)), (b, a, U256::from(7))]);
+        assert!(balanced.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_rejects_failed_transfers_only() {
+        let mut crypto = CryptoManager::new();
+        let a = crypto.generate_keypair().unwrap();
+        let mut counterparty = CryptoManager::new();
+        let b = counterparty.generate_keypair().unwrap();
+        let countersigner = Arc::new(LocalSigner::new(counterparty, SigningPolicy::default()));
+        let batcher = TransferBatcher::new(BatchConfig::default(), Arc::new(crypto), a, countersigner);
+        let executor = OperationExecutor::new(
+            Arc::new(SignatureVerifier::new(2)),
+            Arc::new(ManualClock::new(0)),
+        );
+
+        let mut channel = test_channel(a, 100, b, 100);
+
+        let (first, first_result) = pending(a, b, 60);
+        let (overdraft, overdraft_result) = pending(a, b, 60);
+        let (back, back_result) = pending(b, a, 20);
+
+        let outcome = batcher.apply(&executor, &mut channel, vec![first, overdraft, back]).await;
+
+        assert_eq!(outcome.accepted, 2);
+        assert_eq!(outcome.rejected, 1);
+        assert_eq!(outcome.net_transfers, vec![NetTransfer { from: a, to: b, amount: U256::from(40) }]);
+        assert!(overdraft_result.await.unwrap().is_err());
+
+        let first = first_result.await.unwrap().unwrap();
+        let back = back_result.await.unwrap().unwrap();
+        assert_eq!(first.transaction_hash, back.transaction_hash);
+        assert_eq!(channel.state.get_participant_balance(&a), U256::from(60));
+        assert_eq!(channel.state.get_participant_balance(&b), U256::from(140));
+        // One state update for the whole batch
+        assert_eq!(channel.state.sequence_number, 1);
+        assert_eq!(channel.nonce, 1);
+    }
+
+    #[tokio::test]
+    async fn test_push_returns_full_batch() {
+        let batcher = TransferBatcher::new(
+            BatchConfig { max_batch_size: 2, max_delay: Duration::from_millis(10) },
+            Arc::new(CryptoManager::new()),
+            Address::random(),
+            Arc::new(LocalSigner::new(CryptoManager::new(), SigningPolicy::default())),
+        );
+        let channel_id = H256::random();
+        let (a, b) = (Address::random(), Address::random());
+
+        assert!(batcher.push(channel_id, pending(a, b, 1).0).await.is_none());
+        let batch = batcher.push(channel_id, pending(a, b, 1).0).await.unwrap();
+        assert_eq!(batch.len(), 2);
+        assert!(batcher.drain().await.is_empty());
+    }
+}
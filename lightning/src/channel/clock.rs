@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::H256;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use flashchain_bridge::BridgeManager;
+use tokio::sync::{broadcast, RwLock};
+
+#[derive(Error, Debug)]
+pub enum ClockError {
+    #[error("Provider error: {0}")]
+    Provider(String),
+    #[error("Clock stopped")]
+    Stopped,
+}
+
+/// Source of the current block height for every height-dependent check
+#[async_trait]
+pub trait ChainClock: Send + Sync {
+    async fn current_height(&self) -> Result<u64, ClockError>;
+
+    /// Receives every new height the clock observes
+    fn subscribe(&self) -> broadcast::Receiver<u64>;
+}
+
+/// Clock backed by an Ethereum provider, usually the one held by the bridge
+pub struct ProviderClock<M: Middleware> {
+    provider: Arc<M>,
+    cached: RwLock<Option<(u64, Instant)>>,
+    cache_ttl: Duration,
+    height_tx: broadcast::Sender<u64>,
+}
+
+impl ProviderClock<Provider<Http>> {
+    /// Reads heights from the same provider the bridge contracts are bound to
+    pub fn for_bridge(bridge: &BridgeManager, cache_ttl: Duration) -> Self {
+        Self::new(bridge.provider(), cache_ttl)
+    }
+}
+
+impl<M: Middleware + 'static> ProviderClock<M> {
+    pub fn new(provider: Arc<M>, cache_ttl: Duration) -> Self {
+        let (height_tx, _) = broadcast::channel(1000);
+
+        Self {
+            provider,
+            cached: RwLock::new(None),
+            cache_ttl,
+            height_tx,
+        }
+    }
+
+    /// Polls the provider and publishes new heights to subscribers
+    pub fn start_polling(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            let mut last_height = 0u64;
+
+            loop {
+                interval.tick().await;
+                match self.fetch_height().await {
+                    Ok(height) if height > last_height => {
+                        last_height = height;
+                        let _ = self.height_tx.send(height);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::error!("Failed to poll block height: {:?}", e),
+                }
+            }
+        });
+    }
+
+    async fn fetch_height(&self) -> Result<u64, ClockError> {
+        let height = self.provider.get_block_number().await
+            .map_err(|e| ClockError::Provider(e.to_string()))?
+            .as_u64();
+
+        let mut cached = self.cached.write().await;
+        *cached = Some((height, Instant::now()));
+
+        Ok(height)
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> ChainClock for ProviderClock<M> {
+    async fn current_height(&self) -> Result<u64, ClockError> {
+        if let Some((height, fetched_at)) = *self.cached.read().await {
+            if fetched_at.elapsed() < self.cache_ttl {
+                return Ok(height);
+            }
+        }
+
+        self.fetch_height().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<u64> {
+        self.height_tx.subscribe()
+    }
+}
+
+/// Clock whose height only changes when a test sets it
+pub struct ManualClock {
+    height: AtomicU64,
+    height_tx: broadcast::Sender<u64>,
+}
+
+impl ManualClock {
+    pub fn new(height: u64) -> Self {
+        let (height_tx, _) = broadcast::channel(1000);
+
+        Self {
+            height: AtomicU64::new(height),
+            height_tx,
+        }
+    }
+
+    pub fn set_height(&self, height: u64) {
+        self.height.store(height, Ordering::SeqCst);
+        let _ = self.height_tx.send(height);
+    }
+
+    pub fn advance(&self, blocks: u64) {
+        let height = self.height.fetch_add(blocks, Ordering::SeqCst) + blocks;
+        let _ = self.height_tx.send(height);
+    }
+}
+
+#[async_trait]
+impl ChainClock for ManualClock {
+    async fn current_height(&self) -> Result<u64, ClockError> {
+        Ok(self.height.load(Ordering::SeqCst))
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<u64> {
+        self.height_tx.subscribe()
+    }
+}
+
+/// Clock that produces a block every `block_time` and can be fast-forwarded
+pub struct SimulatedClock {
+    height: AtomicU64,
+    block_time: Duration,
+    height_tx: broadcast::Sender<u64>,
+}
+
+impl SimulatedClock {
+    pub fn new(start_height: u64, block_time: Duration) -> Self {
+        let (height_tx, _) = broadcast::channel(1000);
+
+        Self {
+            height: AtomicU64::new(start_height),
+            block_time,
+            height_tx,
+        }
+    }
+
+    pub fn start(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.block_time);
+            // The first tick completes immediately
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                self.fast_forward(1);
+            }
+        });
+    }
+
+    /// Mines `blocks` blocks at once, publishing each intermediate height
+    pub fn fast_forward(&self, blocks: u64) {
+        for _ in 0..blocks {
+            let height = self.height.fetch_add(1, Ordering::SeqCst) + 1;
+            let _ = self.height_tx.send(height);
+        }
+    }
+}
+
+#[async_trait]
+impl ChainClock for SimulatedClock {
+    async fn current_height(&self) -> Result<u64, ClockError> {
+        Ok(self.height.load(Ordering::SeqCst))
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<u64> {
+        self.height_tx.subscribe()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeoutKind {
+    ChannelExpiry { channel_id: H256 },
+    ChannelOpen { channel_id: H256 },
+    DisputeWindow { channel_id: H256 },
+    Lock { channel_id: H256, lock_id: H256 },
+    Htlc { channel_id: H256, htlc_id: H256 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeoutEvent {
+    pub kind: TimeoutKind,
+    pub expiry_height: u64,
+    pub fired_at: u64,
+}
+
+/// Fires a `TimeoutEvent` once the clock reaches each registered height
+pub struct TimeoutScheduler {
+    clock: Arc<dyn ChainClock>,
+    pending: RwLock<BTreeMap<u64, Vec<TimeoutKind>>>,
+    event_tx: broadcast::Sender<TimeoutEvent>,
+}
+
+impl TimeoutScheduler {
+    pub fn new(clock: Arc<dyn ChainClock>) -> Self {
+        let (event_tx, _) = broadcast::channel(1000);
+
+        Self {
+            clock,
+            pending: RwLock::new(BTreeMap::new()),
+            event_tx,
+        }
+    }
+
+    /// Schedules `kind` to fire at `expiry_height`, unless it already is
+    pub async fn schedule(&self, expiry_height: u64, kind: TimeoutKind) {
+        let mut pending = self.pending.write().await;
+        let kinds = pending.entry(expiry_height).or_insert_with(Vec::new);
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+
+    /// Schedules the expiry of an HTLC at its timeout height
+    pub async fn track_htlc(&self, channel_id: H256, htlc_id: H256, timeout: u64) {
+        self.schedule(timeout, TimeoutKind::Htlc { channel_id, htlc_id }).await;
+    }
+
+    /// Schedules the expiry of a time lock at its expiration height
+    pub async fn track_lock(&self, channel_id: H256, lock_id: H256, expiration_height: u64) {
+        self.schedule(expiration_height, TimeoutKind::Lock { channel_id, lock_id }).await;
+    }
+
+    pub async fn cancel(&self, kind: TimeoutKind) {
+        let mut pending = self.pending.write().await;
+        for kinds in pending.values_mut() {
+            kinds.retain(|k| *k != kind);
+        }
+        pending.retain(|_, kinds| !kinds.is_empty());
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TimeoutEvent> {
+        self.event_tx.subscribe()
+    }
+
+    pub fn start(self: Arc<Self>) {
+        let mut heights = self.clock.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match heights.recv().await {
+                    Ok(height) => self.fire_until(height).await,
+                    // Missed heights are covered by firing everything up to the next one
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if let Ok(height) = self.clock.current_height().await {
+                            self.fire_until(height).await;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Fires and removes every timeout at or below `height`
+    pub async fn fire_until(&self, height: u64) {
+        let mut pending = self.pending.write().await;
+        let remaining = match height.checked_add(1) {
+            Some(next) => pending.split_off(&next),
+            // Nothing lies beyond the last height
+            None => BTreeMap::new(),
+        };
+        let expired = std::mem::replace(&mut *pending, remaining);
+        drop(pending);
+
+        for (expiry_height, kinds) in expired {
+            for kind in kinds {
+                let _ = self.event_tx.send(TimeoutEvent {
+                    kind,
+                    expiry_height,
+                    fired_at: height,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new(10);
+        let mut heights = clock.subscribe();
+
+        clock.advance(5);
+        assert_eq!(clock.current_height().await.unwrap(), 15);
+        assert_eq!(heights.recv().await.unwrap(), 15);
+    }
+
+    #[tokio::test]
+    async fn test_simulated_clock_fast_forward() {
+        let clock = SimulatedClock::new(0, Duration::from_secs(12));
+        let mut heights = clock.subscribe();
+
+        clock.fast_forward(3);
+        assert_eq!(clock.current_height().await.unwrap(), 3);
+        assert_eq!(heights.recv().await.unwrap(), 1);
+        assert_eq!(heights.recv().await.unwrap(), 2);
+        assert_eq!(heights.recv().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_fire_on_advance() {
+        let clock = Arc::new(ManualClock::new(0));
+        let scheduler = Arc::new(TimeoutScheduler::new(clock.clone()));
+        let mut events = scheduler.subscribe();
+
+        let channel_id = H256::random();
+        scheduler.schedule(10, TimeoutKind::DisputeWindow { channel_id }).await;
+        scheduler.schedule(20, TimeoutKind::ChannelExpiry { channel_id }).await;
+        scheduler.clone().start();
+
+        clock.set_height(12);
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.kind, TimeoutKind::DisputeWindow { channel_id });
+        assert_eq!(event.expiry_height, 10);
+        assert_eq!(event.fired_at, 12);
+
+        clock.set_height(20);
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.kind, TimeoutKind::ChannelExpiry { channel_id });
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_scheduled_once() {
+        let scheduler = TimeoutScheduler::new(Arc::new(ManualClock::new(0)));
+        let mut events = scheduler.subscribe();
+
+        let kind = TimeoutKind::Lock { channel_id: H256::random(), lock_id: H256::random() };
+        scheduler.schedule(10, kind).await;
+        scheduler.schedule(10, kind).await;
+
+        scheduler.fire_until(10).await;
+        assert_eq!(events.recv().await.unwrap().kind, kind);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fire_until_last_height() {
+        let scheduler = TimeoutScheduler::new(Arc::new(ManualClock::new(0)));
+        let mut events = scheduler.subscribe();
+
+        let channel_id = H256::random();
+        scheduler.schedule(u64::MAX, TimeoutKind::ChannelExpiry { channel_id }).await;
+        scheduler.fire_until(u64::MAX).await;
+
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.expiry_height, u64::MAX);
+        assert_eq!(event.fired_at, u64::MAX);
+    }
+}
@@ -0,0 +1,749 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use ethers::types::{Address, Signature, H256, U256};
+use flashchain_bridge::types::ChannelState as BridgeChannelState;
+use flashchain_bridge::BridgeManager;
+use flashchain_common::encoding::CanonicalState;
+use flashchain_common::types::AssetId;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+use super::state::{ChannelState, ChannelStatus};
+use super::{Channel, ChannelError, ChannelManager};
+use crate::crypto::signature::{channel_state_message, token_release_message};
+use crate::crypto::signer::SignRequest;
+use crate::crypto::typed_data::{CloseMessage, ClosePayout};
+use crate::crypto::{CryptoError, CryptoManager};
+use crate::network::{MessageHandler, MessageRoute, NetworkError, NetworkManager, NetworkMessage};
+
+#[derive(Error, Debug)]
+pub enum CloseError {
+    #[error("Channel error: {0}")]
+    Channel(#[from] ChannelError),
+    #[error("No close negotiation for channel {0}")]
+    NoNegotiation(H256),
+    #[error("Unexpected close message: {0}")]
+    UnexpectedMessage(String),
+    #[error("Cooperative close needs exactly two participants")]
+    UnsupportedParticipants,
+    #[error("Invalid proposal: {0}")]
+    InvalidProposal(String),
+    #[error("Invalid signature from {0}")]
+    InvalidSignature(Address),
+    #[error("No agreement after {0} rounds")]
+    RoundsExhausted(u32),
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("Bridge error: {0}")]
+    Bridge(String),
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+}
+
+/// Fees this node accepts when negotiating a cooperative close, in wei. The
+/// defaults price the gas of submitting the final state at 1, 20 and 100 gwei.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClosePolicy {
+    pub target_fee: U256,
+    pub min_fee: U256,
+    pub max_fee: U256,
+    /// Largest share of the on-chain fee we pay, in basis points
+    pub max_fee_share_bps: u64,
+    pub max_rounds: u32,
+}
+
+/// Gas `BridgeCore.updateChannelState` is given when a close is submitted
+const CLOSE_GAS: u64 = 300_000;
+const GWEI: u64 = 1_000_000_000;
+
+impl Default for ClosePolicy {
+    fn default() -> Self {
+        Self {
+            target_fee: U256::from(CLOSE_GAS) * U256::from(20 * GWEI),
+            min_fee: U256::from(CLOSE_GAS) * U256::from(GWEI),
+            max_fee: U256::from(CLOSE_GAS) * U256::from(100 * GWEI),
+            max_fee_share_bps: 5_000,
+            max_rounds: 10,
+        }
+    }
+}
+
+impl ClosePolicy {
+    fn max_share(&self, fee: U256) -> U256 {
+        fee * U256::from(self.max_fee_share_bps) / U256::from(10_000u64)
+    }
+
+    fn accepts(&self, fee: U256, our_share: U256) -> bool {
+        fee >= self.min_fee && fee <= self.max_fee && our_share <= self.max_share(fee)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClosePhase {
+    /// Shutdown exchanged, waiting for pending locks to settle or expire
+    Draining,
+    Negotiating,
+    /// Both sides signed the same terms
+    Agreed,
+    Failed,
+}
+
+/// Final balances and fee split one side proposes and signs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClosingProposal {
+    pub channel_id: H256,
+    /// Id the bridge knows the channel by
+    pub bridge_channel_id: H256,
+    pub round: u32,
+    pub proposer: Address,
+    pub fee: U256,
+    pub fee_shares: BTreeMap<Address, U256>,
+    pub final_balances: BTreeMap<Address, U256>,
+    /// State the channel settles to. This is what the proposer signs.
+    pub final_state: CanonicalState,
+    pub signature: Vec<u8>,
+    /// Proposer's signatures over `token_releases`, in the same order
+    #[serde(default)]
+    pub release_signatures: Vec<Vec<u8>>,
+}
+
+impl ClosingProposal {
+    /// Message the proposer signs: the final state, as `BridgeCore` checks it. Round
+    /// and proposer are left out so both sides sign the same message once they agree.
+    pub fn signing_message(&self) -> H256 {
+        channel_state_message(self.bridge_channel_id, self.final_state.sequence, self.final_state.hash())
+    }
+
+    /// The closing terms as an EIP-712 message, for wallets to display and sign
+    pub fn typed_message(&self) -> CloseMessage {
+        CloseMessage {
+            channel_id: self.channel_id,
+            fee: self.fee,
+            payouts: self.final_balances.iter()
+                .map(|(participant, amount)| ClosePayout {
+                    participant: *participant,
+                    amount: *amount,
+                    fee_share: self.fee_shares.get(participant).copied().unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Token deposits the bridge pays out once the final state is settled, one per
+    /// token balance
+    pub fn token_releases(&self) -> Vec<TokenRelease> {
+        self.final_state.balances.iter()
+            .filter(|balance| !balance.asset.is_native())
+            .map(|balance| TokenRelease {
+                channel_id: self.bridge_channel_id,
+                token: balance.asset,
+                amount: balance.amount,
+                recipient: balance.participant,
+                nonce: U256::from(self.final_state.sequence),
+            })
+            .collect()
+    }
+
+    fn same_terms(&self, other: &ClosingProposal) -> bool {
+        self.signing_message() == other.signing_message()
+    }
+}
+
+/// A payout from the bridge's token deposits. `BridgeCore` only makes it with the
+/// signatures of every participant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenRelease {
+    /// Id the bridge knows the channel by
+    pub channel_id: H256,
+    pub token: AssetId,
+    pub amount: U256,
+    pub recipient: Address,
+    /// Sequence of the settled state, so the release can't be paid twice
+    pub nonce: U256,
+}
+
+impl TokenRelease {
+    pub fn signing_message(&self) -> H256 {
+        token_release_message(self.channel_id, self.token.0, self.amount, self.recipient, self.nonce)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CloseNegotiation {
+    pub channel_id: H256,
+    pub counterparty: Address,
+    pub initiator: bool,
+    pub phase: ClosePhase,
+    pub round: u32,
+    pub local: Option<ClosingProposal>,
+    pub remote: Option<ClosingProposal>,
+}
+
+/// Runs the cooperative close protocol: shutdown, drain, signed fee rounds and
+/// submission of the agreed state to the bridge
+pub struct CloseCoordinator {
+    channel_manager: Arc<ChannelManager>,
+    bridge: Arc<BridgeManager>,
+    network: Arc<NetworkManager>,
+    crypto: Arc<CryptoManager>,
+    node_address: Address,
+    policy: ClosePolicy,
+    negotiations: RwLock<HashMap<H256, CloseNegotiation>>,
+}
+
+impl CloseCoordinator {
+    pub fn new(
+        channel_manager: Arc<ChannelManager>,
+        bridge: Arc<BridgeManager>,
+        network: Arc<NetworkManager>,
+        crypto: Arc<CryptoManager>,
+        node_address: Address,
+        policy: ClosePolicy,
+    ) -> Self {
+        Self {
+            channel_manager,
+            bridge,
+            network,
+            crypto,
+            node_address,
+            policy,
+            negotiations: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get_negotiation(&self, channel_id: H256) -> Option<CloseNegotiation> {
+        self.negotiations.read().await.get(&channel_id).cloned()
+    }
+
+    pub async fn initiate_shutdown(&self, channel_id: H256) -> Result<(), CloseError> {
+        let channel = self.channel_manager.get_channel(channel_id).await?;
+        let counterparty = self.counterparty(&channel)?;
+
+        self.channel_manager.begin_shutdown(channel_id).await?;
+        self.negotiations.write().await.insert(channel_id, CloseNegotiation {
+            channel_id,
+            counterparty,
+            initiator: true,
+            phase: ClosePhase::Draining,
+            round: 0,
+            local: None,
+            remote: None,
+        });
+
+        self.network.send_message(counterparty, NetworkMessage::Shutdown {
+            channel_id,
+            sender: self.node_address,
+        }).await?;
+
+        self.poll_drained(channel_id).await
+    }
+
+    pub async fn handle_message(&self, message: NetworkMessage) -> Result<(), CloseError> {
+        match message {
+            NetworkMessage::Shutdown { channel_id, sender } => {
+                self.handle_shutdown(channel_id, sender).await
+            }
+            NetworkMessage::ClosingSigned { channel_id, sender, proposal } => {
+                let proposal: ClosingProposal = serde_json::from_slice(&proposal)
+                    .map_err(|e| CloseError::Encoding(e.to_string()))?;
+                if proposal.channel_id != channel_id || proposal.proposer != sender {
+                    return Err(CloseError::InvalidProposal("Envelope mismatch".into()));
+                }
+                self.handle_proposal(proposal).await
+            }
+            other => Err(CloseError::UnexpectedMessage(format!("{:?}", other))),
+        }
+    }
+
+    /// Takes the close messages the network receives and checks every draining
+    /// negotiation until its pending locks are gone
+    pub fn start(self: Arc<Self>, interval: Duration) {
+        self.network.register_handler(MessageRoute::Close, self.clone());
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+
+                let draining: Vec<H256> = self.negotiations.read().await
+                    .values()
+                    .filter(|negotiation| negotiation.phase == ClosePhase::Draining)
+                    .map(|negotiation| negotiation.channel_id)
+                    .collect();
+
+                for channel_id in draining {
+                    if let Err(e) = self.poll_drained(channel_id).await {
+                        log::error!("Cooperative close of {} failed: {:?}", channel_id, e);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn handle_shutdown(&self, channel_id: H256, sender: Address) -> Result<(), CloseError> {
+        let channel = self.channel_manager.get_channel(channel_id).await?;
+        let counterparty = self.counterparty(&channel)?;
+        if sender != counterparty {
+            return Err(CloseError::UnexpectedMessage(format!("Shutdown from {:?}", sender)));
+        }
+
+        // Both sides sent shutdown at the same time
+        if self.negotiations.read().await.contains_key(&channel_id) {
+            return self.poll_drained(channel_id).await;
+        }
+
+        if channel.status != ChannelStatus::Closing {
+            self.channel_manager.begin_shutdown(channel_id).await?;
+        }
+        self.negotiations.write().await.insert(channel_id, CloseNegotiation {
+            channel_id,
+            counterparty,
+            initiator: false,
+            phase: ClosePhase::Draining,
+            round: 0,
+            local: None,
+            remote: None,
+        });
+
+        self.network.send_message(counterparty, NetworkMessage::Shutdown {
+            channel_id,
+            sender: self.node_address,
+        }).await?;
+
+        self.poll_drained(channel_id).await
+    }
+
+    /// Starts fee negotiation once no locks are pending. The initiator makes the first proposal.
+    async fn poll_drained(&self, channel_id: H256) -> Result<(), CloseError> {
+        let channel = self.channel_manager.get_channel(channel_id).await?;
+        if !is_drained(&channel.state) {
+            return Ok(());
+        }
+
+        let mut negotiations = self.negotiations.write().await;
+        let negotiation = negotiations.get_mut(&channel_id)
+            .ok_or(CloseError::NoNegotiation(channel_id))?;
+        if negotiation.phase != ClosePhase::Draining {
+            return Ok(());
+        }
+        negotiation.phase = ClosePhase::Negotiating;
+
+        if !negotiation.initiator {
+            return Ok(());
+        }
+
+        let fee = self.policy.target_fee;
+        let our_share = self.policy.max_share(fee);
+        let proposal = self.build_proposal(&channel, negotiation, fee, our_share).await?;
+        negotiation.local = Some(proposal.clone());
+        let counterparty = negotiation.counterparty;
+        drop(negotiations);
+
+        self.send_proposal(counterparty, &proposal).await
+    }
+
+    async fn handle_proposal(&self, proposal: ClosingProposal) -> Result<(), CloseError> {
+        let channel_id = proposal.channel_id;
+        let channel = self.channel_manager.get_channel(channel_id).await?;
+
+        let mut negotiations = self.negotiations.write().await;
+        let negotiation = negotiations.get_mut(&channel_id)
+            .ok_or(CloseError::NoNegotiation(channel_id))?;
+
+        if proposal.proposer != negotiation.counterparty {
+            return Err(CloseError::InvalidProposal("Proposer is not the counterparty".into()));
+        }
+
+        // The counterparty may see the channel drain before we do
+        if negotiation.phase == ClosePhase::Draining && is_drained(&channel.state) {
+            negotiation.phase = ClosePhase::Negotiating;
+        }
+        if negotiation.phase != ClosePhase::Negotiating {
+            return Err(CloseError::UnexpectedMessage(
+                format!("Proposal while {:?}", negotiation.phase)
+            ));
+        }
+
+        self.verify_proposal(&channel, &proposal)?;
+        negotiation.remote = Some(proposal.clone());
+
+        // They signed the terms we proposed
+        if let Some(local) = negotiation.local.clone() {
+            if local.same_terms(&proposal) {
+                negotiation.phase = ClosePhase::Agreed;
+                let initiator = negotiation.initiator;
+                drop(negotiations);
+                return self.finalize(&channel, &local, &proposal, initiator).await;
+            }
+        }
+
+        let our_share = proposal.fee_shares.get(&self.node_address).copied().unwrap_or_default();
+        let counterparty = negotiation.counterparty;
+
+        // Their terms are acceptable, sign them as they are
+        if self.policy.accepts(proposal.fee, our_share) {
+            let local = self.build_proposal(&channel, negotiation, proposal.fee, our_share).await?;
+            if !local.same_terms(&proposal) {
+                negotiation.phase = ClosePhase::Failed;
+                return Err(CloseError::InvalidProposal("Accepted terms don't match the proposal".into()));
+            }
+            negotiation.local = Some(local.clone());
+            negotiation.phase = ClosePhase::Agreed;
+            let initiator = negotiation.initiator;
+            drop(negotiations);
+
+            self.send_proposal(counterparty, &local).await?;
+            return self.finalize(&channel, &local, &proposal, initiator).await;
+        }
+
+        if negotiation.round >= self.policy.max_rounds {
+            negotiation.phase = ClosePhase::Failed;
+            return Err(CloseError::RoundsExhausted(negotiation.round));
+        }
+
+        // Meet halfway on the fee, staying inside our own bounds
+        let last_fee = negotiation.local.as_ref()
+            .map(|local| local.fee)
+            .unwrap_or(self.policy.target_fee);
+        let fee = ((last_fee + proposal.fee) / 2)
+            .max(self.policy.min_fee)
+            .min(self.policy.max_fee);
+        let our_share = our_share.min(self.policy.max_share(fee));
+
+        let counter = self.build_proposal(&channel, negotiation, fee, our_share).await?;
+        negotiation.local = Some(counter.clone());
+        drop(negotiations);
+
+        self.send_proposal(counterparty, &counter).await
+    }
+
+    /// Closes the channel locally on the agreed terms. The initiator also submits the
+    /// jointly signed state to the bridge as its latest state; closing on chain still
+    /// waits out the dispute period.
+    async fn finalize(
+        &self,
+        channel: &Channel,
+        local: &ClosingProposal,
+        remote: &ClosingProposal,
+        initiator: bool,
+    ) -> Result<(), CloseError> {
+        let final_state = settled_state(&channel.state, &local.final_balances);
+
+        // Both signed the bridge message for the final state, in participant order
+        let signatures = participant_signatures(channel, self.node_address, &local.signature, &remote.signature);
+
+        self.channel_manager
+            .cooperative_close(channel.channel_id, final_state.clone(), signatures.clone())
+            .await?;
+
+        log::info!(
+            "Cooperatively closed channel {} with fee {}",
+            channel.channel_id, local.fee
+        );
+
+        if !initiator {
+            return Ok(());
+        }
+
+        let bridge_state = BridgeChannelState::from_canonical(
+            local.final_state.clone(),
+            chrono::Utc::now().timestamp(),
+        );
+        let signatures = bridge_signatures(&signatures)?;
+
+        self.bridge
+            .update_channel_state(channel.bridge_id(), bridge_state, signatures)
+            .await
+            .map_err(|e| CloseError::Bridge(e.to_string()))?;
+
+        // Token deposits are held by the bridge and paid out per participant, on
+        // releases both signed along with the final state
+        for (index, release) in local.token_releases().into_iter().enumerate() {
+            let signatures = participant_signatures(
+                channel,
+                self.node_address,
+                &local.release_signatures[index],
+                &remote.release_signatures[index],
+            );
+
+            self.bridge
+                .release_token(
+                    release.channel_id,
+                    release.token,
+                    release.amount,
+                    release.recipient,
+                    release.nonce,
+                    bridge_signatures(&signatures)?,
+                )
+                .await
+                .map_err(|e| CloseError::Bridge(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    async fn build_proposal(
+        &self,
+        channel: &Channel,
+        negotiation: &mut CloseNegotiation,
+        fee: U256,
+        our_share: U256,
+    ) -> Result<ClosingProposal, CloseError> {
+        let their_share = fee.checked_sub(our_share)
+            .ok_or_else(|| CloseError::InvalidProposal("Fee share exceeds fee".into()))?;
+
+        let mut fee_shares = BTreeMap::new();
+        fee_shares.insert(self.node_address, our_share);
+        fee_shares.insert(negotiation.counterparty, their_share);
+
+        let final_balances = final_balances(&channel.state, &fee_shares)?;
+        let final_state = CanonicalState::from(&settled_state(&channel.state, &final_balances));
+
+        negotiation.round += 1;
+        let mut proposal = ClosingProposal {
+            channel_id: channel.channel_id,
+            bridge_channel_id: channel.bridge_id(),
+            round: negotiation.round,
+            proposer: self.node_address,
+            fee,
+            fee_shares,
+            final_balances,
+            final_state,
+            signature: Vec::new(),
+            release_signatures: Vec::new(),
+        };
+        proposal.signature = self.crypto.sign(
+            &self.node_address,
+            SignRequest::CloseChannel(proposal.clone()),
+        ).await?;
+
+        // Signed after the close, which the signer checks the releases against
+        for release in proposal.token_releases() {
+            let signature = self.crypto.sign(&self.node_address, SignRequest::TokenRelease(release)).await?;
+            proposal.release_signatures.push(signature);
+        }
+
+        Ok(proposal)
+    }
+
+    fn verify_proposal(&self, channel: &Channel, proposal: &ClosingProposal) -> Result<(), CloseError> {
+        if proposal.bridge_channel_id != channel.bridge_id() {
+            return Err(CloseError::InvalidProposal("Proposal is for another bridge channel".into()));
+        }
+
+        if let Some(outsider) = proposal.fee_shares.keys().find(|address| !channel.participants.contains(address)) {
+            return Err(CloseError::InvalidProposal(format!("Fee share for non-participant {:?}", outsider)));
+        }
+
+        let total_shares = proposal.fee_shares.values()
+            .fold(U256::zero(), |total, share| total.saturating_add(*share));
+        if total_shares != proposal.fee {
+            return Err(CloseError::InvalidProposal("Fee shares don't add up to the fee".into()));
+        }
+
+        if proposal.final_balances != final_balances(&channel.state, &proposal.fee_shares)? {
+            return Err(CloseError::InvalidProposal("Final balances don't match channel state".into()));
+        }
+
+        if proposal.final_state != CanonicalState::from(&settled_state(&channel.state, &proposal.final_balances)) {
+            return Err(CloseError::InvalidProposal("Final state doesn't match the final balances".into()));
+        }
+
+        let valid = self.crypto.verify_signature(
+            &proposal.proposer,
+            proposal.signing_message().as_bytes(),
+            &proposal.signature,
+        )?;
+        if !valid {
+            return Err(CloseError::InvalidSignature(proposal.proposer));
+        }
+
+        let releases = proposal.token_releases();
+        if proposal.release_signatures.len() != releases.len() {
+            return Err(CloseError::InvalidProposal("Token releases aren't all signed".into()));
+        }
+        for (release, signature) in releases.iter().zip(&proposal.release_signatures) {
+            let valid = self.crypto.verify_signature(
+                &proposal.proposer,
+                release.signing_message().as_bytes(),
+                signature,
+            )?;
+            if !valid {
+                return Err(CloseError::InvalidSignature(proposal.proposer));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_proposal(
+        &self,
+        counterparty: Address,
+        proposal: &ClosingProposal,
+    ) -> Result<(), CloseError> {
+        let encoded = serde_json::to_vec(proposal)
+            .map_err(|e| CloseError::Encoding(e.to_string()))?;
+
+        self.network.send_message(counterparty, NetworkMessage::ClosingSigned {
+            channel_id: proposal.channel_id,
+            sender: self.node_address,
+            proposal: encoded,
+        }).await?;
+
+        Ok(())
+    }
+
+    fn counterparty(&self, channel: &Channel) -> Result<Address, CloseError> {
+        if channel.participants.len() != 2 || !channel.participants.contains(&self.node_address) {
+            return Err(CloseError::UnsupportedParticipants);
+        }
+
+        Ok(channel.participants.iter()
+            .copied()
+            .find(|participant| *participant != self.node_address)
+            .unwrap_or(self.node_address))
+    }
+}
+
+#[async_trait]
+impl MessageHandler for CloseCoordinator {
+    async fn handle(&self, message: NetworkMessage) -> Result<(), NetworkError> {
+        self.handle_message(message).await
+            .map_err(|e| NetworkError::ChannelError(e.to_string()))
+    }
+}
+
+/// Our and their signature over the same message, in participant order
+fn participant_signatures(
+    channel: &Channel,
+    node_address: Address,
+    local: &[u8],
+    remote: &[u8],
+) -> Vec<Vec<u8>> {
+    channel.participants.iter()
+        .map(|participant| if *participant == node_address { local.to_vec() } else { remote.to_vec() })
+        .collect()
+}
+
+fn bridge_signatures(signatures: &[Vec<u8>]) -> Result<Vec<Signature>, CloseError> {
+    signatures.iter()
+        .map(|signature| Signature::try_from(signature.as_slice())
+            .map_err(|e| CloseError::Bridge(e.to_string())))
+        .collect()
+}
+
+fn is_drained(state: &ChannelState) -> bool {
+    state.locks.is_empty() && state.escrows.is_empty()
+}
+
+/// Balances left to each participant after paying their share of the fee
+fn final_balances(
+    state: &ChannelState,
+    fee_shares: &BTreeMap<Address, U256>,
+) -> Result<BTreeMap<Address, U256>, CloseError> {
+    state.balances.iter()
+        .map(|(participant, balance)| {
+            let share = fee_shares.get(participant).copied().unwrap_or_default();
+            balance.checked_sub(share)
+                .map(|remaining| (*participant, remaining))
+                .ok_or_else(|| CloseError::InvalidProposal(
+                    format!("Fee share exceeds balance of {:?}", participant)
+                ))
+        })
+        .collect()
+}
+
+/// The channel state after a close pays out `final_balances`
+fn settled_state(state: &ChannelState, final_balances: &BTreeMap<Address, U256>) -> ChannelState {
+    let mut settled = state.clone();
+    settled.balances = final_balances.iter()
+        .map(|(participant, balance)| (*participant, *balance))
+        .collect();
+    settled.sequence_number += 1;
+    settled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(a: Address, b: Address) -> ChannelState {
+        let mut balances = HashMap::new();
+        balances.insert(a, U256::from(600));
+        balances.insert(b, U256::from(400));
+        ChannelState::new(balances).unwrap()
+    }
+
+    #[test]
+    fn test_signing_message_is_the_final_state() {
+        let a = Address::random();
+        let b = Address::random();
+        let state = test_state(a, b);
+        let mut fee_shares = BTreeMap::new();
+        fee_shares.insert(a, U256::from(6));
+        fee_shares.insert(b, U256::from(4));
+        let balances = final_balances(&state, &fee_shares).unwrap();
+        let final_state = CanonicalState::from(&settled_state(&state, &balances));
+
+        let proposal = ClosingProposal {
+            channel_id: H256::random(),
+            bridge_channel_id: H256::random(),
+            round: 1,
+            proposer: a,
+            fee: U256::from(10),
+            final_balances: balances,
+            fee_shares,
+            final_state: final_state.clone(),
+            signature: Vec::new(),
+            release_signatures: Vec::new(),
+        };
+        assert_eq!(proposal.signing_message(), channel_state_message(proposal.bridge_channel_id, final_state.sequence, final_state.hash()));
+        assert_eq!(final_state.sequence, state.sequence_number + 1);
+
+        let mut answer = proposal.clone();
+        answer.round = 2;
+        answer.proposer = b;
+        assert!(proposal.same_terms(&answer));
+
+        fee_shares = BTreeMap::from([(a, U256::from(7)), (b, U256::from(4))]);
+        answer.final_state = CanonicalState::from(&settled_state(&state, &final_balances(&state, &fee_shares).unwrap()));
+        assert!(!proposal.same_terms(&answer));
+    }
+
+    #[test]
+    fn test_final_balances_deduct_fee_shares() {
+        let a = Address::random();
+        let b = Address::random();
+        let state = test_state(a, b);
+
+        let mut fee_shares = BTreeMap::new();
+        fee_shares.insert(a, U256::from(30));
+        fee_shares.insert(b, U256::from(20));
+        let balances = final_balances(&state, &fee_shares).unwrap();
+        assert_eq!(balances[&a], U256::from(570));
+        assert_eq!(balances[&b], U256::from(380));
+
+        fee_shares.insert(b, U256::from(401));
+        assert!(final_balances(&state, &fee_shares).is_err());
+    }
+
+    #[test]
+    fn test_policy_acceptance() {
+        let policy = ClosePolicy {
+            target_fee: U256::from(100),
+            min_fee: U256::from(50),
+            max_fee: U256::from(150),
+            max_fee_share_bps: 5_000,
+            max_rounds: 5,
+        };
+
+        assert!(policy.accepts(U256::from(100), U256::from(50)));
+        assert!(!policy.accepts(U256::from(100), U256::from(51)));
+        assert!(!policy.accepts(U256::from(200), U256::from(10)));
+    }
+}
@@ -0,0 +1,598 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use ethers::types::H256;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, Mutex};
+
+use super::operations::{
+    ChannelOperation, CloseResult, DisputeResult, EscrowResult, LockResult, OperationError,
+    OperationResult, TransferResult, UnlockResult, UpdateStateResult,
+};
+
+#[derive(Error, Debug)]
+pub enum IdempotencyError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyConfig {
+    /// Completed keys remembered before the oldest are forgotten
+    pub max_entries: usize,
+    /// Where the table is persisted; kept in memory only when unset, as it is by default
+    pub path: Option<PathBuf>,
+    /// How long after admission a key left in flight by a restart keeps refusing retries
+    pub in_flight_timeout: Duration,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            path: None,
+            in_flight_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Successful result of an operation, replayed to retries of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RecordedResult {
+    Transfer(TransferResult),
+    Lock(LockResult),
+    Unlock(UnlockResult),
+    Close(CloseResult),
+    Dispute(DisputeResult),
+    UpdateState(UpdateStateResult),
+    Escrow(EscrowResult),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    fingerprint: H256,
+    /// `None` while the original operation is still running
+    result: Option<RecordedResult>,
+    /// Unix time the key was admitted
+    admitted_at: i64,
+    /// Loaded in flight, so the operation was cut short by a restart
+    #[serde(skip)]
+    interrupted: bool,
+}
+
+impl Entry {
+    fn expired(&self, timeout: Duration) -> bool {
+        self.interrupted
+            && self.admitted_at.saturating_add(timeout.as_secs() as i64) <= chrono::Utc::now().timestamp()
+    }
+}
+
+/// Line of the persisted log: the key's entry as it now is, or `None` once it's forgotten
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: H256,
+    entry: Option<Entry>,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    entries: HashMap<H256, Entry>,
+    // Completed keys, oldest first
+    order: VecDeque<H256>,
+    loaded: bool,
+    // Records in the persisted log, stale ones included
+    records: usize,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Admission {
+    /// First time the key is seen, run the operation
+    New,
+    /// The same operation already completed
+    Replay(RecordedResult),
+    /// The same operation is still running, or was when the node last stopped
+    InFlight,
+    /// The key was used for an operation with different parameters
+    Conflict,
+}
+
+/// Bounded table of idempotency keys and the results of the operations submitted under them
+pub struct IdempotencyStore {
+    config: IdempotencyConfig,
+    table: Mutex<Table>,
+    // Serializes writes of the persisted log
+    persist: Mutex<()>,
+}
+
+impl IdempotencyStore {
+    /// Store persisted at `config.path`, which is loaded when the first key comes in
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Self {
+            config,
+            table: Mutex::new(Table::default()),
+            persist: Mutex::new(()),
+        }
+    }
+
+    pub fn in_memory(max_entries: usize) -> Self {
+        Self::new(IdempotencyConfig { max_entries, ..IdempotencyConfig::default() })
+    }
+
+    /// Opens the store, loading the table persisted at `config.path` if there is one
+    pub async fn open(config: IdempotencyConfig) -> Result<Self, IdempotencyError> {
+        let store = Self::new(config);
+        store.load(&mut *store.table.lock().await).await?;
+        Ok(store)
+    }
+
+    /// Replays the persisted log, dropping a last line left half-written by a crash and
+    /// keys whose operation was interrupted longer than `in_flight_timeout` ago
+    async fn load(&self, table: &mut Table) -> Result<(), IdempotencyError> {
+        if table.loaded {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.config.path {
+            let data = match tokio::fs::read(path).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+
+            // Latest entry of each key, with the line it was written on
+            let mut latest: HashMap<H256, (usize, Entry)> = HashMap::new();
+            let mut records = 0;
+            let mut valid_len = 0;
+
+            for (index, line) in data.split_inclusive(|&byte| byte == b'\n').enumerate() {
+                let record: Record = match serde_json::from_slice(line) {
+                    Ok(record) => record,
+                    Err(_) if !line.ends_with(b"\n") => {
+                        log::warn!("Dropping truncated record at the end of {:?}", path);
+                        break;
+                    }
+                    Err(e) => return Err(IdempotencyError::Encoding(e.to_string())),
+                };
+
+                match record.entry {
+                    Some(entry) => { latest.insert(record.key, (index, entry)); }
+                    None => { latest.remove(&record.key); }
+                }
+                records += 1;
+                valid_len += line.len();
+            }
+
+            if valid_len < data.len() {
+                let file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+                file.set_len(valid_len as u64).await?;
+            }
+
+            let mut completed = Vec::new();
+            for (key, (index, mut entry)) in latest {
+                if entry.result.is_some() {
+                    completed.push((index, key));
+                } else {
+                    entry.interrupted = true;
+                    if entry.expired(self.config.in_flight_timeout) {
+                        continue;
+                    }
+                }
+                table.entries.insert(key, entry);
+            }
+            completed.sort_unstable();
+            table.order.extend(completed.into_iter().map(|(_, key)| key));
+            table.records = records;
+        }
+
+        table.loaded = true;
+        Ok(())
+    }
+
+    /// Admits a key. New keys are persisted as in flight before the operation runs,
+    /// so one interrupted by a restart isn't run a second time until its admission
+    /// is `in_flight_timeout` old.
+    pub async fn admit(&self, key: H256, fingerprint: H256) -> Result<Admission, IdempotencyError> {
+        let entry = {
+            let mut table = self.table.lock().await;
+            self.load(&mut table).await?;
+
+            match table.entries.get(&key) {
+                Some(entry) if entry.expired(self.config.in_flight_timeout) => {}
+                Some(entry) if entry.fingerprint != fingerprint => return Ok(Admission::Conflict),
+                Some(Entry { result: Some(result), .. }) => return Ok(Admission::Replay(result.clone())),
+                Some(Entry { result: None, .. }) => return Ok(Admission::InFlight),
+                None => {}
+            }
+
+            let entry = Entry {
+                fingerprint,
+                result: None,
+                admitted_at: chrono::Utc::now().timestamp(),
+                interrupted: false,
+            };
+            table.entries.insert(key, entry.clone());
+            entry
+        };
+
+        if let Err(e) = self.append(vec![Record { key, entry: Some(entry) }]).await {
+            self.table.lock().await.entries.remove(&key);
+            return Err(e);
+        }
+
+        Ok(Admission::New)
+    }
+
+    /// Records the outcome of an admitted operation. Failed operations are forgotten
+    /// so the client can retry them.
+    pub async fn complete(&self, key: H256, result: Option<RecordedResult>) {
+        let mut records = Vec::new();
+        {
+            let mut table = self.table.lock().await;
+            match result {
+                Some(result) => {
+                    if let Some(entry) = table.entries.get_mut(&key) {
+                        entry.result = Some(result);
+                        records.push(Record { key, entry: Some(entry.clone()) });
+                        table.order.push_back(key);
+                    }
+
+                    while table.order.len() > self.config.max_entries {
+                        if let Some(oldest) = table.order.pop_front() {
+                            table.entries.remove(&oldest);
+                            records.push(Record { key: oldest, entry: None });
+                        }
+                    }
+                }
+                None => {
+                    table.entries.remove(&key);
+                    records.push(Record { key, entry: None });
+                }
+            }
+        }
+
+        if let Err(e) = self.append(records).await {
+            log::error!("Failed to persist idempotency table: {:?}", e);
+        }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.table.lock().await.order.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Appends the records to the log and syncs it, rewriting the log from the live
+    /// entries once most of its records are stale
+    async fn append(&self, records: Vec<Record>) -> Result<(), IdempotencyError> {
+        let Some(path) = &self.config.path else { return Ok(()) };
+        if records.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.persist.lock().await;
+
+        let mut data = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut data, record).map_err(|e| IdempotencyError::Encoding(e.to_string()))?;
+            data.push(b'\n');
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        let length = file.metadata().await?.len();
+        let written = async {
+            file.write_all(&data).await?;
+            file.sync_data().await
+        }.await;
+        if let Err(e) = written {
+            // Don't leave part of a line for the next record to be appended to
+            let _ = file.set_len(length).await;
+            return Err(e.into());
+        }
+
+        let (data, live) = {
+            let mut table = self.table.lock().await;
+            table.records += records.len();
+            if table.records <= 2 * table.entries.len().max(self.config.max_entries) {
+                return Ok(());
+            }
+            Self::snapshot(&table)?
+        };
+
+        // Written next to the log first so a crash never leaves a torn file
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = tokio::fs::File::create(&tmp_path).await?;
+        tmp.write_all(&data).await?;
+        tmp.sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        self.table.lock().await.records = live;
+
+        Ok(())
+    }
+
+    /// The live entries as log records, completed ones oldest first and then those in
+    /// flight, along with how many there are
+    fn snapshot(table: &Table) -> Result<(Vec<u8>, usize), IdempotencyError> {
+        let in_flight = table.entries.iter()
+            .filter(|(_, entry)| entry.result.is_none());
+        let mut data = Vec::new();
+        let mut records = 0;
+        for (key, entry) in table.order.iter()
+            .filter_map(|key| table.entries.get(key).map(|entry| (key, entry)))
+            .chain(in_flight)
+        {
+            serde_json::to_writer(&mut data, &Record { key: *key, entry: Some(entry.clone()) })
+                .map_err(|e| IdempotencyError::Encoding(e.to_string()))?;
+            data.push(b'\n');
+            records += 1;
+        }
+        Ok((data, records))
+    }
+}
+
+/// Checks the operation's idempotency key. Returns the operation to execute, with its
+/// response wrapped so the result gets recorded, or `None` if it was already answered.
+pub(super) async fn guard(
+    store: &Arc<IdempotencyStore>,
+    mut operation: ChannelOperation,
+) -> Option<ChannelOperation> {
+    let Some(key) = operation.idempotency_key() else { return Some(operation) };
+    let admission = match store.admit(key, operation.fingerprint()).await {
+        Ok(admission) => admission,
+        Err(e) => {
+            log::error!("Idempotency table unavailable: {:?}", e);
+            operation.reject(OperationError::Rejected("Idempotency table unavailable".to_string()));
+            return None;
+        }
+    };
+
+    let execute = match &mut operation {
+        ChannelOperation::Transfer { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::Transfer,
+            |result| match result { RecordedResult::Transfer(r) => Some(r), _ => None },
+        ),
+        ChannelOperation::CreateLock { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::Lock,
+            |result| match result { RecordedResult::Lock(r) => Some(r), _ => None },
+        ),
+        ChannelOperation::Unlock { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::Unlock,
+            |result| match result { RecordedResult::Unlock(r) => Some(r), _ => None },
+        ),
+        ChannelOperation::Close { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::Close,
+            |result| match result { RecordedResult::Close(r) => Some(r), _ => None },
+        ),
+        ChannelOperation::Dispute { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::Dispute,
+            |result| match result { RecordedResult::Dispute(r) => Some(r), _ => None },
+        ),
+        ChannelOperation::UpdateState { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::UpdateState,
+            |result| match result { RecordedResult::UpdateState(r) => Some(r), _ => None },
+        ),
+        ChannelOperation::CreateEscrow { response, .. }
+        | ChannelOperation::ReleaseEscrow { response, .. }
+        | ChannelOperation::RefundEscrow { response, .. } => resolve(
+            store, key, admission, response,
+            RecordedResult::Escrow,
+            |result| match result { RecordedResult::Escrow(r) => Some(r), _ => None },
+        ),
+    };
+
+    execute.then_some(operation)
+}
+
+fn resolve<T: Clone + Send + 'static>(
+    store: &Arc<IdempotencyStore>,
+    key: H256,
+    admission: Admission,
+    response: &mut oneshot::Sender<OperationResult<T>>,
+    record: fn(T) -> RecordedResult,
+    extract: fn(RecordedResult) -> Option<T>,
+) -> bool {
+    let reply = |response: &mut oneshot::Sender<OperationResult<T>>, result| {
+        let (placeholder, _) = oneshot::channel();
+        let _ = std::mem::replace(response, placeholder).send(result);
+    };
+
+    match admission {
+        Admission::New => {
+            let (wrapped, result) = oneshot::channel();
+            let client = std::mem::replace(response, wrapped);
+            let store = Arc::clone(store);
+
+            tokio::spawn(async move {
+                let result = result.await.unwrap_or_else(|_| {
+                    Err(OperationError::Rejected("Operation dropped".to_string()))
+                });
+                store.complete(key, result.as_ref().ok().cloned().map(record)).await;
+                let _ = client.send(result);
+            });
+            true
+        }
+        Admission::Replay(recorded) => {
+            let result = extract(recorded).ok_or_else(|| {
+                OperationError::Rejected("Idempotency key reused for a different operation".to_string())
+            });
+            reply(response, result);
+            false
+        }
+        Admission::InFlight => {
+            reply(response, Err(OperationError::Rejected(
+                "Operation with this idempotency key is still in progress".to_string()
+            )));
+            false
+        }
+        Admission::Conflict => {
+            reply(response, Err(OperationError::Rejected(
+                "Idempotency key reused with different parameters".to_string()
+            )));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::state::ChannelState;
+    use ethers::types::{Address, U256};
+
+    fn transfer(
+        key: H256,
+        channel_id: H256,
+        amount: u64,
+    ) -> (ChannelOperation, oneshot::Receiver<OperationResult<TransferResult>>) {
+        let (response, result) = oneshot::channel();
+        let operation = ChannelOperation::Transfer {
+            channel_id,
+            from: Address::zero(),
+            to: Address::repeat_byte(1),
+            amount: U256::from(amount),
+            idempotency_key: Some(key),
+            response,
+        };
+        (operation, result)
+    }
+
+    fn answer(operation: ChannelOperation, transaction_hash: H256) {
+        if let ChannelOperation::Transfer { channel_id, response, .. } = operation {
+            let _ = response.send(Ok(TransferResult {
+                channel_id,
+                new_state: ChannelState::default(),
+                transaction_hash,
+            }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_replays_original_result() {
+        let store = Arc::new(IdempotencyStore::in_memory(16));
+        let (key, channel_id) = (H256::random(), H256::random());
+
+        let (operation, original) = transfer(key, channel_id, 10);
+        let operation = guard(&store, operation).await.unwrap();
+        let transaction_hash = H256::random();
+        answer(operation, transaction_hash);
+        assert_eq!(original.await.unwrap().unwrap().transaction_hash, transaction_hash);
+
+        let (retry, replayed) = transfer(key, channel_id, 10);
+        assert!(guard(&store, retry).await.is_none());
+        assert_eq!(replayed.await.unwrap().unwrap().transaction_hash, transaction_hash);
+    }
+
+    #[tokio::test]
+    async fn test_key_reuse_with_different_parameters_rejected() {
+        let store = Arc::new(IdempotencyStore::in_memory(16));
+        let (key, channel_id) = (H256::random(), H256::random());
+
+        let (operation, _original) = transfer(key, channel_id, 10);
+        let _running = guard(&store, operation).await.unwrap();
+
+        let (other, result) = transfer(key, channel_id, 20);
+        assert!(guard(&store, other).await.is_none());
+        assert!(matches!(result.await.unwrap(), Err(OperationError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn test_failed_operation_can_be_retried() {
+        let store = Arc::new(IdempotencyStore::in_memory(16));
+        let (key, channel_id) = (H256::random(), H256::random());
+
+        let (operation, original) = transfer(key, channel_id, 10);
+        guard(&store, operation).await.unwrap()
+            .reject(OperationError::Timeout);
+        assert!(original.await.unwrap().is_err());
+
+        let (retry, _result) = transfer(key, channel_id, 10);
+        assert!(guard(&store, retry).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_table_is_bounded_and_persisted() {
+        let path = std::env::temp_dir().join(format!("idempotency-{}.json", H256::random()));
+        let config = IdempotencyConfig {
+            max_entries: 2,
+            path: Some(path.clone()),
+            ..IdempotencyConfig::default()
+        };
+        let store = Arc::new(IdempotencyStore::open(config.clone()).await.unwrap());
+        let channel_id = H256::random();
+
+        let keys: Vec<H256> = (0..3).map(|_| H256::random()).collect();
+        for key in &keys {
+            let (operation, result) = transfer(*key, channel_id, 10);
+            answer(guard(&store, operation).await.unwrap(), H256::random());
+            result.await.unwrap().unwrap();
+        }
+        assert_eq!(store.len().await, 2);
+
+        // Interrupted before it completed
+        let (interrupted, _result) = transfer(H256::random(), channel_id, 10);
+        let interrupted = guard(&store, interrupted).await.unwrap();
+
+        // Only completed keys count towards the bound
+        let reopened = IdempotencyStore::open(config).await.unwrap();
+        assert_eq!(reopened.len().await, 2);
+        assert!(matches!(reopened.admit(keys[0], H256::zero()).await.unwrap(), Admission::New));
+        let (retry, _) = transfer(keys[2], channel_id, 10);
+        assert!(matches!(
+            reopened.admit(keys[2], retry.fingerprint()).await.unwrap(),
+            Admission::Replay(RecordedResult::Transfer(_))
+        ));
+        let key = interrupted.idempotency_key().unwrap();
+        assert!(matches!(
+            reopened.admit(key, interrupted.fingerprint()).await.unwrap(),
+            Admission::InFlight
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_keys_expire_and_log_is_compacted() {
+        let path = std::env::temp_dir().join(format!("idempotency-{}.json", H256::random()));
+        let config = IdempotencyConfig {
+            max_entries: 2,
+            path: Some(path.clone()),
+            in_flight_timeout: Duration::ZERO,
+        };
+        let store = Arc::new(IdempotencyStore::open(config.clone()).await.unwrap());
+        let channel_id = H256::random();
+
+        for _ in 0..10 {
+            let (operation, result) = transfer(H256::random(), channel_id, 10);
+            answer(guard(&store, operation).await.unwrap(), H256::random());
+            result.await.unwrap().unwrap();
+        }
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= 2 * config.max_entries + 2, "{} records logged", lines);
+
+        let (interrupted, _result) = transfer(H256::random(), channel_id, 10);
+        let interrupted = guard(&store, interrupted).await.unwrap();
+
+        // Still running in this process, so retries wait for it
+        let key = interrupted.idempotency_key().unwrap();
+        assert!(matches!(store.admit(key, interrupted.fingerprint()).await.unwrap(), Admission::InFlight));
+
+        // After a restart it runs again once its admission has timed out
+        let reopened = IdempotencyStore::open(config).await.unwrap();
+        assert_eq!(reopened.len().await, 2);
+        assert!(matches!(reopened.admit(key, interrupted.fingerprint()).await.unwrap(), Admission::New));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
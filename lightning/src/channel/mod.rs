@@ -1,14 +1,41 @@
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
-use tokio::sync::mpsc;
+use tokio::sync::broadcast;
 use serde::{Serialize, Deserialize};
 use ethers::types::{Address, U256, H256};
+use sha3::{Digest, Keccak256};
 use thiserror::Error;
+use flashchain_common::types::AssetId;
 
 pub mod state;
 pub mod operations;
+pub mod state_machine;
+pub mod clock;
+pub mod sweeper;
+pub mod parameters;
+pub mod closing;
+pub mod open;
+pub mod backup;
+pub mod actor;
+pub mod batching;
+pub mod idempotency;
 
-use state::{ChannelState, ChannelStatus};
-use operations::{ChannelOperation, OperationResult};
+use state::{ChannelState, ChannelStatus, TimeLock};
+use parameters::ChannelParameters;
+use operations::{ChannelOperation, OperationExecutor};
+use actor::{ActorConfig, ActorError, ChannelDirectory, ChannelHandle};
+use batching::TransferBatcher;
+use idempotency::IdempotencyStore;
+use state_machine::{ChannelEvent, TransitionContext, TransitionRecord};
+use clock::{ChainClock, ClockError, TimeoutEvent, TimeoutKind, TimeoutScheduler};
+use sweeper::ExpirySweeper;
+use flashchain_common::encoding::CanonicalState;
+use crate::crypto::signature::{channel_state_message, recover_signer, SignatureVerifier, RECOVERABLE_SIGNATURE_LENGTH};
+use crate::crypto::CryptoManager;
+use crate::events::{EventBus, LightningEvent};
+use crate::network::NetworkManager;
+use crate::routing::payment::PaymentProcessor;
+use crate::state::StateManager;
 
 #[derive(Error, Debug)]
 pub enum ChannelError {
@@ -28,6 +55,20 @@ pub enum ChannelError {
     ChannelExpired,
     #[error("Database error: {0}")]
     DatabaseError(String),
+    #[error("Chain clock error: {0}")]
+    Clock(#[from] ClockError),
+    #[error("Channel actor error: {0}")]
+    Actor(#[from] ActorError),
+    #[error("Reserve violation: {remaining} left, reserve is {reserve}")]
+    ReserveViolation { remaining: U256, reserve: U256 },
+    #[error("Too many pending HTLCs, limit is {0}")]
+    TooManyHtlcs(usize),
+    #[error("In-flight value {in_flight} exceeds limit {limit}")]
+    InFlightExceeded { in_flight: U256, limit: U256 },
+    #[error("HTLC amount {amount} below minimum {minimum}")]
+    HtlcBelowMinimum { amount: U256, minimum: U256 },
+    #[error("Amount {amount} below dust limit {dust_limit}")]
+    DustAmount { amount: U256, dust_limit: U256 },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +84,26 @@ pub struct Channel {
     pub timeout_height: u64,
     pub dispute_period: u64,
     pub last_update: u64,
+    /// Id `BridgeCore` assigned when the channel was registered on chain
+    #[serde(default)]
+    pub bridge_channel_id: Option<H256>,
+}
+
+impl Channel {
+    /// Id the bridge contracts know the channel by, which signatures over its
+    /// states commit to
+    pub fn bridge_id(&self) -> H256 {
+        self.bridge_channel_id.unwrap_or(self.channel_id)
+    }
+
+    /// Funded capacity in `asset`, zero if the channel doesn't hold it
+    pub fn capacity_of(&self, asset: AssetId) -> U256 {
+        if asset.is_native() {
+            return self.capacity;
+        }
+
+        self.state.token_capacity.get(&asset).copied().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,22 +116,133 @@ pub struct ChannelConfig {
 }
 
 pub struct ChannelManager {
-    channels: Arc<RwLock<HashMap<H256, Channel>>>,
+    directory: ChannelDirectory,
+    transitions: Arc<RwLock<HashMap<H256, Vec<TransitionRecord>>>>,
     config: ChannelConfig,
-    operation_tx: mpsc::Sender<ChannelOperation>,
-    operation_rx: mpsc::Receiver<ChannelOperation>,
+    clock: Arc<dyn ChainClock>,
+    timeouts: Arc<TimeoutScheduler>,
+    changes: broadcast::Sender<H256>,
+    events: Arc<EventBus>,
 }
 
 impl ChannelManager {
-    pub fn new(config: ChannelConfig) -> Self {
-        let (operation_tx, operation_rx) = mpsc::channel(1000);
+    pub fn new(
+        config: ChannelConfig,
+        actor_config: ActorConfig,
+        clock: Arc<dyn ChainClock>,
+        signature_verifier: Arc<SignatureVerifier>,
+    ) -> Self {
+        Self::with_events(config, actor_config, clock, signature_verifier, Arc::new(EventBus::new(1000)))
+    }
+
+    /// Like `new`, but publishes channel events on a bus shared with the other subsystems
+    pub fn with_events(
+        config: ChannelConfig,
+        actor_config: ActorConfig,
+        clock: Arc<dyn ChainClock>,
+        signature_verifier: Arc<SignatureVerifier>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        let (changes, _) = broadcast::channel(1000);
+        let timeouts = Arc::new(TimeoutScheduler::new(clock.clone()));
+        let executor = OperationExecutor::new(signature_verifier, clock.clone())
+            .with_events(events.clone())
+            .with_scheduler(timeouts.clone());
         
         Self {
-            channels: Arc::new(RwLock::new(HashMap::new())),
+            directory: ChannelDirectory::new(actor_config, Arc::new(executor))
+                .with_changes(changes.clone()),
+            transitions: Arc::new(RwLock::new(HashMap::new())),
             config,
-            operation_tx,
-            operation_rx,
+            timeouts,
+            clock,
+            changes,
+            events,
+        }
+    }
+
+    /// Replaces the default idempotency table, which is kept in memory only
+    pub fn with_idempotency(mut self, store: Arc<IdempotencyStore>) -> Self {
+        self.directory = self.directory.with_idempotency(store);
+        self
+    }
+
+    /// Settles transfers in batches, each countersigned by the other participants
+    pub fn with_batching(mut self, batcher: Arc<TransferBatcher>) -> Self {
+        self.directory = self.directory.with_batching(batcher);
+        self
+    }
+
+    /// Starts firing timeout events as the chain clock advances
+    pub fn start_timeouts(&self) {
+        self.timeouts.clone().start();
+    }
+
+    /// Expires the HTLCs and time locks of every channel as their timeouts fire,
+    /// refunding their senders and failing the payments they belonged to
+    pub async fn start_sweeper(
+        self: &Arc<Self>,
+        state_manager: Arc<StateManager>,
+        payment_processor: Arc<PaymentProcessor>,
+        network: Arc<NetworkManager>,
+        crypto: Arc<CryptoManager>,
+        node_address: Address,
+    ) -> Arc<ExpirySweeper> {
+        let sweeper = Arc::new(ExpirySweeper::new(
+            self.clone(),
+            state_manager,
+            payment_processor,
+            network,
+            crypto,
+            node_address,
+        ));
+        sweeper.clone().start().await;
+        sweeper
+    }
+
+    pub fn subscribe_timeouts(&self) -> broadcast::Receiver<TimeoutEvent> {
+        self.timeouts.subscribe()
+    }
+
+    pub fn timeout_scheduler(&self) -> Arc<TimeoutScheduler> {
+        self.timeouts.clone()
+    }
+
+    pub fn clock(&self) -> Arc<dyn ChainClock> {
+        self.clock.clone()
+    }
+
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+
+    /// Receives the id of every channel that is created or changed
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<H256> {
+        self.changes.subscribe()
+    }
+
+    pub async fn list_channels(&self) -> Result<Vec<Channel>, ChannelError> {
+        let mut channels = Vec::new();
+        for channel_id in self.directory.channel_ids() {
+            channels.push(self.get_channel(channel_id).await?);
         }
+
+        Ok(channels)
+    }
+
+    /// Handle of the actor that owns the channel, for reading or updating it in place
+    pub fn channel_handle(&self, channel_id: H256) -> Result<ChannelHandle, ChannelError> {
+        self.directory.get(channel_id).ok_or(ChannelError::NotFound(channel_id))
+    }
+
+    /// Queues an operation on the channel's actor, waiting while its mailbox is full
+    pub async fn submit_operation(&self, operation: ChannelOperation) -> Result<(), ChannelError> {
+        Ok(self.directory.dispatch(operation).await?)
+    }
+
+    /// Queues an operation on the channel's actor, rejecting it if the mailbox is full
+    pub fn try_submit_operation(&self, operation: ChannelOperation) -> Result<(), ChannelError> {
+        Ok(self.directory.try_dispatch(operation)?)
     }
 
     pub async fn create_channel(
@@ -79,6 +251,21 @@ impl ChannelManager {
         participants: Vec<Address>,
         capacity: U256,
         dispute_period: u64,
+        parameters: ChannelParameters,
+    ) -> Result<Channel, ChannelError> {
+        let channel_id = self.generate_channel_id(&participants, shard_id);
+        self.create_channel_with_id(channel_id, shard_id, participants, capacity, dispute_period, parameters).await
+    }
+
+    /// Creates the local record for a channel whose id was chosen by the opening peer
+    pub async fn create_channel_with_id(
+        &self,
+        channel_id: H256,
+        shard_id: u64,
+        participants: Vec<Address>,
+        capacity: U256,
+        dispute_period: u64,
+        parameters: ChannelParameters,
     ) -> Result<Channel, ChannelError> {
         // Validate parameters
         if participants.len() > self.config.max_participants {
@@ -99,8 +286,13 @@ impl ChannelManager {
             ));
         }
 
-        let channel_id = self.generate_channel_id(&participants, shard_id);
+        parameters.validate(capacity, participants.len())?;
+
         let current_height = self.get_current_block_height().await?;
+        let state = ChannelState {
+            parameters,
+            ..Default::default()
+        };
 
         let channel = Channel {
             channel_id,
@@ -108,19 +300,33 @@ impl ChannelManager {
             participants,
             capacity,
             balance: U256::zero(),
-            state: ChannelState::default(),
+            state,
             status: ChannelStatus::Initializing,
             nonce: 0,
             timeout_height: current_height + dispute_period,
             dispute_period,
             last_update: current_height,
+            bridge_channel_id: None,
         };
 
-        // Store channel
-        let mut channels = self.channels.write().map_err(|_| {
-            ChannelError::DatabaseError("Failed to acquire write lock".to_string())
+        // The channel's actor owns it from here on
+        self.directory.spawn(channel.clone()).map_err(|e| match e {
+            ActorError::AlreadyRunning(_) => ChannelError::InvalidStateTransition(
+                format!("Channel {} already exists", channel_id)
+            ),
+            other => ChannelError::Actor(other),
         })?;
-        channels.insert(channel_id, channel.clone());
+        let _ = self.changes.send(channel_id);
+        let _ = self.events.publish(LightningEvent::ChannelOpened {
+            channel_id,
+            participants: channel.participants.clone(),
+            capacity,
+        });
+
+        self.timeouts.schedule(
+            channel.timeout_height,
+            TimeoutKind::ChannelExpiry { channel_id },
+        ).await;
 
         Ok(channel)
     }
@@ -128,150 +334,721 @@ impl ChannelManager {
     pub async fn update_channel_state(
         &self,
         channel_id: H256,
-        new_state: ChannelState,
+        mut new_state: ChannelState,
         signatures: Vec<Vec<u8>>,
     ) -> Result<Channel, ChannelError> {
-        let mut channel = self.get_channel(channel_id).await?;
-
-        // Verify channel is not locked or expired
-        if channel.status == ChannelStatus::Locked {
-            return Err(ChannelError::ChannelLocked);
-        }
-
-        if self.is_channel_expired(&channel).await? {
-            return Err(ChannelError::ChannelExpired);
-        }
+        let channel = self.get_channel(channel_id).await?;
 
         // Verify signatures
         self.verify_signatures(&channel, &new_state, &signatures)?;
 
-        // Update channel state
-        channel.state = new_state;
-        channel.nonce += 1;
-        channel.last_update = self.get_current_block_height().await?;
+        let current_height = self.get_current_block_height().await?;
+        let (channel, _) = self.modify(channel_id, move |channel| {
+            // Verify channel is not locked or expired
+            if channel.status == ChannelStatus::Locked {
+                return Err(ChannelError::ChannelLocked);
+            }
 
-        // Store updated channel
-        let mut channels = self.channels.write().map_err(|_| {
-            ChannelError::DatabaseError("Failed to acquire write lock".to_string())
-        })?;
-        channels.insert(channel_id, channel.clone());
+            if channel.status != ChannelStatus::Active {
+                return Err(ChannelError::InvalidStateTransition(
+                    format!("Cannot update channel in {:?} status", channel.status)
+                ));
+            }
+
+            if current_height >= channel.timeout_height {
+                return Err(ChannelError::ChannelExpired);
+            }
+
+            if new_state.sequence_number <= channel.state.sequence_number {
+                return Err(ChannelError::InvalidStateTransition(format!(
+                    "Stale state {}, channel is at {}",
+                    new_state.sequence_number, channel.state.sequence_number
+                )));
+            }
+
+            new_state.keep_unsigned_fields(&channel.state);
+            new_state.verify_state(channel.capacity, &channel.state.token_capacity)
+                .map_err(|e| ChannelError::InvalidStateTransition(e.to_string()))?;
+
+            // Update channel state
+            channel.state = new_state;
+            channel.nonce += 1;
+            channel.last_update = current_height;
+            Ok(())
+        }).await?;
 
         Ok(channel)
     }
 
+    /// Returns an expired time lock to its sender. Gives back the channel, the lock and
+    /// the state before the refund, or `None` if the lock was unlocked in time.
+    pub async fn expire_lock(
+        &self,
+        channel_id: H256,
+        lock_id: H256,
+    ) -> Result<Option<(Channel, TimeLock, CanonicalState)>, ChannelError> {
+        let current_height = self.get_current_block_height().await?;
+        let (channel, expired) = self.modify(channel_id, move |channel| {
+            let Some(lock) = channel.state.get_lock(&lock_id) else {
+                return Ok(None);
+            };
+
+            let previous = CanonicalState::from(&channel.state);
+            channel.state.expire_lock(lock_id, current_height)
+                .map_err(|e| ChannelError::InvalidStateTransition(e.to_string()))?;
+            channel.nonce += 1;
+            channel.last_update = current_height;
+            Ok(Some((lock, previous)))
+        }).await?;
+
+        Ok(expired.map(|(lock, previous)| (channel, lock, previous)))
+    }
+
     pub async fn close_channel(
         &self,
         channel_id: H256,
         final_state: ChannelState,
         signatures: Vec<Vec<u8>>,
     ) -> Result<Channel, ChannelError> {
-        let mut channel = self.get_channel(channel_id).await?;
+        let channel = self.get_channel(channel_id).await?;
 
         // Verify signatures
         self.verify_signatures(&channel, &final_state, &signatures)?;
 
         // Update channel status
-        channel.status = ChannelStatus::Closing;
-        channel.state = final_state;
-        channel.timeout_height = self.get_current_block_height().await? + channel.dispute_period;
+        let current_height = self.get_current_block_height().await?;
+        let (channel, record) = self.modify(channel_id, move |channel| {
+            let record = apply_transition(channel, ChannelEvent::InitiateClose, current_height)?;
+            channel.state = final_state;
+            channel.timeout_height = current_height + channel.dispute_period;
+            Ok(record)
+        }).await?;
+        self.record_transition(record)?;
 
-        // Store updated channel
-        let mut channels = self.channels.write().map_err(|_| {
-            ChannelError::DatabaseError("Failed to acquire write lock".to_string())
-        })?;
-        channels.insert(channel_id, channel.clone());
+        self.timeouts.schedule(
+            channel.timeout_height,
+            TimeoutKind::DisputeWindow { channel_id },
+        ).await;
 
         Ok(channel)
     }
 
-    pub async fn dispute_channel(
+    /// Moves the channel into Closing for a cooperative close. No dispute window is
+    /// scheduled; new HTLCs are rejected from here on because the channel is no longer Active.
+    pub async fn begin_shutdown(&self, channel_id: H256) -> Result<Channel, ChannelError> {
+        self.transition_channel(channel_id, ChannelEvent::InitiateClose).await
+    }
+
+    /// Closes the channel on a final state signed by every participant, skipping the dispute period
+    pub async fn cooperative_close(
         &self,
         channel_id: H256,
-        disputed_state: ChannelState,
-        proof: Vec<u8>,
+        final_state: ChannelState,
+        signatures: Vec<Vec<u8>>,
     ) -> Result<Channel, ChannelError> {
-        let mut channel = self.get_channel(channel_id).await?;
+        let channel = self.get_channel(channel_id).await?;
 
-        // Verify channel is in closing state
-        if channel.status != ChannelStatus::Closing {
+        if signatures.len() != channel.participants.len() {
+            return Err(ChannelError::InvalidSignature);
+        }
+
+        if !final_state.locks.is_empty() || !final_state.escrows.is_empty() {
             return Err(ChannelError::InvalidStateTransition(
-                "Channel must be in closing state".to_string()
+                "Final state still has pending locks".to_string()
             ));
         }
 
-        // Verify dispute is within timeframe
+        // Verify signatures
+        self.verify_signatures(&channel, &final_state, &signatures)?;
+
         let current_height = self.get_current_block_height().await?;
-        if current_height >= channel.timeout_height {
-            return Err(ChannelError::ChannelExpired);
-        }
+        let (channel, record) = self.modify(channel_id, move |channel| {
+            let record = apply_transition(channel, ChannelEvent::CooperativeClose, current_height)?;
+            channel.state = final_state;
+            channel.nonce += 1;
+            channel.last_update = current_height;
+            Ok(record)
+        }).await?;
+        self.record_transition(record)?;
+
+        self.timeouts.cancel(TimeoutKind::ChannelExpiry { channel_id }).await;
+        self.timeouts.cancel(TimeoutKind::DisputeWindow { channel_id }).await;
+
+        Ok(channel)
+    }
+
+    pub async fn dispute_channel(
+        &self,
+        channel_id: H256,
+        disputed_state: ChannelState,
+        proof: Vec<u8>,
+    ) -> Result<Channel, ChannelError> {
+        let channel = self.get_channel(channel_id).await?;
 
         // Verify proof
         self.verify_dispute_proof(&channel, &disputed_state, &proof)?;
 
-        // Update channel state
-        channel.state = disputed_state;
-        channel.status = ChannelStatus::Disputed;
+        let current_height = self.get_current_block_height().await?;
+        let (channel, record) = self.modify(channel_id, move |channel| {
+            // Verify dispute is within timeframe
+            if channel.status == ChannelStatus::Closing && current_height >= channel.timeout_height {
+                return Err(ChannelError::ChannelExpired);
+            }
 
-        // Store updated channel
-        let mut channels = self.channels.write().map_err(|_| {
-            ChannelError::DatabaseError("Failed to acquire write lock".to_string())
-        })?;
-        channels.insert(channel_id, channel.clone());
+            // Only a newer state, or the one we already hold, may be disputed
+            let current = &channel.state;
+            let newer = disputed_state.sequence_number > current.sequence_number;
+            let same = disputed_state.sequence_number == current.sequence_number
+                && disputed_state.state_hash() == current.state_hash();
+            if !newer && !same {
+                return Err(ChannelError::InvalidStateTransition(format!(
+                    "Stale disputed state {}, channel is at {}",
+                    disputed_state.sequence_number, current.sequence_number
+                )));
+            }
+
+            let mut disputed_state = disputed_state;
+            disputed_state.keep_unsigned_fields(&channel.state);
+            disputed_state.verify_state(channel.capacity, &channel.state.token_capacity)
+                .map_err(|e| ChannelError::InvalidStateTransition(e.to_string()))?;
+
+            // Escrow releases can't be rolled back by an older state
+            verify_dispute_escrows(&channel.state, &disputed_state)?;
+
+            let record = apply_transition(channel, ChannelEvent::Dispute, current_height)?;
+            // Kept exactly as signed; expired escrows are refunded at settlement
+            channel.state = disputed_state;
+            Ok(record)
+        }).await?;
+        self.record_transition(record)?;
 
         Ok(channel)
     }
 
-    // Helper functions
+    /// Sets the initial balances both participants signed while the channel is still being funded
+    pub async fn set_initial_state(
+        &self,
+        channel_id: H256,
+        initial_balances: HashMap<Address, U256>,
+        signatures: Vec<Vec<u8>>,
+    ) -> Result<Channel, ChannelError> {
+        let channel = self.get_channel(channel_id).await?;
+
+        let total = initial_balances.values()
+            .try_fold(U256::zero(), |acc, &val| acc.checked_add(val))
+            .ok_or_else(|| ChannelError::CapacityExceeded("Initial balances overflow".into()))?;
+        if total != channel.capacity {
+            return Err(ChannelError::CapacityExceeded(
+                format!("Initial balances {} don't match capacity {}", total, channel.capacity)
+            ));
+        }
+
+        let mut state = ChannelState::new(initial_balances)
+            .map_err(|e| ChannelError::InvalidStateTransition(e.to_string()))?;
+        state.parameters = channel.state.parameters.clone();
+
+        // Verify signatures
+        self.verify_signatures(&channel, &state, &signatures)?;
+
+        let (channel, _) = self.modify(channel_id, move |channel| {
+            if channel.status != ChannelStatus::Initializing {
+                return Err(ChannelError::InvalidStateTransition(
+                    format!("Cannot fund channel in {:?} status", channel.status)
+                ));
+            }
+
+            channel.state = state;
+            channel.balance = total;
+            Ok(())
+        }).await?;
+
+        Ok(channel)
+    }
+
+    /// Drops a channel that never got funded
+    pub async fn abort_channel(&self, channel_id: H256) -> Result<Channel, ChannelError> {
+        let channel = self.transition_channel(channel_id, ChannelEvent::Abort).await?;
+        self.timeouts.cancel(TimeoutKind::ChannelExpiry { channel_id }).await;
+        Ok(channel)
+    }
+
+    pub async fn activate_channel(&self, channel_id: H256) -> Result<Channel, ChannelError> {
+        self.transition_channel(channel_id, ChannelEvent::Funded).await
+    }
+
+    /// Records the id the channel was registered under on chain
+    pub async fn set_bridge_channel_id(
+        &self,
+        channel_id: H256,
+        bridge_channel_id: H256,
+    ) -> Result<Channel, ChannelError> {
+        let (channel, _) = self.modify(channel_id, move |channel| {
+            channel.bridge_channel_id = Some(bridge_channel_id);
+            Ok(())
+        }).await?;
+
+        Ok(channel)
+    }
+
+    /// Closes the channel once its dispute window is over. Escrows that timed out by
+    /// then go back to their senders, since nobody can co-sign a refund anymore.
+    pub async fn settle_channel(&self, channel_id: H256) -> Result<Channel, ChannelError> {
+        let current_height = self.get_current_block_height().await?;
+        let (channel, record) = self.modify(channel_id, move |channel| {
+            let record = apply_transition(channel, ChannelEvent::Settle, current_height)?;
+            channel.state.refund_expired_escrows(current_height)
+                .map_err(|e| ChannelError::InvalidStateTransition(e.to_string()))?;
+            Ok(record)
+        }).await?;
+        self.record_transition(record)?;
 
-    async fn get_channel(&self, channel_id: H256) -> Result<Channel, ChannelError> {
-        let channels = self.channels.read().map_err(|_| {
+        Ok(channel)
+    }
+
+    pub async fn transition_channel(
+        &self,
+        channel_id: H256,
+        event: ChannelEvent,
+    ) -> Result<Channel, ChannelError> {
+        let current_height = self.get_current_block_height().await?;
+        let (channel, record) = self.modify(channel_id, move |channel| {
+            apply_transition(channel, event, current_height)
+        }).await?;
+        self.record_transition(record)?;
+
+        Ok(channel)
+    }
+
+    pub fn get_transition_history(&self, channel_id: H256) -> Result<Vec<TransitionRecord>, ChannelError> {
+        let transitions = self.transitions.read().map_err(|_| {
             ChannelError::DatabaseError("Failed to acquire read lock".to_string())
         })?;
 
-        channels.get(&channel_id)
-            .cloned()
-            .ok_or(ChannelError::NotFound(channel_id))
+        Ok(transitions.get(&channel_id).cloned().unwrap_or_default())
     }
 
-    fn generate_channel_id(&self, participants: &[Address], shard_id: u64) -> H256 {
-        // Implement channel ID generation logic
-        H256::zero() // Placeholder
+    pub async fn get_channel(&self, channel_id: H256) -> Result<Channel, ChannelError> {
+        let handle = self.directory.get(channel_id)
+            .ok_or(ChannelError::NotFound(channel_id))?;
+
+        Ok(handle.snapshot().await?)
     }
 
-    async fn get_current_block_height(&self) -> Result<u64, ChannelError> {
-        // Implement block height retrieval
-        Ok(0) // Placeholder
+    // Helper functions
+
+    /// Runs `update` on the channel's actor against a copy of the channel,
+    /// which replaces the channel only if `update` succeeds
+    async fn modify<T, F>(&self, channel_id: H256, update: F) -> Result<(Channel, T), ChannelError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Channel) -> Result<T, ChannelError> + Send + 'static,
+    {
+        let handle = self.directory.get(channel_id)
+            .ok_or(ChannelError::NotFound(channel_id))?;
+        let events = self.events.clone();
+
+        let (channel, value) = handle.update(move |channel| {
+            let mut updated = channel.clone();
+            let value = update(&mut updated)?;
+            // Published from the actor, so events follow the order updates are applied in
+            publish_channel_changes(&events, channel, &updated);
+            *channel = updated.clone();
+            Ok::<_, ChannelError>((updated, value))
+        }).await??;
+
+        Ok((channel, value))
     }
 
-    async fn is_channel_expired(&self, channel: &Channel) -> Result<bool, ChannelError> {
-        let current_height = self.get_current_block_height().await?;
-        Ok(current_height >= channel.timeout_height)
+    fn record_transition(&self, record: TransitionRecord) -> Result<(), ChannelError> {
+        let mut transitions = self.transitions.write().map_err(|_| {
+            ChannelError::DatabaseError("Failed to acquire write lock".to_string())
+        })?;
+        transitions.entry(record.channel_id).or_insert_with(Vec::new).push(record);
+
+        Ok(())
     }
 
+    fn generate_channel_id(&self, participants: &[Address], shard_id: u64) -> H256 {
+        let mut data = Vec::new();
+        for participant in participants {
+            data.extend_from_slice(participant.as_bytes());
+        }
+        data.extend_from_slice(&shard_id.to_be_bytes());
+        // Random nonce so the same participants can open several channels
+        data.extend_from_slice(&rand::random::<[u8; 32]>());
+        H256::from_slice(&keccak256(&data))
+    }
+
+    async fn get_current_block_height(&self) -> Result<u64, ChannelError> {
+        Ok(self.clock.current_height().await?)
+    }
+
+    /// Checks that every participant, in order, signed `state` the way `BridgeCore` verifies it
     fn verify_signatures(
         &self,
         channel: &Channel,
         state: &ChannelState,
         signatures: &[Vec<u8>],
     ) -> Result<(), ChannelError> {
-        // Implement signature verification logic
-        Ok(()) // Placeholder
+        if !signed_by_all(channel, state, signatures) {
+            return Err(ChannelError::InvalidSignature);
+        }
+
+        Ok(())
     }
 
+    /// The proof is every participant's signature on the disputed state, r || s || v
+    /// each, concatenated in participant order
     fn verify_dispute_proof(
         &self,
         channel: &Channel,
         disputed_state: &ChannelState,
         proof: &[u8],
     ) -> Result<(), ChannelError> {
-        // Implement dispute proof verification logic
-        Ok(()) // Placeholder
+        if !proof.len().is_multiple_of(RECOVERABLE_SIGNATURE_LENGTH) {
+            return Err(ChannelError::InvalidSignature);
+        }
+
+        let signatures: Vec<Vec<u8>> = proof.chunks(RECOVERABLE_SIGNATURE_LENGTH)
+            .map(<[u8]>::to_vec)
+            .collect();
+        self.verify_signatures(channel, disputed_state, &signatures)
+    }
+}
+
+/// Moves the channel through the transition table and returns the record to store
+fn apply_transition(
+    channel: &mut Channel,
+    event: ChannelEvent,
+    current_height: u64,
+) -> Result<TransitionRecord, ChannelError> {
+    let record = state_machine::transition(
+        channel.channel_id,
+        &channel.status,
+        event,
+        TransitionContext {
+            current_height,
+            timeout_height: channel.timeout_height,
+        },
+    )?;
+
+    log::debug!(
+        "Channel {} transition {:?} -> {:?} on {:?}",
+        channel.channel_id, record.from, record.to, record.event
+    );
+
+    channel.status = record.to.clone();
+    Ok(record)
+}
+
+fn verify_dispute_escrows(
+    current_state: &ChannelState,
+    disputed_state: &ChannelState,
+) -> Result<(), ChannelError> {
+    for (lock_id, escrow) in &current_state.escrows {
+        match disputed_state.escrows.get(lock_id) {
+            Some(disputed) => {
+                if disputed.amount != escrow.amount
+                    || disputed.sender != escrow.sender
+                    || disputed.recipient != escrow.recipient
+                    || disputed.signers != escrow.signers
+                    || disputed.threshold != escrow.threshold
+                    || disputed.timeout_height != escrow.timeout_height
+                    || disputed.asset != escrow.asset
+                {
+                    return Err(ChannelError::InvalidStateTransition(
+                        format!("Escrow {} terms modified", lock_id)
+                    ));
+                }
+
+                if disputed.released < escrow.released {
+                    return Err(ChannelError::InvalidStateTransition(
+                        format!("Escrow {} release rolled back", lock_id)
+                    ));
+                }
+            }
+            // A missing escrow is only acceptable if a newer state settled it
+            None => {
+                if disputed_state.sequence_number <= current_state.sequence_number {
+                    return Err(ChannelError::InvalidStateTransition(
+                        format!("Escrow {} missing from disputed state", lock_id)
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether every participant, in order, signed `state` over the message `BridgeCore` checks
+pub(crate) fn signed_by_all(channel: &Channel, state: &ChannelState, signatures: &[Vec<u8>]) -> bool {
+    if signatures.len() != channel.participants.len() {
+        return false;
+    }
+
+    let state = CanonicalState::from(state);
+    let message = channel_state_message(channel.bridge_id(), state.sequence, state.hash());
+    channel.participants.iter()
+        .zip(signatures)
+        .all(|(participant, signature)| {
+            recover_signer(message.as_bytes(), signature).is_ok_and(|signer| signer == *participant)
+        })
+}
+
+/// Publishes the status change and state update between two versions of a channel
+pub(crate) fn publish_channel_changes(events: &EventBus, before: &Channel, after: &Channel) {
+    let channel_id = after.channel_id;
+
+    if before.status != after.status {
+        let _ = events.publish(LightningEvent::ChannelStatusChanged {
+            channel_id,
+            from: before.status.clone(),
+            to: after.status.clone(),
+        });
+
+        match after.status {
+            ChannelStatus::Disputed => {
+                let _ = events.publish(LightningEvent::DisputeStarted { channel_id });
+            }
+            ChannelStatus::Closed => {
+                let _ = events.publish(LightningEvent::ChannelClosed { channel_id });
+            }
+            _ => {}
+        }
+    }
+
+    let state_hash = after.state.state_hash();
+    if before.nonce != after.nonce || before.state.state_hash() != state_hash {
+        let _ = events.publish(LightningEvent::StateUpdated {
+            channel_id,
+            sequence: after.nonce,
+            state_hash,
+        });
+    }
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// An active two-party channel holding exactly the given balances, for other modules' tests
+#[cfg(test)]
+pub(crate) fn test_channel(a: Address, a_balance: u64, b: Address, b_balance: u64) -> Channel {
+    let balances = HashMap::from([(a, U256::from(a_balance)), (b, U256::from(b_balance))]);
+    let capacity = U256::from(a_balance) + U256::from(b_balance);
+    Channel {
+        channel_id: H256::random(),
+        shard_id: 0,
+        participants: vec![a, b],
+        capacity,
+        balance: capacity,
+        state: ChannelState::new(balances).unwrap(),
+        status: ChannelStatus::Active,
+        nonce: 0,
+        timeout_height: 1000,
+        dispute_period: 100,
+        last_update: 0,
+        bridge_channel_id: None,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use clock::ManualClock;
+    use crate::crypto::CryptoManager;
+
+    /// An active two-party channel of 1000, split 600 / 400, with both keys in `crypto`
+    async fn active_channel(crypto: &mut CryptoManager) -> (ChannelManager, H256, Address, Address) {
+        active_channel_at(crypto, Arc::new(ManualClock::new(10))).await
+    }
+
+    async fn active_channel_at(crypto: &mut CryptoManager, clock: Arc<ManualClock>) -> (ChannelManager, H256, Address, Address) {
+        let a = crypto.generate_keypair().unwrap();
+        let b = crypto.generate_keypair().unwrap();
+
+        let config = ChannelConfig {
+            min_capacity: U256::from(1),
+            max_capacity: U256::from(1_000_000),
+            min_dispute_period: 1,
+            max_dispute_period: 1000,
+            max_participants: 2,
+        };
+        let manager = ChannelManager::new(
+            config,
+            ActorConfig::default(),
+            clock,
+            Arc::new(SignatureVerifier::new(2)),
+        );
+        let channel = manager.create_channel(0, vec![a, b], U256::from(1000), 100, ChannelParameters::default())
+            .await.unwrap();
+        let channel_id = channel.channel_id;
+
+        let balances = HashMap::from([(a, U256::from(600)), (b, U256::from(400))]);
+        let mut opening = ChannelState::new(balances.clone()).unwrap();
+        opening.parameters = channel.state.parameters.clone();
+        let mut signatures = Vec::new();
+        for signer in [a, b] {
+            let state = CanonicalState::from(&opening);
+            signatures.push(crypto.sign_channel_state(&signer, channel_id, None, state).await.unwrap());
+        }
+        manager.set_initial_state(channel_id, balances, signatures).await.unwrap();
+        manager.activate_channel(channel_id).await.unwrap();
+
+        (manager, channel_id, a, b)
+    }
+
+    async fn sign_by_all(crypto: &CryptoManager, channel_id: H256, signers: [Address; 2], state: &ChannelState) -> Vec<Vec<u8>> {
+        let mut signatures = Vec::new();
+        for signer in signers {
+            let state = CanonicalState::from(state);
+            signatures.push(crypto.sign_channel_state(&signer, channel_id, None, state).await.unwrap());
+        }
+        signatures
+    }
+
+    #[tokio::test]
+    async fn test_overflowing_initial_balances_rejected() {
+        let (a, b) = (Address::random(), Address::random());
+        let config = ChannelConfig {
+            min_capacity: U256::from(1),
+            max_capacity: U256::from(1_000_000),
+            min_dispute_period: 1,
+            max_dispute_period: 1000,
+            max_participants: 2,
+        };
+        let manager = ChannelManager::new(
+            config,
+            ActorConfig::default(),
+            Arc::new(ManualClock::new(10)),
+            Arc::new(SignatureVerifier::new(2)),
+        );
+        let channel_id = manager.create_channel(0, vec![a, b], U256::from(1000), 100, ChannelParameters::default())
+            .await.unwrap()
+            .channel_id;
+
+        // Wraps around to the capacity if added without checks
+        let balances = HashMap::from([(a, U256::MAX), (b, U256::from(1001))]);
+        let result = manager.set_initial_state(channel_id, balances, Vec::new()).await;
+        assert!(matches!(result, Err(ChannelError::CapacityExceeded(_))));
+    }
+
+    #[tokio::test]
+    async fn test_state_updates_keep_parameters_and_advance() {
+        let mut crypto = CryptoManager::new();
+        let (manager, channel_id, a, b) = active_channel(&mut crypto).await;
+        let current = manager.get_channel(channel_id).await.unwrap().state;
+
+        // Parameters and token capacity aren't signed, so they can't be smuggled in
+        let mut next = current.clone();
+        next.sequence_number += 1;
+        next.balances = HashMap::from([(a, U256::from(500)), (b, U256::from(500))]);
+        next.parameters.max_htlc_count = 5;
+        next.token_capacity.insert(AssetId(Address::random()), U256::from(1_000_000));
+        let signatures = sign_by_all(&crypto, channel_id, [a, b], &next).await;
+        let channel = manager.update_channel_state(channel_id, next.clone(), signatures.clone()).await.unwrap();
+        assert_eq!(channel.state.parameters, current.parameters);
+        assert_eq!(channel.state.token_capacity, current.token_capacity);
+        assert_eq!(channel.state.balances[&b], U256::from(500));
+
+        // Replaying the same or an older state
+        let result = manager.update_channel_state(channel_id, next.clone(), signatures).await;
+        assert!(matches!(result, Err(ChannelError::InvalidStateTransition(_))));
+
+        // More than the channel holds
+        let mut inflated = next;
+        inflated.sequence_number += 1;
+        inflated.balances.insert(a, U256::from(900));
+        let signatures = sign_by_all(&crypto, channel_id, [a, b], &inflated).await;
+        let result = manager.update_channel_state(channel_id, inflated, signatures).await;
+        assert!(matches!(result, Err(ChannelError::InvalidStateTransition(_))));
+    }
+
+    #[tokio::test]
+    async fn test_disputes_need_every_participants_signature() {
+        let mut crypto = CryptoManager::new();
+        let (manager, channel_id, a, b) = active_channel(&mut crypto).await;
+        manager.begin_shutdown(channel_id).await.unwrap();
+
+        let mut disputed = manager.get_channel(channel_id).await.unwrap().state;
+        disputed.sequence_number += 1;
+        let mut proof = Vec::new();
+        for signer in [a, b] {
+            let state = CanonicalState::from(&disputed);
+            proof.extend(crypto.sign_channel_state(&signer, channel_id, None, state).await.unwrap());
+        }
+
+        // Only one participant's signature
+        let partial = proof[..RECOVERABLE_SIGNATURE_LENGTH].to_vec();
+        let result = manager.dispute_channel(channel_id, disputed.clone(), partial).await;
+        assert!(matches!(result, Err(ChannelError::InvalidSignature)));
 
-    // Add tests here
-}
\ No newline at end of file
+        // Signatures on another state
+        let mut other = disputed.clone();
+        other.sequence_number += 1;
+        let result = manager.dispute_channel(channel_id, other, proof.clone()).await;
+        assert!(matches!(result, Err(ChannelError::InvalidSignature)));
+
+        let channel = manager.dispute_channel(channel_id, disputed, proof).await.unwrap();
+        assert_eq!(channel.status, ChannelStatus::Disputed);
+    }
+
+    #[tokio::test]
+    async fn test_disputes_need_a_newer_state() {
+        let mut crypto = CryptoManager::new();
+        let (manager, channel_id, a, b) = active_channel(&mut crypto).await;
+        manager.begin_shutdown(channel_id).await.unwrap();
+        let current = manager.get_channel(channel_id).await.unwrap().state;
+
+        // Another state at the same sequence, signed by both
+        let mut replaced = current.clone();
+        replaced.balances.insert(a, U256::from(500));
+        replaced.balances.insert(b, U256::from(500));
+        let mut proof = Vec::new();
+        for signature in sign_by_all(&crypto, channel_id, [a, b], &replaced).await {
+            proof.extend(signature);
+        }
+        let result = manager.dispute_channel(channel_id, replaced, proof).await;
+        assert!(matches!(result, Err(ChannelError::InvalidStateTransition(_))));
+
+        // The state already held can be disputed as it is
+        let mut proof = Vec::new();
+        for signature in sign_by_all(&crypto, channel_id, [a, b], &current).await {
+            proof.extend(signature);
+        }
+        let channel = manager.dispute_channel(channel_id, current, proof).await.unwrap();
+        assert_eq!(channel.status, ChannelStatus::Disputed);
+    }
+
+    #[tokio::test]
+    async fn test_expired_escrows_refunded_at_settlement() {
+        let mut crypto = CryptoManager::new();
+        let clock = Arc::new(ManualClock::new(10));
+        let (manager, channel_id, a, b) = active_channel_at(&mut crypto, clock.clone()).await;
+        manager.begin_shutdown(channel_id).await.unwrap();
+
+        let mut disputed = manager.get_channel(channel_id).await.unwrap().state;
+        disputed.create_escrow(a, b, U256::from(100), vec![a, b], 2, 50).unwrap();
+        disputed.sequence_number += 1;
+        let mut proof = Vec::new();
+        for signature in sign_by_all(&crypto, channel_id, [a, b], &disputed).await {
+            proof.extend(signature);
+        }
+
+        // The escrow times out during the dispute, but the disputed state is what was signed
+        clock.set_height(60);
+        let channel = manager.dispute_channel(channel_id, disputed.clone(), proof).await.unwrap();
+        assert_eq!(channel.state.state_hash(), disputed.state_hash());
+        assert_eq!(channel.state.escrows.len(), 1);
+        assert!(manager.settle_channel(channel_id).await.is_err());
+
+        clock.set_height(channel.timeout_height);
+        let channel = manager.settle_channel(channel_id).await.unwrap();
+        assert_eq!(channel.status, ChannelStatus::Closed);
+        assert!(channel.state.escrows.is_empty());
+        assert_eq!(channel.state.balances[&a], U256::from(600));
+    }
+}
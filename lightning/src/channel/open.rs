@@ -0,0 +1,591 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use async_trait::async_trait;
+use ethers::types::{Address, H256, U256};
+use flashchain_bridge::BridgeManager;
+use flashchain_common::encoding::CanonicalState;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::sync::{broadcast, RwLock};
+
+use super::clock::{ChainClock, ClockError, TimeoutEvent, TimeoutKind, TimeoutScheduler};
+use super::parameters::ChannelParameters;
+use super::state::ChannelState;
+use super::{ChannelError, ChannelManager};
+use crate::crypto::signature::channel_state_message;
+use crate::crypto::signer::SignRequest;
+use crate::crypto::{CryptoError, CryptoManager};
+use crate::network::{MessageHandler, MessageRoute, NetworkError, NetworkManager, NetworkMessage};
+use crate::routing::RoutingManager;
+
+#[derive(Error, Debug)]
+pub enum OpenError {
+    #[error("Channel error: {0}")]
+    Channel(#[from] ChannelError),
+    #[error("No pending open for channel {0}")]
+    NoPendingOpen(H256),
+    #[error("Unexpected open message: {0}")]
+    UnexpectedMessage(String),
+    #[error("Proposal rejected: {0}")]
+    Rejected(String),
+    #[error("Invalid signature from {0}")]
+    InvalidSignature(Address),
+    #[error("Chain clock error: {0}")]
+    Clock(#[from] ClockError),
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("Bridge error: {0}")]
+    Bridge(String),
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+}
+
+/// What this node requires before accepting or activating a channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenPolicy {
+    /// Smallest reserve we accept for either side
+    pub min_reserve: U256,
+    pub confirmations: u64,
+    /// Blocks an open may stay unfunded before it is abandoned
+    pub open_timeout: u64,
+}
+
+impl Default for OpenPolicy {
+    fn default() -> Self {
+        Self {
+            min_reserve: U256::zero(),
+            confirmations: 6,
+            open_timeout: 144,
+        }
+    }
+}
+
+/// Channel terms proposed by the opener
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenProposal {
+    pub channel_id: H256,
+    pub shard_id: u64,
+    pub initiator: Address,
+    pub participants: Vec<Address>,
+    pub capacity: U256,
+    pub dispute_period: u64,
+    pub parameters: ChannelParameters,
+    pub initial_balances: BTreeMap<Address, U256>,
+}
+
+impl OpenProposal {
+    /// The channel's opening state
+    pub fn initial_state(&self) -> ChannelState {
+        ChannelState {
+            balances: self.initial_balances.iter()
+                .map(|(participant, balance)| (*participant, *balance))
+                .collect(),
+            parameters: self.parameters.clone(),
+            ..ChannelState::default()
+        }
+    }
+
+    /// Id the bridge will register the channel under
+    pub fn bridge_channel_id(&self) -> H256 {
+        BridgeManager::channel_id_for(&self.participants, self.capacity, self.channel_id)
+    }
+
+    /// Message both participants sign: the opening state, as `BridgeCore` checks it
+    pub fn commitment_message(&self) -> H256 {
+        let opening = CanonicalState::from(&self.initial_state());
+        channel_state_message(self.bridge_channel_id(), opening.sequence, opening.hash())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpenPhase {
+    /// Proposal sent, waiting for the counterparty's commitment signature
+    Proposed,
+    /// Commitment signed, waiting for the funding transaction
+    Accepted,
+    AwaitingConfirmations { funding_tx: H256 },
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingOpen {
+    pub proposal: OpenProposal,
+    pub counterparty: Address,
+    pub phase: OpenPhase,
+    pub signatures: HashMap<Address, Vec<u8>>,
+    pub expires_at: u64,
+    /// Keychain index of this node's keys for the channel, if keys are derived
+    pub channel_index: Option<u32>,
+}
+
+/// Runs the channel open handshake: proposal, signed initial commitments,
+/// bridge registration and activation after enough confirmations
+pub struct ChannelOpener {
+    channel_manager: Arc<ChannelManager>,
+    bridge: Arc<BridgeManager>,
+    network: Arc<NetworkManager>,
+    crypto: Arc<CryptoManager>,
+    clock: Arc<dyn ChainClock>,
+    timeouts: Arc<TimeoutScheduler>,
+    node_address: Address,
+    policy: OpenPolicy,
+    pending: RwLock<HashMap<H256, PendingOpen>>,
+    routing: Option<Arc<RoutingManager>>,
+}
+
+impl ChannelOpener {
+    pub fn new(
+        channel_manager: Arc<ChannelManager>,
+        bridge: Arc<BridgeManager>,
+        network: Arc<NetworkManager>,
+        crypto: Arc<CryptoManager>,
+        node_address: Address,
+        policy: OpenPolicy,
+    ) -> Self {
+        Self {
+            clock: channel_manager.clock(),
+            timeouts: channel_manager.timeout_scheduler(),
+            channel_manager,
+            bridge,
+            network,
+            crypto,
+            node_address,
+            policy,
+            pending: RwLock::new(HashMap::new()),
+            routing: None,
+        }
+    }
+
+    /// Announces the parameters of every channel this opener activates
+    pub fn with_routing(mut self, routing: Arc<RoutingManager>) -> Self {
+        self.routing = Some(routing);
+        self
+    }
+
+    pub async fn get_pending_open(&self, channel_id: H256) -> Option<PendingOpen> {
+        self.pending.read().await.get(&channel_id).cloned()
+    }
+
+    pub async fn open_channel(
+        &self,
+        shard_id: u64,
+        counterparty: Address,
+        capacity: U256,
+        dispute_period: u64,
+        parameters: ChannelParameters,
+        initial_balances: BTreeMap<Address, U256>,
+    ) -> Result<H256, OpenError> {
+        let participants = vec![self.node_address, counterparty];
+        let channel = self.channel_manager
+            .create_channel(shard_id, participants.clone(), capacity, dispute_period, parameters.clone())
+            .await?;
+
+        let proposal = OpenProposal {
+            channel_id: channel.channel_id,
+            shard_id,
+            initiator: self.node_address,
+            participants: participants.clone(),
+            capacity,
+            dispute_period,
+            parameters,
+            initial_balances,
+        };
+        if let Err(e) = self.check_proposal(&proposal) {
+            self.channel_manager.abort_channel(channel.channel_id).await?;
+            return Err(e);
+        }
+        // Commitments are signed for the bridge channel, before it is registered
+        self.channel_manager.set_bridge_channel_id(channel.channel_id, proposal.bridge_channel_id()).await?;
+
+        self.track(proposal.clone(), counterparty, OpenPhase::Proposed).await?;
+
+        let encoded = serde_json::to_vec(&proposal)
+            .map_err(|e| OpenError::Encoding(e.to_string()))?;
+        self.network.send_message(counterparty, NetworkMessage::ChannelOpen {
+            channel_id: channel.channel_id,
+            initiator: self.node_address,
+            participants,
+            initial_state: encoded,
+        }).await?;
+
+        Ok(channel.channel_id)
+    }
+
+    pub async fn handle_message(&self, message: NetworkMessage) -> Result<(), OpenError> {
+        match message {
+            NetworkMessage::ChannelOpen { channel_id, initiator, initial_state, .. } => {
+                let proposal: OpenProposal = serde_json::from_slice(&initial_state)
+                    .map_err(|e| OpenError::Encoding(e.to_string()))?;
+                if proposal.channel_id != channel_id || proposal.initiator != initiator {
+                    return Err(OpenError::Rejected("Envelope mismatch".into()));
+                }
+                self.handle_open(proposal).await
+            }
+            NetworkMessage::ChannelAccept { channel_id, sender, signature } => {
+                self.handle_accept(channel_id, sender, signature).await
+            }
+            NetworkMessage::FundingCreated { channel_id, sender, signature, funding_tx } => {
+                self.handle_funding_created(channel_id, sender, signature, funding_tx).await
+            }
+            other => Err(OpenError::UnexpectedMessage(format!("{:?}", other))),
+        }
+    }
+
+    /// Takes the open messages the network receives, activates funded channels as
+    /// blocks arrive and abandons opens that time out
+    pub fn start(self: Arc<Self>) {
+        self.network.register_handler(MessageRoute::Open, self.clone());
+
+        let mut heights = self.clock.subscribe();
+        let mut timeouts = self.timeouts.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    height = heights.recv() => match height {
+                        Ok(height) => self.check_confirmations(height).await,
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            if let Ok(height) = self.clock.current_height().await {
+                                self.check_confirmations(height).await;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    event = timeouts.recv() => match event {
+                        Ok(event) => self.handle_timeout(event).await,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            log::warn!("Channel opener lagged by {} timeout events", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                }
+            }
+        });
+    }
+
+    async fn handle_open(&self, proposal: OpenProposal) -> Result<(), OpenError> {
+        if !proposal.participants.contains(&self.node_address) || proposal.participants.len() != 2 {
+            return Err(OpenError::Rejected("Not a two-party channel with this node".into()));
+        }
+        self.check_proposal(&proposal)?;
+
+        self.channel_manager.create_channel_with_id(
+            proposal.channel_id,
+            proposal.shard_id,
+            proposal.participants.clone(),
+            proposal.capacity,
+            proposal.dispute_period,
+            proposal.parameters.clone(),
+        ).await?;
+        self.channel_manager.set_bridge_channel_id(proposal.channel_id, proposal.bridge_channel_id()).await?;
+
+        let signature = self.crypto.sign(
+            &self.node_address,
+            SignRequest::OpenChannel(proposal.clone()),
+        ).await?;
+
+        let initiator = proposal.initiator;
+        let channel_id = proposal.channel_id;
+        self.track(proposal, initiator, OpenPhase::Accepted).await?;
+        self.pending.write().await.get_mut(&channel_id)
+            .ok_or(OpenError::NoPendingOpen(channel_id))?
+            .signatures.insert(self.node_address, signature.clone());
+
+        self.network.send_message(initiator, NetworkMessage::ChannelAccept {
+            channel_id,
+            sender: self.node_address,
+            signature,
+        }).await?;
+
+        Ok(())
+    }
+
+    /// Opener side: both commitments are signed, so fund the channel through the bridge
+    async fn handle_accept(
+        &self,
+        channel_id: H256,
+        sender: Address,
+        signature: Vec<u8>,
+    ) -> Result<(), OpenError> {
+        let mut pending = self.pending.write().await;
+        let open = pending.get_mut(&channel_id)
+            .ok_or(OpenError::NoPendingOpen(channel_id))?;
+
+        if open.phase != OpenPhase::Proposed || sender != open.counterparty {
+            return Err(OpenError::UnexpectedMessage(format!("Accept from {:?}", sender)));
+        }
+
+        let commitment = open.proposal.commitment_message();
+        self.verify(sender, commitment, &signature)?;
+        let own_signature = self.crypto.sign(
+            &self.node_address,
+            SignRequest::OpenChannel(open.proposal.clone()),
+        ).await?;
+
+        open.signatures.insert(sender, signature);
+        open.signatures.insert(self.node_address, own_signature.clone());
+        let proposal = open.proposal.clone();
+        let signatures = ordered_signatures(&proposal, &open.signatures);
+        drop(pending);
+
+        self.channel_manager.set_initial_state(
+            channel_id,
+            proposal.initial_balances.clone().into_iter().collect(),
+            signatures,
+        ).await?;
+
+        let funding_tx = self.bridge
+            .register_channel(proposal.participants.clone(), proposal.capacity, proposal.channel_id)
+            .await
+            .map_err(|e| OpenError::Bridge(e.to_string()))?;
+
+        if let Some(open) = self.pending.write().await.get_mut(&channel_id) {
+            open.phase = OpenPhase::AwaitingConfirmations { funding_tx };
+        }
+
+        self.network.send_message(sender, NetworkMessage::FundingCreated {
+            channel_id,
+            sender: self.node_address,
+            signature: own_signature,
+            funding_tx,
+        }).await?;
+
+        Ok(())
+    }
+
+    async fn handle_funding_created(
+        &self,
+        channel_id: H256,
+        sender: Address,
+        signature: Vec<u8>,
+        funding_tx: H256,
+    ) -> Result<(), OpenError> {
+        let mut pending = self.pending.write().await;
+        let open = pending.get_mut(&channel_id)
+            .ok_or(OpenError::NoPendingOpen(channel_id))?;
+
+        if open.phase != OpenPhase::Accepted || sender != open.counterparty {
+            return Err(OpenError::UnexpectedMessage(format!("Funding from {:?}", sender)));
+        }
+
+        self.verify(sender, open.proposal.commitment_message(), &signature)?;
+        open.signatures.insert(sender, signature);
+        open.phase = OpenPhase::AwaitingConfirmations { funding_tx };
+        let proposal = open.proposal.clone();
+        let signatures = ordered_signatures(&proposal, &open.signatures);
+        drop(pending);
+
+        self.channel_manager.set_initial_state(
+            channel_id,
+            proposal.initial_balances.into_iter().collect(),
+            signatures,
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn check_confirmations(&self, height: u64) {
+        let awaiting: Vec<(H256, H256, H256)> = self.pending.read().await
+            .iter()
+            .filter_map(|(channel_id, open)| match open.phase {
+                OpenPhase::AwaitingConfirmations { funding_tx } => {
+                    Some((*channel_id, funding_tx, open.proposal.bridge_channel_id()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for (channel_id, funding_tx, expected_id) in awaiting {
+            let (bridge_channel_id, mined_at) = match self.bridge.registered_channel(funding_tx).await {
+                Ok(Some(registered)) => registered,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("Funding transaction for {} failed: {:?}", channel_id, e);
+                    self.abandon(channel_id).await;
+                    continue;
+                }
+            };
+
+            // The commitments were signed for the id the channel should have been given
+            if bridge_channel_id != expected_id {
+                log::error!(
+                    "Channel {} was registered as {:?}, expected {:?}",
+                    channel_id, bridge_channel_id, expected_id
+                );
+                self.abandon(channel_id).await;
+                continue;
+            }
+
+            if height + 1 < mined_at + self.policy.confirmations {
+                continue;
+            }
+
+            match self.channel_manager.activate_channel(channel_id).await {
+                Ok(channel) => {
+                    log::info!("Channel {} active after {} confirmations", channel_id, self.policy.confirmations);
+                    self.pending.write().await.remove(&channel_id);
+                    self.timeouts.cancel(TimeoutKind::ChannelOpen { channel_id }).await;
+
+                    if let Some(routing) = &self.routing {
+                        if let Err(e) = routing.announce_channel_parameters(channel_id, &channel.state.parameters).await {
+                            log::error!("Failed to announce parameters of channel {}: {:?}", channel_id, e);
+                        }
+                    }
+                }
+                Err(e) => log::error!("Failed to activate channel {}: {:?}", channel_id, e),
+            }
+        }
+    }
+
+    async fn handle_timeout(&self, event: TimeoutEvent) {
+        if let TimeoutKind::ChannelOpen { channel_id } = event.kind {
+            if self.pending.read().await.contains_key(&channel_id) {
+                log::warn!("Channel open {} timed out at height {}", channel_id, event.fired_at);
+                self.abandon(channel_id).await;
+            }
+        }
+    }
+
+    async fn abandon(&self, channel_id: H256) {
+        self.pending.write().await.remove(&channel_id);
+        self.timeouts.cancel(TimeoutKind::ChannelOpen { channel_id }).await;
+
+        if let Err(e) = self.channel_manager.abort_channel(channel_id).await {
+            log::error!("Failed to abort channel {}: {:?}", channel_id, e);
+        }
+    }
+
+    async fn track(
+        &self,
+        proposal: OpenProposal,
+        counterparty: Address,
+        phase: OpenPhase,
+    ) -> Result<(), OpenError> {
+        let channel_id = proposal.channel_id;
+        let expires_at = self.clock.current_height().await? + self.policy.open_timeout;
+        // Every channel gets its own funding and revocation keys
+        let channel_index = if self.crypto.has_keychain() {
+            Some(self.crypto.next_channel_keys()?.index)
+        } else {
+            None
+        };
+
+        self.pending.write().await.insert(channel_id, PendingOpen {
+            proposal,
+            counterparty,
+            phase,
+            signatures: HashMap::new(),
+            expires_at,
+            channel_index,
+        });
+        self.timeouts.schedule(expires_at, TimeoutKind::ChannelOpen { channel_id }).await;
+
+        Ok(())
+    }
+
+    fn check_proposal(&self, proposal: &OpenProposal) -> Result<(), OpenError> {
+        if !proposal.participants.contains(&proposal.initiator) {
+            return Err(OpenError::Rejected("Initiator is not a participant".into()));
+        }
+
+        if proposal.parameters.min_reserve < self.policy.min_reserve {
+            return Err(OpenError::Rejected(format!(
+                "Reserve {} below required {}",
+                proposal.parameters.min_reserve, self.policy.min_reserve
+            )));
+        }
+
+        if let Some(outsider) = proposal.initial_balances.keys().find(|owner| !proposal.participants.contains(owner)) {
+            return Err(OpenError::Rejected(format!("Initial balance for non-participant {:?}", outsider)));
+        }
+
+        let total = proposal.initial_balances.values()
+            .fold(U256::zero(), |acc, &val| acc.saturating_add(val));
+        if total != proposal.capacity {
+            return Err(OpenError::Rejected("Initial balances don't match capacity".into()));
+        }
+
+        for participant in &proposal.participants {
+            let balance = proposal.initial_balances.get(participant).copied().unwrap_or_default();
+            proposal.parameters.check_reserve(U256::zero(), balance)?;
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self, signer: Address, commitment: H256, signature: &[u8]) -> Result<(), OpenError> {
+        if !self.crypto.verify_signature(&signer, commitment.as_bytes(), signature)? {
+            return Err(OpenError::InvalidSignature(signer));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageHandler for ChannelOpener {
+    async fn handle(&self, message: NetworkMessage) -> Result<(), NetworkError> {
+        self.handle_message(message).await
+            .map_err(|e| NetworkError::ChannelError(e.to_string()))
+    }
+}
+
+/// Signatures in participant order, as `ChannelManager` expects them
+fn ordered_signatures(proposal: &OpenProposal, signatures: &HashMap<Address, Vec<u8>>) -> Vec<Vec<u8>> {
+    proposal.participants.iter()
+        .filter_map(|participant| signatures.get(participant).cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_proposal() -> OpenProposal {
+        let a = Address::random();
+        let b = Address::random();
+        let mut initial_balances = BTreeMap::new();
+        initial_balances.insert(a, U256::from(700));
+        initial_balances.insert(b, U256::from(300));
+
+        OpenProposal {
+            channel_id: H256::random(),
+            shard_id: 1,
+            initiator: a,
+            participants: vec![a, b],
+            capacity: U256::from(1000),
+            dispute_period: 100,
+            parameters: ChannelParameters {
+                min_reserve: U256::from(100),
+                ..ChannelParameters::default()
+            },
+            initial_balances,
+        }
+    }
+
+    #[test]
+    fn test_commitment_message_is_the_opening_state() {
+        let proposal = test_proposal();
+        let opening = ChannelState::new(proposal.initial_balances.clone().into_iter().collect()).unwrap();
+        assert_eq!(
+            proposal.commitment_message(),
+            channel_state_message(proposal.bridge_channel_id(), 0, CanonicalState::from(&opening).hash()),
+        );
+        assert_ne!(proposal.bridge_channel_id(), proposal.channel_id);
+
+        let mut changed = proposal.clone();
+        changed.initial_balances.insert(proposal.participants[0], U256::from(600));
+        assert_ne!(proposal.commitment_message(), changed.commitment_message());
+    }
+
+    #[test]
+    fn test_ordered_signatures() {
+        let proposal = test_proposal();
+        let mut signatures = HashMap::new();
+        signatures.insert(proposal.participants[1], vec![2u8]);
+        signatures.insert(proposal.participants[0], vec![1u8]);
+
+        assert_eq!(ordered_signatures(&proposal, &signatures), vec![vec![1u8], vec![2u8]]);
+    }
+}
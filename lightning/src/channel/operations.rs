@@ -1,12 +1,16 @@
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::oneshot;
 use serde::{Serialize, Deserialize};
 use ethers::types::{Address, U256, H256};
 use async_trait::async_trait;
 use thiserror::Error;
+use flashchain_common::encoding::u256_bytes;
 
 use super::state::{ChannelState, ChannelStatus, StateError};
-use super::Channel;
+use super::{publish_channel_changes, signed_by_all, Channel, ChannelError};
+use super::clock::{ChainClock, TimeoutScheduler};
+use crate::crypto::signature::{SignatureSet, SignatureVerifier};
+use crate::events::EventBus;
 
 #[derive(Error, Debug)]
 pub enum OperationError {
@@ -15,20 +19,33 @@ pub enum OperationError {
     #[error("Channel error: {0}")]
     ChannelError(String),
     #[error("State error: {0}")]
-    StateError(#[from] StateError),
+    StateError(StateError),
+    #[error("Channel limit: {0}")]
+    Limit(ChannelError),
     #[error("Invalid operation: {0}")]
     InvalidOperation(String),
     #[error("Operation rejected: {0}")]
     Rejected(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl From<StateError> for OperationError {
+    fn from(error: StateError) -> Self {
+        // Limit violations keep their distinct channel error
+        match error {
+            StateError::Limit(limit) => OperationError::Limit(limit),
+            other => OperationError::StateError(other),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum ChannelOperation {
     Transfer {
         channel_id: H256,
         from: Address,
         to: Address,
         amount: U256,
+        idempotency_key: Option<H256>,
         response: oneshot::Sender<OperationResult<TransferResult>>,
     },
     CreateLock {
@@ -38,32 +55,63 @@ pub enum ChannelOperation {
         amount: U256,
         expiration_height: u64,
         secret_hash: H256,
+        idempotency_key: Option<H256>,
         response: oneshot::Sender<OperationResult<LockResult>>,
     },
     Unlock {
         channel_id: H256,
         lock_id: H256,
         secret: H256,
+        idempotency_key: Option<H256>,
         response: oneshot::Sender<OperationResult<UnlockResult>>,
     },
     Close {
         channel_id: H256,
         final_state: ChannelState,
         signatures: Vec<Vec<u8>>,
+        idempotency_key: Option<H256>,
         response: oneshot::Sender<OperationResult<CloseResult>>,
     },
     Dispute {
         channel_id: H256,
         disputed_state: ChannelState,
         proof: Vec<u8>,
+        idempotency_key: Option<H256>,
         response: oneshot::Sender<OperationResult<DisputeResult>>,
     },
     UpdateState {
         channel_id: H256,
         new_state: ChannelState,
         signatures: Vec<Vec<u8>>,
+        idempotency_key: Option<H256>,
         response: oneshot::Sender<OperationResult<UpdateStateResult>>,
     },
+    CreateEscrow {
+        channel_id: H256,
+        sender: Address,
+        recipient: Address,
+        amount: U256,
+        signers: Vec<Address>,
+        threshold: usize,
+        timeout_height: u64,
+        idempotency_key: Option<H256>,
+        response: oneshot::Sender<OperationResult<EscrowResult>>,
+    },
+    ReleaseEscrow {
+        channel_id: H256,
+        lock_id: H256,
+        beneficiary: Address,
+        amount: U256,
+        signatures: SignatureSet,
+        idempotency_key: Option<H256>,
+        response: oneshot::Sender<OperationResult<EscrowResult>>,
+    },
+    RefundEscrow {
+        channel_id: H256,
+        lock_id: H256,
+        idempotency_key: Option<H256>,
+        response: oneshot::Sender<OperationResult<EscrowResult>>,
+    },
 }
 
 pub type OperationResult<T> = Result<T, OperationError>;
@@ -111,63 +159,217 @@ pub struct UpdateStateResult {
     pub state_update_hash: H256,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EscrowResult {
+    pub channel_id: H256,
+    pub lock_id: H256,
+    pub new_state: ChannelState,
+    pub amount: U256,
+}
+
 #[async_trait]
 pub trait OperationHandler {
     async fn handle_operation(&self, operation: ChannelOperation) -> Result<(), OperationError>;
 }
 
-pub struct ChannelOperationHandler {
-    operation_tx: mpsc::Sender<ChannelOperation>,
-    channels: Arc<tokio::sync::RwLock<std::collections::HashMap<H256, Channel>>>,
+impl ChannelOperation {
+    pub fn channel_id(&self) -> H256 {
+        match self {
+            ChannelOperation::Transfer { channel_id, .. }
+            | ChannelOperation::CreateLock { channel_id, .. }
+            | ChannelOperation::Unlock { channel_id, .. }
+            | ChannelOperation::Close { channel_id, .. }
+            | ChannelOperation::Dispute { channel_id, .. }
+            | ChannelOperation::UpdateState { channel_id, .. }
+            | ChannelOperation::CreateEscrow { channel_id, .. }
+            | ChannelOperation::ReleaseEscrow { channel_id, .. }
+            | ChannelOperation::RefundEscrow { channel_id, .. } => *channel_id,
+        }
+    }
+
+    pub fn idempotency_key(&self) -> Option<H256> {
+        match self {
+            ChannelOperation::Transfer { idempotency_key, .. }
+            | ChannelOperation::CreateLock { idempotency_key, .. }
+            | ChannelOperation::Unlock { idempotency_key, .. }
+            | ChannelOperation::Close { idempotency_key, .. }
+            | ChannelOperation::Dispute { idempotency_key, .. }
+            | ChannelOperation::UpdateState { idempotency_key, .. }
+            | ChannelOperation::CreateEscrow { idempotency_key, .. }
+            | ChannelOperation::ReleaseEscrow { idempotency_key, .. }
+            | ChannelOperation::RefundEscrow { idempotency_key, .. } => *idempotency_key,
+        }
+    }
+
+    /// Hash of the operation's kind and parameters, used to tell a retry
+    /// from a different operation submitted under the same idempotency key
+    pub fn fingerprint(&self) -> H256 {
+        let mut data = Vec::new();
+        match self {
+            ChannelOperation::Transfer { channel_id, from, to, amount, .. } => {
+                data.push(0);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(from.as_bytes());
+                data.extend_from_slice(to.as_bytes());
+                data.extend_from_slice(&u256_bytes(*amount));
+            }
+            ChannelOperation::CreateLock {
+                channel_id, sender, recipient, amount, expiration_height, secret_hash, ..
+            } => {
+                data.push(1);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(sender.as_bytes());
+                data.extend_from_slice(recipient.as_bytes());
+                data.extend_from_slice(&u256_bytes(*amount));
+                data.extend_from_slice(&expiration_height.to_be_bytes());
+                data.extend_from_slice(secret_hash.as_bytes());
+            }
+            ChannelOperation::Unlock { channel_id, lock_id, secret, .. } => {
+                data.push(2);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(lock_id.as_bytes());
+                data.extend_from_slice(secret.as_bytes());
+            }
+            ChannelOperation::Close { channel_id, final_state, signatures, .. } => {
+                data.push(3);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(final_state.state_hash().as_bytes());
+                for signature in signatures {
+                    data.extend_from_slice(signature);
+                }
+            }
+            ChannelOperation::Dispute { channel_id, disputed_state, proof, .. } => {
+                data.push(4);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(disputed_state.state_hash().as_bytes());
+                data.extend_from_slice(proof);
+            }
+            ChannelOperation::UpdateState { channel_id, new_state, signatures, .. } => {
+                data.push(5);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(new_state.state_hash().as_bytes());
+                for signature in signatures {
+                    data.extend_from_slice(signature);
+                }
+            }
+            ChannelOperation::CreateEscrow {
+                channel_id, sender, recipient, amount, signers, threshold, timeout_height, ..
+            } => {
+                data.push(6);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(sender.as_bytes());
+                data.extend_from_slice(recipient.as_bytes());
+                data.extend_from_slice(&u256_bytes(*amount));
+                for signer in signers {
+                    data.extend_from_slice(signer.as_bytes());
+                }
+                data.extend_from_slice(&(*threshold as u64).to_be_bytes());
+                data.extend_from_slice(&timeout_height.to_be_bytes());
+            }
+            ChannelOperation::ReleaseEscrow { channel_id, lock_id, beneficiary, amount, signatures, .. } => {
+                data.push(7);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(lock_id.as_bytes());
+                data.extend_from_slice(beneficiary.as_bytes());
+                data.extend_from_slice(&u256_bytes(*amount));
+                data.extend_from_slice(signatures.message_hash.as_bytes());
+            }
+            ChannelOperation::RefundEscrow { channel_id, lock_id, .. } => {
+                data.push(8);
+                data.extend_from_slice(channel_id.as_bytes());
+                data.extend_from_slice(lock_id.as_bytes());
+            }
+        }
+        H256::from_slice(&keccak256(&data))
+    }
+
+    /// Answers the operation with `error` without executing it
+    pub fn reject(self, error: OperationError) {
+        match self {
+            ChannelOperation::Transfer { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::CreateLock { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::Unlock { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::Close { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::Dispute { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::UpdateState { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::CreateEscrow { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::ReleaseEscrow { response, .. } => { let _ = response.send(Err(error)); }
+            ChannelOperation::RefundEscrow { response, .. } => { let _ = response.send(Err(error)); }
+        }
+    }
+}
+
+/// Applies operations to a single channel for the per-channel actors, which own
+/// their channel outright
+pub struct OperationExecutor {
+    signature_verifier: Arc<SignatureVerifier>,
+    clock: Arc<dyn ChainClock>,
+    events: Option<Arc<EventBus>>,
+    scheduler: Option<Arc<TimeoutScheduler>>,
 }
 
-impl ChannelOperationHandler {
-    pub fn new(
-        operation_tx: mpsc::Sender<ChannelOperation>,
-        channels: Arc<tokio::sync::RwLock<std::collections::HashMap<H256, Channel>>>,
-    ) -> Self {
+impl OperationExecutor {
+    pub fn new(signature_verifier: Arc<SignatureVerifier>, clock: Arc<dyn ChainClock>) -> Self {
         Self {
-            operation_tx,
-            channels,
+            signature_verifier,
+            clock,
+            events: None,
+            scheduler: None,
         }
     }
 
-    pub async fn start_operation_processor(&self) {
-        let mut operation_rx = self.operation_tx.subscribe();
+    /// Schedules the expiry of every lock created from here on, for the expiry sweeper
+    pub fn with_scheduler(mut self, scheduler: Arc<TimeoutScheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
 
-        tokio::spawn(async move {
-            while let Some(operation) = operation_rx.recv().await {
-                match self.process_operation(operation).await {
-                    Ok(_) => log::debug!("Operation processed successfully"),
-                    Err(e) => log::error!("Operation processing failed: {:?}", e),
-                }
-            }
-        });
+    /// Publishes the changes every operation makes to its channel
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Executes `operation` against `channel` and sends the result to its response channel
+    pub async fn execute(&self, channel: &mut Channel, operation: ChannelOperation) {
+        let before = self.events.as_ref().map(|_| channel.clone());
+        self.dispatch(channel, operation).await;
+
+        if let Some(before) = before {
+            self.publish_changes(&before, channel);
+        }
     }
 
-    async fn process_operation(&self, operation: ChannelOperation) -> Result<(), OperationError> {
+    /// Publishes what changed between `before` and `after`, if events are enabled
+    pub(super) fn publish_changes(&self, before: &Channel, after: &Channel) {
+        if let Some(events) = &self.events {
+            publish_channel_changes(events, before, after);
+        }
+    }
+
+    async fn dispatch(&self, channel: &mut Channel, operation: ChannelOperation) {
         match operation {
             ChannelOperation::Transfer { 
-                channel_id, 
                 from, 
                 to, 
                 amount, 
-                response 
+                response,
+                ..
             } => {
-                let result = self.handle_transfer(channel_id, from, to, amount).await;
+                let result = self.handle_transfer(channel, from, to, amount).await;
                 let _ = response.send(result);
             },
             ChannelOperation::CreateLock { 
-                channel_id,
                 sender,
                 recipient,
                 amount,
                 expiration_height,
                 secret_hash,
                 response,
+                ..
             } => {
                 let result = self.handle_create_lock(
-                    channel_id,
+                    channel,
                     sender,
                     recipient,
                     amount,
@@ -177,57 +379,97 @@ impl ChannelOperationHandler {
                 let _ = response.send(result);
             },
             ChannelOperation::Unlock { 
-                channel_id,
                 lock_id,
                 secret,
                 response,
+                ..
             } => {
-                let result = self.handle_unlock(channel_id, lock_id, secret).await;
+                let result = self.handle_unlock(channel, lock_id, secret).await;
                 let _ = response.send(result);
             },
             ChannelOperation::Close {
-                channel_id,
                 final_state,
                 signatures,
                 response,
+                ..
             } => {
-                let result = self.handle_close(channel_id, final_state, signatures).await;
+                let result = self.handle_close(channel, final_state, signatures).await;
                 let _ = response.send(result);
             },
             ChannelOperation::Dispute {
-                channel_id,
                 disputed_state,
                 proof,
                 response,
+                ..
             } => {
-                let result = self.handle_dispute(channel_id, disputed_state, proof).await;
+                let result = self.handle_dispute(channel, disputed_state, proof).await;
                 let _ = response.send(result);
             },
             ChannelOperation::UpdateState {
-                channel_id,
                 new_state,
                 signatures,
                 response,
+                ..
             } => {
-                let result = self.handle_update_state(channel_id, new_state, signatures).await;
+                let result = self.handle_update_state(channel, new_state, signatures).await;
+                let _ = response.send(result);
+            },
+            ChannelOperation::CreateEscrow {
+                sender,
+                recipient,
+                amount,
+                signers,
+                threshold,
+                timeout_height,
+                response,
+                ..
+            } => {
+                let result = self.handle_create_escrow(
+                    channel,
+                    sender,
+                    recipient,
+                    amount,
+                    signers,
+                    threshold,
+                    timeout_height,
+                ).await;
+                let _ = response.send(result);
+            },
+            ChannelOperation::ReleaseEscrow {
+                lock_id,
+                beneficiary,
+                amount,
+                signatures,
+                response,
+                ..
+            } => {
+                let result = self.handle_release_escrow(
+                    channel,
+                    lock_id,
+                    beneficiary,
+                    amount,
+                    signatures,
+                ).await;
+                let _ = response.send(result);
+            },
+            ChannelOperation::RefundEscrow {
+                lock_id,
+                response,
+                ..
+            } => {
+                let result = self.handle_refund_escrow(channel, lock_id).await;
                 let _ = response.send(result);
             },
         }
-
-        Ok(())
     }
 
     async fn handle_transfer(
         &self,
-        channel_id: H256,
+        channel: &mut Channel,
         from: Address,
         to: Address,
         amount: U256,
     ) -> OperationResult<TransferResult> {
-        let mut channels = self.channels.write().await;
-        let channel = channels.get_mut(&channel_id)
-            .ok_or_else(|| OperationError::ChannelError("Channel not found".to_string()))?;
-
         if channel.status != ChannelStatus::Active {
             return Err(OperationError::InvalidOperation("Channel not active".to_string()));
         }
@@ -236,14 +478,14 @@ impl ChannelOperationHandler {
         new_state.transfer(from, to, amount)?;
 
         // Generate and verify merkle proof
-        let proof = new_state.generate_proof(from);
+        let _proof = new_state.generate_proof(from);
 
         // Update channel state
         channel.state = new_state.clone();
         channel.nonce += 1;
 
         Ok(TransferResult {
-            channel_id,
+            channel_id: channel.channel_id,
             new_state,
             transaction_hash: H256::zero(), // Generate actual transaction hash
         })
@@ -253,63 +495,292 @@ impl ChannelOperationHandler {
 
     async fn handle_create_lock(
         &self,
-        channel_id: H256,
+        channel: &mut Channel,
         sender: Address,
         recipient: Address,
         amount: U256,
         expiration_height: u64,
         secret_hash: H256,
     ) -> OperationResult<LockResult> {
-        // Implementation
-        todo!()
+        if channel.status != ChannelStatus::Active {
+            return Err(OperationError::InvalidOperation("Channel not active".to_string()));
+        }
+
+        if expiration_height <= self.current_height().await? {
+            return Err(OperationError::InvalidOperation("Lock expiration already passed".to_string()));
+        }
+
+        let mut new_state = channel.state.clone();
+        let lock_id = new_state.create_lock(sender, recipient, amount, expiration_height, secret_hash)?;
+
+        channel.state = new_state.clone();
+        channel.nonce += 1;
+
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.track_lock(channel.channel_id, lock_id, expiration_height).await;
+        }
+
+        Ok(LockResult {
+            channel_id: channel.channel_id,
+            lock_id,
+            new_state,
+        })
     }
 
     async fn handle_unlock(
         &self,
-        channel_id: H256,
+        channel: &mut Channel,
         lock_id: H256,
         secret: H256,
     ) -> OperationResult<UnlockResult> {
-        // Implementation
-        todo!()
+        if channel.status != ChannelStatus::Active {
+            return Err(OperationError::InvalidOperation("Channel not active".to_string()));
+        }
+
+        let mut new_state = channel.state.clone();
+        new_state.unlock(lock_id, secret)?;
+
+        // The sweeper skips locks that are gone by the time they expire
+        channel.state = new_state.clone();
+        channel.nonce += 1;
+
+        Ok(UnlockResult {
+            channel_id: channel.channel_id,
+            lock_id,
+            new_state,
+            secret,
+        })
     }
 
     async fn handle_close(
         &self,
-        channel_id: H256,
-        final_state: ChannelState,
-        signatures: Vec<Vec<u8>>,
+        _channel: &mut Channel,
+        _final_state: ChannelState,
+        _signatures: Vec<Vec<u8>>,
     ) -> OperationResult<CloseResult> {
-        // Implementation
-        todo!()
+        // Closing needs the peers' signatures and the bridge, which only the close coordinator has
+        Err(OperationError::InvalidOperation(
+            "Channels are closed through CloseCoordinator::initiate_shutdown".to_string(),
+        ))
     }
 
     async fn handle_dispute(
         &self,
-        channel_id: H256,
-        disputed_state: ChannelState,
-        proof: Vec<u8>,
+        _channel: &mut Channel,
+        _disputed_state: ChannelState,
+        _proof: Vec<u8>,
     ) -> OperationResult<DisputeResult> {
-        // Implementation
-        todo!()
+        Err(OperationError::InvalidOperation(
+            "Disputes are raised through ChannelManager::dispute_channel".to_string(),
+        ))
     }
 
-    async fn handle_update_state(
+    pub(super) async fn handle_update_state(
         &self,
-        channel_id: H256,
-        new_state: ChannelState,
+        channel: &mut Channel,
+        mut new_state: ChannelState,
         signatures: Vec<Vec<u8>>,
     ) -> OperationResult<UpdateStateResult> {
-        // Implementation
-        todo!()
+        if channel.status != ChannelStatus::Active {
+            return Err(OperationError::InvalidOperation("Channel not active".to_string()));
+        }
+
+        if new_state.sequence_number <= channel.state.sequence_number {
+            return Err(OperationError::Rejected(format!(
+                "Stale state {}, channel is at {}",
+                new_state.sequence_number, channel.state.sequence_number
+            )));
+        }
+
+        if !signed_by_all(channel, &new_state, &signatures) {
+            return Err(OperationError::Rejected("State update is not signed by every participant".to_string()));
+        }
+
+        new_state.keep_unsigned_fields(&channel.state);
+        new_state.verify_state(channel.capacity, &channel.state.token_capacity)?;
+
+        let state_update_hash = new_state.state_hash();
+        channel.state = new_state.clone();
+        channel.nonce += 1;
+
+        Ok(UpdateStateResult {
+            channel_id: channel.channel_id,
+            new_state,
+            state_update_hash,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_create_escrow(
+        &self,
+        channel: &mut Channel,
+        sender: Address,
+        recipient: Address,
+        amount: U256,
+        signers: Vec<Address>,
+        threshold: usize,
+        timeout_height: u64,
+    ) -> OperationResult<EscrowResult> {
+        if channel.status != ChannelStatus::Active {
+            return Err(OperationError::InvalidOperation("Channel not active".to_string()));
+        }
+
+        if !signers.contains(&sender) || !signers.contains(&recipient) {
+            return Err(OperationError::InvalidOperation(
+                "Escrow signers must include sender and recipient".to_string()
+            ));
+        }
+
+        if timeout_height <= self.current_height().await? {
+            return Err(OperationError::InvalidOperation("Escrow timeout already passed".to_string()));
+        }
+
+        let mut new_state = channel.state.clone();
+        let lock_id = new_state.create_escrow(
+            sender,
+            recipient,
+            amount,
+            signers,
+            threshold,
+            timeout_height,
+        )?;
+
+        channel.state = new_state.clone();
+        channel.nonce += 1;
+
+        Ok(EscrowResult {
+            channel_id: channel.channel_id,
+            lock_id,
+            new_state,
+            amount,
+        })
+    }
+
+    async fn handle_release_escrow(
+        &self,
+        channel: &mut Channel,
+        lock_id: H256,
+        beneficiary: Address,
+        amount: U256,
+        signatures: SignatureSet,
+    ) -> OperationResult<EscrowResult> {
+        if channel.status != ChannelStatus::Active {
+            return Err(OperationError::InvalidOperation("Channel not active".to_string()));
+        }
+
+        let escrow = channel.state.get_escrow(&lock_id)
+            .ok_or_else(|| OperationError::InvalidOperation("Escrow not found".to_string()))?;
+
+        // The signature set must cover exactly this release
+        if signatures.message_hash != escrow.release_hash(channel.channel_id, beneficiary, amount) {
+            return Err(OperationError::Rejected("Signatures do not match release".to_string()));
+        }
+
+        let approved = self.signature_verifier
+            .verify_threshold(&signatures, &escrow.signers, escrow.threshold)
+            .map_err(|e| OperationError::Rejected(e.to_string()))?;
+        if !approved {
+            return Err(OperationError::Rejected("Insufficient escrow signatures".to_string()));
+        }
+
+        let mut new_state = channel.state.clone();
+        new_state.release_escrow(lock_id, beneficiary, amount)?;
+
+        channel.state = new_state.clone();
+        channel.nonce += 1;
+
+        Ok(EscrowResult {
+            channel_id: channel.channel_id,
+            lock_id,
+            new_state,
+            amount,
+        })
+    }
+
+    async fn handle_refund_escrow(
+        &self,
+        channel: &mut Channel,
+        lock_id: H256,
+    ) -> OperationResult<EscrowResult> {
+        if channel.status != ChannelStatus::Active {
+            return Err(OperationError::InvalidOperation("Channel not active".to_string()));
+        }
+
+        let current_height = self.current_height().await?;
+
+        let mut new_state = channel.state.clone();
+        let amount = new_state.refund_escrow(lock_id, current_height)?;
+
+        channel.state = new_state.clone();
+        channel.nonce += 1;
+
+        Ok(EscrowResult {
+            channel_id: channel.channel_id,
+            lock_id,
+            new_state,
+            amount,
+        })
+    }
+
+    async fn current_height(&self) -> OperationResult<u64> {
+        self.clock.current_height().await
+            .map_err(|e| OperationError::ChannelError(e.to_string()))
     }
 }
 
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    use sha3::{Digest, Keccak256};
+    let mut hasher = Keccak256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+    use sha3::{Digest, Keccak256};
+    use crate::channel::clock::ManualClock;
+    use crate::channel::test_channel;
+    use crate::crypto::CryptoManager;
+
+    fn test_executor() -> OperationExecutor {
+        OperationExecutor::new(Arc::new(SignatureVerifier::new(2)), Arc::new(ManualClock::new(10)))
+    }
+
+    async fn create_escrow(
+        executor: &OperationExecutor,
+        channel: &mut Channel,
+        signers: Vec<Address>,
+        threshold: usize,
+    ) -> OperationResult<EscrowResult> {
+        let (sender, recipient) = (channel.participants[0], channel.participants[1]);
+        let (response, result) = oneshot::channel();
+        executor.execute(channel, ChannelOperation::CreateEscrow {
+            channel_id: channel.channel_id,
+            sender,
+            recipient,
+            amount: U256::from(100),
+            signers,
+            threshold,
+            timeout_height: 50,
+            idempotency_key: None,
+            response,
+        }).await;
+        result.await.unwrap()
+    }
+
+    async fn refund_escrow(executor: &OperationExecutor, channel: &mut Channel, lock_id: H256) -> OperationResult<EscrowResult> {
+        let (response, result) = oneshot::channel();
+        executor.execute(channel, ChannelOperation::RefundEscrow {
+            channel_id: channel.channel_id,
+            lock_id,
+            idempotency_key: None,
+            response,
+        }).await;
+        result.await.unwrap()
+    }
 
-    // Add tests for operation handling
     #[tokio::test]
     async fn test_transfer_operation() {
         // Test implementation
@@ -322,11 +793,131 @@ mod tests {
 
     #[tokio::test]
     async fn test_unlock_operation() {
-        // Test implementation
+        let executor = test_executor();
+        let (a, b) = (Address::random(), Address::random());
+        let mut channel = test_channel(a, 600, b, 400);
+
+        let secret = H256::random();
+        let secret_hash = H256::from_slice(&Keccak256::digest(secret.as_bytes()));
+        let lock = executor.handle_create_lock(&mut channel, a, b, U256::from(100), 50, secret_hash).await.unwrap();
+
+        // Only the preimage of the lock's hash releases it
+        let result = executor.handle_unlock(&mut channel, lock.lock_id, H256::random()).await;
+        assert!(result.is_err());
+        assert!(channel.state.get_lock(&lock.lock_id).is_some());
+
+        let unlocked = executor.handle_unlock(&mut channel, lock.lock_id, secret).await.unwrap();
+        assert_eq!(unlocked.new_state.balances[&b], U256::from(500));
+        assert!(channel.state.get_lock(&lock.lock_id).is_none());
+        assert_eq!(channel.nonce, 2);
+    }
+
+    #[tokio::test]
+    async fn test_escrow_signers_counted_once() {
+        let executor = test_executor();
+        let (a, b) = (Address::random(), Address::random());
+        let mut channel = test_channel(a, 600, b, 400);
+
+        // Listing the sender twice doesn't let it approve releases on its own
+        let result = create_escrow(&executor, &mut channel, vec![a, a, b], 3).await;
+        assert!(matches!(result, Err(OperationError::StateError(_))));
+
+        let escrow = create_escrow(&executor, &mut channel, vec![a, a, b], 2).await.unwrap();
+        let lock = channel.state.get_escrow(&escrow.lock_id).unwrap();
+        assert_eq!(lock.signers, vec![a, b]);
+        assert_eq!(channel.state.balances[&a], U256::from(500));
+    }
+
+    #[tokio::test]
+    async fn test_escrow_release() {
+        let executor = test_executor();
+        let mut crypto = CryptoManager::new();
+        let (a, b) = (crypto.generate_keypair().unwrap(), crypto.generate_keypair().unwrap());
+        let mut channel = test_channel(a, 600, b, 400);
+        let escrow = create_escrow(&executor, &mut channel, vec![a, b], 2).await.unwrap();
+        let lock = channel.state.get_escrow(&escrow.lock_id).unwrap();
+
+        let release_hash = lock.release_hash(channel.channel_id, b, U256::from(60));
+        let mut signatures = SignatureSet {
+            signatures: HashMap::new(),
+            message_hash: release_hash,
+            timestamp: 0,
+        };
+        for signer in [a, b] {
+            let signature = crypto.sign_message(&signer, release_hash.as_bytes()).await.unwrap();
+            signatures.signatures.insert(signer, signature);
+        }
+
+        let (channel_id, lock_id) = (channel.channel_id, escrow.lock_id);
+        let release = move |amount: u64, signatures: SignatureSet| {
+            let (response, result) = oneshot::channel();
+            let operation = ChannelOperation::ReleaseEscrow {
+                channel_id,
+                lock_id,
+                beneficiary: b,
+                amount: U256::from(amount),
+                signatures,
+                idempotency_key: None,
+                response,
+            };
+            (operation, result)
+        };
+
+        // Signatures cover an exact amount
+        let (operation, result) = release(70, signatures.clone());
+        executor.execute(&mut channel, operation).await;
+        assert!(matches!(result.await.unwrap(), Err(OperationError::Rejected(_))));
+
+        // Under the threshold
+        let mut partial = signatures.clone();
+        partial.signatures.remove(&a);
+        let (operation, result) = release(60, partial);
+        executor.execute(&mut channel, operation).await;
+        assert!(matches!(result.await.unwrap(), Err(OperationError::Rejected(_))));
+
+        let (operation, result) = release(60, signatures);
+        executor.execute(&mut channel, operation).await;
+        result.await.unwrap().unwrap();
+        assert_eq!(channel.state.balances[&b], U256::from(460));
+        assert_eq!(channel.state.get_escrow(&escrow.lock_id).unwrap().remaining(), U256::from(40));
+    }
+
+    #[tokio::test]
+    async fn test_escrow_refund_after_timeout() {
+        let clock = Arc::new(ManualClock::new(10));
+        let executor = OperationExecutor::new(Arc::new(SignatureVerifier::new(2)), clock.clone());
+        let (a, b) = (Address::random(), Address::random());
+        let mut channel = test_channel(a, 600, b, 400);
+        let escrow = create_escrow(&executor, &mut channel, vec![a, b], 2).await.unwrap();
+
+        let result = refund_escrow(&executor, &mut channel, escrow.lock_id).await;
+        assert!(matches!(result, Err(OperationError::StateError(_))));
+
+        clock.set_height(50);
+        // Once the channel is disputed, expired escrows are refunded at settlement
+        channel.status = ChannelStatus::Disputed;
+        let result = refund_escrow(&executor, &mut channel, escrow.lock_id).await;
+        assert!(matches!(result, Err(OperationError::InvalidOperation(_))));
+        assert!(channel.state.get_escrow(&escrow.lock_id).is_some());
+
+        channel.status = ChannelStatus::Active;
+        let refund = refund_escrow(&executor, &mut channel, escrow.lock_id).await.unwrap();
+        assert_eq!(refund.amount, U256::from(100));
+        assert_eq!(channel.state.balances[&a], U256::from(600));
+        assert!(channel.state.get_escrow(&escrow.lock_id).is_none());
     }
 
     #[tokio::test]
     async fn test_channel_closing() {
-        // Test implementation
+        // Close and dispute need the bridge, so they aren't operations
+        let executor = test_executor();
+        let mut channel = test_channel(Address::random(), 600, Address::random(), 400);
+        let state = channel.state.clone();
+
+        let result = executor.handle_close(&mut channel, state.clone(), Vec::new()).await;
+        assert!(matches!(result, Err(OperationError::InvalidOperation(_))));
+        let result = executor.handle_dispute(&mut channel, state, Vec::new()).await;
+        assert!(matches!(result, Err(OperationError::InvalidOperation(_))));
+        assert_eq!(channel.nonce, 0);
     }
 }
\ No newline at end of file
@@ -0,0 +1,154 @@
+use ethers::types::U256;
+use serde::{Serialize, Deserialize};
+
+use super::ChannelError;
+
+/// Limits both participants agreed on when the channel was opened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelParameters {
+    pub min_reserve: U256,
+    pub max_htlc_count: usize,
+    pub max_in_flight: U256,
+    pub min_htlc_amount: U256,
+    pub dust_limit: U256,
+}
+
+impl Default for ChannelParameters {
+    fn default() -> Self {
+        Self {
+            min_reserve: U256::zero(),
+            max_htlc_count: usize::MAX,
+            max_in_flight: U256::max_value(),
+            min_htlc_amount: U256::zero(),
+            dust_limit: U256::zero(),
+        }
+    }
+}
+
+impl ChannelParameters {
+    /// Checks that the parameters are consistent with each other and the channel capacity
+    pub fn validate(&self, capacity: U256, participants: usize) -> Result<(), ChannelError> {
+        if self.min_reserve.saturating_mul(U256::from(participants)) > capacity {
+            return Err(ChannelError::InvalidStateTransition(
+                "Reserves exceed channel capacity".to_string()
+            ));
+        }
+
+        if self.dust_limit > self.min_htlc_amount {
+            return Err(ChannelError::InvalidStateTransition(
+                "Dust limit above minimum HTLC amount".to_string()
+            ));
+        }
+
+        if self.max_htlc_count == 0 || self.max_in_flight.is_zero() {
+            return Err(ChannelError::InvalidStateTransition(
+                "HTLC limits must allow at least one HTLC".to_string()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks a new HTLC or lock of `amount` given the sender's balance before it
+    /// and the HTLCs already pending in the channel
+    pub fn check_htlc(
+        &self,
+        amount: U256,
+        sender_balance: U256,
+        pending_count: usize,
+        in_flight: U256,
+    ) -> Result<(), ChannelError> {
+        self.check_htlc_amount(amount, pending_count, in_flight)?;
+        self.check_reserve(amount, sender_balance)
+    }
+
+    /// The HTLC limits without the reserve, which only applies to the native asset.
+    /// Token HTLCs are held to the same amounts.
+    pub fn check_htlc_amount(
+        &self,
+        amount: U256,
+        pending_count: usize,
+        in_flight: U256,
+    ) -> Result<(), ChannelError> {
+        if amount < self.dust_limit {
+            return Err(ChannelError::DustAmount { amount, dust_limit: self.dust_limit });
+        }
+
+        if amount < self.min_htlc_amount {
+            return Err(ChannelError::HtlcBelowMinimum { amount, minimum: self.min_htlc_amount });
+        }
+
+        if pending_count >= self.max_htlc_count {
+            return Err(ChannelError::TooManyHtlcs(self.max_htlc_count));
+        }
+
+        if in_flight.saturating_add(amount) > self.max_in_flight {
+            return Err(ChannelError::InFlightExceeded {
+                in_flight: in_flight.saturating_add(amount),
+                limit: self.max_in_flight,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that paying `amount` out of `sender_balance` leaves the reserve intact
+    pub fn check_reserve(&self, amount: U256, sender_balance: U256) -> Result<(), ChannelError> {
+        let remaining = sender_balance.saturating_sub(amount);
+        if remaining < self.min_reserve {
+            return Err(ChannelError::ReserveViolation { remaining, reserve: self.min_reserve });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_parameters() -> ChannelParameters {
+        ChannelParameters {
+            min_reserve: U256::from(100),
+            max_htlc_count: 2,
+            max_in_flight: U256::from(500),
+            min_htlc_amount: U256::from(10),
+            dust_limit: U256::from(5),
+        }
+    }
+
+    #[test]
+    fn test_parameter_validation() {
+        let parameters = test_parameters();
+        assert!(parameters.validate(U256::from(1000), 2).is_ok());
+        assert!(parameters.validate(U256::from(150), 2).is_err());
+    }
+
+    #[test]
+    fn test_htlc_limits() {
+        let parameters = test_parameters();
+        let balance = U256::from(1000);
+
+        assert!(matches!(
+            parameters.check_htlc(U256::from(1), balance, 0, U256::zero()),
+            Err(ChannelError::DustAmount { .. })
+        ));
+        assert!(matches!(
+            parameters.check_htlc(U256::from(7), balance, 0, U256::zero()),
+            Err(ChannelError::HtlcBelowMinimum { .. })
+        ));
+        assert!(matches!(
+            parameters.check_htlc(U256::from(50), balance, 2, U256::zero()),
+            Err(ChannelError::TooManyHtlcs(2))
+        ));
+        assert!(matches!(
+            parameters.check_htlc(U256::from(200), balance, 1, U256::from(400)),
+            Err(ChannelError::InFlightExceeded { .. })
+        ));
+        assert!(matches!(
+            parameters.check_htlc(U256::from(450), U256::from(500), 0, U256::zero()),
+            Err(ChannelError::ReserveViolation { .. })
+        ));
+        assert!(parameters.check_htlc(U256::from(50), balance, 1, U256::from(100)).is_ok());
+    }
+}
@@ -3,6 +3,12 @@ use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use thiserror::Error;
 
+use flashchain_common::encoding::{u256_bytes, CanonicalBalance, CanonicalEscrow, CanonicalHtlc, CanonicalState};
+use flashchain_common::types::AssetId;
+
+use super::parameters::ChannelParameters;
+use super::ChannelError;
+
 #[derive(Error, Debug)]
 pub enum StateError {
     #[error("Invalid balance allocation")]
@@ -15,6 +21,8 @@ pub enum StateError {
     InvalidLock(String),
     #[error("Lock already exists: {0}")]
     LockExists(H256),
+    #[error("Channel limit: {0}")]
+    Limit(#[from] ChannelError),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -30,19 +38,69 @@ pub enum ChannelStatus {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeLock {
     pub lock_id: H256,
+    #[serde(default)]
+    pub sender: Address,
     pub amount: U256,
     pub expiration_height: u64,
     pub recipient: Address,
     pub secret_hash: H256,
+    #[serde(default)]
+    pub asset: AssetId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EscrowLock {
+    pub lock_id: H256,
+    pub sender: Address,
+    pub recipient: Address,
+    pub amount: U256,
+    pub released: U256,
+    pub signers: Vec<Address>,
+    pub threshold: usize,
+    pub timeout_height: u64,
+    #[serde(default)]
+    pub asset: AssetId,
+}
+
+impl EscrowLock {
+    pub fn remaining(&self) -> U256 {
+        self.amount - self.released
+    }
+
+    /// Hash the signer set has to sign to release `amount` to `beneficiary` in `channel_id`.
+    /// The already released amount is included so a partial release can't be replayed,
+    /// and the channel and asset so it can't be replayed against another escrow.
+    pub fn release_hash(&self, channel_id: H256, beneficiary: Address, amount: U256) -> H256 {
+        let mut data = Vec::new();
+        data.extend_from_slice(channel_id.as_bytes());
+        data.extend_from_slice(self.lock_id.as_bytes());
+        data.extend_from_slice(self.asset.0.as_bytes());
+        data.extend_from_slice(beneficiary.as_bytes());
+        data.extend_from_slice(&u256_bytes(amount));
+        data.extend_from_slice(&u256_bytes(self.released));
+        H256::from_slice(&keccak256(&data))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelState {
     pub balances: HashMap<Address, U256>,
     pub locks: HashMap<H256, TimeLock>,
+    #[serde(default)]
+    pub escrows: HashMap<H256, EscrowLock>,
+    #[serde(default)]
+    pub parameters: ChannelParameters,
     pub merkle_root: H256,
     pub sequence_number: u64,
     pub total_locked: U256,
+    /// Balances in tokens, the native asset is kept in `balances`
+    #[serde(default)]
+    pub token_balances: HashMap<AssetId, HashMap<Address, U256>>,
+    /// Deposited amount of each token
+    #[serde(default)]
+    pub token_capacity: HashMap<AssetId, U256>,
+    #[serde(default)]
+    pub token_locked: HashMap<AssetId, U256>,
 }
 
 impl Default for ChannelState {
@@ -50,46 +108,122 @@ impl Default for ChannelState {
         Self {
             balances: HashMap::new(),
             locks: HashMap::new(),
+            escrows: HashMap::new(),
+            parameters: ChannelParameters::default(),
             merkle_root: H256::zero(),
             sequence_number: 0,
             total_locked: U256::zero(),
+            token_balances: HashMap::new(),
+            token_capacity: HashMap::new(),
+            token_locked: HashMap::new(),
         }
     }
 }
 
 impl ChannelState {
     pub fn new(initial_balances: HashMap<Address, U256>) -> Result<Self, StateError> {
-        let total_balance = initial_balances.values().fold(U256::zero(), |acc, &val| acc + val);
+        let total_balance = checked_sum(initial_balances.values())?;
         if total_balance.is_zero() {
             return Err(StateError::InvalidBalance);
         }
 
         Ok(Self {
             balances: initial_balances,
-            locks: HashMap::new(),
-            merkle_root: H256::zero(),
-            sequence_number: 0,
-            total_locked: U256::zero(),
+            ..Self::default()
         })
     }
 
+    /// Adds a token to the channel with the participants' deposited balances,
+    /// which also fix the channel's capacity in that token
+    pub fn add_asset(
+        &mut self,
+        asset: AssetId,
+        initial_balances: HashMap<Address, U256>,
+    ) -> Result<(), StateError> {
+        if asset.is_native() || self.token_capacity.contains_key(&asset) {
+            return Err(StateError::InvalidTransition(format!("Asset {:?} already in channel", asset)));
+        }
+
+        let total_balance = checked_sum(initial_balances.values())?;
+        if total_balance.is_zero() {
+            return Err(StateError::InvalidBalance);
+        }
+
+        self.token_capacity.insert(asset, total_balance);
+        self.token_balances.insert(asset, initial_balances);
+        self.sequence_number += 1;
+
+        self.update_merkle_root()?;
+
+        Ok(())
+    }
+
+    /// Assets held in the channel, native first
+    pub fn assets(&self) -> Vec<AssetId> {
+        let mut tokens: Vec<AssetId> = self.token_capacity.keys().copied().collect();
+        tokens.sort();
+
+        let mut assets = vec![AssetId::NATIVE];
+        assets.extend(tokens);
+        assets
+    }
+
+    pub fn has_asset(&self, asset: AssetId) -> bool {
+        asset.is_native() || self.token_capacity.contains_key(&asset)
+    }
+
+    pub fn balance_of(&self, asset: AssetId, participant: &Address) -> U256 {
+        if asset.is_native() {
+            return self.get_participant_balance(participant);
+        }
+
+        self.token_balances.get(&asset)
+            .and_then(|balances| balances.get(participant))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn locked_of(&self, asset: AssetId) -> U256 {
+        if asset.is_native() {
+            return self.total_locked;
+        }
+
+        self.token_locked.get(&asset).copied().unwrap_or_default()
+    }
+
     pub fn transfer(
         &mut self,
         from: Address,
         to: Address,
         amount: U256,
+    ) -> Result<(), StateError> {
+        self.transfer_asset(AssetId::NATIVE, from, to, amount)
+    }
+
+    pub fn transfer_asset(
+        &mut self,
+        asset: AssetId,
+        from: Address,
+        to: Address,
+        amount: U256,
     ) -> Result<(), StateError> {
         // Verify participants exist
-        let from_balance = self.balances.get(&from)
+        let from_balance = self.asset_balances(asset)?.get(&from).copied()
             .ok_or(StateError::MissingParticipant(from))?;
-        
-        if amount > *from_balance {
+
+        if amount > from_balance {
             return Err(StateError::InvalidBalance);
         }
 
+        // Reserves are denominated in the native asset
+        if asset.is_native() {
+            self.parameters.check_reserve(amount, from_balance)?;
+        }
+
         // Update balances
-        *self.balances.entry(from).or_insert(U256::zero()) -= amount;
-        *self.balances.entry(to).or_insert(U256::zero()) += amount;
+        let balances = self.asset_balances_mut(asset)?;
+        *balances.entry(from).or_insert(U256::zero()) -= amount;
+        *balances.entry(to).or_insert(U256::zero()) += amount;
         self.sequence_number += 1;
 
         // Update merkle root
@@ -105,15 +239,29 @@ impl ChannelState {
         amount: U256,
         expiration_height: u64,
         secret_hash: H256,
+    ) -> Result<H256, StateError> {
+        self.create_asset_lock(AssetId::NATIVE, sender, recipient, amount, expiration_height, secret_hash)
+    }
+
+    pub fn create_asset_lock(
+        &mut self,
+        asset: AssetId,
+        sender: Address,
+        recipient: Address,
+        amount: U256,
+        expiration_height: u64,
+        secret_hash: H256,
     ) -> Result<H256, StateError> {
         // Verify sender has sufficient balance
-        let sender_balance = self.balances.get(&sender)
+        let sender_balance = self.asset_balances(asset)?.get(&sender).copied()
             .ok_or(StateError::MissingParticipant(sender))?;
 
-        if amount > *sender_balance {
+        if amount > sender_balance {
             return Err(StateError::InvalidBalance);
         }
 
+        self.check_lock_limits(asset, amount, sender_balance)?;
+
         // Create lock
         let lock_id = self.generate_lock_id(sender, recipient, amount, secret_hash);
         if self.locks.contains_key(&lock_id) {
@@ -122,15 +270,17 @@ impl ChannelState {
 
         let lock = TimeLock {
             lock_id,
+            sender,
             amount,
             expiration_height,
             recipient,
             secret_hash,
+            asset,
         };
 
         // Update state
-        *self.balances.get_mut(&sender).unwrap() -= amount;
-        self.total_locked += amount;
+        *self.asset_balances_mut(asset)?.get_mut(&sender).unwrap() -= amount;
+        *self.locked_mut(asset) += amount;
         self.locks.insert(lock_id, lock);
         self.sequence_number += 1;
 
@@ -146,7 +296,8 @@ impl ChannelState {
         secret: H256,
     ) -> Result<(), StateError> {
         let lock = self.locks.get(&lock_id)
-            .ok_or(StateError::InvalidLock("Lock not found".to_string()))?;
+            .ok_or(StateError::InvalidLock("Lock not found".to_string()))?
+            .clone();
 
         // Verify secret
         if !self.verify_secret(lock.secret_hash, secret) {
@@ -154,8 +305,8 @@ impl ChannelState {
         }
 
         // Transfer locked amount to recipient
-        *self.balances.entry(lock.recipient).or_insert(U256::zero()) += lock.amount;
-        self.total_locked -= lock.amount;
+        *self.asset_balances_mut(lock.asset)?.entry(lock.recipient).or_insert(U256::zero()) += lock.amount;
+        *self.locked_mut(lock.asset) -= lock.amount;
         self.locks.remove(&lock_id);
         self.sequence_number += 1;
 
@@ -167,7 +318,8 @@ impl ChannelState {
 
     pub fn expire_lock(&mut self, lock_id: H256, current_height: u64) -> Result<(), StateError> {
         let lock = self.locks.get(&lock_id)
-            .ok_or(StateError::InvalidLock("Lock not found".to_string()))?;
+            .ok_or(StateError::InvalidLock("Lock not found".to_string()))?
+            .clone();
 
         if current_height < lock.expiration_height {
             return Err(StateError::InvalidLock("Lock not expired".to_string()));
@@ -175,8 +327,8 @@ impl ChannelState {
 
         // Return locked amount to sender
         let sender = self.find_lock_sender(lock_id)?;
-        *self.balances.entry(sender).or_insert(U256::zero()) += lock.amount;
-        self.total_locked -= lock.amount;
+        *self.asset_balances_mut(lock.asset)?.entry(sender).or_insert(U256::zero()) += lock.amount;
+        *self.locked_mut(lock.asset) -= lock.amount;
         self.locks.remove(&lock_id);
         self.sequence_number += 1;
 
@@ -186,16 +338,189 @@ impl ChannelState {
         Ok(())
     }
 
-    pub fn verify_state(&self, capacity: U256) -> Result<(), StateError> {
+    pub fn create_escrow(
+        &mut self,
+        sender: Address,
+        recipient: Address,
+        amount: U256,
+        signers: Vec<Address>,
+        threshold: usize,
+        timeout_height: u64,
+    ) -> Result<H256, StateError> {
+        self.create_asset_escrow(AssetId::NATIVE, sender, recipient, amount, signers, threshold, timeout_height)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_asset_escrow(
+        &mut self,
+        asset: AssetId,
+        sender: Address,
+        recipient: Address,
+        amount: U256,
+        signers: Vec<Address>,
+        threshold: usize,
+        timeout_height: u64,
+    ) -> Result<H256, StateError> {
+        // A signer listed twice would count twice towards the threshold
+        let mut unique = Vec::with_capacity(signers.len());
+        for signer in signers {
+            if !unique.contains(&signer) {
+                unique.push(signer);
+            }
+        }
+        let signers = unique;
+
+        if threshold == 0 || threshold > signers.len() {
+            return Err(StateError::InvalidLock("Invalid escrow threshold".to_string()));
+        }
+
+        let sender_balance = self.asset_balances(asset)?.get(&sender).copied()
+            .ok_or(StateError::MissingParticipant(sender))?;
+
+        if amount.is_zero() || amount > sender_balance {
+            return Err(StateError::InvalidBalance);
+        }
+
+        self.check_lock_limits(asset, amount, sender_balance)?;
+
+        let lock_id = self.generate_escrow_id(sender, recipient, amount, &signers, timeout_height);
+        if self.escrows.contains_key(&lock_id) {
+            return Err(StateError::LockExists(lock_id));
+        }
+
+        let escrow = EscrowLock {
+            lock_id,
+            sender,
+            recipient,
+            amount,
+            released: U256::zero(),
+            signers,
+            threshold,
+            timeout_height,
+            asset,
+        };
+
+        // Update state
+        *self.asset_balances_mut(asset)?.get_mut(&sender).unwrap() -= amount;
+        *self.locked_mut(asset) += amount;
+        self.escrows.insert(lock_id, escrow);
+        self.sequence_number += 1;
+
+        // Update merkle root
+        self.update_merkle_root()?;
+
+        Ok(lock_id)
+    }
+
+    /// Releases part or all of an escrow to the sender or the recipient.
+    /// The caller is responsible for checking the m-of-n signatures over
+    /// `EscrowLock::release_hash` before calling this.
+    pub fn release_escrow(
+        &mut self,
+        lock_id: H256,
+        beneficiary: Address,
+        amount: U256,
+    ) -> Result<(), StateError> {
+        let escrow = self.escrows.get_mut(&lock_id)
+            .ok_or(StateError::InvalidLock("Escrow not found".to_string()))?;
+
+        if beneficiary != escrow.sender && beneficiary != escrow.recipient {
+            return Err(StateError::InvalidLock("Invalid escrow beneficiary".to_string()));
+        }
+
+        if amount.is_zero() || amount > escrow.remaining() {
+            return Err(StateError::InvalidLock("Invalid release amount".to_string()));
+        }
+
+        escrow.released += amount;
+        let fully_released = escrow.remaining().is_zero();
+        let asset = escrow.asset;
+
+        *self.asset_balances_mut(asset)?.entry(beneficiary).or_insert(U256::zero()) += amount;
+        *self.locked_mut(asset) -= amount;
+        if fully_released {
+            self.escrows.remove(&lock_id);
+        }
+        self.sequence_number += 1;
+
+        // Update merkle root
+        self.update_merkle_root()?;
+
+        Ok(())
+    }
+
+    pub fn refund_escrow(&mut self, lock_id: H256, current_height: u64) -> Result<U256, StateError> {
+        let escrow = self.escrows.get(&lock_id)
+            .ok_or(StateError::InvalidLock("Escrow not found".to_string()))?
+            .clone();
+
+        if current_height < escrow.timeout_height {
+            return Err(StateError::InvalidLock("Escrow not expired".to_string()));
+        }
+
+        // Return whatever was not released to the sender
+        let refund = escrow.remaining();
+        *self.asset_balances_mut(escrow.asset)?.entry(escrow.sender).or_insert(U256::zero()) += refund;
+        *self.locked_mut(escrow.asset) -= refund;
+        self.escrows.remove(&lock_id);
+        self.sequence_number += 1;
+
+        // Update merkle root
+        self.update_merkle_root()?;
+
+        Ok(refund)
+    }
+
+    pub fn refund_expired_escrows(&mut self, current_height: u64) -> Result<Vec<H256>, StateError> {
+        let expired: Vec<H256> = self.escrows.values()
+            .filter(|escrow| current_height >= escrow.timeout_height)
+            .map(|escrow| escrow.lock_id)
+            .collect();
+
+        for lock_id in &expired {
+            self.refund_escrow(*lock_id, current_height)?;
+        }
+
+        Ok(expired)
+    }
+
+    pub fn get_escrow(&self, lock_id: &H256) -> Option<EscrowLock> {
+        self.escrows.get(lock_id).cloned()
+    }
+
+    /// Checks the state against the channel's funded capacity in every asset.
+    /// `token_capacity` is what the channel holds of each token, as recorded when the
+    /// tokens were deposited; a state can't change it.
+    pub fn verify_state(&self, capacity: U256, token_capacity: &HashMap<AssetId, U256>) -> Result<(), StateError> {
         // Verify total balances don't exceed capacity
-        let total_balance: U256 = self.balances.values().fold(U256::zero(), |acc, &val| acc + val);
-        if total_balance + self.total_locked > capacity {
+        let total_balance = checked_sum(self.balances.values())?;
+        if total_balance.checked_add(self.total_locked).ok_or(StateError::InvalidBalance)? > capacity {
             return Err(StateError::InvalidBalance);
         }
 
+        if self.token_capacity != *token_capacity {
+            return Err(StateError::InvalidTransition("State changes the channel's token capacity".into()));
+        }
+
+        // Every token is bounded by its own deposited capacity
+        for (asset, balances) in &self.token_balances {
+            let capacity = token_capacity.get(asset).copied().unwrap_or_default();
+            let total_balance = checked_sum(balances.values())?;
+            if total_balance.checked_add(self.locked_of(*asset)).ok_or(StateError::InvalidBalance)? > capacity {
+                return Err(StateError::InvalidBalance);
+            }
+        }
+
         Ok(())
     }
 
+    /// Takes what signatures don't cover from `current`: the channel parameters and
+    /// the deposited token capacity. A signed update can't change either.
+    pub fn keep_unsigned_fields(&mut self, current: &ChannelState) {
+        self.parameters = current.parameters.clone();
+        self.token_capacity = current.token_capacity.clone();
+    }
+
     pub fn get_participant_balance(&self, participant: &Address) -> U256 {
         self.balances.get(participant).copied().unwrap_or_default()
     }
@@ -204,8 +529,55 @@ impl ChannelState {
         self.locks.get(lock_id).cloned()
     }
 
+    /// Number of time locks and escrows counted against `max_htlc_count`
+    pub fn pending_lock_count(&self) -> usize {
+        self.locks.len() + self.escrows.len()
+    }
+
+    /// Hash participants sign to agree on this state
+    pub fn state_hash(&self) -> H256 {
+        CanonicalState::from(self).hash()
+    }
+
     // Helper functions
 
+    fn asset_balances(&self, asset: AssetId) -> Result<&HashMap<Address, U256>, StateError> {
+        if asset.is_native() {
+            return Ok(&self.balances);
+        }
+
+        self.token_balances.get(&asset)
+            .ok_or_else(|| StateError::InvalidTransition(format!("Asset {:?} not in channel", asset)))
+    }
+
+    fn asset_balances_mut(&mut self, asset: AssetId) -> Result<&mut HashMap<Address, U256>, StateError> {
+        if asset.is_native() {
+            return Ok(&mut self.balances);
+        }
+
+        self.token_balances.get_mut(&asset)
+            .ok_or_else(|| StateError::InvalidTransition(format!("Asset {:?} not in channel", asset)))
+    }
+
+    fn locked_mut(&mut self, asset: AssetId) -> &mut U256 {
+        if asset.is_native() {
+            return &mut self.total_locked;
+        }
+
+        self.token_locked.entry(asset).or_insert_with(U256::zero)
+    }
+
+    /// Every asset is held to the amount limits; the reserve only applies to the native asset
+    fn check_lock_limits(&self, asset: AssetId, amount: U256, sender_balance: U256) -> Result<(), StateError> {
+        if asset.is_native() {
+            self.parameters.check_htlc(amount, sender_balance, self.pending_lock_count(), self.total_locked)?;
+        } else {
+            self.parameters.check_htlc_amount(amount, self.pending_lock_count(), self.locked_of(asset))?;
+        }
+
+        Ok(())
+    }
+
     fn update_merkle_root(&mut self) -> Result<(), StateError> {
         // Implement merkle root calculation
         // This should include balances and locks in the merkle tree
@@ -220,29 +592,104 @@ impl ChannelState {
         amount: U256,
         secret_hash: H256,
     ) -> H256 {
-        // Implement lock ID generation
-        H256::zero() // Placeholder
+        let mut data = Vec::new();
+        data.extend_from_slice(sender.as_bytes());
+        data.extend_from_slice(recipient.as_bytes());
+        data.extend_from_slice(&u256_bytes(amount));
+        data.extend_from_slice(secret_hash.as_bytes());
+        data.extend_from_slice(&self.sequence_number.to_be_bytes());
+        H256::from_slice(&keccak256(&data))
+    }
+
+    fn generate_escrow_id(
+        &self,
+        sender: Address,
+        recipient: Address,
+        amount: U256,
+        signers: &[Address],
+        timeout_height: u64,
+    ) -> H256 {
+        let mut data = Vec::new();
+        data.extend_from_slice(sender.as_bytes());
+        data.extend_from_slice(recipient.as_bytes());
+        data.extend_from_slice(&u256_bytes(amount));
+        for signer in signers {
+            data.extend_from_slice(signer.as_bytes());
+        }
+        data.extend_from_slice(&timeout_height.to_be_bytes());
+        data.extend_from_slice(&self.sequence_number.to_be_bytes());
+        H256::from_slice(&keccak256(&data))
     }
 
     fn verify_secret(&self, secret_hash: H256, secret: H256) -> bool {
-        // Implement secret verification
-        true // Placeholder
+        keccak256(secret.as_bytes()) == secret_hash.as_bytes()
     }
 
     fn find_lock_sender(&self, lock_id: H256) -> Result<Address, StateError> {
-        // Implement sender lookup logic
-        Ok(Address::zero()) // Placeholder
+        self.locks.get(&lock_id)
+            .map(|lock| lock.sender)
+            .ok_or(StateError::InvalidLock("Lock not found".to_string()))
     }
 
-    pub fn generate_proof(&self, participant: Address) -> Vec<u8> {
+    pub fn generate_proof(&self, _participant: Address) -> Vec<u8> {
         // Implement merkle proof generation for participant's balance
         Vec::new() // Placeholder
     }
 }
 
+impl From<&ChannelState> for CanonicalState {
+    fn from(state: &ChannelState) -> Self {
+        let balances = state.assets().into_iter()
+            .flat_map(|asset| {
+                state.asset_balances(asset).into_iter().flatten().map(move |(&participant, &amount)| {
+                    CanonicalBalance { asset, participant, amount }
+                })
+            });
+
+        let htlcs = state.locks.values().map(|lock| CanonicalHtlc {
+            id: lock.lock_id,
+            asset: lock.asset,
+            sender: lock.sender,
+            receiver: lock.recipient,
+            amount: lock.amount,
+            hash_lock: lock.secret_hash,
+            expiration: lock.expiration_height,
+        });
+
+        let escrows = state.escrows.values().map(|escrow| CanonicalEscrow {
+            id: escrow.lock_id,
+            asset: escrow.asset,
+            sender: escrow.sender,
+            recipient: escrow.recipient,
+            amount: escrow.amount,
+            released: escrow.released,
+            threshold: escrow.threshold as u64,
+            timeout: escrow.timeout_height,
+            signers: escrow.signers.clone(),
+        });
+
+        CanonicalState::new(state.sequence_number, balances, htlcs, escrows)
+    }
+}
+
+/// Sum of the balances, which peers supply and so may overflow
+fn checked_sum<'a>(balances: impl IntoIterator<Item = &'a U256>) -> Result<U256, StateError> {
+    balances.into_iter()
+        .try_fold(U256::zero(), |total, &balance| total.checked_add(balance))
+        .ok_or(StateError::InvalidBalance)
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    use sha3::{Digest, Keccak256};
+    let mut hasher = Keccak256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
 
     #[test]
     fn test_channel_state_creation() {
@@ -263,7 +710,28 @@ mod tests {
 
     #[test]
     fn test_lock_creation() {
-        // Add lock creation tests
+        let sender = Address::random();
+        let recipient = Address::random();
+
+        let mut initial_balances = HashMap::new();
+        initial_balances.insert(sender, U256::from(1000));
+        initial_balances.insert(recipient, U256::zero());
+        let mut state = ChannelState::new(initial_balances).unwrap();
+        state.parameters.max_htlc_count = 3;
+
+        // Identical terms still get their own lock
+        let secret_hash = H256::random();
+        let mut lock_ids = HashSet::new();
+        for _ in 0..3 {
+            lock_ids.insert(state.create_lock(sender, recipient, U256::from(100), 50, secret_hash).unwrap());
+        }
+        assert_eq!(lock_ids.len(), 3);
+        assert_eq!(state.total_locked, U256::from(300));
+
+        assert!(matches!(
+            state.create_lock(sender, recipient, U256::from(100), 50, H256::random()),
+            Err(StateError::Limit(ChannelError::TooManyHtlcs(3)))
+        ));
     }
 
     #[test]
@@ -275,4 +743,191 @@ mod tests {
     fn test_lock_expiration() {
         // Add lock expiration tests
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_escrow_partial_release_and_refund() {
+        let buyer = Address::random();
+        let seller = Address::random();
+        let arbiter = Address::random();
+
+        let mut initial_balances = HashMap::new();
+        initial_balances.insert(buyer, U256::from(1000));
+        initial_balances.insert(seller, U256::zero());
+        let mut state = ChannelState::new(initial_balances).unwrap();
+
+        let lock_id = state.create_escrow(
+            buyer,
+            seller,
+            U256::from(600),
+            vec![buyer, seller, arbiter],
+            2,
+            100,
+        ).unwrap();
+        assert_eq!(state.total_locked, U256::from(600));
+        assert_eq!(state.get_participant_balance(&buyer), U256::from(400));
+
+        // Partial release to the seller
+        state.release_escrow(lock_id, seller, U256::from(200)).unwrap();
+        assert_eq!(state.get_participant_balance(&seller), U256::from(200));
+        assert_eq!(state.get_escrow(&lock_id).unwrap().remaining(), U256::from(400));
+
+        // Arbiter is not a valid beneficiary
+        assert!(state.release_escrow(lock_id, arbiter, U256::from(1)).is_err());
+
+        // Refund is only possible after the timeout
+        assert!(state.refund_escrow(lock_id, 99).is_err());
+        assert_eq!(state.refund_escrow(lock_id, 100).unwrap(), U256::from(400));
+        assert_eq!(state.get_participant_balance(&buyer), U256::from(800));
+        assert_eq!(state.total_locked, U256::zero());
+        assert!(state.get_escrow(&lock_id).is_none());
+    }
+
+    #[test]
+    fn test_release_hash_binds_channel_and_asset() {
+        let escrow = EscrowLock {
+            lock_id: H256::random(),
+            sender: Address::random(),
+            recipient: Address::random(),
+            amount: U256::from(100),
+            released: U256::zero(),
+            signers: Vec::new(),
+            threshold: 2,
+            timeout_height: 100,
+            asset: AssetId::NATIVE,
+        };
+        let channel_id = H256::random();
+        let hash = escrow.release_hash(channel_id, escrow.recipient, U256::from(10));
+
+        assert_ne!(hash, escrow.release_hash(H256::random(), escrow.recipient, U256::from(10)));
+        let token_escrow = EscrowLock { asset: AssetId::token(Address::random()), ..escrow.clone() };
+        assert_ne!(hash, token_escrow.release_hash(channel_id, escrow.recipient, U256::from(10)));
+    }
+
+    #[test]
+    fn test_lock_respects_reserve() {
+        let sender = Address::random();
+        let mut initial_balances = HashMap::new();
+        initial_balances.insert(sender, U256::from(1000));
+        let mut state = ChannelState::new(initial_balances).unwrap();
+        state.parameters.min_reserve = U256::from(100);
+
+        let result = state.create_lock(sender, Address::random(), U256::from(950), 100, H256::random());
+        assert!(matches!(
+            result,
+            Err(StateError::Limit(ChannelError::ReserveViolation { .. }))
+        ));
+
+        assert!(state.create_lock(sender, Address::random(), U256::from(900), 100, H256::random()).is_ok());
+    }
+
+    #[test]
+    fn test_escrow_invalid_threshold() {
+        let buyer = Address::random();
+        let mut initial_balances = HashMap::new();
+        initial_balances.insert(buyer, U256::from(1000));
+        let mut state = ChannelState::new(initial_balances).unwrap();
+
+        assert!(state.create_escrow(
+            buyer,
+            Address::random(),
+            U256::from(100),
+            vec![buyer],
+            2,
+            100,
+        ).is_err());
+    }
+
+    #[test]
+    fn test_token_balances_are_separate() {
+        let (a, b) = (Address::random(), Address::random());
+        let usdc = AssetId::token(Address::random());
+
+        let mut initial_balances = HashMap::new();
+        initial_balances.insert(a, U256::from(1000));
+        initial_balances.insert(b, U256::from(1000));
+        let mut state = ChannelState::new(initial_balances).unwrap();
+
+        let mut token_balances = HashMap::new();
+        token_balances.insert(a, U256::from(500));
+        token_balances.insert(b, U256::zero());
+        state.add_asset(usdc, token_balances).unwrap();
+
+        state.transfer_asset(usdc, a, b, U256::from(200)).unwrap();
+        let lock_id = state.create_asset_lock(usdc, a, b, U256::from(100), 100, H256::random()).unwrap();
+
+        assert_eq!(state.balance_of(usdc, &a), U256::from(200));
+        assert_eq!(state.balance_of(usdc, &b), U256::from(200));
+        assert_eq!(state.locked_of(usdc), U256::from(100));
+        // The native asset is untouched
+        assert_eq!(state.get_participant_balance(&a), U256::from(1000));
+        assert!(state.total_locked.is_zero());
+        let deposited = state.token_capacity.clone();
+        state.verify_state(U256::from(2000), &deposited).unwrap();
+        // A state can't raise its own token capacity
+        let mut inflated = state.clone();
+        inflated.token_capacity.insert(usdc, U256::from(5000));
+        assert!(inflated.verify_state(U256::from(2000), &deposited).is_err());
+
+        state.expire_lock(lock_id, 100).unwrap();
+        assert_eq!(state.balance_of(usdc, &a), U256::from(300));
+
+        // Can't spend more of a token than was deposited
+        assert!(state.transfer_asset(usdc, a, b, U256::from(301)).is_err());
+        assert!(state.transfer_asset(AssetId::token(Address::random()), a, b, U256::from(1)).is_err());
+    }
+
+    #[test]
+    fn test_overflowing_balances_rejected() {
+        let (a, b) = (Address::random(), Address::random());
+        let usdc = AssetId::token(Address::random());
+        let overflowing = HashMap::from([(a, U256::MAX), (b, U256::from(1))]);
+
+        assert!(matches!(ChannelState::new(overflowing.clone()), Err(StateError::InvalidBalance)));
+
+        let mut state = ChannelState::new(HashMap::from([(a, U256::from(1000))])).unwrap();
+        assert!(matches!(state.add_asset(usdc, overflowing.clone()), Err(StateError::InvalidBalance)));
+
+        // A peer's state whose balances wrap around to within capacity
+        let mut wrapped = state.clone();
+        wrapped.balances = overflowing.clone();
+        assert!(matches!(wrapped.verify_state(U256::from(1000), &HashMap::new()), Err(StateError::InvalidBalance)));
+
+        let mut locked = state.clone();
+        locked.balances.insert(a, U256::MAX);
+        locked.total_locked = U256::from(1);
+        assert!(matches!(locked.verify_state(U256::from(1000), &HashMap::new()), Err(StateError::InvalidBalance)));
+
+        state.add_asset(usdc, HashMap::from([(a, U256::from(500))])).unwrap();
+        let deposited = state.token_capacity.clone();
+        state.token_balances.insert(usdc, overflowing);
+        assert!(matches!(state.verify_state(U256::from(1000), &deposited), Err(StateError::InvalidBalance)));
+    }
+
+    #[test]
+    fn test_bridge_state_keeps_hash() {
+        use flashchain_bridge::types::ChannelState as BridgeChannelState;
+
+        let (a, b) = (Address::random(), Address::random());
+        let usdc = AssetId::token(Address::random());
+
+        let mut initial_balances = HashMap::new();
+        initial_balances.insert(a, U256::from(1000));
+        initial_balances.insert(b, U256::from(1000));
+        let mut state = ChannelState::new(initial_balances).unwrap();
+
+        let mut token_balances = HashMap::new();
+        token_balances.insert(a, U256::from(500));
+        token_balances.insert(b, U256::zero());
+        state.add_asset(usdc, token_balances).unwrap();
+
+        state.create_asset_lock(usdc, a, b, U256::from(100), 100, H256::random()).unwrap();
+        state.create_escrow(a, b, U256::from(300), vec![b, a], 2, 200).unwrap();
+
+        let canonical = CanonicalState::from(&state);
+        let bridge_state = BridgeChannelState::from_canonical(canonical.clone(), 0);
+
+        assert_eq!(bridge_state.htlcs.len(), 1);
+        assert_eq!(CanonicalState::from(&bridge_state), canonical);
+        assert_eq!(bridge_state.hash(), state.state_hash());
+    }
+}
@@ -0,0 +1,209 @@
+use ethers::types::H256;
+use serde::{Serialize, Deserialize};
+
+use super::state::ChannelStatus;
+use super::ChannelError;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum ChannelEvent {
+    Funded,
+    Lock,
+    Unlock,
+    InitiateClose,
+    Dispute,
+    Settle,
+    CooperativeClose,
+    Abort,
+}
+
+impl ChannelEvent {
+    pub const ALL: [ChannelEvent; 8] = [
+        ChannelEvent::Funded,
+        ChannelEvent::Lock,
+        ChannelEvent::Unlock,
+        ChannelEvent::InitiateClose,
+        ChannelEvent::Dispute,
+        ChannelEvent::Settle,
+        ChannelEvent::CooperativeClose,
+        ChannelEvent::Abort,
+    ];
+}
+
+/// Inputs the transition guards are evaluated against
+#[derive(Debug, Clone, Copy)]
+pub struct TransitionContext {
+    pub current_height: u64,
+    pub timeout_height: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransitionRecord {
+    pub channel_id: H256,
+    pub from: ChannelStatus,
+    pub to: ChannelStatus,
+    pub event: ChannelEvent,
+    pub block_height: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Guard {
+    None,
+    BeforeTimeout,
+    AfterTimeout,
+}
+
+/// The complete set of legal status transitions. Anything not listed here is rejected.
+const TRANSITIONS: &[(ChannelStatus, ChannelEvent, ChannelStatus, Guard)] = &[
+    (ChannelStatus::Initializing, ChannelEvent::Funded, ChannelStatus::Active, Guard::None),
+    (ChannelStatus::Initializing, ChannelEvent::Abort, ChannelStatus::Closed, Guard::None),
+    (ChannelStatus::Active, ChannelEvent::Lock, ChannelStatus::Locked, Guard::None),
+    (ChannelStatus::Locked, ChannelEvent::Unlock, ChannelStatus::Active, Guard::None),
+    (ChannelStatus::Active, ChannelEvent::InitiateClose, ChannelStatus::Closing, Guard::None),
+    (ChannelStatus::Locked, ChannelEvent::InitiateClose, ChannelStatus::Closing, Guard::None),
+    (ChannelStatus::Closing, ChannelEvent::Dispute, ChannelStatus::Disputed, Guard::BeforeTimeout),
+    (ChannelStatus::Closing, ChannelEvent::Settle, ChannelStatus::Closed, Guard::AfterTimeout),
+    // Both participants signed the final state, so there is nothing left to dispute
+    (ChannelStatus::Closing, ChannelEvent::CooperativeClose, ChannelStatus::Closed, Guard::None),
+    (ChannelStatus::Disputed, ChannelEvent::Settle, ChannelStatus::Closed, Guard::AfterTimeout),
+];
+
+/// Looks up the target status for `event` without evaluating guards
+pub fn next_status(from: &ChannelStatus, event: ChannelEvent) -> Option<ChannelStatus> {
+    TRANSITIONS.iter()
+        .find(|(source, trigger, _, _)| source == from && *trigger == event)
+        .map(|(_, _, target, _)| target.clone())
+}
+
+pub fn transition(
+    channel_id: H256,
+    from: &ChannelStatus,
+    event: ChannelEvent,
+    context: TransitionContext,
+) -> Result<TransitionRecord, ChannelError> {
+    let (_, _, to, guard) = TRANSITIONS.iter()
+        .find(|(source, trigger, _, _)| source == from && *trigger == event)
+        .ok_or_else(|| ChannelError::InvalidStateTransition(
+            format!("{:?} is not allowed from {:?}", event, from)
+        ))?;
+
+    match guard {
+        Guard::None => {}
+        Guard::BeforeTimeout => {
+            if context.current_height >= context.timeout_height {
+                return Err(ChannelError::InvalidStateTransition(
+                    format!("{:?} requires height below {}", event, context.timeout_height)
+                ));
+            }
+        }
+        Guard::AfterTimeout => {
+            if context.current_height < context.timeout_height {
+                return Err(ChannelError::InvalidStateTransition(
+                    format!("{:?} requires height {} or above", event, context.timeout_height)
+                ));
+            }
+        }
+    }
+
+    Ok(TransitionRecord {
+        channel_id,
+        from: from.clone(),
+        to: to.clone(),
+        event,
+        block_height: context.current_height,
+        timestamp: chrono::Utc::now().timestamp() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const ALL_STATUSES: [ChannelStatus; 6] = [
+        ChannelStatus::Initializing,
+        ChannelStatus::Active,
+        ChannelStatus::Locked,
+        ChannelStatus::Closing,
+        ChannelStatus::Disputed,
+        ChannelStatus::Closed,
+    ];
+
+    fn event_strategy() -> impl Strategy<Value = ChannelEvent> {
+        prop::sample::select(ChannelEvent::ALL.to_vec())
+    }
+
+    #[test]
+    fn test_closed_is_terminal() {
+        for event in ChannelEvent::ALL {
+            assert!(next_status(&ChannelStatus::Closed, event).is_none());
+        }
+    }
+
+    #[test]
+    fn test_unlisted_transitions_rejected() {
+        let context = TransitionContext { current_height: 0, timeout_height: 0 };
+
+        for status in ALL_STATUSES.iter() {
+            for event in ChannelEvent::ALL {
+                let result = transition(H256::zero(), status, event, context);
+                if next_status(status, event).is_none() {
+                    assert!(matches!(result, Err(ChannelError::InvalidStateTransition(_))));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dispute_guard() {
+        let before = TransitionContext { current_height: 5, timeout_height: 10 };
+        let after = TransitionContext { current_height: 10, timeout_height: 10 };
+
+        assert!(transition(H256::zero(), &ChannelStatus::Closing, ChannelEvent::Dispute, before).is_ok());
+        assert!(transition(H256::zero(), &ChannelStatus::Closing, ChannelEvent::Dispute, after).is_err());
+        assert!(transition(H256::zero(), &ChannelStatus::Disputed, ChannelEvent::Settle, before).is_err());
+        assert!(transition(H256::zero(), &ChannelStatus::Disputed, ChannelEvent::Settle, after).is_ok());
+    }
+
+    #[test]
+    fn test_cooperative_close_skips_dispute_window() {
+        let context = TransitionContext { current_height: 5, timeout_height: 10 };
+
+        let record = transition(H256::zero(), &ChannelStatus::Closing, ChannelEvent::CooperativeClose, context).unwrap();
+        assert_eq!(record.to, ChannelStatus::Closed);
+        assert!(transition(H256::zero(), &ChannelStatus::Active, ChannelEvent::CooperativeClose, context).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_only_table_transitions_are_reachable(
+            events in prop::collection::vec((event_strategy(), 0u64..20), 0..50),
+            timeout_height in 0u64..20,
+        ) {
+            let mut status = ChannelStatus::Initializing;
+
+            for (event, current_height) in events {
+                let context = TransitionContext { current_height, timeout_height };
+                match transition(H256::zero(), &status, event, context) {
+                    Ok(record) => {
+                        prop_assert_eq!(&record.from, &status);
+                        prop_assert_eq!(Some(record.to.clone()), next_status(&status, event));
+                        status = record.to;
+                    }
+                    Err(ChannelError::InvalidStateTransition(_)) => {}
+                    Err(e) => prop_assert!(false, "unexpected error {:?}", e),
+                }
+            }
+        }
+
+        #[test]
+        fn prop_closed_never_left(
+            events in prop::collection::vec((event_strategy(), 0u64..20), 0..50),
+        ) {
+            for (event, current_height) in events {
+                let context = TransitionContext { current_height, timeout_height: 10 };
+                prop_assert!(transition(H256::zero(), &ChannelStatus::Closed, event, context).is_err());
+            }
+        }
+    }
+}
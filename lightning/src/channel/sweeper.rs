@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use ethers::types::{Address, H256};
+use flashchain_common::encoding::CanonicalState;
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+use super::clock::{TimeoutEvent, TimeoutKind, TimeoutScheduler};
+use super::{ChannelError, ChannelManager};
+use crate::crypto::{CryptoError, CryptoManager};
+use crate::network::{NetworkError, NetworkManager, NetworkMessage};
+use crate::routing::payment::PaymentProcessor;
+use crate::state::{StateError, StateManager, StateUpdate};
+
+#[derive(Error, Debug)]
+pub enum SweepError {
+    #[error("Channel error: {0}")]
+    Channel(#[from] ChannelError),
+    #[error("Lock error: {0}")]
+    Lock(String),
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+}
+
+/// Expires HTLCs and time locks once the chain clock passes their expiry height.
+/// Expired funds go back to the sender, the upstream payment is failed and the
+/// resulting state update is signed and sent to the counterparty.
+pub struct ExpirySweeper {
+    scheduler: Arc<TimeoutScheduler>,
+    channel_manager: Arc<ChannelManager>,
+    state_manager: Arc<StateManager>,
+    payment_processor: Arc<PaymentProcessor>,
+    network: Arc<NetworkManager>,
+    crypto: Arc<CryptoManager>,
+    node_address: Address,
+}
+
+impl ExpirySweeper {
+    /// Sweeps the timeouts of `channel_manager`'s scheduler, which the state manager
+    /// should share through `StateManager::with_scheduler`
+    pub fn new(
+        channel_manager: Arc<ChannelManager>,
+        state_manager: Arc<StateManager>,
+        payment_processor: Arc<PaymentProcessor>,
+        network: Arc<NetworkManager>,
+        crypto: Arc<CryptoManager>,
+        node_address: Address,
+    ) -> Self {
+        Self {
+            scheduler: channel_manager.timeout_scheduler(),
+            channel_manager,
+            state_manager,
+            payment_processor,
+            network,
+            crypto,
+            node_address,
+        }
+    }
+
+    /// HTLCs and locks created after startup are tracked by the state manager and
+    /// channel executor sharing this sweeper's scheduler
+    pub async fn track_htlc(&self, channel_id: H256, htlc_id: H256, timeout: u64) {
+        self.scheduler.track_htlc(channel_id, htlc_id, timeout).await;
+    }
+
+    pub async fn track_lock(&self, channel_id: H256, lock_id: H256, expiration_height: u64) {
+        self.scheduler.track_lock(channel_id, lock_id, expiration_height).await;
+    }
+
+    /// Indexes every pending HTLC and time lock currently known to the node. Entries
+    /// already in the index aren't scheduled twice.
+    pub async fn rebuild_index(&self) {
+        for (channel_id, htlc_id, timeout) in self.state_manager.pending_htlc_timeouts().await {
+            self.track_htlc(channel_id, htlc_id, timeout).await;
+        }
+
+        let channels = match self.channel_manager.list_channels().await {
+            Ok(channels) => channels,
+            Err(e) => {
+                log::error!("Failed to list channels for the expiry index: {:?}", e);
+                return;
+            }
+        };
+        for channel in channels {
+            for lock in channel.state.locks.values() {
+                self.track_lock(channel.channel_id, lock.lock_id, lock.expiration_height).await;
+            }
+        }
+    }
+
+    pub async fn start(self: Arc<Self>) {
+        self.rebuild_index().await;
+        let mut events = self.scheduler.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if let Err(e) = self.handle_timeout(event).await {
+                            log::error!("Failed to sweep {:?}: {:?}", event.kind, e);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // Re-index so nothing that fired while we lagged stays locked
+                        log::warn!("Expiry sweeper lagged by {} events", skipped);
+                        self.rebuild_index().await;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn handle_timeout(&self, event: TimeoutEvent) -> Result<(), SweepError> {
+        match event.kind {
+            TimeoutKind::Htlc { channel_id, htlc_id } => {
+                self.expire_htlc(channel_id, htlc_id).await
+            }
+            TimeoutKind::Lock { channel_id, lock_id } => {
+                self.expire_lock(channel_id, lock_id).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn expire_htlc(&self, channel_id: H256, htlc_id: H256) -> Result<(), SweepError> {
+        let (htlc, update) = match self.state_manager.expire_htlc(channel_id, htlc_id).await {
+            Ok(expired) => expired,
+            // Settled or failed before it timed out
+            Err(StateError::InvalidTransition(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        log::info!("Expired HTLC {} on channel {}", htlc_id, channel_id);
+
+        self.fail_upstream_payment(htlc.hash_lock).await;
+
+        let state = self.state_manager.get_channel_state(channel_id).await?;
+        let bridge_channel_id = self.channel_manager.get_channel(channel_id).await?.bridge_id();
+        let counterparty = if htlc.sender == self.node_address {
+            htlc.receiver
+        } else {
+            htlc.sender
+        };
+        let state = CanonicalState::from(&state);
+        self.send_signed_update(update, bridge_channel_id, None, state, &[counterparty]).await
+    }
+
+    async fn expire_lock(&self, channel_id: H256, lock_id: H256) -> Result<(), SweepError> {
+        // Unlocked before it timed out
+        let Some((channel, lock, previous)) = self.channel_manager.expire_lock(channel_id, lock_id).await? else {
+            return Ok(());
+        };
+
+        log::info!("Expired lock {} on channel {}", lock_id, channel_id);
+
+        let update = StateUpdate {
+            channel_id,
+            sequence: channel.state.sequence_number,
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            previous_state: previous.hash(),
+            new_state: channel.state.state_hash(),
+            signatures: HashMap::new(),
+        };
+        let counterparties: Vec<Address> = channel.participants.iter()
+            .filter(|&&participant| participant != self.node_address)
+            .copied()
+            .collect();
+
+        self.fail_upstream_payment(lock.secret_hash).await;
+        let state = CanonicalState::from(&channel.state);
+        self.send_signed_update(update, channel.bridge_id(), Some(previous), state, &counterparties).await
+    }
+
+    async fn fail_upstream_payment(&self, payment_hash: H256) {
+        // Only payments we are routing or sending are tracked here
+        if self.payment_processor.get_payment_info(payment_hash).await.is_err() {
+            return;
+        }
+
+        if let Err(e) = self.payment_processor
+            .fail_payment(payment_hash, "HTLC expired".into())
+            .await
+        {
+            log::error!("Failed to fail payment {}: {:?}", payment_hash, e);
+        }
+    }
+
+    async fn send_signed_update(
+        &self,
+        mut update: StateUpdate,
+        bridge_channel_id: H256,
+        previous: Option<CanonicalState>,
+        state: CanonicalState,
+        counterparties: &[Address],
+    ) -> Result<(), SweepError> {
+        let signature = self.crypto
+            .sign_channel_state(&self.node_address, bridge_channel_id, previous, state)
+            .await?;
+        update.signatures.insert(self.node_address, signature.clone());
+
+        let encoded = serde_json::to_vec(&update)
+            .map_err(|e| SweepError::Encoding(e.to_string()))?;
+
+        for counterparty in counterparties {
+            self.network.send_message(*counterparty, NetworkMessage::ChannelUpdate {
+                channel_id: update.channel_id,
+                new_state: encoded.clone(),
+                signatures: vec![signature.clone()],
+            }).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use ethers::types::U256;
+    use tokio::sync::{mpsc, oneshot};
+    use crate::channel::actor::ActorConfig;
+    use crate::channel::clock::ManualClock;
+    use crate::channel::operations::ChannelOperation;
+    use crate::channel::parameters::ChannelParameters;
+    use crate::channel::state::ChannelState;
+    use crate::channel::ChannelConfig;
+    use crate::crypto::signature::SignatureVerifier;
+    use crate::events::{EventBus, EventFilter, LightningEvent};
+    use crate::network::peer::{PeerCapability, PeerInfo};
+    use crate::network::transport::MemoryTransport;
+    use crate::network::NetworkConfig;
+    use crate::routing::payment::{PaymentInfo, PaymentStatus};
+    use crate::routing::Route;
+    use crate::state::persistence::StatePersistence;
+
+    /// An active channel between `a` and `b` whose timeouts are swept for `a`,
+    /// with `b`'s inbox collecting the signed refunds
+    struct SweptChannel {
+        channels: Arc<ChannelManager>,
+        channel_id: H256,
+        a: Address,
+        b: Address,
+        clock: Arc<ManualClock>,
+        events: Arc<EventBus>,
+        payments: Arc<PaymentProcessor>,
+        received: mpsc::Receiver<NetworkMessage>,
+    }
+
+    async fn swept_channel() -> SweptChannel {
+        let mut crypto = CryptoManager::new();
+        let a = crypto.generate_keypair().unwrap();
+        let b = crypto.generate_keypair().unwrap();
+        let clock = Arc::new(ManualClock::new(10));
+        let events = Arc::new(EventBus::new(100));
+
+        let channels = Arc::new(ChannelManager::with_events(
+            ChannelConfig {
+                min_capacity: U256::from(1),
+                max_capacity: U256::from(1_000_000),
+                min_dispute_period: 1,
+                max_dispute_period: 1000,
+                max_participants: 2,
+            },
+            ActorConfig::default(),
+            clock.clone(),
+            Arc::new(SignatureVerifier::new(2)),
+            events.clone(),
+        ));
+        let channel_id = channels.create_channel(0, vec![a, b], U256::from(1000), 100, ChannelParameters::default())
+            .await.unwrap()
+            .channel_id;
+        let balances = HashMap::from([(a, U256::from(600)), (b, U256::from(400))]);
+        let opening = CanonicalState::from(&ChannelState::new(balances.clone()).unwrap());
+        let mut signatures = Vec::new();
+        for signer in [a, b] {
+            signatures.push(crypto.sign_channel_state(&signer, channel_id, None, opening.clone()).await.unwrap());
+        }
+        channels.set_initial_state(channel_id, balances, signatures).await.unwrap();
+        channels.activate_channel(channel_id).await.unwrap();
+
+        let transport = Arc::new(MemoryTransport::new());
+        let (inbox, received) = mpsc::channel(16);
+        transport.register(b, inbox).await;
+        let mut network = NetworkManager::new(NetworkConfig {
+            max_peers: 10,
+            heartbeat_interval: 60,
+            connection_timeout: 30,
+            max_retry_attempts: 3,
+            bandwidth_limit: 1000.0,
+        }).with_transport(transport);
+        network.start().await.unwrap();
+        network.connect_peer(PeerInfo {
+            address: b,
+            endpoint: "memory".to_string(),
+            shard_id: 0,
+            version: "1.0.0".to_string(),
+            capabilities: vec![PeerCapability::FullNode],
+            last_seen: 0,
+        }).await.unwrap();
+
+        let payments = Arc::new(PaymentProcessor::new(clock.clone()));
+        let state_manager = StateManager::new(StatePersistence::in_memory(), clock.clone()).await.unwrap()
+            .with_scheduler(channels.timeout_scheduler());
+        channels.start_sweeper(Arc::new(state_manager), payments.clone(), Arc::new(network), Arc::new(crypto), a).await;
+        channels.start_timeouts();
+
+        SweptChannel { channels, channel_id, a, b, clock, events, payments, received }
+    }
+
+    async fn create_lock(swept: &SweptChannel, expiration_height: u64, secret_hash: H256) -> H256 {
+        let (response, result) = oneshot::channel();
+        swept.channels.submit_operation(ChannelOperation::CreateLock {
+            channel_id: swept.channel_id,
+            sender: swept.a,
+            recipient: swept.b,
+            amount: U256::from(100),
+            expiration_height,
+            secret_hash,
+            idempotency_key: None,
+            response,
+        }).await.unwrap();
+        result.await.unwrap().unwrap().lock_id
+    }
+
+    #[tokio::test]
+    async fn test_expired_lock_is_refunded() {
+        let mut swept = swept_channel().await;
+        let (channels, channel_id, a) = (swept.channels.clone(), swept.channel_id, swept.a);
+
+        // A payment through the channel, locked until height 20
+        let secret_hash = H256::random();
+        let lock_id = create_lock(&swept, 20, secret_hash).await;
+
+        let payments = swept.payments.clone();
+        payments.init_payment(PaymentInfo {
+            route: Route {
+                path: vec![channel_id],
+                channels: Vec::new(),
+                total_amount: U256::from(100),
+                total_fees: U256::zero(),
+                total_timelock: 144,
+                swaps: Vec::new(),
+            },
+            payment_hash: secret_hash,
+            payment_secret: H256::random(),
+            amount: U256::from(100),
+            timestamp: 0,
+        }).await.unwrap();
+
+        let mut updates = swept.events.subscribe(EventFilter::all().channel(channel_id));
+        swept.clock.set_height(20);
+
+        let message = tokio::time::timeout(Duration::from_secs(5), swept.received.recv()).await.unwrap().unwrap();
+        assert!(matches!(message, NetworkMessage::ChannelUpdate { channel_id: id, .. } if id == channel_id));
+
+        let channel = channels.get_channel(channel_id).await.unwrap();
+        assert!(channel.state.get_lock(&lock_id).is_none());
+        assert_eq!(channel.state.balances[&a], U256::from(600));
+        assert_eq!(payments.get_payment_status(secret_hash).await.unwrap(), PaymentStatus::Failed);
+
+        let envelope = tokio::time::timeout(Duration::from_secs(5), updates.recv()).await.unwrap().unwrap();
+        assert!(matches!(
+            envelope.event,
+            LightningEvent::StateUpdated { sequence, state_hash, .. }
+                if sequence == channel.nonce && state_hash == channel.state.state_hash()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_locks_in_one_channel_swept_separately() {
+        let mut swept = swept_channel().await;
+
+        let first = create_lock(&swept, 20, H256::random()).await;
+        let second = create_lock(&swept, 25, H256::random()).await;
+        assert_ne!(first, second);
+
+        swept.clock.set_height(25);
+        for _ in 0..2 {
+            let message = tokio::time::timeout(Duration::from_secs(5), swept.received.recv()).await.unwrap().unwrap();
+            assert!(matches!(message, NetworkMessage::ChannelUpdate { .. }));
+        }
+
+        let channel = swept.channels.get_channel(swept.channel_id).await.unwrap();
+        assert!(channel.state.get_lock(&first).is_none());
+        assert!(channel.state.get_lock(&second).is_none());
+        assert_eq!(channel.state.balances[&swept.a], U256::from(600));
+    }
+}
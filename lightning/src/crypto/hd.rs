@@ -0,0 +1,352 @@
+use std::fmt;
+use std::str::FromStr;
+use ethers::signers::coins_bip39::{English, Mnemonic};
+use ethers::types::{Address, H256};
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::elliptic_curve::PrimeField;
+use k256::{NonZeroScalar, Scalar, SecretKey};
+use sha2::Sha512;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::signature::address_of;
+
+#[derive(Error, Debug)]
+pub enum HdError {
+    #[error("Invalid mnemonic: {0}")]
+    InvalidMnemonic(String),
+    #[error("Invalid derivation path: {0}")]
+    InvalidPath(String),
+    #[error("Index {0} derives an invalid key")]
+    InvalidChild(u32),
+}
+
+/// Added to an index to make it hardened
+pub const HARDENED: u32 = 0x8000_0000;
+/// BIP-44 purpose
+const PURPOSE: u32 = 44;
+/// SLIP-44 coin type of Ethereum
+const COIN_TYPE: u32 = 60;
+
+/// A BIP-32 path such as `m/44'/60'/0'/0/0`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    pub fn new(indices: Vec<u32>) -> Self {
+        Self(indices)
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = HdError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let mut components = path.split('/');
+        if components.next() != Some("m") {
+            return Err(HdError::InvalidPath(path.to_string()));
+        }
+
+        components
+            .map(|component| {
+                let (index, hardened) = match component.strip_suffix('\'').or_else(|| component.strip_suffix('h')) {
+                    Some(index) => (index, true),
+                    None => (component, false),
+                };
+                let index: u32 = index.parse().map_err(|_| HdError::InvalidPath(path.to_string()))?;
+                if index >= HARDENED {
+                    return Err(HdError::InvalidPath(path.to_string()));
+                }
+                Ok(if hardened { index + HARDENED } else { index })
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            if *index >= HARDENED {
+                write!(f, "/{}'", index - HARDENED)?;
+            } else {
+                write!(f, "/{}", index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a derived key is used for. Each purpose is its own BIP-44 account, so
+/// keys of one purpose never reveal anything about another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyPurpose {
+    /// `m/44'/60'/0'/0/0`, the address a wallet shows for the same mnemonic
+    NodeIdentity,
+    /// `m/44'/60'/1'/<channel>'/0`
+    ChannelFunding(u32),
+    /// `m/44'/60'/2'/<channel>'/0`
+    RevocationBase(u32),
+    /// `m/44'/60'/3'/0'/<payment>'`
+    PaymentSecret(u32),
+    /// `m/44'/60'/4'/0'/0'`, encrypts static channel backups
+    StaticBackup,
+}
+
+impl KeyPurpose {
+    /// Path of the key, rejecting channel and payment indices that are already hardened
+    pub fn path(&self) -> Result<DerivationPath, HdError> {
+        if let KeyPurpose::ChannelFunding(index) | KeyPurpose::RevocationBase(index) | KeyPurpose::PaymentSecret(index) = *self {
+            if index >= HARDENED {
+                return Err(HdError::InvalidChild(index));
+            }
+        }
+
+        let account = |account: u32, change: u32, index: u32| DerivationPath(vec![
+            PURPOSE + HARDENED,
+            COIN_TYPE + HARDENED,
+            account + HARDENED,
+            change,
+            index,
+        ]);
+
+        Ok(match *self {
+            KeyPurpose::NodeIdentity => account(0, 0, 0),
+            KeyPurpose::ChannelFunding(channel) => account(1, channel + HARDENED, 0),
+            KeyPurpose::RevocationBase(channel) => account(2, channel + HARDENED, 0),
+            KeyPurpose::PaymentSecret(payment) => account(3, HARDENED, payment + HARDENED),
+            KeyPurpose::StaticBackup => account(4, HARDENED, HARDENED),
+        })
+    }
+}
+
+/// A BIP-32 extended private key
+#[derive(Clone)]
+pub struct ExtendedKey {
+    secret: SecretKey,
+    chain_code: Zeroizing<[u8; 32]>,
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Result<Self, HdError> {
+        let (key, chain_code) = hmac_sha512(b"Bitcoin seed", &[seed]);
+        let secret = SecretKey::from_slice(&key[..]).map_err(|_| HdError::InvalidChild(0))?;
+
+        Ok(Self { secret, chain_code })
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, HdError> {
+        let index_bytes = index.to_be_bytes();
+        let (tweak, chain_code) = if index >= HARDENED {
+            let secret = Zeroizing::new(self.secret.to_bytes());
+            hmac_sha512(&self.chain_code[..], &[&[0], &secret[..], &index_bytes])
+        } else {
+            let public = self.secret.public_key().to_encoded_point(true);
+            hmac_sha512(&self.chain_code[..], &[public.as_bytes(), &index_bytes])
+        };
+
+        // The tweak has to be below the curve order and the child key nonzero
+        let tweak = Option::<Scalar>::from(Scalar::from_repr((*tweak).into()))
+            .ok_or(HdError::InvalidChild(index))?;
+        let child = Option::<NonZeroScalar>::from(NonZeroScalar::new(tweak + *self.secret.to_nonzero_scalar()))
+            .ok_or(HdError::InvalidChild(index))?;
+
+        Ok(Self { secret: SecretKey::from(child), chain_code })
+    }
+
+    pub fn derive(&self, path: &DerivationPath) -> Result<Self, HdError> {
+        path.indices().iter().try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    pub fn secret_key(&self) -> &SecretKey {
+        &self.secret
+    }
+
+    pub fn address(&self) -> Address {
+        address_of(&self.secret.public_key().into())
+    }
+}
+
+/// Keys of one channel
+#[derive(Clone)]
+pub struct ChannelKeys {
+    pub index: u32,
+    pub funding: ExtendedKey,
+    pub revocation_base: ExtendedKey,
+}
+
+/// Every key of a node, derived from one seed. Recovering from the mnemonic
+/// regenerates all of them.
+pub struct HdKeychain {
+    master: ExtendedKey,
+}
+
+impl HdKeychain {
+    pub fn from_seed(seed: &[u8]) -> Result<Self, HdError> {
+        Ok(Self { master: ExtendedKey::master(seed)? })
+    }
+
+    /// Keychain of a BIP-39 mnemonic, with an optional passphrase
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Self, HdError> {
+        let mnemonic = Mnemonic::<English>::new_from_phrase(phrase)
+            .map_err(|e| HdError::InvalidMnemonic(e.to_string()))?;
+        let seed = Zeroizing::new(mnemonic.to_seed(Some(passphrase))
+            .map_err(|e| HdError::InvalidMnemonic(e.to_string()))?);
+
+        Self::from_seed(&seed[..])
+    }
+
+    /// A new 24-word mnemonic to back up a node with
+    pub fn generate_mnemonic() -> Result<String, HdError> {
+        Mnemonic::<English>::new_with_count(&mut rand::thread_rng(), 24)
+            .map(|mnemonic| mnemonic.to_phrase())
+            .map_err(|e| HdError::InvalidMnemonic(e.to_string()))
+    }
+
+    pub fn derive(&self, purpose: KeyPurpose) -> Result<ExtendedKey, HdError> {
+        self.master.derive(&purpose.path()?)
+    }
+
+    pub fn node_key(&self) -> Result<ExtendedKey, HdError> {
+        self.derive(KeyPurpose::NodeIdentity)
+    }
+
+    pub fn channel_keys(&self, index: u32) -> Result<ChannelKeys, HdError> {
+        Ok(ChannelKeys {
+            index,
+            funding: self.derive(KeyPurpose::ChannelFunding(index))?,
+            revocation_base: self.derive(KeyPurpose::RevocationBase(index))?,
+        })
+    }
+
+    /// Preimage of the payment with this index; its hash lock is `keccak256(preimage)`
+    pub fn payment_preimage(&self, index: u32) -> Result<H256, HdError> {
+        let key = self.derive(KeyPurpose::PaymentSecret(index))?;
+        Ok(H256::from_slice(&key.secret_key().to_bytes()))
+    }
+
+    /// Key static channel backups are encrypted with. Only the seed is needed to
+    /// decrypt a backup, however the node's keys were rotated since.
+    pub fn backup_key(&self) -> Result<Zeroizing<[u8; 32]>, HdError> {
+        let key = self.derive(KeyPurpose::StaticBackup)?;
+        Ok(Zeroizing::new(key.secret_key().to_bytes().into()))
+    }
+
+    /// Keys of the channels in use, found by walking channel indices until `gap_limit`
+    /// unused ones in a row, as BIP-44 wallets scan for accounts
+    pub fn recover_channels(
+        &self,
+        gap_limit: u32,
+        is_used: impl Fn(Address) -> bool,
+    ) -> Result<Vec<ChannelKeys>, HdError> {
+        let mut channels = Vec::new();
+        let mut unused = 0;
+        let mut index = 0;
+
+        while unused < gap_limit && index < HARDENED {
+            let keys = self.channel_keys(index)?;
+            if is_used(keys.funding.address()) {
+                channels.push(keys);
+                unused = 0;
+            } else {
+                unused += 1;
+            }
+            index += 1;
+        }
+
+        Ok(channels)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in data {
+        mac.update(part);
+    }
+    let output = Zeroizing::new(<[u8; 64]>::from(mac.finalize().into_bytes()));
+
+    let mut left = Zeroizing::new([0u8; 32]);
+    let mut right = Zeroizing::new([0u8; 32]);
+    left.copy_from_slice(&output[..32]);
+    right.copy_from_slice(&output[32..]);
+    (left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_bip32_test_vector() {
+        // Test vector 1 from BIP-32
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::master(&seed).unwrap();
+        assert_eq!(
+            hex::encode(master.secret_key().to_bytes()),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35",
+        );
+        assert_eq!(
+            hex::encode(&master.chain_code[..]),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508",
+        );
+
+        let path: DerivationPath = "m/0'/1/2'/2/1000000000".parse().unwrap();
+        assert_eq!(path.to_string(), "m/0'/1/2'/2/1000000000");
+        assert_eq!(
+            hex::encode(master.derive(&path).unwrap().secret_key().to_bytes()),
+            "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8",
+        );
+        assert!("0'/1".parse::<DerivationPath>().is_err());
+    }
+
+    #[test]
+    fn test_hardened_indices_rejected() {
+        assert_eq!(KeyPurpose::ChannelFunding(7).path().unwrap().to_string(), "m/44'/60'/1'/7'/0");
+        assert_eq!(KeyPurpose::StaticBackup.path().unwrap().to_string(), "m/44'/60'/4'/0'/0'");
+        assert!(matches!(KeyPurpose::ChannelFunding(HARDENED).path(), Err(HdError::InvalidChild(HARDENED))));
+        assert!(matches!(KeyPurpose::PaymentSecret(u32::MAX).path(), Err(HdError::InvalidChild(u32::MAX))));
+
+        let keychain = HdKeychain::from_seed(&[7u8; 32]).unwrap();
+        assert!(keychain.channel_keys(HARDENED).is_err());
+    }
+
+    #[test]
+    fn test_mnemonic_recovers_every_key() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let keychain = HdKeychain::from_mnemonic(phrase, "").unwrap();
+
+        // The first account wallets derive for this mnemonic
+        let expected: Address = "0x9858EfFD232B4033E47d90003D41EC34EcaEda94".parse().unwrap();
+        assert_eq!(keychain.node_key().unwrap().address(), expected);
+
+        let channel = keychain.channel_keys(3).unwrap();
+        let keys: HashSet<_> = [
+            keychain.node_key().unwrap().address(),
+            channel.funding.address(),
+            channel.revocation_base.address(),
+            keychain.channel_keys(4).unwrap().funding.address(),
+        ].into_iter().collect();
+        assert_eq!(keys.len(), 4);
+        assert_ne!(keychain.payment_preimage(0).unwrap(), keychain.payment_preimage(1).unwrap());
+
+        let used: HashSet<_> = [0, 2, 5].iter()
+            .map(|index| keychain.channel_keys(*index).unwrap().funding.address())
+            .collect();
+        let recovered = HdKeychain::from_mnemonic(phrase, "").unwrap()
+            .recover_channels(3, |address| used.contains(&address))
+            .unwrap();
+        assert_eq!(recovered.iter().map(|keys| keys.index).collect::<Vec<_>>(), vec![0, 2, 5]);
+
+        // A passphrase makes it another wallet
+        let other = HdKeychain::from_mnemonic(phrase, "passphrase").unwrap();
+        assert_ne!(other.node_key().unwrap().address(), expected);
+        assert!(HdKeychain::from_mnemonic("abandon about", "").is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use aes::cipher::{KeyIvInit, StreamCipher};
+use ethers::types::Address;
+use hmac::Hmac;
+use k256::SecretKey;
+use rand::{Rng, RngCore};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::signature::address_of;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+#[derive(Error, Debug)]
+pub enum KeystoreError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid keystore file: {0}")]
+    InvalidFile(String),
+    #[error("Unsupported {0}")]
+    Unsupported(String),
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("Keystore file is for {expected:?}, the key inside is {actual:?}")]
+    AddressMismatch { expected: Address, actual: Address },
+    #[error("No key for {0:?} in the keystore")]
+    NotFound(Address),
+}
+
+const HISTORY_FILE: &str = "history.json";
+const DERIVED_KEY_LENGTH: usize = 32;
+
+/// How the encryption key is derived from the password
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KdfConfig {
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    Pbkdf2 { rounds: u32 },
+}
+
+impl Default for KdfConfig {
+    /// The parameters geth writes keys with
+    fn default() -> Self {
+        KdfConfig::Scrypt { log_n: 18, r: 8, p: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KdfParams {
+    Scrypt { dklen: usize, n: u64, r: u32, p: u32, salt: String },
+    Pbkdf2 { c: u32, dklen: usize, prf: String, salt: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CipherParams {
+    pub iv: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CryptoSection {
+    pub cipher: String,
+    pub cipherparams: CipherParams,
+    pub ciphertext: String,
+    pub kdf: String,
+    pub kdfparams: KdfParams,
+    pub mac: String,
+}
+
+/// A private key in the Web3 Secret Storage (v3) format geth, MetaMask and
+/// ethers read and write
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedKey {
+    pub version: u32,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(alias = "Crypto")]
+    pub crypto: CryptoSection,
+}
+
+impl EncryptedKey {
+    pub fn encrypt(secret: &SecretKey, password: &str, kdf: KdfConfig) -> Result<Self, KeystoreError> {
+        let mut rng = rand::thread_rng();
+        let salt: [u8; 32] = rng.gen();
+        let iv: [u8; 16] = rng.gen();
+
+        let kdfparams = match kdf {
+            KdfConfig::Scrypt { log_n, r, p } => KdfParams::Scrypt {
+                dklen: DERIVED_KEY_LENGTH,
+                n: 1 << log_n,
+                r,
+                p,
+                salt: hex::encode(salt),
+            },
+            KdfConfig::Pbkdf2 { rounds } => KdfParams::Pbkdf2 {
+                c: rounds,
+                dklen: DERIVED_KEY_LENGTH,
+                prf: "hmac-sha256".to_string(),
+                salt: hex::encode(salt),
+            },
+        };
+        let derived = derive_key(password, &kdfparams)?;
+
+        let mut ciphertext = secret.to_bytes().to_vec();
+        Aes128Ctr::new(derived[..16].into(), &iv.into()).apply_keystream(&mut ciphertext);
+        let mac = mac(&derived, &ciphertext);
+
+        let address = address_of(&secret.public_key().into());
+        Ok(Self {
+            version: 3,
+            id: random_uuid(&mut rng),
+            address: Some(hex::encode(address.as_bytes())),
+            crypto: CryptoSection {
+                cipher: "aes-128-ctr".to_string(),
+                cipherparams: CipherParams { iv: hex::encode(iv) },
+                ciphertext: hex::encode(ciphertext),
+                kdf: match kdf {
+                    KdfConfig::Scrypt { .. } => "scrypt",
+                    KdfConfig::Pbkdf2 { .. } => "pbkdf2",
+                }.to_string(),
+                kdfparams,
+                mac: hex::encode(mac),
+            },
+        })
+    }
+
+    /// Decrypts the key, checking the MAC before anything is decrypted
+    pub fn decrypt(&self, password: &str) -> Result<SecretKey, KeystoreError> {
+        if self.version != 3 {
+            return Err(KeystoreError::Unsupported(format!("keystore version {}", self.version)));
+        }
+        if self.crypto.cipher != "aes-128-ctr" {
+            return Err(KeystoreError::Unsupported(format!("cipher {}", self.crypto.cipher)));
+        }
+
+        let derived = derive_key(password, &self.crypto.kdfparams)?;
+        let ciphertext = decode_hex(&self.crypto.ciphertext)?;
+        // Compared in constant time so timing doesn't leak how much of a guess matched
+        if !bool::from(mac(&derived, &ciphertext).as_slice().ct_eq(&decode_hex(&self.crypto.mac)?)) {
+            return Err(KeystoreError::WrongPassword);
+        }
+
+        let iv: [u8; 16] = decode_hex(&self.crypto.cipherparams.iv)?
+            .try_into()
+            .map_err(|_| KeystoreError::InvalidFile("IV is not 16 bytes".into()))?;
+        let mut plaintext = Zeroizing::new(ciphertext);
+        Aes128Ctr::new(derived[..16].into(), &iv.into()).apply_keystream(&mut plaintext);
+
+        let secret = SecretKey::from_slice(&plaintext)
+            .map_err(|e| KeystoreError::InvalidFile(e.to_string()))?;
+        let actual = address_of(&secret.public_key().into());
+        match self.address()? {
+            Some(expected) if expected != actual => Err(KeystoreError::AddressMismatch { expected, actual }),
+            _ => Ok(secret),
+        }
+    }
+
+    /// The address the file claims to hold a key for
+    pub fn address(&self) -> Result<Option<Address>, KeystoreError> {
+        self.address.as_ref()
+            .map(|address| {
+                let bytes = decode_hex(address)?;
+                if bytes.len() != 20 {
+                    return Err(KeystoreError::InvalidFile(format!("Invalid address {}", address)));
+                }
+                Ok(Address::from_slice(&bytes))
+            })
+            .transpose()
+    }
+
+    pub fn to_json(&self) -> Result<String, KeystoreError> {
+        serde_json::to_string_pretty(self).map_err(|e| KeystoreError::InvalidFile(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, KeystoreError> {
+        serde_json::from_str(json).map_err(|e| KeystoreError::InvalidFile(e.to_string()))
+    }
+}
+
+/// When a key was in use. Retired keys stay in the keystore so signatures made
+/// with them can still be attributed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub address: Address,
+    pub created_at: u64,
+    pub retired_at: Option<u64>,
+    /// The key that took over when this one was rotated out
+    pub replaced_by: Option<Address>,
+}
+
+/// Encrypted keys, in a directory of v3 files or only in memory
+pub struct Keystore {
+    dir: Option<PathBuf>,
+    kdf: KdfConfig,
+    keys: HashMap<Address, EncryptedKey>,
+    records: HashMap<Address, KeyRecord>,
+}
+
+impl Keystore {
+    /// Opens the keystore in `dir`, loading every key file in it
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, KeystoreError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut keystore = Self::in_memory();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json")
+                || path.file_name().is_some_and(|name| name == HISTORY_FILE)
+            {
+                continue;
+            }
+
+            let key = EncryptedKey::from_json(&std::fs::read_to_string(&path)?)?;
+            let address = key.address()?
+                .ok_or_else(|| KeystoreError::InvalidFile(format!("{:?} has no address", path)))?;
+            keystore.keys.insert(address, key);
+        }
+
+        let history_path = dir.join(HISTORY_FILE);
+        if history_path.exists() {
+            let records: Vec<KeyRecord> = serde_json::from_slice(&std::fs::read(&history_path)?)
+                .map_err(|e| KeystoreError::InvalidFile(e.to_string()))?;
+            keystore.records = records.into_iter().map(|record| (record.address, record)).collect();
+        }
+        for address in keystore.keys.keys() {
+            keystore.records.entry(*address).or_insert_with(|| KeyRecord {
+                address: *address,
+                created_at: 0,
+                retired_at: None,
+                replaced_by: None,
+            });
+        }
+
+        keystore.dir = Some(dir);
+        Ok(keystore)
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            dir: None,
+            kdf: KdfConfig::default(),
+            keys: HashMap::new(),
+            records: HashMap::new(),
+        }
+    }
+
+    pub fn with_kdf(mut self, kdf: KdfConfig) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Encrypts the key with `password` and stores it
+    pub fn store(&mut self, secret: &SecretKey, password: &str) -> Result<Address, KeystoreError> {
+        let key = EncryptedKey::encrypt(secret, password, self.kdf)?;
+        let address = address_of(&secret.public_key().into());
+        self.insert(address, key)?;
+        Ok(address)
+    }
+
+    /// Adds a v3 keystore file, checking it decrypts with `password`
+    pub fn import(&mut self, json: &str, password: &str) -> Result<(Address, SecretKey), KeystoreError> {
+        let key = EncryptedKey::from_json(json)?;
+        let secret = key.decrypt(password)?;
+        let address = address_of(&secret.public_key().into());
+        self.insert(address, key)?;
+        Ok((address, secret))
+    }
+
+    /// The v3 keystore file of a key, still encrypted with the password it was stored with
+    pub fn export(&self, address: &Address) -> Result<String, KeystoreError> {
+        self.keys.get(address).ok_or(KeystoreError::NotFound(*address))?.to_json()
+    }
+
+    pub fn decrypt(&self, address: &Address, password: &str) -> Result<SecretKey, KeystoreError> {
+        self.keys.get(address).ok_or(KeystoreError::NotFound(*address))?.decrypt(password)
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.keys.contains_key(address)
+    }
+
+    /// Addresses of keys that haven't been rotated out
+    pub fn active_keys(&self) -> Vec<Address> {
+        self.records.values()
+            .filter(|record| record.retired_at.is_none())
+            .map(|record| record.address)
+            .collect()
+    }
+
+    /// Records `old` as replaced by `new`. The old key file is kept.
+    pub fn retire(&mut self, old: Address, new: Address) -> Result<(), KeystoreError> {
+        let now = now();
+        let record = self.records.entry(old).or_insert_with(|| KeyRecord {
+            address: old,
+            created_at: 0,
+            retired_at: None,
+            replaced_by: None,
+        });
+        record.retired_at = Some(now);
+        record.replaced_by = Some(new);
+
+        self.records.entry(new).or_insert_with(|| KeyRecord {
+            address: new,
+            created_at: now,
+            retired_at: None,
+            replaced_by: None,
+        });
+        self.write_history()
+    }
+
+    /// The keys `address` replaced, oldest first, followed by `address` itself
+    pub fn lineage(&self, address: &Address) -> Vec<KeyRecord> {
+        let mut lineage = Vec::new();
+        let mut current = self.records.get(address).cloned();
+
+        while let Some(record) = current {
+            current = self.records.values()
+                .find(|previous| previous.replaced_by == Some(record.address))
+                .filter(|previous| lineage.iter().all(|seen: &KeyRecord| seen.address != previous.address))
+                .cloned();
+            lineage.push(record);
+        }
+
+        lineage.reverse();
+        lineage
+    }
+
+    fn insert(&mut self, address: Address, key: EncryptedKey) -> Result<(), KeystoreError> {
+        if let Some(dir) = &self.dir {
+            write_atomic(&dir.join(format!("{}.json", hex::encode(address.as_bytes()))), key.to_json()?.as_bytes())?;
+        }
+
+        self.keys.insert(address, key);
+        self.records.entry(address).or_insert_with(|| KeyRecord {
+            address,
+            created_at: now(),
+            retired_at: None,
+            replaced_by: None,
+        });
+        self.write_history()
+    }
+
+    fn write_history(&self) -> Result<(), KeystoreError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+
+        let mut records: Vec<_> = self.records.values().collect();
+        records.sort_by_key(|record| (record.created_at, record.address));
+        let json = serde_json::to_vec_pretty(&records)
+            .map_err(|e| KeystoreError::InvalidFile(e.to_string()))?;
+        write_atomic(&dir.join(HISTORY_FILE), &json)
+    }
+}
+
+fn derive_key(password: &str, params: &KdfParams) -> Result<Zeroizing<[u8; DERIVED_KEY_LENGTH]>, KeystoreError> {
+    let mut derived = Zeroizing::new([0u8; DERIVED_KEY_LENGTH]);
+
+    match params {
+        KdfParams::Scrypt { dklen, n, r, p, salt } => {
+            if *dklen != DERIVED_KEY_LENGTH || !n.is_power_of_two() {
+                return Err(KeystoreError::Unsupported(format!("scrypt parameters n={} dklen={}", n, dklen)));
+            }
+            let params = scrypt::Params::new(n.trailing_zeros() as u8, *r, *p)
+                .map_err(|e| KeystoreError::Unsupported(format!("scrypt parameters: {}", e)))?;
+            scrypt::scrypt(password.as_bytes(), &decode_hex(salt)?, &params, derived.as_mut())
+                .map_err(|e| KeystoreError::Unsupported(format!("scrypt output: {}", e)))?;
+        }
+        KdfParams::Pbkdf2 { c, dklen, prf, salt } => {
+            if *dklen != DERIVED_KEY_LENGTH || prf != "hmac-sha256" {
+                return Err(KeystoreError::Unsupported(format!("pbkdf2 with {} and dklen={}", prf, dklen)));
+            }
+            pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), &decode_hex(salt)?, *c, derived.as_mut());
+        }
+    }
+
+    Ok(derived)
+}
+
+// keccak256(derived[16..32] || ciphertext)
+fn mac(derived: &[u8; DERIVED_KEY_LENGTH], ciphertext: &[u8]) -> [u8; 32] {
+    use sha3::{Digest, Keccak256};
+    let mut hasher = Keccak256::new();
+    hasher.update(&derived[16..]);
+    hasher.update(ciphertext);
+    hasher.finalize().into()
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, KeystoreError> {
+    hex::decode(value.trim_start_matches("0x")).map_err(|e| KeystoreError::InvalidFile(e.to_string()))
+}
+
+fn random_uuid(rng: &mut impl RngCore) -> String {
+    let mut bytes = [0u8; 16];
+    rng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex::encode(bytes);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), KeystoreError> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST_KDF: KdfConfig = KdfConfig::Scrypt { log_n: 10, r: 8, p: 1 };
+
+    #[test]
+    fn test_reads_the_web3_secret_storage_test_vector() {
+        // The PBKDF2 test vector from the Web3 Secret Storage definition
+        let json = r#"{
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+                "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+                "kdf": "pbkdf2",
+                "kdfparams": {
+                    "c": 262144,
+                    "dklen": 32,
+                    "prf": "hmac-sha256",
+                    "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+                },
+                "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+            },
+            "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+            "version": 3
+        }"#;
+
+        let key = EncryptedKey::from_json(json).unwrap();
+        let secret = key.decrypt("testpassword").unwrap();
+        assert_eq!(
+            hex::encode(secret.to_bytes()),
+            "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d",
+        );
+        assert!(matches!(key.decrypt("wrong"), Err(KeystoreError::WrongPassword)));
+    }
+
+    #[test]
+    fn test_keys_and_history_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("flashchain-keystore-{}", random_uuid(&mut rand::thread_rng())));
+        let old = SecretKey::random(&mut rand::thread_rng());
+        let new = SecretKey::random(&mut rand::thread_rng());
+
+        let mut keystore = Keystore::open(&dir).unwrap().with_kdf(FAST_KDF);
+        let old_address = keystore.store(&old, "old password").unwrap();
+        let new_address = keystore.store(&new, "new password").unwrap();
+        keystore.retire(old_address, new_address).unwrap();
+        let exported = keystore.export(&new_address).unwrap();
+
+        let reopened = Keystore::open(&dir).unwrap();
+        assert_eq!(reopened.decrypt(&old_address, "old password").unwrap(), old);
+        assert_eq!(reopened.active_keys(), vec![new_address]);
+        let lineage: Vec<_> = reopened.lineage(&new_address).iter().map(|record| record.address).collect();
+        assert_eq!(lineage, vec![old_address, new_address]);
+
+        let mut other = Keystore::in_memory();
+        assert_eq!(other.import(&exported, "new password").unwrap(), (new_address, new));
+        assert!(matches!(other.import(&exported, "old password"), Err(KeystoreError::WrongPassword)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,15 +1,30 @@
-use ethers::types::{Address, H256, U256};
+use ethers::types::transaction::eip712::EIP712Domain;
+use ethers::types::{Address, H256};
 use k256::{
-    ecdsa::{SigningKey, VerifyingKey, Signature, signature::Signer, signature::Verifier},
-    SecretKey,
+    ecdsa::{SigningKey, VerifyingKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+    ProjectivePoint, SecretKey,
 };
 use sha3::{Keccak256, Digest};
 use thiserror::Error;
 use rand::rngs::OsRng;
 use std::collections::HashMap;
-
-pub mod signatures;
-pub mod merkle;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub mod signature;
+pub mod typed_data;
+pub mod keystore;
+pub mod hd;
+pub mod signer;
+pub mod remote_signer;
+
+use hd::{ChannelKeys, ExtendedKey, HdError, HdKeychain};
+use keystore::{KeyRecord, Keystore, KeystoreError};
+use signer::{SignRequest, Signer};
+use typed_data::{TypedMessage, TypedStruct};
+use flashchain_common::encoding::CanonicalState;
 
 #[derive(Error, Debug)]
 pub enum CryptoError {
@@ -27,60 +42,258 @@ pub enum CryptoError {
     KeyGenerationError(String),
     #[error("Invalid state encoding: {0}")]
     InvalidStateEncoding(String),
+    #[error("Key {0:?} is locked")]
+    Locked(Address),
+    #[error("Keystore error: {0}")]
+    Keystore(#[from] KeystoreError),
+    #[error("Key derivation error: {0}")]
+    Derivation(#[from] HdError),
+}
+
+struct UnlockedKey {
+    // Zeroized when dropped, so locking a key wipes it from memory
+    secret: SecretKey,
+    locks_at: Option<Instant>,
 }
 
 pub struct CryptoManager {
-    // Secure key storage
-    keys: HashMap<Address, SecretKey>,
-    // Cached verifying keys
+    // Unlocked keys
+    keys: Mutex<HashMap<Address, UnlockedKey>>,
+    // Cached verifying keys, rotated out ones included
     verifying_keys: HashMap<Address, VerifyingKey>,
+    keystore: Keystore,
+    auto_lock: Option<Duration>,
+    // Holds the keys instead of this manager when set
+    signer: Option<Arc<dyn Signer>>,
+    // Seed every node, channel and payment key is derived from
+    keychain: Option<HdKeychain>,
+    node_address: Option<Address>,
+    next_channel_index: AtomicU32,
+    next_payment_index: AtomicU32,
+}
+
+impl Default for CryptoManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CryptoManager {
     pub fn new() -> Self {
         Self {
-            keys: HashMap::new(),
+            keys: Mutex::new(HashMap::new()),
             verifying_keys: HashMap::new(),
+            keystore: Keystore::in_memory(),
+            auto_lock: None,
+            signer: None,
+            keychain: None,
+            node_address: None,
+            next_channel_index: AtomicU32::new(0),
+            next_payment_index: AtomicU32::new(0),
         }
     }
 
-    /// Generates a new key pair and returns the associated address
+    pub fn with_keystore(mut self, keystore: Keystore) -> Self {
+        self.keystore = keystore;
+        self
+    }
+
+    /// Locks keys unlocked from the keystore again after `timeout`
+    pub fn with_auto_lock(mut self, timeout: Duration) -> Self {
+        self.auto_lock = Some(timeout);
+        self
+    }
+
+    /// Signs requests with `signer`, such as a `RemoteSigner`, instead of local keys
+    pub fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Derives the node's keys from `keychain`, unlocking the node identity key
+    /// that `node_address` returns. Called once at node startup.
+    pub fn with_keychain(mut self, keychain: HdKeychain) -> Result<Self, CryptoError> {
+        let node_address = self.add_derived_key(&keychain.node_key()?);
+        self.node_address = Some(node_address);
+        self.keychain = Some(keychain);
+        Ok(self)
+    }
+
+    /// Continues deriving channel keys and payment preimages at these indices, so a
+    /// restarted node doesn't reuse the ones it already handed out
+    pub fn with_key_indices(self, next_channel: u32, next_payment: u32) -> Self {
+        self.next_channel_index.store(next_channel, Ordering::SeqCst);
+        self.next_payment_index.store(next_payment, Ordering::SeqCst);
+        self
+    }
+
+    /// Address of the keychain's node identity key
+    pub fn node_address(&self) -> Option<Address> {
+        self.node_address
+    }
+
+    pub fn has_keychain(&self) -> bool {
+        self.keychain.is_some()
+    }
+
+    /// Next channel and payment indices, to persist across restarts
+    pub fn key_indices(&self) -> (u32, u32) {
+        (self.next_channel_index.load(Ordering::SeqCst), self.next_payment_index.load(Ordering::SeqCst))
+    }
+
+    /// Derives the keys of a new channel and unlocks them
+    pub fn next_channel_keys(&self) -> Result<ChannelKeys, CryptoError> {
+        let keychain = self.keychain()?;
+        let keys = keychain.channel_keys(self.next_channel_index.fetch_add(1, Ordering::SeqCst))?;
+        self.unlock_secret(keys.funding.secret_key().clone(), None);
+        self.unlock_secret(keys.revocation_base.secret_key().clone(), None);
+
+        Ok(keys)
+    }
+
+    /// Preimage of a new payment to this node, with the index that regenerates it
+    pub fn next_payment_preimage(&self) -> Result<(u32, H256), CryptoError> {
+        let keychain = self.keychain()?;
+        let index = self.next_payment_index.fetch_add(1, Ordering::SeqCst);
+        Ok((index, keychain.payment_preimage(index)?))
+    }
+
+    /// Generates a new key pair and returns the associated address. The key only
+    /// lives in memory; use `create_key` for one that is kept in the keystore.
     pub fn generate_keypair(&mut self) -> Result<Address, CryptoError> {
         let secret_key = SigningKey::random(&mut OsRng);
-        let public_key = secret_key.verifying_key();
-        let address = self.public_key_to_address(&public_key)?;
-
-        self.keys.insert(address, secret_key.into());
-        self.verifying_keys.insert(address, public_key);
+        let address = self.add_key(secret_key.into(), None);
 
         Ok(address)
     }
 
-    /// Signs a message with the key associated with the given address
-    pub fn sign_message(&self, address: &Address, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let secret_key = self.keys.get(address)
-            .ok_or_else(|| CryptoError::InvalidKey("Key not found".into()))?;
-        
-        let signing_key = SigningKey::from(secret_key);
-        let signature: Signature = signing_key.sign(message);
-        
+    /// Generates a key, stores it in the keystore encrypted with `password` and unlocks it
+    pub fn create_key(&mut self, password: &str) -> Result<Address, CryptoError> {
+        let secret_key = SecretKey::random(&mut OsRng);
+        self.keystore.store(&secret_key, password)?;
+
+        Ok(self.add_key(secret_key, self.auto_lock))
+    }
+
+    /// Adds a Web3 Secret Storage (v3) key file to the keystore and unlocks the key
+    pub fn import_key(&mut self, json: &str, password: &str) -> Result<Address, CryptoError> {
+        let (_, secret_key) = self.keystore.import(json, password)?;
+        Ok(self.add_key(secret_key, self.auto_lock))
+    }
+
+    /// Unlocks a key derived from an HD keychain. Derived keys aren't stored; the
+    /// mnemonic regenerates them.
+    pub fn add_derived_key(&mut self, key: &ExtendedKey) -> Address {
+        self.add_key(key.secret_key().clone(), None)
+    }
+
+    /// The v3 key file of a key in the keystore
+    pub fn export_key(&self, address: &Address) -> Result<String, CryptoError> {
+        Ok(self.keystore.export(address)?)
+    }
+
+    /// Decrypts a key from the keystore. It locks again after the auto-lock timeout.
+    pub fn unlock(&self, address: &Address, password: &str) -> Result<(), CryptoError> {
+        let secret = self.keystore.decrypt(address, password)?;
+        let locks_at = self.auto_lock.map(|timeout| Instant::now() + timeout);
+        self.keys.lock().unwrap().insert(*address, UnlockedKey { secret, locks_at });
+        Ok(())
+    }
+
+    pub fn lock(&self, address: &Address) {
+        self.keys.lock().unwrap().remove(address);
+    }
+
+    pub fn lock_all(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+
+    pub fn is_unlocked(&self, address: &Address) -> bool {
+        self.secret_key(address).is_ok()
+    }
+
+    /// Addresses of the unlocked keys
+    pub fn addresses(&self) -> Vec<Address> {
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .filter(|(_, key)| key.locks_at.is_none_or(|locks_at| Instant::now() < locks_at))
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    /// Signs a request with the configured signer, or with a local key if there is none
+    pub async fn sign(&self, address: &Address, request: SignRequest) -> Result<Vec<u8>, CryptoError> {
+        match &self.signer {
+            Some(signer) => signer.sign(*address, request).await
+                .map_err(|e| CryptoError::SigningError(e.to_string())),
+            None => self.sign_locally(address, &request),
+        }
+    }
+
+    /// Signs a request with a local key, without any policy checks
+    pub fn sign_locally(&self, address: &Address, request: &SignRequest) -> Result<Vec<u8>, CryptoError> {
+        let signing_key = SigningKey::from(self.secret_key(address)?);
+        let signature = match request {
+            SignRequest::Transaction(transaction) => signature::sign_digest(&signing_key, transaction.sighash())?,
+            SignRequest::TypedData { domain, message } => signature::sign_digest(&signing_key, message.digest(domain))?,
+            request => signature::sign_recoverable(&signing_key, &request.message())?,
+        };
         Ok(signature.to_vec())
     }
 
-    /// Verifies a signature against a message and address
+    /// Signs a message with the key associated with the given address, as `eth_sign`
+    /// does: 65 bytes r || s || v over the EIP-191 hash of `message`
+    pub async fn sign_message(&self, address: &Address, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.sign(address, SignRequest::Message(message.to_vec())).await
+    }
+
+    /// Signs a channel state so `BridgeCore.updateChannelState` accepts the signature.
+    /// `channel_id` is the id the bridge knows the channel by.
+    pub async fn sign_channel_state(
+        &self,
+        address: &Address,
+        channel_id: H256,
+        previous: Option<CanonicalState>,
+        state: CanonicalState,
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.sign(address, SignRequest::ChannelState { channel_id, previous, state }).await
+    }
+
+    /// Verifies a signature against a message and address. The signer is recovered
+    /// from the signature, so no key of theirs has to be known.
     pub fn verify_signature(
         &self,
         address: &Address,
         message: &[u8],
         signature: &[u8]
     ) -> Result<bool, CryptoError> {
-        let verifying_key = self.verifying_keys.get(address)
-            .ok_or_else(|| CryptoError::InvalidKey("Verifying key not found".into()))?;
+        Ok(signature::recover_signer(message, signature)? == *address)
+    }
 
-        let signature = Signature::try_from(signature)
-            .map_err(|e| CryptoError::InvalidSignature)?;
+    /// Address whose key produced `signature` over `message`
+    pub fn recover_signer(&self, message: &[u8], signature: &[u8]) -> Result<Address, CryptoError> {
+        signature::recover_signer(message, signature)
+    }
 
-        Ok(verifying_key.verify(message, &signature).is_ok())
+    /// Signs an EIP-712 message, as `eth_signTypedData_v4` does
+    pub async fn sign_typed_data(
+        &self,
+        address: &Address,
+        domain: &EIP712Domain,
+        message: impl Into<TypedMessage>,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let request = SignRequest::TypedData { domain: domain.clone(), message: message.into() };
+        self.sign(address, request).await
+    }
+
+    pub fn verify_typed_data<T: TypedStruct>(
+        &self,
+        address: &Address,
+        domain: &EIP712Domain,
+        message: &T,
+        signature: &[u8],
+    ) -> Result<bool, CryptoError> {
+        Ok(typed_data::recover_typed_data_signer(domain, message, signature)? == *address)
     }
 
     /// Hashes data using Keccak256
@@ -91,137 +304,160 @@ impl CryptoManager {
         H256::from_slice(&result)
     }
 
-    /// Creates a hash of the channel state
-    pub fn hash_channel_state(&self, 
-        balances: &HashMap<Address, U256>,
-        nonce: u64,
-        additional_data: &[u8]
-    ) -> Result<H256, CryptoError> {
-        let mut state_data = Vec::new();
-
-        // Add nonce
-        state_data.extend_from_slice(&nonce.to_be_bytes());
-
-        // Add sorted balances
-        let mut balance_entries: Vec<_> = balances.iter().collect();
-        balance_entries.sort_by_key(|&(addr, _)| *addr);
-
-        for (addr, balance) in balance_entries {
-            state_data.extend_from_slice(addr.as_bytes());
-            state_data.extend_from_slice(&balance.to_be_bytes());
-        }
-
-        // Add additional data
-        state_data.extend_from_slice(additional_data);
-
-        Ok(self.hash_data(&state_data))
-    }
-
-    /// Derives address from public key
-    fn public_key_to_address(&self, public_key: &VerifyingKey) -> Result<Address, CryptoError> {
-        let public_key_bytes = public_key.to_encoded_point(false).as_bytes().to_vec();
-        let hash = self.hash_data(&public_key_bytes);
-        let mut address_bytes = [0u8; 20];
-        address_bytes.copy_from_slice(&hash.as_bytes()[12..]);
-        Ok(Address::from_slice(&address_bytes))
-    }
-
     /// Implements ECDH (Elliptic Curve Diffie-Hellman) for secure channel establishment
     pub fn generate_shared_secret(
         &self,
         our_address: &Address,
         their_public_key: &[u8]
     ) -> Result<H256, CryptoError> {
-        let our_secret_key = self.keys.get(our_address)
-            .ok_or_else(|| CryptoError::InvalidKey("Key not found".into()))?;
+        let our_secret_key = self.secret_key(our_address)?;
 
         let their_verifying_key = VerifyingKey::from_sec1_bytes(their_public_key)
             .map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
 
         // Perform ECDH
-        let shared_point = their_verifying_key
-            .as_affine()
-            .mul_by_scalar(our_secret_key.as_scalar_primitive());
+        let shared_point = (ProjectivePoint::from(*their_verifying_key.as_affine())
+            * *our_secret_key.to_nonzero_scalar())
+            .to_affine();
 
         // Hash the shared point to derive the secret
         let shared_point_bytes = shared_point.to_encoded_point(false).as_bytes().to_vec();
         Ok(self.hash_data(&shared_point_bytes))
     }
 
-    /// Implements key rotation for enhanced security
-    pub fn rotate_key(&mut self, address: &Address) -> Result<(), CryptoError> {
-        let new_secret_key = SigningKey::random(&mut OsRng);
-        let new_public_key = new_secret_key.verifying_key();
-        
-        // Verify the new key generation was successful
-        let new_address = self.public_key_to_address(&new_public_key)?;
-        if new_address != *address {
-            return Err(CryptoError::KeyGenerationError(
-                "New key generated incorrect address".into()
-            ));
+    /// Derives the symmetric key static channel backups are encrypted with from the
+    /// HD seed, so a backup can be decrypted after data loss and key rotations.
+    pub fn derive_backup_key(&self) -> Result<[u8; 32], CryptoError> {
+        Ok(*self.keychain()?.backup_key()?)
+    }
+
+    /// Replaces a key with a new one, stored encrypted with `password`. The old key
+    /// is locked but kept in the keystore, so its signatures can still be verified.
+    /// Keys that only live in memory can't be rotated, as locking them would lose them.
+    pub fn rotate_key(&mut self, address: &Address, password: &str) -> Result<Address, CryptoError> {
+        // Only whoever can use the old key may rotate it
+        self.secret_key(address)?;
+        if !self.keystore.contains(address) {
+            return Err(KeystoreError::NotFound(*address).into());
         }
 
-        // Update the keys
-        self.keys.insert(*address, new_secret_key.into());
-        self.verifying_keys.insert(*address, new_public_key);
+        let new_secret_key = SecretKey::random(&mut OsRng);
+        let new_address = self.keystore.store(&new_secret_key, password)?;
+        self.keystore.retire(*address, new_address)?;
 
-        Ok(())
+        self.lock(address);
+        Ok(self.add_key(new_secret_key, self.auto_lock))
+    }
+
+    /// The keys `address` replaced through rotations, oldest first, ending with `address`
+    pub fn key_history(&self, address: &Address) -> Vec<KeyRecord> {
+        self.keystore.lineage(address)
     }
 
-    /// Implements secure state encoding for channel updates
-    pub fn encode_channel_state(
+    /// Whether `signature` over `message`, made at `signed_at`, was made by `address` or a
+    /// key it replaced. A key's signatures only count from before it was retired.
+    pub fn verify_historical_signature(
         &self,
-        balances: &HashMap<Address, U256>,
-        locks: &HashMap<H256, Vec<u8>>,
-        nonce: u64
-    ) -> Result<Vec<u8>, CryptoError> {
-        let mut encoded = Vec::new();
+        address: &Address,
+        message: &[u8],
+        signature: &[u8],
+        signed_at: u64,
+    ) -> Result<bool, CryptoError> {
+        let signer = signature::recover_signer(message, signature)?;
+        let history = self.key_history(address);
 
-        // Encode nonce
-        encoded.extend_from_slice(&nonce.to_be_bytes());
+        Ok(match history.iter().find(|record| record.address == signer) {
+            Some(record) => record.retired_at.is_none_or(|retired_at| signed_at < retired_at),
+            None => signer == *address,
+        })
+    }
 
-        // Encode balances
-        let mut balance_entries: Vec<_> = balances.iter().collect();
-        balance_entries.sort_by_key(|&(addr, _)| *addr);
+    /// Wipes every unlocked key whose auto-lock timeout has passed
+    pub fn lock_expired(&self) {
+        let now = Instant::now();
+        self.keys.lock().unwrap()
+            .retain(|_, key| key.locks_at.is_none_or(|locks_at| now < locks_at));
+    }
 
-        for (addr, balance) in balance_entries {
-            encoded.extend_from_slice(addr.as_bytes());
-            encoded.extend_from_slice(&balance.to_be_bytes());
-        }
+    /// Wipes expired keys every `interval`, so they don't linger in memory until their next use
+    pub fn start_auto_lock(self: Arc<Self>, interval: Duration) {
+        let manager = Arc::downgrade(&self);
+        drop(self);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+                match manager.upgrade() {
+                    Some(manager) => manager.lock_expired(),
+                    None => break,
+                }
+            }
+        });
+    }
 
-        // Encode locks
-        let mut lock_entries: Vec<_> = locks.iter().collect();
-        lock_entries.sort_by_key(|&(hash, _)| *hash);
+    fn add_key(&mut self, secret: SecretKey, auto_lock: Option<Duration>) -> Address {
+        let public_key = VerifyingKey::from(secret.public_key());
+        self.verifying_keys.insert(signature::address_of(&public_key), public_key);
+        self.unlock_secret(secret, auto_lock)
+    }
 
-        for (hash, lock_data) in lock_entries {
-            encoded.extend_from_slice(hash.as_bytes());
-            encoded.extend_from_slice(&(lock_data.len() as u64).to_be_bytes());
-            encoded.extend_from_slice(lock_data);
-        }
+    fn unlock_secret(&self, secret: SecretKey, auto_lock: Option<Duration>) -> Address {
+        let address = signature::address_of(&VerifyingKey::from(secret.public_key()));
+        self.keys.lock().unwrap().insert(address, UnlockedKey {
+            secret,
+            locks_at: auto_lock.map(|timeout| Instant::now() + timeout),
+        });
+        address
+    }
 
-        Ok(encoded)
+    fn keychain(&self) -> Result<&HdKeychain, CryptoError> {
+        self.keychain.as_ref()
+            .ok_or_else(|| CryptoError::InvalidKey("No HD keychain".into()))
+    }
+
+    // Copy of an unlocked key, wiping it first if its auto-lock timeout passed
+    fn secret_key(&self, address: &Address) -> Result<SecretKey, CryptoError> {
+        let mut keys = self.keys.lock().unwrap();
+
+        match keys.get(address) {
+            Some(key) if key.locks_at.is_none_or(|locks_at| Instant::now() < locks_at) => Ok(key.secret.clone()),
+            Some(_) => {
+                keys.remove(address);
+                Err(CryptoError::Locked(*address))
+            }
+            None if self.keystore.contains(address) => Err(CryptoError::Locked(*address)),
+            None => Err(CryptoError::InvalidKey("Key not found".into())),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use keystore::KdfConfig;
+
+    fn keystore_manager() -> CryptoManager {
+        CryptoManager::new()
+            .with_keystore(Keystore::in_memory().with_kdf(KdfConfig::Scrypt { log_n: 10, r: 8, p: 1 }))
+    }
 
     #[test]
     fn test_keypair_generation() {
         let mut crypto_manager = CryptoManager::new();
         let address = crypto_manager.generate_keypair().unwrap();
-        assert!(crypto_manager.keys.contains_key(&address));
+        assert!(crypto_manager.is_unlocked(&address));
         assert!(crypto_manager.verifying_keys.contains_key(&address));
     }
 
-    #[test]
-    fn test_signature_verification() {
+    #[tokio::test]
+    async fn test_signature_verification() {
         let mut crypto_manager = CryptoManager::new();
         let address = crypto_manager.generate_keypair().unwrap();
         
         let message = b"Test message";
-        let signature = crypto_manager.sign_message(&address, message).unwrap();
+        let signature = crypto_manager.sign_message(&address, message).await.unwrap();
         
         assert!(crypto_manager.verify_signature(&address, message, &signature).unwrap());
     }
@@ -241,14 +477,90 @@ mod tests {
         assert_ne!(secret, H256::zero());
     }
 
-    #[test]
-    fn test_key_rotation() {
-        let mut crypto_manager = CryptoManager::new();
-        let address = crypto_manager.generate_keypair().unwrap();
-        
-        let old_key = crypto_manager.keys[&address].clone();
-        crypto_manager.rotate_key(&address).unwrap();
-        
-        assert_ne!(crypto_manager.keys[&address], old_key);
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let mut crypto_manager = keystore_manager();
+        let address = crypto_manager.create_key("password").unwrap();
+        let message = b"Signed before the rotation";
+        let old_signature = crypto_manager.sign_message(&address, message).await.unwrap();
+
+        let new_address = crypto_manager.rotate_key(&address, "new password").unwrap();
+        assert_ne!(new_address, address);
+        assert!(matches!(crypto_manager.sign_message(&address, message).await, Err(CryptoError::Locked(_))));
+        assert!(crypto_manager.is_unlocked(&new_address));
+
+        let history: Vec<_> = crypto_manager.key_history(&new_address).iter().map(|record| record.address).collect();
+        assert_eq!(history, vec![address, new_address]);
+        let retired_at = history_record(&crypto_manager, &new_address, address).retired_at.unwrap();
+        assert!(crypto_manager.verify_historical_signature(&new_address, message, &old_signature, retired_at - 1).unwrap());
+        // The old key's signatures don't count once it was retired
+        assert!(!crypto_manager.verify_historical_signature(&new_address, message, &old_signature, retired_at).unwrap());
+        assert!(!crypto_manager.verify_signature(&new_address, message, &old_signature).unwrap());
+
+        // The retired key can still be unlocked from the keystore
+        crypto_manager.unlock(&address, "password").unwrap();
+        assert!(crypto_manager.is_unlocked(&address));
+
+        // A key that was never stored stays in use rather than being lost
+        let unstored = crypto_manager.generate_keypair().unwrap();
+        let result = crypto_manager.rotate_key(&unstored, "new password");
+        assert!(matches!(result, Err(CryptoError::Keystore(KeystoreError::NotFound(_)))));
+        assert!(crypto_manager.sign_message(&unstored, message).await.is_ok());
+        assert!(crypto_manager.key_history(&unstored).iter().all(|record| record.retired_at.is_none()));
+    }
+
+    fn history_record(crypto_manager: &CryptoManager, address: &Address, key: Address) -> KeyRecord {
+        crypto_manager.key_history(address).into_iter().find(|record| record.address == key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unlock_lock_and_auto_lock() {
+        let mut crypto_manager = keystore_manager().with_auto_lock(Duration::from_millis(50));
+        let address = crypto_manager.create_key("password").unwrap();
+
+        crypto_manager.lock(&address);
+        assert!(matches!(crypto_manager.sign_message(&address, b"message").await, Err(CryptoError::Locked(_))));
+        assert!(matches!(
+            crypto_manager.unlock(&address, "wrong"),
+            Err(CryptoError::Keystore(KeystoreError::WrongPassword))
+        ));
+
+        crypto_manager.unlock(&address, "password").unwrap();
+        assert!(crypto_manager.sign_message(&address, b"message").await.is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!crypto_manager.is_unlocked(&address));
+
+        // The sweep wipes expired keys without waiting for them to be used
+        crypto_manager.unlock(&address, "password").unwrap();
+        let crypto_manager = Arc::new(crypto_manager);
+        crypto_manager.clone().start_auto_lock(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(crypto_manager.keys.lock().unwrap().is_empty());
+
+        // An exported key file imports into another keystore
+        let exported = crypto_manager.export_key(&address).unwrap();
+        let mut other = keystore_manager();
+        assert_eq!(other.import_key(&exported, "password").unwrap(), address);
+    }
+
+    #[tokio::test]
+    async fn test_keys_derived_from_keychain() {
+        let seed = [3u8; 32];
+        let crypto_manager = CryptoManager::new().with_keychain(HdKeychain::from_seed(&seed).unwrap()).unwrap();
+        let node = crypto_manager.node_address().unwrap();
+        assert!(crypto_manager.sign_message(&node, b"message").await.is_ok());
+
+        let channel = crypto_manager.next_channel_keys().unwrap();
+        assert!(crypto_manager.is_unlocked(&channel.funding.address()));
+        let (index, preimage) = crypto_manager.next_payment_preimage().unwrap();
+        assert_eq!(crypto_manager.key_indices(), (1, 1));
+
+        // A restored node resumes at the persisted indices and regenerates the same keys
+        let restored = CryptoManager::new().with_keychain(HdKeychain::from_seed(&seed).unwrap()).unwrap()
+            .with_key_indices(1, 1);
+        assert_eq!(restored.node_address(), Some(node));
+        assert_eq!(restored.next_channel_keys().unwrap().index, 1);
+        assert_eq!(HdKeychain::from_seed(&seed).unwrap().payment_preimage(index).unwrap(), preimage);
+        assert!(CryptoManager::new().next_payment_preimage().is_err());
     }
 }
\ No newline at end of file
@@ -0,0 +1,320 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
+use ethers::types::Address;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use zeroize::Zeroizing;
+
+use super::signer::{SignRequest, Signer, SignerError};
+
+// Bound into every MAC, so a response can never pass for a request
+const REQUEST_TAG: &[u8] = b"flashchain-signer-request";
+const RESPONSE_TAG: &[u8] = b"flashchain-signer-response";
+
+/// First message on a connection. Every MAC on the connection covers the
+/// challenge, so messages can't be replayed on another connection.
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    challenge: String,
+}
+
+/// A message body with the HMAC-SHA256 of tag || challenge || body
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    body: String,
+    mac: String,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize)]
+enum Call {
+    Addresses,
+    Sign { address: Address, request: SignRequest },
+}
+
+#[derive(Serialize, Deserialize)]
+struct RequestBody {
+    /// Strictly increasing on a connection
+    id: u64,
+    call: Call,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Reply {
+    Addresses(Vec<Address>),
+    Signature(String),
+    Refused(String),
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResponseBody {
+    id: u64,
+    reply: Reply,
+}
+
+/// Serves a signer to nodes over a Unix socket. Requests are authenticated with a
+/// key shared with the node; the signer's own policy decides what gets signed.
+pub struct SignerServer {
+    signer: Arc<dyn Signer>,
+    auth_key: Zeroizing<[u8; 32]>,
+}
+
+impl SignerServer {
+    pub fn new(signer: Arc<dyn Signer>, auth_key: [u8; 32]) -> Self {
+        Self {
+            signer,
+            auth_key: Zeroizing::new(auth_key),
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_connection(stream).await {
+                    log::warn!("Signer connection closed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, stream: UnixStream) -> Result<(), SignerError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        let challenge: [u8; 32] = rand::thread_rng().gen();
+        write_line(&mut writer, &Hello { challenge: hex::encode(challenge) }).await?;
+
+        let mut last_id = 0;
+        while let Some(line) = lines.next_line().await.map_err(transport)? {
+            let envelope: Envelope = decode(&line)?;
+            // Unauthenticated connections are dropped without an answer
+            verify_mac(&self.auth_key, REQUEST_TAG, &challenge, &envelope)?;
+
+            let request: RequestBody = decode(&envelope.body)?;
+            if request.id <= last_id {
+                return Err(SignerError::Protocol(format!("Request {} was replayed", request.id)));
+            }
+            last_id = request.id;
+
+            let reply = match request.call {
+                Call::Addresses => match self.signer.addresses().await {
+                    Ok(addresses) => Reply::Addresses(addresses),
+                    Err(e) => Reply::Failed(e.to_string()),
+                },
+                Call::Sign { address, request } => match self.signer.sign(address, request).await {
+                    Ok(signature) => Reply::Signature(hex::encode(signature)),
+                    Err(SignerError::Refused(reason)) => Reply::Refused(reason),
+                    Err(e) => Reply::Failed(e.to_string()),
+                },
+            };
+
+            let body = encode(&ResponseBody { id: request.id, reply })?;
+            let mac = mac(&self.auth_key, RESPONSE_TAG, &challenge, &body);
+            write_line(&mut writer, &Envelope { body, mac }).await?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Connection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    challenge: [u8; 32],
+    next_id: u64,
+}
+
+/// Signs through a `SignerServer` on a Unix socket, so the node never holds keys
+pub struct RemoteSigner {
+    path: PathBuf,
+    auth_key: Zeroizing<[u8; 32]>,
+    // One request at a time; reconnects after the connection breaks
+    connection: Mutex<Option<Connection>>,
+}
+
+impl RemoteSigner {
+    pub fn new(path: impl Into<PathBuf>, auth_key: [u8; 32]) -> Self {
+        Self {
+            path: path.into(),
+            auth_key: Zeroizing::new(auth_key),
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn call(&self, call: Call) -> Result<Reply, SignerError> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+
+        let result = self.exchange(connection.as_mut().unwrap(), call).await;
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<Connection, SignerError> {
+        let stream = UnixStream::connect(&self.path).await.map_err(transport)?;
+        let (reader, writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        let hello: Hello = decode(&read_line(&mut lines).await?)?;
+        let challenge = hex::decode(&hello.challenge).ok()
+            .and_then(|challenge| <[u8; 32]>::try_from(challenge).ok())
+            .ok_or_else(|| SignerError::Protocol("Invalid challenge".into()))?;
+
+        Ok(Connection { lines, writer, challenge, next_id: 1 })
+    }
+
+    async fn exchange(&self, connection: &mut Connection, call: Call) -> Result<Reply, SignerError> {
+        let id = connection.next_id;
+        connection.next_id += 1;
+
+        let body = encode(&RequestBody { id, call })?;
+        let mac = mac(&self.auth_key, REQUEST_TAG, &connection.challenge, &body);
+        write_line(&mut connection.writer, &Envelope { body, mac }).await?;
+
+        // The server hangs up on requests it can't authenticate
+        let line = read_line(&mut connection.lines).await.map_err(|e| match e {
+            SignerError::Transport(_) => SignerError::Unauthenticated,
+            e => e,
+        })?;
+        let envelope: Envelope = decode(&line)?;
+        verify_mac(&self.auth_key, RESPONSE_TAG, &connection.challenge, &envelope)?;
+
+        let response: ResponseBody = decode(&envelope.body)?;
+        if response.id != id {
+            return Err(SignerError::Protocol(format!("Response {} to request {}", response.id, id)));
+        }
+        Ok(response.reply)
+    }
+}
+
+#[async_trait]
+impl Signer for RemoteSigner {
+    async fn addresses(&self) -> Result<Vec<Address>, SignerError> {
+        match self.call(Call::Addresses).await? {
+            Reply::Addresses(addresses) => Ok(addresses),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    async fn sign(&self, address: Address, request: SignRequest) -> Result<Vec<u8>, SignerError> {
+        match self.call(Call::Sign { address, request }).await? {
+            Reply::Signature(signature) => hex::decode(signature)
+                .map_err(|e| SignerError::Protocol(e.to_string())),
+            reply => Err(unexpected(reply)),
+        }
+    }
+}
+
+fn unexpected(reply: Reply) -> SignerError {
+    match reply {
+        Reply::Refused(reason) => SignerError::Refused(reason),
+        Reply::Failed(reason) => SignerError::Protocol(reason),
+        _ => SignerError::Protocol("Unexpected reply".into()),
+    }
+}
+
+fn mac(key: &[u8; 32], tag: &[u8], challenge: &[u8; 32], body: &str) -> String {
+    hex::encode(hmac(key, tag, challenge, body).finalize().into_bytes())
+}
+
+fn verify_mac(key: &[u8; 32], tag: &[u8], challenge: &[u8; 32], envelope: &Envelope) -> Result<(), SignerError> {
+    let mac = hex::decode(&envelope.mac).map_err(|_| SignerError::Unauthenticated)?;
+    hmac(key, tag, challenge, &envelope.body)
+        .verify_slice(&mac)
+        .map_err(|_| SignerError::Unauthenticated)
+}
+
+fn hmac(key: &[u8; 32], tag: &[u8], challenge: &[u8; 32], body: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(tag);
+    mac.update(challenge);
+    mac.update(body.as_bytes());
+    mac
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<String, SignerError> {
+    serde_json::to_string(value).map_err(|e| SignerError::Protocol(e.to_string()))
+}
+
+fn decode<T: DeserializeOwned>(line: &str) -> Result<T, SignerError> {
+    serde_json::from_str(line).map_err(|e| SignerError::Protocol(e.to_string()))
+}
+
+async fn read_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Result<String, SignerError> {
+    lines.next_line().await
+        .map_err(transport)?
+        .ok_or_else(|| SignerError::Transport("Connection closed".into()))
+}
+
+async fn write_line<T: Serialize>(writer: &mut OwnedWriteHalf, value: &T) -> Result<(), SignerError> {
+    let mut line = encode(value)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await.map_err(transport)
+}
+
+fn transport(e: std::io::Error) -> SignerError {
+    SignerError::Transport(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::H256;
+    use flashchain_common::encoding::{CanonicalBalance, CanonicalState};
+    use flashchain_common::types::AssetId;
+    use crate::crypto::signature::{channel_state_message, recover_signer};
+    use crate::crypto::signer::{LocalSigner, SigningPolicy};
+    use crate::crypto::CryptoManager;
+
+    #[tokio::test]
+    async fn test_remote_signing_is_authenticated_and_policed() {
+        let mut keys = CryptoManager::new();
+        let node = keys.generate_keypair().unwrap();
+        let auth_key: [u8; 32] = rand::thread_rng().gen();
+
+        let path = std::env::temp_dir().join(format!("flashchain-signer-{}.sock", H256::random()));
+        let server = Arc::new(SignerServer::new(
+            Arc::new(LocalSigner::new(keys, SigningPolicy::default())),
+            auth_key,
+        ));
+        tokio::spawn(server.serve(UnixListener::bind(&path).unwrap()));
+
+        let signer = RemoteSigner::new(&path, auth_key);
+        assert_eq!(signer.addresses().await.unwrap(), vec![node]);
+
+        let state = CanonicalState::new(1, vec![
+            CanonicalBalance { asset: AssetId::NATIVE, participant: node, amount: 1000.into() },
+        ], vec![], vec![]);
+        let channel_id = H256::random();
+        let request = SignRequest::ChannelState { channel_id, previous: Some(state.clone()), state: state.clone() };
+        let signature = signer.sign(node, request).await.unwrap();
+        let message = channel_state_message(channel_id, state.sequence, state.hash());
+        assert_eq!(recover_signer(message.as_bytes(), &signature).unwrap(), node);
+
+        // The policy on the signer's side still applies
+        assert!(matches!(
+            signer.sign(node, SignRequest::Message(b"anything".to_vec())).await,
+            Err(SignerError::Refused(_))
+        ));
+
+        let intruder = RemoteSigner::new(&path, [7u8; 32]);
+        assert!(matches!(intruder.addresses().await, Err(SignerError::Unauthenticated)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,8 +1,11 @@
 use super::CryptoError;
+use super::typed_data::{self, TypedStruct};
+use ethers::types::transaction::eip712::EIP712Domain;
 use ethers::types::{Address, H256, U256};
-use k256::ecdsa::{Signature, SigningKey, VerifyingKey, signature::{Signer, Verifier}};
+use flashchain_common::encoding::u256_bytes;
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey, signature::Verifier};
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignatureSet {
@@ -18,6 +21,110 @@ pub struct SignedState {
     pub signatures: SignatureSet,
 }
 
+/// Length of an `ecrecover` signature, r || s || v
+pub const RECOVERABLE_SIGNATURE_LENGTH: usize = 65;
+
+/// Ethereum address of a public key
+pub fn address_of(key: &VerifyingKey) -> Address {
+    let point = key.to_encoded_point(false);
+    Address::from_slice(&keccak256(&point.as_bytes()[1..])[12..])
+}
+
+/// Hash `eth_sign` and `ECDSA.toEthSignedMessageHash` sign for `message`
+pub fn eth_message_hash(message: &[u8]) -> H256 {
+    ethers::utils::hash_message(message)
+}
+
+/// Message participants sign so `BridgeCore.updateChannelState` accepts a state.
+/// The sequence is signed along with the hash so the bridge can refuse older states.
+pub fn channel_state_message(channel_id: H256, sequence: u64, state_hash: H256) -> H256 {
+    let mut data = Vec::new();
+    data.extend_from_slice(channel_id.as_bytes());
+    data.extend_from_slice(&u256_bytes(U256::from(sequence)));
+    data.extend_from_slice(state_hash.as_bytes());
+    H256::from_slice(&keccak256(&data))
+}
+
+/// Message participants sign so `BridgeCore.releaseToken` pays `amount` of `token`
+/// to `recipient`
+pub fn token_release_message(
+    channel_id: H256,
+    token: Address,
+    amount: U256,
+    recipient: Address,
+    nonce: U256,
+) -> H256 {
+    let mut data = Vec::new();
+    data.extend_from_slice(channel_id.as_bytes());
+    data.extend_from_slice(token.as_bytes());
+    data.extend_from_slice(&u256_bytes(amount));
+    data.extend_from_slice(recipient.as_bytes());
+    data.extend_from_slice(&u256_bytes(nonce));
+    H256::from_slice(&keccak256(&data))
+}
+
+/// Signs the EIP-191 hash of `message`, returning r || s || v with v in {27, 28}
+pub fn sign_recoverable(key: &SigningKey, message: &[u8]) -> Result<[u8; RECOVERABLE_SIGNATURE_LENGTH], CryptoError> {
+    sign_digest(key, eth_message_hash(message))
+}
+
+/// Signs a 32-byte digest as is, returning r || s || v with v in {27, 28}
+pub fn sign_digest(key: &SigningKey, digest: H256) -> Result<[u8; RECOVERABLE_SIGNATURE_LENGTH], CryptoError> {
+    let (signature, recovery_id) = key.sign_prehash_recoverable(digest.as_bytes())
+        .map_err(|e| CryptoError::SigningError(e.to_string()))?;
+
+    let mut bytes = [0u8; RECOVERABLE_SIGNATURE_LENGTH];
+    bytes[..64].copy_from_slice(&signature.to_bytes());
+    bytes[64] = 27 + recovery_id.to_byte();
+    Ok(bytes)
+}
+
+/// Address that signed the EIP-191 hash of `message`, as `ecrecover` would return it.
+/// High-s signatures are rejected, like OpenZeppelin's `ECDSA.recover` does.
+pub fn recover_signer(message: &[u8], signature: &[u8]) -> Result<Address, CryptoError> {
+    recover_digest_signer(eth_message_hash(message), signature)
+}
+
+/// Address that signed `digest` itself, as `ecrecover(digest, v, r, s)` would return it
+pub fn recover_digest_signer(digest: H256, signature: &[u8]) -> Result<Address, CryptoError> {
+    if signature.len() != RECOVERABLE_SIGNATURE_LENGTH {
+        return Err(CryptoError::InvalidSignature);
+    }
+
+    let v = match signature[64] {
+        v @ (27 | 28) => v - 27,
+        v @ (0 | 1) => v,
+        _ => return Err(CryptoError::InvalidSignature),
+    };
+    let recovery_id = RecoveryId::from_byte(v).ok_or(CryptoError::InvalidSignature)?;
+    let signature = Signature::try_from(&signature[..64])
+        .map_err(|_| CryptoError::InvalidSignature)?;
+    if signature.normalize_s().is_some() {
+        return Err(CryptoError::InvalidSignature);
+    }
+
+    let key = VerifyingKey::recover_from_prehash(digest.as_bytes(), &signature, recovery_id)
+        .map_err(|e| CryptoError::VerificationError(e.to_string()))?;
+
+    Ok(address_of(&key))
+}
+
+/// How many of a set of signers have to sign
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quorum {
+    All,
+    AtLeast(usize),
+}
+
+impl Quorum {
+    pub fn required(&self, signers: usize) -> usize {
+        match self {
+            Quorum::All => signers,
+            Quorum::AtLeast(threshold) => (*threshold).min(signers),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SignatureVerifier {
     required_signatures: usize,
@@ -36,31 +143,96 @@ impl SignatureVerifier {
         self.verifying_keys.insert(address, key);
     }
 
-    pub fn verify_signature_set(&self, set: &SignatureSet) -> Result<bool, CryptoError> {
-        // Check if we have enough signatures
-        if set.signatures.len() < self.required_signatures {
-            return Ok(false);
+    /// Whether `required_signatures` of `participants`, and nobody else, signed the set
+    pub fn verify_signature_set(&self, set: &SignatureSet, participants: &[Address]) -> Result<bool, CryptoError> {
+        match self.verify_quorum(set, participants, Quorum::AtLeast(self.required_signatures)) {
+            Ok(()) => Ok(true),
+            Err(CryptoError::InvalidSignature | CryptoError::VerificationError(_)) => Ok(false),
+            Err(e) => Err(e),
         }
+    }
+
+    /// Checks that `quorum` of `signers` signed the set's message. Every signature
+    /// has to come from one of `signers` and verify under its key, and no key may
+    /// sign twice under different addresses.
+    pub fn verify_quorum(
+        &self,
+        set: &SignatureSet,
+        signers: &[Address],
+        quorum: Quorum,
+    ) -> Result<(), CryptoError> {
+        let mut signers_seen = HashSet::new();
 
-        // Verify each signature
         for (address, signature) in &set.signatures {
-            if let Some(verifying_key) = self.verifying_keys.get(address) {
-                let signature = Signature::try_from(signature.as_slice())
-                    .map_err(|_| CryptoError::InvalidSignature)?;
-
-                if verifying_key.verify(set.message_hash.as_ref(), &signature).is_err() {
-                    return Ok(false);
-                }
-            } else {
-                return Ok(false);
+            if !signers.contains(address) {
+                return Err(CryptoError::VerificationError(format!("Unknown signer {:?}", address)));
             }
+
+            let signer = self.verify_entry(address, set.message_hash, signature)?;
+            if !signers_seen.insert(signer) {
+                return Err(CryptoError::VerificationError(format!("Duplicate signature from {:?}", address)));
+            }
+        }
+
+        let required = quorum.required(signers.len());
+        if set.signatures.len() < required {
+            return Err(CryptoError::VerificationError(format!(
+                "{} of {} required signatures", set.signatures.len(), required
+            )));
         }
 
-        Ok(true)
+        Ok(())
+    }
+
+    /// Verifies that at least `threshold` of `signers` signed the set's message.
+    /// Signatures from addresses outside `signers` are not counted.
+    pub fn verify_threshold(
+        &self,
+        set: &SignatureSet,
+        signers: &[Address],
+        threshold: usize,
+    ) -> Result<bool, CryptoError> {
+        let mut valid_signatures = 0;
+
+        for (address, signature) in &set.signatures {
+            if !signers.contains(address) {
+                continue;
+            }
+
+            // A bad entry doesn't count, but can't veto the valid ones either
+            match self.verify_entry(address, set.message_hash, signature) {
+                Ok(_) => valid_signatures += 1,
+                Err(CryptoError::InvalidKey(_) | CryptoError::InvalidSignature) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(valid_signatures >= threshold)
+    }
+
+    /// Verifies one signature of `address` and returns who actually signed: the
+    /// recovered address for `ecrecover` signatures, the registered key otherwise
+    fn verify_entry(&self, address: &Address, message_hash: H256, signature: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if signature.len() == RECOVERABLE_SIGNATURE_LENGTH {
+            let signer = recover_signer(message_hash.as_bytes(), signature)?;
+            if signer != *address {
+                return Err(CryptoError::InvalidSignature);
+            }
+            return Ok(signer.as_bytes().to_vec());
+        }
+
+        let verifying_key = self.verifying_keys.get(address)
+            .ok_or_else(|| CryptoError::InvalidKey(format!("No verifying key for {:?}", address)))?;
+        let signature = Signature::try_from(signature)
+            .map_err(|_| CryptoError::InvalidSignature)?;
+        verifying_key.verify(message_hash.as_ref(), &signature)
+            .map_err(|_| CryptoError::InvalidSignature)?;
+
+        Ok(verifying_key.to_encoded_point(true).as_bytes().to_vec())
     }
 
-    pub fn verify_signed_state(&self, signed_state: &SignedState) -> Result<bool, CryptoError> {
-        self.verify_signature_set(&signed_state.signatures)
+    pub fn verify_signed_state(&self, signed_state: &SignedState, participants: &[Address]) -> Result<bool, CryptoError> {
+        self.verify_signature_set(&signed_state.signatures, participants)
     }
 }
 
@@ -112,14 +284,20 @@ impl SignatureAggregator {
 
 pub struct SignatureBuilder {
     data: Vec<u8>,
-    typed_data: bool,
+    domain_separator: Option<H256>,
+}
+
+impl Default for SignatureBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SignatureBuilder {
     pub fn new() -> Self {
         Self {
             data: Vec::new(),
-            typed_data: false,
+            domain_separator: None,
         }
     }
 
@@ -129,7 +307,7 @@ impl SignatureBuilder {
     }
 
     pub fn add_amount(&mut self, amount: U256) -> &mut Self {
-        self.data.extend_from_slice(&amount.to_be_bytes());
+        self.data.extend_from_slice(&u256_bytes(amount));
         self
     }
 
@@ -143,21 +321,25 @@ impl SignatureBuilder {
         self
     }
 
-    pub fn enable_typed_data(&mut self) -> &mut Self {
-        self.typed_data = true;
+    /// Adds `typeHash || encodeData` of an EIP-712 struct
+    pub fn add_struct<T: TypedStruct>(&mut self, value: &T) -> &mut Self {
+        self.data.extend_from_slice(T::type_hash().as_bytes());
+        self.data.extend_from_slice(&value.encode_data());
+        self
+    }
+
+    /// Builds the EIP-712 digest in `domain` instead of a plain hash. The data
+    /// should then be a single struct added with `add_struct`.
+    pub fn enable_typed_data(&mut self, domain: &EIP712Domain) -> &mut Self {
+        self.domain_separator = Some(H256::from(domain.separator()));
         self
     }
 
     pub fn build(&self) -> H256 {
-        if self.typed_data {
-            // EIP-712 typed data hashing
-            let domain_separator = H256::from_slice(b"FlashChain Channel v1.0\x00\x00\x00");
-            let mut typed_data = Vec::new();
-            typed_data.extend_from_slice(domain_separator.as_bytes());
-            typed_data.extend_from_slice(&self.data);
-            H256::from_slice(&keccak256(&typed_data))
-        } else {
-            H256::from_slice(&keccak256(&self.data))
+        let hash = H256::from_slice(&keccak256(&self.data));
+        match self.domain_separator {
+            Some(domain_separator) => typed_data::typed_data_digest(domain_separator, hash),
+            None => hash,
         }
     }
 }
@@ -172,24 +354,28 @@ fn keccak256(data: &[u8]) -> [u8; 32] {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use k256::SecretKey;
+    use k256::ecdsa::signature::Signer;
 
     fn generate_test_keypair() -> (Address, SigningKey, VerifyingKey) {
         let secret_key = SigningKey::random(&mut rand::thread_rng());
-        let public_key = secret_key.verifying_key();
+        let public_key = *secret_key.verifying_key();
         let address = Address::random(); // In real implementation, derive from public key
         (address, secret_key, public_key)
     }
 
+    fn sign(key: &SigningKey, message: H256) -> Vec<u8> {
+        let signature: Signature = key.sign(message.as_ref());
+        signature.to_vec()
+    }
+
     #[test]
     fn test_signature_verification() {
-        let (address, secret_key, public_key) = generate_test_keypair();
-        
-        let mut verifier = SignatureVerifier::new(1);
-        verifier.add_verifying_key(address, public_key);
+        let secret_key = SigningKey::random(&mut rand::thread_rng());
+        let address = address_of(secret_key.verifying_key());
+        let verifier = SignatureVerifier::new(1);
 
-        let message = H256::random();
-        let signature = secret_key.sign(message.as_ref());
+        let message = channel_state_message(H256::random(), 1, H256::random());
+        let signature = sign_recoverable(&secret_key, message.as_bytes()).unwrap();
 
         let mut signature_set = SignatureSet {
             signatures: HashMap::new(),
@@ -198,7 +384,37 @@ mod tests {
         };
         signature_set.signatures.insert(address, signature.to_vec());
 
-        assert!(verifier.verify_signature_set(&signature_set).unwrap());
+        assert!(verifier.verify_signature_set(&signature_set, &[address]).unwrap());
+        // A valid signature from someone who isn't expected doesn't count
+        assert!(!verifier.verify_signature_set(&signature_set, &[Address::random()]).unwrap());
+    }
+
+    #[test]
+    fn test_threshold_verification() {
+        let (buyer, buyer_key, buyer_public) = generate_test_keypair();
+        let (seller, _, seller_public) = generate_test_keypair();
+        let (arbiter, arbiter_key, arbiter_public) = generate_test_keypair();
+
+        let mut verifier = SignatureVerifier::new(2);
+        verifier.add_verifying_key(buyer, buyer_public);
+        verifier.add_verifying_key(seller, seller_public);
+        verifier.add_verifying_key(arbiter, arbiter_public);
+
+        let message = H256::random();
+        let mut signature_set = SignatureSet {
+            signatures: HashMap::new(),
+            message_hash: message,
+            timestamp: 0,
+        };
+        signature_set.signatures.insert(buyer, sign(&buyer_key, message));
+
+        let signers = [buyer, seller, arbiter];
+        assert!(!verifier.verify_threshold(&signature_set, &signers, 2).unwrap());
+
+        // A garbage signature from the seller is skipped rather than failing the set
+        signature_set.signatures.insert(seller, vec![0u8; 64]);
+        signature_set.signatures.insert(arbiter, sign(&arbiter_key, message));
+        assert!(verifier.verify_threshold(&signature_set, &signers, 2).unwrap());
     }
 
     #[test]
@@ -209,8 +425,8 @@ mod tests {
         let (address1, secret_key1, _) = generate_test_keypair();
         let (address2, secret_key2, _) = generate_test_keypair();
 
-        let signature1 = secret_key1.sign(message_hash.as_ref()).to_vec();
-        let signature2 = secret_key2.sign(message_hash.as_ref()).to_vec();
+        let signature1 = sign_recoverable(&secret_key1, message_hash.as_bytes()).unwrap().to_vec();
+        let signature2 = sign_recoverable(&secret_key2, message_hash.as_bytes()).unwrap().to_vec();
 
         aggregator.add_signature(address1, signature1).unwrap();
         assert!(!aggregator.is_complete());
@@ -232,10 +448,96 @@ mod tests {
         builder
             .add_address(address)
             .add_amount(amount)
-            .add_nonce(nonce)
-            .enable_typed_data();
+            .add_nonce(nonce);
 
         let hash = builder.build();
         assert_ne!(hash, H256::zero());
+
+        let domain = EIP712Domain {
+            name: Some(typed_data::DOMAIN_NAME.to_string()),
+            chain_id: Some(U256::one()),
+            ..Default::default()
+        };
+        let payout = typed_data::ClosePayout { participant: address, amount, fee_share: U256::zero() };
+        let typed = SignatureBuilder::new()
+            .add_struct(&payout)
+            .enable_typed_data(&domain)
+            .build();
+        assert_eq!(typed, typed_data::typed_data_hash(&domain, &payout));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_quorum_rejects_unknown_and_duplicate_signers() {
+        let (a, a_key, a_public) = generate_test_keypair();
+        let (b, b_key, b_public) = generate_test_keypair();
+        let alias = Address::random();
+
+        let mut verifier = SignatureVerifier::new(2);
+        verifier.add_verifying_key(a, a_public);
+        verifier.add_verifying_key(b, b_public);
+        verifier.add_verifying_key(alias, a_public);
+
+        let message = H256::random();
+        let mut set = SignatureSet {
+            signatures: HashMap::new(),
+            message_hash: message,
+            timestamp: 0,
+        };
+        set.signatures.insert(a, sign(&a_key, message));
+        assert!(verifier.verify_quorum(&set, &[a, b], Quorum::AtLeast(1)).is_ok());
+        assert!(verifier.verify_quorum(&set, &[a, b], Quorum::All).is_err());
+
+        set.signatures.insert(b, sign(&b_key, H256::random()));
+        assert!(matches!(verifier.verify_quorum(&set, &[a, b], Quorum::All), Err(CryptoError::InvalidSignature)));
+
+        set.signatures.insert(b, sign(&b_key, message));
+        assert!(verifier.verify_quorum(&set, &[a, b], Quorum::All).is_ok());
+        assert!(verifier.verify_quorum(&set, &[a], Quorum::All).is_err());
+
+        // The same key under a second address counts once
+        set.signatures.insert(alias, sign(&a_key, message));
+        assert!(verifier.verify_quorum(&set, &[a, b, alias], Quorum::AtLeast(2)).is_err());
+    }
+
+    #[test]
+    fn test_recoverable_signatures_match_ecrecover() {
+        let key = SigningKey::random(&mut rand::thread_rng());
+        let address = address_of(key.verifying_key());
+        let message = channel_state_message(H256::random(), 1, H256::random());
+
+        let signature = sign_recoverable(&key, message.as_bytes()).unwrap();
+        assert!(signature[64] == 27 || signature[64] == 28);
+        assert_eq!(recover_signer(message.as_bytes(), &signature).unwrap(), address);
+
+        // ethers recovers the same signer, as the contracts' ECDSA.recover does
+        let ethers_signature = ethers::types::Signature::try_from(&signature[..]).unwrap();
+        assert_eq!(ethers_signature.recover(message.as_bytes()).unwrap(), address);
+
+        assert_ne!(recover_signer(H256::random().as_bytes(), &signature).unwrap(), address);
+        let mut bad_v = signature;
+        bad_v[64] = 29;
+        assert!(recover_signer(message.as_bytes(), &bad_v).is_err());
+
+        // The high-s twin of a valid signature is refused
+        let low = Signature::try_from(&signature[..64]).unwrap();
+        let high = Signature::from_scalars(low.r(), -low.s()).unwrap();
+        let mut malleated = signature;
+        malleated[..64].copy_from_slice(&high.to_bytes());
+        malleated[64] ^= 1;
+        assert!(recover_signer(message.as_bytes(), &malleated).is_err());
+
+        let mut verifier = SignatureVerifier::new(1);
+        let mut set = SignatureSet {
+            signatures: HashMap::new(),
+            message_hash: message,
+            timestamp: 0,
+        };
+        set.signatures.insert(address, signature.to_vec());
+        assert!(verifier.verify_quorum(&set, &[address], Quorum::All).is_ok());
+
+        // No key registration needed, and a registered key doesn't override recovery
+        verifier.add_verifying_key(Address::random(), *key.verifying_key());
+        set.signatures.insert(Address::random(), signature.to_vec());
+        assert!(verifier.verify_quorum(&set, &set.signatures.keys().copied().collect::<Vec<_>>(), Quorum::All).is_err());
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip712::EIP712Domain;
+use ethers::types::{Address, NameOrAddress, Signature, H256, U256};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use flashchain_bridge::TransactionSigner;
+use flashchain_common::encoding::CanonicalState;
+use flashchain_common::types::AssetId;
+use crate::channel::closing::{ClosingProposal, TokenRelease};
+use crate::channel::open::OpenProposal;
+use crate::routing::swap::RateQuote;
+use super::signature::channel_state_message;
+use super::typed_data::TypedMessage;
+use super::{CryptoError, CryptoManager};
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Signing refused: {0}")]
+    Refused(String),
+    #[error("Signer unreachable: {0}")]
+    Transport(String),
+    #[error("Signer authentication failed")]
+    Unauthenticated,
+    #[error("Signer protocol error: {0}")]
+    Protocol(String),
+    #[error("Signed state store error: {0}")]
+    Store(String),
+}
+
+/// Something the node wants signed. Requests carry what is being agreed to rather
+/// than a hash, so the signer can check them against its policy before signing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum SignRequest {
+    /// A channel state to sign, under the id the bridge knows the channel by.
+    /// `previous` is the state it replaces, as the node sees it.
+    ChannelState {
+        channel_id: H256,
+        previous: Option<CanonicalState>,
+        state: CanonicalState,
+    },
+    OpenChannel(OpenProposal),
+    CloseChannel(ClosingProposal),
+    /// A payout of token deposits, signed along with a close
+    TokenRelease(TokenRelease),
+    RateQuote(RateQuote),
+    /// An on-chain transaction, such as a call to the bridge contracts
+    Transaction(TypedTransaction),
+    /// An EIP-712 message
+    TypedData {
+        domain: EIP712Domain,
+        message: TypedMessage,
+    },
+    /// Anything else, such as an invoice
+    Message(Vec<u8>),
+}
+
+impl SignRequest {
+    /// Bytes signed with an EIP-191 signature. Transactions and typed data are signed
+    /// over their digest instead.
+    pub fn message(&self) -> Vec<u8> {
+        match self {
+            SignRequest::ChannelState { channel_id, state, .. } => {
+                channel_state_message(*channel_id, state.sequence, state.hash()).as_bytes().to_vec()
+            }
+            SignRequest::OpenChannel(proposal) => proposal.commitment_message().as_bytes().to_vec(),
+            SignRequest::CloseChannel(proposal) => proposal.signing_message().as_bytes().to_vec(),
+            SignRequest::TokenRelease(release) => release.signing_message().as_bytes().to_vec(),
+            SignRequest::RateQuote(quote) => quote.digest().as_bytes().to_vec(),
+            SignRequest::Transaction(transaction) => transaction.sighash().as_bytes().to_vec(),
+            SignRequest::TypedData { domain, message } => message.digest(domain).as_bytes().to_vec(),
+            SignRequest::Message(message) => message.clone(),
+        }
+    }
+}
+
+/// Holds the node's keys and signs for it
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// Addresses this signer has keys for
+    async fn addresses(&self) -> Result<Vec<Address>, SignerError>;
+
+    /// Signs `request` with the key of `address`, returning r || s || v
+    async fn sign(&self, address: Address, request: SignRequest) -> Result<Vec<u8>, SignerError>;
+}
+
+/// What a signer agrees to sign, checked on the signer's side so a compromised
+/// node can't get arbitrary signatures out of it
+#[derive(Debug, Clone, Default)]
+pub struct SigningPolicy {
+    /// Sign `SignRequest::Message`, whose content can't be checked
+    pub allow_messages: bool,
+    /// Contracts transactions may call. Transactions to anything else are refused.
+    pub allowed_contracts: HashSet<Address>,
+    /// How much our balance may drop in one update without a matching HTLC or
+    /// escrow. Direct transfers need this above zero.
+    pub max_unmatched_decrease: U256,
+    /// Where signed states are persisted, so a restart can't be used to rewind them
+    pub state_file: Option<PathBuf>,
+    // Latest state signed for each channel
+    signed_states: HashMap<H256, CanonicalState>,
+    loaded: bool,
+}
+
+impl SigningPolicy {
+    pub fn with_messages(mut self) -> Self {
+        self.allow_messages = true;
+        self
+    }
+
+    pub fn with_contract(mut self, contract: Address) -> Self {
+        self.allowed_contracts.insert(contract);
+        self
+    }
+
+    pub fn with_max_unmatched_decrease(mut self, amount: U256) -> Self {
+        self.max_unmatched_decrease = amount;
+        self
+    }
+
+    pub fn with_state_file(mut self, path: PathBuf) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+
+    /// Loads the states signed before a restart, the first time it is called
+    pub fn load(&mut self) -> Result<(), SignerError> {
+        if self.loaded {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.state_file {
+            match std::fs::read(path) {
+                Ok(data) => {
+                    let states: Vec<(H256, CanonicalState)> = serde_json::from_slice(&data)
+                        .map_err(|e| SignerError::Store(e.to_string()))?;
+                    self.signed_states.extend(states);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(SignerError::Store(e.to_string())),
+            }
+        }
+
+        self.loaded = true;
+        Ok(())
+    }
+
+    pub fn check(&self, signer: Address, request: &SignRequest) -> Result<(), SignerError> {
+        match request {
+            SignRequest::ChannelState { channel_id, previous, state } => {
+                self.check_state(signer, *channel_id, previous.as_ref(), state)
+            }
+            SignRequest::OpenChannel(proposal) => {
+                if !proposal.participants.contains(&signer) {
+                    return Err(SignerError::Refused(format!("{:?} is not part of the channel", signer)));
+                }
+                if self.signed_states.contains_key(&proposal.bridge_channel_id()) {
+                    return Err(SignerError::Refused(format!("Channel {:?} is already open", proposal.channel_id)));
+                }
+                Ok(())
+            }
+            SignRequest::CloseChannel(proposal) => {
+                self.check_close(signer, proposal.bridge_channel_id, &proposal.final_state, |asset| {
+                    let fee_share = proposal.fee_shares.get(&signer).copied().unwrap_or_default();
+                    let paid = proposal.final_state.balance_of(asset, signer);
+                    if asset.is_native() { paid.saturating_add(fee_share) } else { paid }
+                })
+            }
+            // Only the token balances of a state we signed are paid out
+            SignRequest::TokenRelease(release) => {
+                let signed = self.signed_states.get(&release.channel_id)
+                    .filter(|signed| U256::from(signed.sequence) == release.nonce);
+                match signed {
+                    Some(signed) if !release.token.is_native()
+                        && signed.balance_of(release.token, release.recipient) == release.amount => Ok(()),
+                    _ => Err(SignerError::Refused(format!(
+                        "Release of {} {:?} isn't part of a signed state", release.amount, release.token.0,
+                    ))),
+                }
+            }
+            SignRequest::RateQuote(quote) => {
+                if quote.node != signer {
+                    return Err(SignerError::Refused(format!("Quote is for node {:?}", quote.node)));
+                }
+                Ok(())
+            }
+            SignRequest::Transaction(transaction) => {
+                if transaction.from().is_some_and(|from| *from != signer) {
+                    return Err(SignerError::Refused("Transaction is from another account".into()));
+                }
+                match transaction.to() {
+                    Some(NameOrAddress::Address(to)) if self.allowed_contracts.contains(to) => Ok(()),
+                    to => Err(SignerError::Refused(format!("Transactions to {:?} aren't allowed", to))),
+                }
+            }
+            SignRequest::TypedData { message, .. } => match message {
+                TypedMessage::State(message) => self.check_state(signer, message.channel_id, None, &message.state),
+                TypedMessage::Htlc(message) => {
+                    if message.htlc.sender != signer && message.htlc.receiver != signer {
+                        return Err(SignerError::Refused(format!("{:?} isn't part of the HTLC", signer)));
+                    }
+                    Ok(())
+                }
+                // Only the native asset is paid out by the typed close terms
+                TypedMessage::Close(message) => {
+                    let payout = message.payouts.iter()
+                        .find(|payout| payout.participant == signer)
+                        .map(|payout| payout.amount.saturating_add(payout.fee_share))
+                        .unwrap_or_default();
+                    match self.signed_states.get(&message.channel_id) {
+                        Some(signed) => self.check_payouts(signer, signed, |asset| {
+                            if asset.is_native() { payout } else { U256::zero() }
+                        }),
+                        None => Ok(()),
+                    }
+                }
+            },
+            SignRequest::Message(_) if self.allow_messages => Ok(()),
+            SignRequest::Message(_) => Err(SignerError::Refused("Raw messages aren't allowed".into())),
+        }
+    }
+
+    /// Remembers what was signed, for checking the requests that follow, and persists
+    /// it before the signature is handed out
+    pub fn record(&mut self, request: &SignRequest) -> Result<(), SignerError> {
+        let (channel_id, state) = match request {
+            SignRequest::ChannelState { channel_id, state, .. } => (*channel_id, state.clone()),
+            SignRequest::OpenChannel(proposal) => {
+                (proposal.bridge_channel_id(), CanonicalState::from(&proposal.initial_state()))
+            }
+            SignRequest::CloseChannel(proposal) => (proposal.bridge_channel_id, proposal.final_state.clone()),
+            SignRequest::TypedData { message: TypedMessage::State(message), .. } => {
+                (message.channel_id, message.state.clone())
+            }
+            _ => return Ok(()),
+        };
+
+        if self.signed_states.get(&channel_id).is_some_and(|signed| signed.sequence >= state.sequence) {
+            return Ok(());
+        }
+        self.signed_states.insert(channel_id, state);
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<(), SignerError> {
+        let Some(path) = &self.state_file else { return Ok(()) };
+
+        let states: Vec<(&H256, &CanonicalState)> = self.signed_states.iter().collect();
+        let data = serde_json::to_vec(&states).map_err(|e| SignerError::Store(e.to_string()))?;
+        // Written aside and renamed, so a crash never leaves a truncated file behind
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)
+            .and_then(|_| std::fs::rename(&temporary, path))
+            .map_err(|e| SignerError::Store(e.to_string()))
+    }
+
+    fn check_state(
+        &self,
+        signer: Address,
+        channel_id: H256,
+        previous: Option<&CanonicalState>,
+        state: &CanonicalState,
+    ) -> Result<(), SignerError> {
+        // What we signed ourselves is trusted over what the node says came before
+        let signed = self.signed_states.get(&channel_id);
+        if let Some(signed) = signed {
+            if state.sequence < signed.sequence {
+                return Err(SignerError::Refused(format!(
+                    "State {} is older than the signed state {}", state.sequence, signed.sequence,
+                )));
+            }
+            if state.sequence == signed.sequence {
+                return if state == signed {
+                    Ok(())
+                } else {
+                    Err(SignerError::Refused(format!("Another state {} was already signed", state.sequence)))
+                };
+            }
+        }
+
+        let base = match signed.or(previous) {
+            Some(base) => base,
+            None => return Err(SignerError::Refused(format!("No earlier state of channel {:?} to check against", channel_id))),
+        };
+
+        let assets: HashSet<AssetId> = base.balances.iter()
+            .chain(&state.balances)
+            .map(|balance| balance.asset)
+            .collect();
+        for asset in assets {
+            let before = base.balance_of(asset, signer);
+            let after = state.balance_of(asset, signer);
+            if after >= before {
+                continue;
+            }
+
+            let offered = state.htlcs.iter()
+                .filter(|htlc| htlc.sender == signer && htlc.asset == asset)
+                .filter(|htlc| base.htlcs.iter().all(|old| old.id != htlc.id))
+                .fold(U256::zero(), |total, htlc| total.saturating_add(htlc.amount));
+            let escrowed = state.escrows.iter()
+                .filter(|escrow| escrow.sender == signer && escrow.asset == asset)
+                .filter(|escrow| base.escrows.iter().all(|old| old.id != escrow.id))
+                .fold(U256::zero(), |total, escrow| total.saturating_add(escrow.amount));
+
+            let unmatched = (before - after).saturating_sub(offered.saturating_add(escrowed));
+            if unmatched > self.max_unmatched_decrease {
+                return Err(SignerError::Refused(format!(
+                    "Balance of {:?} drops by {} without a matching HTLC", asset.0, unmatched,
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A close may only settle to a newer state, paying out at least what the last
+    /// signed state gave us in every asset
+    fn check_close(
+        &self,
+        signer: Address,
+        channel_id: H256,
+        final_state: &CanonicalState,
+        payout: impl Fn(AssetId) -> U256,
+    ) -> Result<(), SignerError> {
+        let Some(signed) = self.signed_states.get(&channel_id) else { return Ok(()) };
+
+        if final_state.sequence <= signed.sequence {
+            return Err(SignerError::Refused(format!(
+                "Close settles to state {}, {} was already signed", final_state.sequence, signed.sequence,
+            )));
+        }
+        self.check_payouts(signer, signed, payout)
+    }
+
+    fn check_payouts(
+        &self,
+        signer: Address,
+        signed: &CanonicalState,
+        payout: impl Fn(AssetId) -> U256,
+    ) -> Result<(), SignerError> {
+        for balance in signed.balances.iter().filter(|balance| balance.participant == signer) {
+            let paid = payout(balance.asset);
+            if paid < balance.amount {
+                return Err(SignerError::Refused(format!(
+                    "Close pays out {} of the {} last signed in {:?}", paid, balance.amount, balance.asset.0,
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Signs in process with keys held by a `CryptoManager`. Used in tests, and behind
+/// the socket of a remote signer.
+pub struct LocalSigner {
+    keys: CryptoManager,
+    policy: Mutex<SigningPolicy>,
+}
+
+impl LocalSigner {
+    pub fn new(keys: CryptoManager, policy: SigningPolicy) -> Self {
+        Self {
+            keys,
+            policy: Mutex::new(policy),
+        }
+    }
+}
+
+#[async_trait]
+impl Signer for LocalSigner {
+    async fn addresses(&self) -> Result<Vec<Address>, SignerError> {
+        Ok(self.keys.addresses())
+    }
+
+    async fn sign(&self, address: Address, request: SignRequest) -> Result<Vec<u8>, SignerError> {
+        // Held while signing, so concurrent requests are checked one after another
+        let mut policy = self.policy.lock().await;
+        policy.load()?;
+        policy.check(address, &request)?;
+
+        let signature = self.keys.sign_locally(&address, &request)?;
+        policy.record(&request)?;
+        Ok(signature)
+    }
+}
+
+/// Signs the bridge's transactions through a `CryptoManager`, and so through its
+/// `Signer` when one is configured
+pub struct BridgeTransactionSigner {
+    crypto: Arc<CryptoManager>,
+    address: Address,
+}
+
+impl BridgeTransactionSigner {
+    pub fn new(crypto: Arc<CryptoManager>, address: Address) -> Self {
+        Self { crypto, address }
+    }
+}
+
+#[async_trait]
+impl TransactionSigner for BridgeTransactionSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn sign_transaction(&self, transaction: &TypedTransaction) -> anyhow::Result<Signature> {
+        let signature = self.crypto.sign(&self.address, SignRequest::Transaction(transaction.clone())).await?;
+        let mut signature = Signature::try_from(signature.as_slice())?;
+
+        // Signers return v as 27 or 28. Legacy transactions fold in the chain id
+        // (EIP-155), typed ones carry the bare recovery id.
+        let recovery_id = signature.v - 27;
+        signature.v = match transaction {
+            TypedTransaction::Legacy(legacy) => match legacy.chain_id {
+                Some(chain_id) => recovery_id + 35 + 2 * chain_id.as_u64(),
+                None => signature.v,
+            },
+            _ => recovery_id,
+        };
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flashchain_common::encoding::{CanonicalBalance, CanonicalHtlc};
+    use crate::crypto::keystore::{KdfConfig, Keystore};
+    use crate::crypto::signature::recover_signer;
+
+    fn state(sequence: u64, ours: u64, theirs: u64, htlcs: Vec<CanonicalHtlc>, us: Address, them: Address) -> CanonicalState {
+        CanonicalState::new(
+            sequence,
+            vec![
+                CanonicalBalance { asset: AssetId::NATIVE, participant: us, amount: U256::from(ours) },
+                CanonicalBalance { asset: AssetId::NATIVE, participant: them, amount: U256::from(theirs) },
+            ],
+            htlcs,
+            vec![],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_policy_refuses_unmatched_decreases_and_stale_states() {
+        let mut keys = CryptoManager::new();
+        let us = keys.generate_keypair().unwrap();
+        let them = Address::random();
+        let signer = LocalSigner::new(keys, SigningPolicy::default());
+        let channel_id = H256::random();
+
+        let opening = state(0, 500, 500, vec![], us, them);
+        let request = |previous: &CanonicalState, next: &CanonicalState| SignRequest::ChannelState {
+            channel_id,
+            previous: Some(previous.clone()),
+            state: next.clone(),
+        };
+
+        // Paying them directly isn't backed by anything
+        let paid = state(1, 400, 600, vec![], us, them);
+        assert!(matches!(signer.sign(us, request(&opening, &paid)).await, Err(SignerError::Refused(_))));
+
+        // Offering an HTLC of the same amount is
+        let htlc = CanonicalHtlc {
+            id: H256::random(),
+            asset: AssetId::NATIVE,
+            sender: us,
+            receiver: them,
+            amount: U256::from(100),
+            hash_lock: H256::random(),
+            expiration: 1000,
+        };
+        let offered = state(1, 400, 500, vec![htlc], us, them);
+        let signature = signer.sign(us, request(&opening, &offered)).await.unwrap();
+        let message = channel_state_message(channel_id, offered.sequence, offered.hash());
+        assert_eq!(recover_signer(message.as_bytes(), &signature).unwrap(), us);
+
+        // Signing the same state again is fine, a different one at the same sequence isn't
+        assert!(signer.sign(us, request(&opening, &offered)).await.is_ok());
+        let conflicting = state(1, 500, 500, vec![], us, them);
+        assert!(matches!(signer.sign(us, request(&opening, &conflicting)).await, Err(SignerError::Refused(_))));
+
+        // The node can't rewind the baseline to an older state
+        let drained = state(2, 0, 1000, vec![], us, them);
+        let forged_previous = state(1, 0, 1000, vec![], us, them);
+        assert!(matches!(signer.sign(us, request(&forged_previous, &drained)).await, Err(SignerError::Refused(_))));
+
+        assert!(matches!(
+            signer.sign(us, SignRequest::Message(b"anything".to_vec())).await,
+            Err(SignerError::Refused(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_token_releases_only_pay_signed_balances() {
+        let mut keys = CryptoManager::new();
+        let us = keys.generate_keypair().unwrap();
+        let them = Address::random();
+        let signer = LocalSigner::new(keys, SigningPolicy::default());
+        let channel_id = H256::random();
+        let token = AssetId(Address::random());
+
+        let opening = state(0, 500, 500, vec![], us, them);
+        let mut settled = state(1, 500, 500, vec![], us, them);
+        settled.balances.push(CanonicalBalance { asset: token, participant: them, amount: U256::from(70) });
+        let settled = CanonicalState::new(settled.sequence, settled.balances, vec![], vec![]);
+        signer.sign(us, SignRequest::ChannelState {
+            channel_id,
+            previous: Some(opening),
+            state: settled,
+        }).await.unwrap();
+
+        let release = TokenRelease {
+            channel_id,
+            token,
+            amount: U256::from(70),
+            recipient: them,
+            nonce: U256::one(),
+        };
+        let signature = signer.sign(us, SignRequest::TokenRelease(release.clone())).await.unwrap();
+        assert_eq!(recover_signer(release.signing_message().as_bytes(), &signature).unwrap(), us);
+
+        // More than the signed balance, or against another state, is refused
+        let inflated = TokenRelease { amount: U256::from(71), ..release.clone() };
+        assert!(matches!(signer.sign(us, SignRequest::TokenRelease(inflated)).await, Err(SignerError::Refused(_))));
+        let replayed = TokenRelease { nonce: U256::from(2), ..release.clone() };
+        assert!(matches!(signer.sign(us, SignRequest::TokenRelease(replayed)).await, Err(SignerError::Refused(_))));
+        let redirected = TokenRelease { recipient: Address::random(), ..release };
+        assert!(matches!(signer.sign(us, SignRequest::TokenRelease(redirected)).await, Err(SignerError::Refused(_))));
+    }
+
+    #[tokio::test]
+    async fn test_signed_states_survive_restarts() {
+        let keystore = || CryptoManager::new()
+            .with_keystore(Keystore::in_memory().with_kdf(KdfConfig::Scrypt { log_n: 10, r: 8, p: 1 }));
+        let mut keys = keystore();
+        let us = keys.create_key("password").unwrap();
+        let exported = keys.export_key(&us).unwrap();
+        let them = Address::random();
+        let channel_id = H256::random();
+        let path = std::env::temp_dir().join(format!("flashchain-signed-{}.json", H256::random()));
+        let policy = || SigningPolicy::default().with_state_file(path.clone());
+
+        // Nothing to check a state against
+        let signer = LocalSigner::new(keys, policy());
+        let opening = state(0, 500, 500, vec![], us, them);
+        let unanchored = SignRequest::ChannelState { channel_id, previous: None, state: opening.clone() };
+        assert!(matches!(signer.sign(us, unanchored).await, Err(SignerError::Refused(_))));
+
+        let received = state(1, 600, 400, vec![], us, them);
+        signer.sign(us, SignRequest::ChannelState {
+            channel_id,
+            previous: Some(opening.clone()),
+            state: received,
+        }).await.unwrap();
+
+        // After a restart the node can't rewind the channel to the opening state
+        let mut keys = keystore();
+        keys.import_key(&exported, "password").unwrap();
+        let restarted = LocalSigner::new(keys, policy());
+        let rewound = state(2, 500, 500, vec![], us, them);
+        assert!(matches!(
+            restarted.sign(us, SignRequest::ChannelState { channel_id, previous: Some(opening), state: rewound }).await,
+            Err(SignerError::Refused(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
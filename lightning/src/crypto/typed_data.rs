@@ -0,0 +1,477 @@
+use ethers::abi::{self, Token};
+use ethers::types::transaction::eip712::EIP712Domain;
+use ethers::types::{Address, H256, U256};
+use k256::ecdsa::SigningKey;
+use serde::{Serialize, Deserialize};
+
+use flashchain_common::encoding::{CanonicalBalance, CanonicalEscrow, CanonicalHtlc, CanonicalState};
+use flashchain_common::types::NetworkConfig;
+use super::signature::{self, RECOVERABLE_SIGNATURE_LENGTH};
+use super::CryptoError;
+
+/// Name of the EIP-712 signing domain of channel messages
+pub const DOMAIN_NAME: &str = "FlashChain Channel";
+/// Version of the EIP-712 signing domain. Signatures don't carry over between versions.
+pub const DOMAIN_VERSION: &str = "1";
+
+const BALANCE_TYPE: &str = "Balance(address token,address participant,uint256 amount)";
+const HTLC_TYPE: &str = "Htlc(bytes32 id,address token,address sender,address receiver,uint256 amount,bytes32 hashLock,uint256 expiration)";
+const ESCROW_TYPE: &str = "Escrow(bytes32 id,address token,address sender,address recipient,uint256 amount,uint256 released,uint256 threshold,uint256 timeout,address[] signers)";
+const PAYOUT_TYPE: &str = "Payout(address participant,uint256 amount,uint256 feeShare)";
+
+/// The domain channel messages are signed in on `network`, with `BridgeCore` as the
+/// verifying contract. It checks typed channel states in `updateChannelStateTyped`;
+/// HTLC and close messages are only verified off-chain.
+pub fn channel_domain(network: &NetworkConfig) -> EIP712Domain {
+    EIP712Domain {
+        name: Some(DOMAIN_NAME.to_string()),
+        version: Some(DOMAIN_VERSION.to_string()),
+        chain_id: Some(U256::from(network.chain_id)),
+        verifying_contract: Some(network.contracts.bridge_core),
+        salt: None,
+    }
+}
+
+/// A struct with an EIP-712 type
+pub trait TypedStruct {
+    /// `encodeType` of the struct, with the types it references appended by name
+    fn encode_type() -> String;
+
+    /// `encodeData` of the struct without its type hash, one 32-byte word per member
+    fn encode_data(&self) -> Vec<u8>;
+
+    fn type_hash() -> H256 {
+        H256::from(keccak256(Self::encode_type().as_bytes()))
+    }
+
+    /// `hashStruct`
+    fn struct_hash(&self) -> H256 {
+        let mut data = Self::type_hash().as_bytes().to_vec();
+        data.extend_from_slice(&self.encode_data());
+        H256::from(keccak256(&data))
+    }
+}
+
+/// `keccak256("\x19\x01" || domainSeparator || structHash)`
+pub fn typed_data_digest(domain_separator: H256, struct_hash: H256) -> H256 {
+    let mut data = Vec::with_capacity(66);
+    data.extend_from_slice(b"\x19\x01");
+    data.extend_from_slice(domain_separator.as_bytes());
+    data.extend_from_slice(struct_hash.as_bytes());
+    H256::from(keccak256(&data))
+}
+
+/// The digest `eth_signTypedData_v4` signs for `message` in `domain`
+pub fn typed_data_hash<T: TypedStruct>(domain: &EIP712Domain, message: &T) -> H256 {
+    typed_data_digest(H256::from(domain.separator()), message.struct_hash())
+}
+
+pub fn sign_typed_data<T: TypedStruct>(
+    key: &SigningKey,
+    domain: &EIP712Domain,
+    message: &T,
+) -> Result<[u8; RECOVERABLE_SIGNATURE_LENGTH], CryptoError> {
+    signature::sign_digest(key, typed_data_hash(domain, message))
+}
+
+/// Address that signed `message` in `domain`
+pub fn recover_typed_data_signer<T: TypedStruct>(
+    domain: &EIP712Domain,
+    message: &T,
+    signature: &[u8],
+) -> Result<Address, CryptoError> {
+    signature::recover_digest_signer(typed_data_hash(domain, message), signature)
+}
+
+/// A channel state at a sequence number
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateMessage {
+    pub channel_id: H256,
+    pub state: CanonicalState,
+}
+
+impl StateMessage {
+    pub fn new(channel_id: H256, state: impl Into<CanonicalState>) -> Self {
+        Self { channel_id, state: state.into() }
+    }
+}
+
+/// An HTLC offered in a channel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HtlcMessage {
+    pub channel_id: H256,
+    pub htlc: CanonicalHtlc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClosePayout {
+    pub participant: Address,
+    pub amount: U256,
+    pub fee_share: U256,
+}
+
+/// The terms a channel is closed cooperatively on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloseMessage {
+    pub channel_id: H256,
+    pub fee: U256,
+    /// Sorted by participant
+    pub payouts: Vec<ClosePayout>,
+}
+
+/// A typed message a `Signer` can be asked to sign
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "message", rename_all = "snake_case")]
+pub enum TypedMessage {
+    State(StateMessage),
+    Htlc(HtlcMessage),
+    Close(CloseMessage),
+}
+
+impl TypedMessage {
+    /// The digest `eth_signTypedData_v4` signs for the message in `domain`
+    pub fn digest(&self, domain: &EIP712Domain) -> H256 {
+        match self {
+            TypedMessage::State(message) => typed_data_hash(domain, message),
+            TypedMessage::Htlc(message) => typed_data_hash(domain, message),
+            TypedMessage::Close(message) => typed_data_hash(domain, message),
+        }
+    }
+}
+
+impl From<StateMessage> for TypedMessage {
+    fn from(message: StateMessage) -> Self {
+        TypedMessage::State(message)
+    }
+}
+
+impl From<HtlcMessage> for TypedMessage {
+    fn from(message: HtlcMessage) -> Self {
+        TypedMessage::Htlc(message)
+    }
+}
+
+impl From<CloseMessage> for TypedMessage {
+    fn from(message: CloseMessage) -> Self {
+        TypedMessage::Close(message)
+    }
+}
+
+impl TypedStruct for CanonicalBalance {
+    fn encode_type() -> String {
+        BALANCE_TYPE.to_string()
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        abi::encode(&[
+            Token::Address(self.asset.0),
+            Token::Address(self.participant),
+            Token::Uint(self.amount),
+        ])
+    }
+}
+
+impl TypedStruct for CanonicalHtlc {
+    fn encode_type() -> String {
+        HTLC_TYPE.to_string()
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        abi::encode(&[
+            Token::FixedBytes(self.id.as_bytes().to_vec()),
+            Token::Address(self.asset.0),
+            Token::Address(self.sender),
+            Token::Address(self.receiver),
+            Token::Uint(self.amount),
+            Token::FixedBytes(self.hash_lock.as_bytes().to_vec()),
+            Token::Uint(U256::from(self.expiration)),
+        ])
+    }
+}
+
+impl TypedStruct for CanonicalEscrow {
+    fn encode_type() -> String {
+        ESCROW_TYPE.to_string()
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        let signers = abi::encode(&self.signers.iter().copied().map(Token::Address).collect::<Vec<_>>());
+
+        abi::encode(&[
+            Token::FixedBytes(self.id.as_bytes().to_vec()),
+            Token::Address(self.asset.0),
+            Token::Address(self.sender),
+            Token::Address(self.recipient),
+            Token::Uint(self.amount),
+            Token::Uint(self.released),
+            Token::Uint(U256::from(self.threshold)),
+            Token::Uint(U256::from(self.timeout)),
+            Token::FixedBytes(keccak256(&signers).to_vec()),
+        ])
+    }
+}
+
+impl TypedStruct for StateMessage {
+    fn encode_type() -> String {
+        format!(
+            "ChannelState(bytes32 channelId,uint256 sequence,Balance[] balances,Htlc[] htlcs,Escrow[] escrows){}{}{}",
+            BALANCE_TYPE, ESCROW_TYPE, HTLC_TYPE,
+        )
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        abi::encode(&[
+            Token::FixedBytes(self.channel_id.as_bytes().to_vec()),
+            Token::Uint(U256::from(self.state.sequence)),
+            Token::FixedBytes(hash_array(&self.state.balances).as_bytes().to_vec()),
+            Token::FixedBytes(hash_array(&self.state.htlcs).as_bytes().to_vec()),
+            Token::FixedBytes(hash_array(&self.state.escrows).as_bytes().to_vec()),
+        ])
+    }
+}
+
+impl TypedStruct for HtlcMessage {
+    fn encode_type() -> String {
+        format!("HtlcOffer(bytes32 channelId,Htlc htlc){}", HTLC_TYPE)
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        abi::encode(&[
+            Token::FixedBytes(self.channel_id.as_bytes().to_vec()),
+            Token::FixedBytes(self.htlc.struct_hash().as_bytes().to_vec()),
+        ])
+    }
+}
+
+impl TypedStruct for ClosePayout {
+    fn encode_type() -> String {
+        PAYOUT_TYPE.to_string()
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        abi::encode(&[
+            Token::Address(self.participant),
+            Token::Uint(self.amount),
+            Token::Uint(self.fee_share),
+        ])
+    }
+}
+
+impl TypedStruct for CloseMessage {
+    fn encode_type() -> String {
+        format!("CloseChannel(bytes32 channelId,uint256 fee,Payout[] payouts){}", PAYOUT_TYPE)
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        abi::encode(&[
+            Token::FixedBytes(self.channel_id.as_bytes().to_vec()),
+            Token::Uint(self.fee),
+            Token::FixedBytes(hash_array(&self.payouts).as_bytes().to_vec()),
+        ])
+    }
+}
+
+// An array of structs is encoded as the hash of its members' struct hashes
+fn hash_array<T: TypedStruct>(items: &[T]) -> H256 {
+    let mut data = Vec::with_capacity(items.len() * 32);
+    for item in items {
+        data.extend_from_slice(item.struct_hash().as_bytes());
+    }
+    H256::from(keccak256(&data))
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    use sha3::{Digest, Keccak256};
+    let mut hasher = Keccak256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::transaction::eip712::{Eip712, TypedData};
+    use flashchain_common::types::AssetId;
+    use serde_json::json;
+
+    fn domain() -> EIP712Domain {
+        EIP712Domain {
+            name: Some(DOMAIN_NAME.to_string()),
+            version: Some(DOMAIN_VERSION.to_string()),
+            chain_id: Some(U256::from(31337)),
+            verifying_contract: Some(Address::repeat_byte(0xbc)),
+            salt: None,
+        }
+    }
+
+    fn domain_json() -> serde_json::Value {
+        json!({
+            "name": DOMAIN_NAME,
+            "version": DOMAIN_VERSION,
+            "chainId": 31337,
+            "verifyingContract": Address::repeat_byte(0xbc),
+        })
+    }
+
+    fn domain_types() -> serde_json::Value {
+        json!([
+            { "name": "name", "type": "string" },
+            { "name": "version", "type": "string" },
+            { "name": "chainId", "type": "uint256" },
+            { "name": "verifyingContract", "type": "address" },
+        ])
+    }
+
+    #[test]
+    fn test_state_digest_matches_eth_sign_typed_data() {
+        let (alice, bob) = (Address::repeat_byte(0xa1), Address::repeat_byte(0xb0));
+        let token = AssetId(Address::repeat_byte(0x70));
+        let message = StateMessage::new(H256::repeat_byte(0xc1), CanonicalState::new(
+            7,
+            vec![
+                CanonicalBalance { asset: AssetId::NATIVE, participant: alice, amount: U256::from(600) },
+                CanonicalBalance { asset: token, participant: bob, amount: U256::from(400) },
+            ],
+            vec![CanonicalHtlc {
+                id: H256::repeat_byte(0x11),
+                asset: token,
+                sender: alice,
+                receiver: bob,
+                amount: U256::from(50),
+                hash_lock: H256::repeat_byte(0x22),
+                expiration: 1_700_000_000,
+            }],
+            vec![CanonicalEscrow {
+                id: H256::repeat_byte(0x33),
+                asset: AssetId::NATIVE,
+                sender: bob,
+                recipient: alice,
+                amount: U256::from(80),
+                released: U256::from(10),
+                threshold: 2,
+                timeout: 1_800_000_000,
+                signers: vec![alice, bob],
+            }],
+        ));
+
+        // What a wallet is handed for eth_signTypedData_v4
+        let typed_data: TypedData = serde_json::from_value(json!({
+            "types": {
+                "EIP712Domain": domain_types(),
+                "ChannelState": [
+                    { "name": "channelId", "type": "bytes32" },
+                    { "name": "sequence", "type": "uint256" },
+                    { "name": "balances", "type": "Balance[]" },
+                    { "name": "htlcs", "type": "Htlc[]" },
+                    { "name": "escrows", "type": "Escrow[]" },
+                ],
+                "Balance": [
+                    { "name": "token", "type": "address" },
+                    { "name": "participant", "type": "address" },
+                    { "name": "amount", "type": "uint256" },
+                ],
+                "Htlc": [
+                    { "name": "id", "type": "bytes32" },
+                    { "name": "token", "type": "address" },
+                    { "name": "sender", "type": "address" },
+                    { "name": "receiver", "type": "address" },
+                    { "name": "amount", "type": "uint256" },
+                    { "name": "hashLock", "type": "bytes32" },
+                    { "name": "expiration", "type": "uint256" },
+                ],
+                "Escrow": [
+                    { "name": "id", "type": "bytes32" },
+                    { "name": "token", "type": "address" },
+                    { "name": "sender", "type": "address" },
+                    { "name": "recipient", "type": "address" },
+                    { "name": "amount", "type": "uint256" },
+                    { "name": "released", "type": "uint256" },
+                    { "name": "threshold", "type": "uint256" },
+                    { "name": "timeout", "type": "uint256" },
+                    { "name": "signers", "type": "address[]" },
+                ],
+            },
+            "primaryType": "ChannelState",
+            "domain": domain_json(),
+            "message": {
+                "channelId": H256::repeat_byte(0xc1),
+                "sequence": 7,
+                "balances": [
+                    { "token": AssetId::NATIVE.0, "participant": alice, "amount": 600 },
+                    { "token": token.0, "participant": bob, "amount": 400 },
+                ],
+                "htlcs": [{
+                    "id": H256::repeat_byte(0x11),
+                    "token": token.0,
+                    "sender": alice,
+                    "receiver": bob,
+                    "amount": 50,
+                    "hashLock": H256::repeat_byte(0x22),
+                    "expiration": 1_700_000_000u64,
+                }],
+                "escrows": [{
+                    "id": H256::repeat_byte(0x33),
+                    "token": AssetId::NATIVE.0,
+                    "sender": bob,
+                    "recipient": alice,
+                    "amount": 80,
+                    "released": 10,
+                    "threshold": 2,
+                    "timeout": 1_800_000_000u64,
+                    "signers": [alice, bob],
+                }],
+            },
+        })).unwrap();
+
+        assert_eq!(H256::from(typed_data.domain_separator().unwrap()), H256::from(domain().separator()));
+        assert_eq!(H256::from(typed_data.struct_hash().unwrap()), message.struct_hash());
+        assert_eq!(H256::from(typed_data.encode_eip712().unwrap()), typed_data_hash(&domain(), &message));
+    }
+
+    #[test]
+    fn test_close_messages_sign_and_recover() {
+        let key = SigningKey::random(&mut rand::thread_rng());
+        let address = signature::address_of(key.verifying_key());
+        let message = CloseMessage {
+            channel_id: H256::repeat_byte(0xc1),
+            fee: U256::from(21),
+            payouts: vec![ClosePayout {
+                participant: address,
+                amount: U256::from(979),
+                fee_share: U256::from(21),
+            }],
+        };
+
+        let typed_data: TypedData = serde_json::from_value(json!({
+            "types": {
+                "EIP712Domain": domain_types(),
+                "CloseChannel": [
+                    { "name": "channelId", "type": "bytes32" },
+                    { "name": "fee", "type": "uint256" },
+                    { "name": "payouts", "type": "Payout[]" },
+                ],
+                "Payout": [
+                    { "name": "participant", "type": "address" },
+                    { "name": "amount", "type": "uint256" },
+                    { "name": "feeShare", "type": "uint256" },
+                ],
+            },
+            "primaryType": "CloseChannel",
+            "domain": domain_json(),
+            "message": {
+                "channelId": H256::repeat_byte(0xc1),
+                "fee": 21,
+                "payouts": [{ "participant": address, "amount": 979, "feeShare": 21 }],
+            },
+        })).unwrap();
+        assert_eq!(H256::from(typed_data.encode_eip712().unwrap()), typed_data_hash(&domain(), &message));
+
+        let signature = sign_typed_data(&key, &domain(), &message).unwrap();
+        assert_eq!(recover_typed_data_signer(&domain(), &message, &signature).unwrap(), address);
+
+        // Another chain or contract is another domain
+        let mut other = domain();
+        other.chain_id = Some(U256::one());
+        assert_ne!(recover_typed_data_signer(&other, &message, &signature).unwrap(), address);
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Mutex;
+use ethers::types::{Address, H256, U256};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::sync::{broadcast, oneshot};
+
+pub mod persistence;
+
+use crate::channel::state::ChannelStatus;
+use persistence::EventLog;
+
+#[derive(Error, Debug)]
+pub enum EventError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+    #[error("Subscriber lagged, {0} events skipped")]
+    Lagged(u64),
+    #[error("Event bus closed")]
+    Closed,
+    #[error("No event log configured")]
+    NoLog,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    ChannelOpened,
+    ChannelStatusChanged,
+    StateUpdated,
+    HtlcAdded,
+    HtlcResolved,
+    DisputeStarted,
+    ChannelClosed,
+    PeerConnected,
+    PeerDisconnected,
+    PaymentCompleted,
+    PaymentFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HtlcOutcome {
+    Fulfilled,
+    Expired,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LightningEvent {
+    ChannelOpened {
+        channel_id: H256,
+        participants: Vec<Address>,
+        capacity: U256,
+    },
+    ChannelStatusChanged {
+        channel_id: H256,
+        from: ChannelStatus,
+        to: ChannelStatus,
+    },
+    StateUpdated {
+        channel_id: H256,
+        sequence: u64,
+        state_hash: H256,
+    },
+    HtlcAdded {
+        channel_id: H256,
+        htlc_id: H256,
+        amount: U256,
+        hash_lock: H256,
+        timeout: u64,
+    },
+    HtlcResolved {
+        channel_id: H256,
+        htlc_id: H256,
+        outcome: HtlcOutcome,
+    },
+    DisputeStarted {
+        channel_id: H256,
+    },
+    ChannelClosed {
+        channel_id: H256,
+    },
+    PeerConnected {
+        peer: Address,
+    },
+    PeerDisconnected {
+        peer: Address,
+    },
+    PaymentCompleted {
+        payment_hash: H256,
+        amount: U256,
+        fees: U256,
+    },
+    PaymentFailed {
+        payment_hash: H256,
+        reason: String,
+    },
+}
+
+impl LightningEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            LightningEvent::ChannelOpened { .. } => EventKind::ChannelOpened,
+            LightningEvent::ChannelStatusChanged { .. } => EventKind::ChannelStatusChanged,
+            LightningEvent::StateUpdated { .. } => EventKind::StateUpdated,
+            LightningEvent::HtlcAdded { .. } => EventKind::HtlcAdded,
+            LightningEvent::HtlcResolved { .. } => EventKind::HtlcResolved,
+            LightningEvent::DisputeStarted { .. } => EventKind::DisputeStarted,
+            LightningEvent::ChannelClosed { .. } => EventKind::ChannelClosed,
+            LightningEvent::PeerConnected { .. } => EventKind::PeerConnected,
+            LightningEvent::PeerDisconnected { .. } => EventKind::PeerDisconnected,
+            LightningEvent::PaymentCompleted { .. } => EventKind::PaymentCompleted,
+            LightningEvent::PaymentFailed { .. } => EventKind::PaymentFailed,
+        }
+    }
+
+    /// Channel the event belongs to, if any
+    pub fn channel_id(&self) -> Option<H256> {
+        match self {
+            LightningEvent::ChannelOpened { channel_id, .. }
+            | LightningEvent::ChannelStatusChanged { channel_id, .. }
+            | LightningEvent::StateUpdated { channel_id, .. }
+            | LightningEvent::HtlcAdded { channel_id, .. }
+            | LightningEvent::HtlcResolved { channel_id, .. }
+            | LightningEvent::DisputeStarted { channel_id }
+            | LightningEvent::ChannelClosed { channel_id } => Some(*channel_id),
+            _ => None,
+        }
+    }
+}
+
+/// An event as delivered to subscribers and written to the log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    /// Position among all events published on the bus
+    pub sequence: u64,
+    /// Position among the events of the same channel
+    pub channel_sequence: Option<u64>,
+    pub timestamp: u64,
+    pub event: LightningEvent,
+}
+
+/// Selects events by kind and channel; an unset criterion matches everything
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    kinds: Option<HashSet<EventKind>>,
+    channels: Option<HashSet<H256>>,
+}
+
+impl EventFilter {
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn kind(mut self, kind: EventKind) -> Self {
+        self.kinds.get_or_insert_with(HashSet::new).insert(kind);
+        self
+    }
+
+    /// Only events of this channel, which leaves out events not tied to any channel
+    pub fn channel(mut self, channel_id: H256) -> Self {
+        self.channels.get_or_insert_with(HashSet::new).insert(channel_id);
+        self
+    }
+
+    pub fn matches(&self, event: &LightningEvent) -> bool {
+        if let Some(kinds) = &self.kinds {
+            if !kinds.contains(&event.kind()) {
+                return false;
+            }
+        }
+
+        match &self.channels {
+            Some(channels) => event.channel_id().is_some_and(|id| channels.contains(&id)),
+            None => true,
+        }
+    }
+}
+
+pub struct EventSubscription {
+    receiver: broadcast::Receiver<EventEnvelope>,
+    filter: EventFilter,
+}
+
+impl EventSubscription {
+    /// Waits for the next event that matches the filter. After `Lagged`, missed
+    /// events can be read back from the bus's log.
+    pub async fn recv(&mut self) -> Result<EventEnvelope, EventError> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(envelope) if self.filter.matches(&envelope.event) => return Ok(envelope),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => return Err(EventError::Lagged(skipped)),
+                Err(broadcast::error::RecvError::Closed) => return Err(EventError::Closed),
+            }
+        }
+    }
+}
+
+/// Next sequence numbers, overall and per channel
+struct Sequencer {
+    next_sequence: u64,
+    channel_sequences: HashMap<H256, u64>,
+}
+
+impl Sequencer {
+    /// Numbers the event as the next one, without taking the numbers yet
+    fn envelope(&self, event: LightningEvent) -> EventEnvelope {
+        EventEnvelope {
+            sequence: self.next_sequence,
+            channel_sequence: event.channel_id()
+                .map(|id| self.channel_sequences.get(&id).map_or(0, |last| last + 1)),
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            event,
+        }
+    }
+
+    fn advance(&mut self, envelope: &EventEnvelope) {
+        self.next_sequence = envelope.sequence + 1;
+        if let (Some(id), Some(sequence)) = (envelope.event.channel_id(), envelope.channel_sequence) {
+            self.channel_sequences.insert(id, sequence);
+        }
+    }
+}
+
+/// Work queued for the log writer, handled strictly in order
+enum LogCommand {
+    Append(LightningEvent),
+    Replay {
+        sequence: u64,
+        filter: EventFilter,
+        reply: oneshot::Sender<Result<(Vec<EventEnvelope>, EventSubscription), EventError>>,
+    },
+}
+
+/// Crate-wide stream of channel, peer and payment events.
+///
+/// Events are numbered in the order they are published, so every subscriber sees
+/// them in publish order, and those of a channel in the order its actor applied
+/// them. With a log, a writer thread numbers, appends and then broadcasts each
+/// event, and publishers only wait when its queue is full.
+pub struct EventBus {
+    sender: broadcast::Sender<EventEnvelope>,
+    // Numbers events on a bus without a log
+    sequencer: Mutex<Sequencer>,
+    writer: Option<SyncSender<LogCommand>>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self {
+            sender,
+            sequencer: Mutex::new(Sequencer {
+                next_sequence: 0,
+                channel_sequences: HashMap::new(),
+            }),
+            writer: None,
+        }
+    }
+
+    /// A bus that appends every event to `log` before delivering it, continuing
+    /// the sequence numbers already in the log. Up to `capacity` events wait to
+    /// be written before publishers block.
+    pub fn with_log(capacity: usize, log: EventLog) -> Result<Self, EventError> {
+        let (sender, _) = broadcast::channel(capacity);
+        let (writer, commands) = std::sync::mpsc::sync_channel(capacity);
+
+        let sequencer = Sequencer {
+            next_sequence: log.next_sequence(),
+            channel_sequences: log.channel_sequences().clone(),
+        };
+        let broadcaster = sender.clone();
+        std::thread::Builder::new()
+            .name("event-log".into())
+            .spawn(move || run_writer(log, sequencer, commands, broadcaster))?;
+
+        Ok(Self {
+            sender,
+            sequencer: Mutex::new(Sequencer {
+                next_sequence: 0,
+                channel_sequences: HashMap::new(),
+            }),
+            writer: Some(writer),
+        })
+    }
+
+    /// Numbers the event and delivers it. With a log, the event is numbered and
+    /// delivered once it has been written; one that fails to write is dropped.
+    pub fn publish(&self, event: LightningEvent) -> Result<(), EventError> {
+        if let Some(writer) = &self.writer {
+            return writer.send(LogCommand::Append(event)).map_err(|_| EventError::Closed);
+        }
+
+        let mut sequencer = self.sequencer.lock().map_err(|_| EventError::Closed)?;
+        let envelope = sequencer.envelope(event);
+        sequencer.advance(&envelope);
+        let _ = self.sender.send(envelope);
+
+        Ok(())
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> EventSubscription {
+        EventSubscription {
+            receiver: self.sender.subscribe(),
+            filter,
+        }
+    }
+
+    /// Logged events from `sequence` on, followed by a subscription to everything
+    /// after them, with no gap or overlap between the two
+    pub async fn subscribe_from(
+        &self,
+        sequence: u64,
+        filter: EventFilter,
+    ) -> Result<(Vec<EventEnvelope>, EventSubscription), EventError> {
+        let writer = self.writer.as_ref().ok_or(EventError::NoLog)?;
+
+        // Queued behind every event published so far, which are then all in the log
+        let (reply, response) = oneshot::channel();
+        writer.send(LogCommand::Replay { sequence, filter, reply })
+            .map_err(|_| EventError::Closed)?;
+
+        response.await.map_err(|_| EventError::Closed)?
+    }
+}
+
+/// Numbers, appends and then broadcasts each queued event until the bus is dropped.
+/// Being the only broadcaster on a logged bus, it can read the log and subscribe
+/// without missing an event.
+fn run_writer(
+    mut event_log: EventLog,
+    mut sequencer: Sequencer,
+    commands: Receiver<LogCommand>,
+    sender: broadcast::Sender<EventEnvelope>,
+) {
+    while let Ok(command) = commands.recv() {
+        match command {
+            LogCommand::Append(event) => {
+                // Numbered only once written, so a failed write leaves no gap, and
+                // nothing is delivered that didn't make it into the log
+                let envelope = sequencer.envelope(event);
+                if let Err(e) = event_log.append(&envelope) {
+                    log::error!("Failed to log {:?} event: {:?}", envelope.event.kind(), e);
+                    continue;
+                }
+                sequencer.advance(&envelope);
+                let _ = sender.send(envelope);
+            }
+            LogCommand::Replay { sequence, filter, reply } => {
+                let result = event_log.read_from(sequence).map(|envelopes| {
+                    let missed = envelopes.into_iter()
+                        .filter(|envelope| filter.matches(&envelope.event))
+                        .collect();
+                    let subscription = EventSubscription {
+                        receiver: sender.subscribe(),
+                        filter,
+                    };
+                    (missed, subscription)
+                });
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_updated(channel_id: H256, sequence: u64) -> LightningEvent {
+        LightningEvent::StateUpdated {
+            channel_id,
+            sequence,
+            state_hash: H256::random(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filtered_subscription_keeps_channel_order() {
+        let bus = EventBus::new(100);
+        let (a, b) = (H256::random(), H256::random());
+        let mut channel_a = bus.subscribe(EventFilter::all().channel(a));
+        let mut peers = bus.subscribe(EventFilter::all().kind(EventKind::PeerConnected));
+
+        for sequence in 0..3 {
+            bus.publish(state_updated(a, sequence)).unwrap();
+            bus.publish(state_updated(b, sequence)).unwrap();
+        }
+        bus.publish(LightningEvent::PeerConnected { peer: Address::random() }).unwrap();
+
+        for expected in 0..3 {
+            let envelope = channel_a.recv().await.unwrap();
+            assert_eq!(envelope.channel_sequence, Some(expected));
+            assert!(matches!(envelope.event, LightningEvent::StateUpdated { sequence, .. } if sequence == expected));
+        }
+
+        let envelope = peers.recv().await.unwrap();
+        assert_eq!(envelope.sequence, 6);
+        assert_eq!(envelope.channel_sequence, None);
+    }
+
+    #[tokio::test]
+    async fn test_log_replays_across_restarts() {
+        let path = std::env::temp_dir().join(format!("events-{:x}.log", H256::random()));
+        let channel_id = H256::random();
+
+        {
+            let bus = EventBus::with_log(100, EventLog::open(&path).unwrap()).unwrap();
+            let mut subscription = bus.subscribe(EventFilter::all());
+            for sequence in 0..3 {
+                bus.publish(state_updated(channel_id, sequence)).unwrap();
+            }
+            // Delivered events have been written
+            for _ in 0..3 {
+                subscription.recv().await.unwrap();
+            }
+        }
+
+        let bus = EventBus::with_log(100, EventLog::open(&path).unwrap()).unwrap();
+        let (missed, mut subscription) = bus.subscribe_from(1, EventFilter::all()).await.unwrap();
+        assert_eq!(missed.iter().map(|e| e.sequence).collect::<Vec<_>>(), vec![1, 2]);
+
+        // Numbering carries on where the log left off
+        bus.publish(state_updated(channel_id, 3)).unwrap();
+        let envelope = subscription.recv().await.unwrap();
+        assert_eq!(envelope.sequence, 3);
+        assert_eq!(envelope.channel_sequence, Some(3));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_replay_joins_live_events_without_gap() {
+        let path = std::env::temp_dir().join(format!("events-{:x}.log", H256::random()));
+        let channel_id = H256::random();
+        let bus = EventBus::with_log(100, EventLog::open(&path).unwrap()).unwrap();
+
+        // Still queued for the writer when the replay is requested
+        for sequence in 0..2 {
+            bus.publish(state_updated(channel_id, sequence)).unwrap();
+        }
+        let (missed, mut subscription) = bus.subscribe_from(0, EventFilter::all()).await.unwrap();
+        bus.publish(state_updated(channel_id, 2)).unwrap();
+
+        assert_eq!(missed.iter().map(|e| e.sequence).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(subscription.recv().await.unwrap().sequence, 2);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use ethers::types::H256;
+
+use super::{EventEnvelope, EventError};
+
+/// Append-only file of events, one JSON envelope per line
+pub struct EventLog {
+    path: PathBuf,
+    file: File,
+    next_sequence: u64,
+    channel_sequences: HashMap<H256, u64>,
+}
+
+impl EventLog {
+    /// Opens or creates the log, dropping a last line left half-written by a crash
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EventError> {
+        let path = path.as_ref().to_path_buf();
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut next_sequence = 0;
+        let mut channel_sequences = HashMap::new();
+        let mut valid_len = 0;
+
+        for line in data.split_inclusive(|&byte| byte == b'\n') {
+            let envelope: EventEnvelope = match serde_json::from_slice(line) {
+                Ok(envelope) => envelope,
+                Err(_) if !line.ends_with(b"\n") => {
+                    log::warn!("Dropping truncated entry at the end of {:?}", path);
+                    break;
+                }
+                Err(e) => return Err(EventError::Encoding(e.to_string())),
+            };
+
+            next_sequence = envelope.sequence + 1;
+            if let (Some(id), Some(sequence)) = (envelope.event.channel_id(), envelope.channel_sequence) {
+                channel_sequences.insert(id, sequence);
+            }
+            valid_len += line.len();
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid_len < data.len() {
+            file.set_len(valid_len as u64)?;
+        }
+
+        Ok(Self {
+            path,
+            file,
+            next_sequence,
+            channel_sequences,
+        })
+    }
+
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Last sequence number logged for each channel
+    pub fn channel_sequences(&self) -> &HashMap<H256, u64> {
+        &self.channel_sequences
+    }
+
+    /// Writes the envelope and syncs it to disk
+    pub fn append(&mut self, envelope: &EventEnvelope) -> Result<(), EventError> {
+        let mut line = serde_json::to_vec(envelope)
+            .map_err(|e| EventError::Encoding(e.to_string()))?;
+        line.push(b'\n');
+
+        let length = self.file.metadata()?.len();
+        if let Err(e) = self.file.write_all(&line).and_then(|_| self.file.sync_data()) {
+            // Don't leave part of a line for the next entry to be appended to
+            let _ = self.file.set_len(length);
+            return Err(e.into());
+        }
+
+        self.next_sequence = envelope.sequence + 1;
+        if let (Some(id), Some(sequence)) = (envelope.event.channel_id(), envelope.channel_sequence) {
+            self.channel_sequences.insert(id, sequence);
+        }
+
+        Ok(())
+    }
+
+    /// Every logged event from `sequence` on
+    pub fn read_from(&self, sequence: u64) -> Result<Vec<EventEnvelope>, EventError> {
+        let data = std::fs::read(&self.path)?;
+        let mut envelopes = Vec::new();
+
+        for line in data.split(|&byte| byte == b'\n').filter(|line| !line.is_empty()) {
+            let envelope: EventEnvelope = serde_json::from_slice(line)
+                .map_err(|e| EventError::Encoding(e.to_string()))?;
+            if envelope.sequence >= sequence {
+                envelopes.push(envelope);
+            }
+        }
+
+        Ok(envelopes)
+    }
+}
@@ -0,0 +1,6 @@
+pub mod channel;
+pub mod crypto;
+pub mod events;
+pub mod network;
+pub mod routing;
+pub mod state;
@@ -1,7 +1,8 @@
+use std::collections::HashMap;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
 use serde::{Serialize, Deserialize};
-use ethers::types::{Address, H256};
+use ethers::types::Address;
 use crate::network::{NetworkError, PeerMetrics, NetworkMessage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -145,18 +146,14 @@ pub struct PeerManager {
     max_peers: usize,
     peers: HashMap<Address, Peer>,
     banned_peers: HashMap<Address, u64>,
-    metrics_tx: mpsc::Sender<(Address, PeerMetrics)>,
 }
 
 impl PeerManager {
     pub fn new(max_peers: usize) -> Self {
-        let (metrics_tx, _) = mpsc::channel(1000);
-        
         Self {
             max_peers,
             peers: HashMap::new(),
             banned_peers: HashMap::new(),
-            metrics_tx,
         }
     }
 
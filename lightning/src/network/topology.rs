@@ -1,6 +1,6 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use ethers::types::{Address, H256};
-use priority_queue::PriorityQueue;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
 use tokio::sync::RwLock;
@@ -52,6 +52,12 @@ pub struct NetworkTopology {
     min_reliability_threshold: f64,
 }
 
+impl Default for NetworkTopology {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NetworkTopology {
     pub fn new() -> Self {
         Self {
@@ -91,10 +97,16 @@ impl NetworkTopology {
         // Validate connection
         if source_shard == target_shard {
             return Err(NetworkError::TopologyError(
-                TopologyError::InvalidConnection("Self-connection not allowed".into())
+                TopologyError::InvalidConnection("Self-connection not allowed".into()).to_string()
             ));
         }
 
+        for shard in [source_shard, target_shard] {
+            if self.get_shard_connections(shard).len() >= self.max_connections_per_shard {
+                return Err(NetworkError::TopologyError(TopologyError::CapacityExceeded.to_string()));
+            }
+        }
+
         let connection_id = self.generate_connection_id(source_shard, target_shard);
         let connection = ShardConnection {
             source_shard,
@@ -118,36 +130,35 @@ impl NetworkTopology {
         target_shard: u64,
     ) -> Result<Vec<u64>, NetworkError> {
         // Implement Dijkstra's algorithm for route finding
-        let mut distances: HashMap<u64, f64> = HashMap::new();
+        let mut distances: HashMap<u64, u64> = HashMap::new();
         let mut previous: HashMap<u64, u64> = HashMap::new();
-        let mut queue = PriorityQueue::new();
+        let mut queue = BinaryHeap::new();
 
-        // Initialize distances
-        for &shard in self.peer_shards.values() {
-            distances.insert(shard, f64::INFINITY);
-        }
-        distances.insert(source_shard, 0.0);
-        queue.push(source_shard, std::cmp::Reverse(0.0));
+        distances.insert(source_shard, 0);
+        queue.push(Reverse((0u64, source_shard)));
 
-        while let Some((current_shard, _)) = queue.pop() {
+        while let Some(Reverse((current_distance, current_shard))) = queue.pop() {
             if current_shard == target_shard {
                 return Ok(self.reconstruct_path(source_shard, target_shard, &previous));
             }
 
-            let current_distance = *distances.get(&current_shard).unwrap();
+            // Skip stale queue entries
+            if current_distance > *distances.get(&current_shard).unwrap_or(&u64::MAX) {
+                continue;
+            }
 
             // Check all neighbors
             for (neighbor_shard, connection) in self.get_shard_connections(current_shard) {
-                let distance = current_distance + connection.latency as f64;
-                if distance < *distances.get(&neighbor_shard).unwrap_or(&f64::INFINITY) {
+                let distance = current_distance + connection.latency;
+                if distance < *distances.get(&neighbor_shard).unwrap_or(&u64::MAX) {
                     distances.insert(neighbor_shard, distance);
                     previous.insert(neighbor_shard, current_shard);
-                    queue.push(neighbor_shard, std::cmp::Reverse(distance));
+                    queue.push(Reverse((distance, neighbor_shard)));
                 }
             }
         }
 
-        Err(NetworkError::TopologyError(TopologyError::RouteNotFound))
+        Err(NetworkError::TopologyError(TopologyError::RouteNotFound.to_string()))
     }
 
     pub async fn optimize(&mut self) -> Result<(), NetworkError> {
@@ -158,6 +169,20 @@ impl NetworkTopology {
         Ok(())
     }
 
+    /// Channels open across all shard connections
+    pub fn channel_count(&self) -> usize {
+        self.connections.values().map(|conn| conn.active_channels as usize).sum()
+    }
+
+    /// Mean latency of the shard connections, in milliseconds
+    pub fn average_latency(&self) -> f64 {
+        if self.connections.is_empty() {
+            return 0.0;
+        }
+        self.connections.values().map(|conn| conn.latency as f64).sum::<f64>()
+            / self.connections.len() as f64
+    }
+
     pub fn get_connection_metrics(&self, connection_id: H256) -> Option<ShardConnection> {
         self.connections.values()
             .find(|conn| conn.connection_id == connection_id)
@@ -180,7 +205,7 @@ impl NetworkTopology {
             }
         }
         Err(NetworkError::TopologyError(
-            TopologyError::InvalidConnection("Connection not found".into())
+            TopologyError::InvalidConnection("Connection not found".into()).to_string()
         ))
     }
 
@@ -196,7 +221,10 @@ impl NetworkTopology {
     fn get_shard_connections(&self, shard_id: u64) -> Vec<(u64, ShardConnection)> {
         self.connections.iter()
             .filter(|((source, target), _)| *source == shard_id || *target == shard_id)
-            .map(|((_, target), conn)| (*target, conn.clone()))
+            .map(|((source, target), conn)| {
+                let neighbor = if *source == shard_id { *target } else { *source };
+                (neighbor, conn.clone())
+            })
             .collect()
     }
 
@@ -232,8 +260,8 @@ impl NetworkTopology {
 
         for ((source, target), connection) in &self.connections {
             if connection.reliability >= self.min_reliability_threshold {
-                self.routing_table.get_mut(source)
-                    .unwrap()
+                self.routing_table.entry(*source)
+                    .or_default()
                     .insert(*target, vec![*target]);
             }
         }
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use ethers::types::Address;
+use tokio::sync::{mpsc, RwLock};
+
+use super::{NetworkError, NetworkMessage};
+
+/// Carries messages to other nodes
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, recipient: Address, message: NetworkMessage) -> Result<(), NetworkError>;
+}
+
+/// Delivers messages between network managers in the same process, straight into
+/// the recipient's inbound queue. Used in tests and simulations.
+#[derive(Default)]
+pub struct MemoryTransport {
+    inboxes: RwLock<HashMap<Address, mpsc::Sender<NetworkMessage>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delivers messages for `address` to `inbox`, usually `NetworkManager::inbound`
+    pub async fn register(&self, address: Address, inbox: mpsc::Sender<NetworkMessage>) {
+        self.inboxes.write().await.insert(address, inbox);
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn send(&self, recipient: Address, message: NetworkMessage) -> Result<(), NetworkError> {
+        let inbox = self.inboxes.read().await
+            .get(&recipient)
+            .cloned()
+            .ok_or(NetworkError::PeerNotFound(recipient))?;
+
+        inbox.send(message).await
+            .map_err(|e| NetworkError::MessageDeliveryFailed(e.to_string()))
+    }
+}
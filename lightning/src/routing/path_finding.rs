@@ -4,8 +4,11 @@ use ethers::types::{Address, H256, U256};
 use tokio::sync::RwLock;
 use serde::{Serialize, Deserialize};
 
+use flashchain_common::types::AssetId;
+
 use super::RoutingError;
 use super::RoutingPolicy;
+use super::swap::{RateQuote, SwapError, SwapStep, MAX_FEE_RATE};
 use crate::channel::Channel;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,11 +18,12 @@ pub struct RouteHint {
     pub target: Address,
     pub fee_rate: u32,
     pub timelock_delta: u64,
+    #[serde(default)]
+    pub asset: AssetId,
 }
 
 #[derive(Debug, Clone)]
 struct Node {
-    address: Address,
     channels: HashSet<H256>,
 }
 
@@ -31,14 +35,40 @@ struct ChannelInfo {
     fee_rate: u32,
     timelock_delta: u64,
     reliability: f64,
+    min_htlc: U256,
+    max_in_flight: U256,
+    token_capacity: HashMap<AssetId, U256>,
+}
+
+impl ChannelInfo {
+    fn capacity_of(&self, asset: AssetId) -> U256 {
+        if asset.is_native() {
+            return self.capacity;
+        }
+
+        self.token_capacity.get(&asset).copied().unwrap_or_default()
+    }
+}
+
+/// Extra cost weight of every conversion, for the counterparty risk it adds
+const SWAP_PENALTY: u64 = 500;
+
+/// A path that may change asset at gateways along the way
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwapPath {
+    pub channels: Vec<H256>,
+    pub swaps: Vec<SwapStep>,
+    pub amount_received: U256,
 }
 
 #[derive(Debug)]
 struct PathState {
     node: Address,
-    cost: u64,
+    asset: AssetId,
+    cost: U256,
     capacity: U256,
     path: Vec<H256>,
+    swaps: Vec<SwapStep>,
 }
 
 impl Ord for PathState {
@@ -65,6 +95,13 @@ pub struct PathFinder {
     nodes: RwLock<HashMap<Address, Node>>,
     channels: RwLock<HashMap<H256, ChannelInfo>>,
     reliability_history: RwLock<HashMap<H256, Vec<bool>>>,
+    quotes: RwLock<HashMap<Address, Vec<RateQuote>>>,
+}
+
+impl Default for PathFinder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PathFinder {
@@ -73,6 +110,7 @@ impl PathFinder {
             nodes: RwLock::new(HashMap::new()),
             channels: RwLock::new(HashMap::new()),
             reliability_history: RwLock::new(HashMap::new()),
+            quotes: RwLock::new(HashMap::new()),
         }
     }
 
@@ -85,37 +123,114 @@ impl PathFinder {
         hints: Option<Vec<RouteHint>>,
         policy: &RoutingPolicy,
     ) -> Result<Vec<Vec<H256>>, RoutingError> {
+        self.find_paths_avoiding(channels, source, target, amount, hints, policy, &HashSet::new()).await
+    }
+
+    /// Same as `find_paths`, but never routes through any of the `avoid` nodes
+    #[allow(clippy::too_many_arguments)]
+    pub async fn find_paths_avoiding(
+        &self,
+        channels: &HashMap<H256, Channel>,
+        source: Address,
+        target: Address,
+        amount: U256,
+        hints: Option<Vec<RouteHint>>,
+        policy: &RoutingPolicy,
+        avoid: &HashSet<Address>,
+    ) -> Result<Vec<Vec<H256>>, RoutingError> {
+        self.find_asset_paths(channels, source, target, AssetId::NATIVE, amount, hints, policy, avoid).await
+    }
+
+    /// Finds paths over channels that can all forward `amount` of `asset`
+    #[allow(clippy::too_many_arguments)]
+    pub async fn find_asset_paths(
+        &self,
+        _channels: &HashMap<H256, Channel>,
+        source: Address,
+        target: Address,
+        asset: AssetId,
+        amount: U256,
+        hints: Option<Vec<RouteHint>>,
+        policy: &RoutingPolicy,
+        avoid: &HashSet<Address>,
+    ) -> Result<Vec<Vec<H256>>, RoutingError> {
+        let paths = self.search(source, target, asset, asset, amount, hints, policy, avoid, None).await?;
+        Ok(paths.into_iter().map(|path| path.channels).collect())
+    }
+
+    /// Finds paths that send `send_asset` and deliver `receive_asset`, converting at
+    /// gateways whose quotes are valid at `now`
+    #[allow(clippy::too_many_arguments)]
+    pub async fn find_swap_paths(
+        &self,
+        _channels: &HashMap<H256, Channel>,
+        source: Address,
+        target: Address,
+        send_asset: AssetId,
+        receive_asset: AssetId,
+        amount: U256,
+        hints: Option<Vec<RouteHint>>,
+        policy: &RoutingPolicy,
+        now: u64,
+    ) -> Result<Vec<SwapPath>, RoutingError> {
+        self.search(source, target, send_asset, receive_asset, amount, hints, policy, &HashSet::new(), Some(now)).await
+    }
+
+    /// Cheapest-first search over (node, asset) pairs; conversions are only
+    /// considered when `now` is given
+    #[allow(clippy::too_many_arguments)]
+    async fn search(
+        &self,
+        source: Address,
+        target: Address,
+        send_asset: AssetId,
+        receive_asset: AssetId,
+        amount: U256,
+        hints: Option<Vec<RouteHint>>,
+        policy: &RoutingPolicy,
+        avoid: &HashSet<Address>,
+        now: Option<u64>,
+    ) -> Result<Vec<SwapPath>, RoutingError> {
         // Apply route hints if available
         if let Some(hints) = hints {
             self.apply_route_hints(hints).await?;
         }
 
         // Initialize data structures for pathfinding
-        let mut visited = HashSet::new();
+        let blocked: HashSet<Address> = avoid.iter()
+            .copied()
+            .filter(|&address| address != source && address != target)
+            .collect();
+        let mut visited: HashSet<(Address, AssetId)> = HashSet::new();
         let mut paths = Vec::new();
         let mut queue = BinaryHeap::new();
 
         // Initialize starting point
         queue.push(PathState {
             node: source,
-            cost: 0,
+            asset: send_asset,
+            cost: U256::zero(),
             capacity: amount,
             path: Vec::new(),
+            swaps: Vec::new(),
         });
 
         while let Some(current) = queue.pop() {
-            if current.node == target {
-                paths.push(current.path);
+            if current.node == target && current.asset == receive_asset {
+                paths.push(SwapPath {
+                    channels: current.path,
+                    swaps: current.swaps,
+                    amount_received: current.capacity,
+                });
                 if paths.len() >= 3 {  // Limit number of paths
                     break;
                 }
                 continue;
             }
 
-            if visited.contains(&current.node) {
+            if blocked.contains(&current.node) || !visited.insert((current.node, current.asset)) {
                 continue;
             }
-            visited.insert(current.node);
 
             // Get outgoing channels
             let nodes = self.nodes.read().await;
@@ -124,8 +239,17 @@ impl PathFinder {
             if let Some(node) = nodes.get(&current.node) {
                 for &channel_id in &node.channels {
                     if let Some(channel_info) = channels_info.get(&channel_id) {
-                        // Skip if channel doesn't have enough capacity
-                        if channel_info.capacity < current.capacity {
+                        // Skip if channel doesn't hold enough of the asset
+                        if channel_info.capacity_of(current.asset) < current.capacity {
+                            continue;
+                        }
+
+                        // Skip if the amount is outside the channel's announced HTLC limits,
+                        // which are denominated in the native asset
+                        if current.asset.is_native()
+                            && (current.capacity < channel_info.min_htlc
+                                || current.capacity > channel_info.max_in_flight)
+                        {
                             continue;
                         }
 
@@ -140,26 +264,64 @@ impl PathFinder {
                             channel_info.source
                         };
 
-                        if !visited.contains(&next_node) {
+                        if !blocked.contains(&next_node) && !visited.contains(&(next_node, current.asset)) {
                             let mut new_path = current.path.clone();
                             new_path.push(channel_id);
 
                             let new_cost = self.calculate_path_cost(
                                 &new_path,
-                                current.capacity,
+                                amount,
+                                &current.swaps,
                                 &channels_info,
                             )?;
 
                             queue.push(PathState {
                                 node: next_node,
+                                asset: current.asset,
                                 cost: new_cost,
                                 capacity: current.capacity,
                                 path: new_path,
+                                swaps: current.swaps.clone(),
                             });
                         }
                     }
                 }
             }
+
+            // Gateways between source and target may convert to another asset
+            let now = match now {
+                Some(now) if current.node != source && current.node != target => now,
+                _ => continue,
+            };
+
+            let quotes = self.quotes.read().await;
+            for quote in quotes.get(&current.node).into_iter().flatten() {
+                if quote.from_asset != current.asset
+                    || !quote.is_valid_at(now)
+                    || visited.contains(&(current.node, quote.to_asset))
+                {
+                    continue;
+                }
+
+                let converted = match quote.convert(current.capacity) {
+                    Some(converted) if !converted.is_zero() => converted,
+                    _ => continue,
+                };
+
+                let mut swaps = current.swaps.clone();
+                swaps.push(SwapStep::new(current.path.len(), quote.clone(), current.capacity, converted));
+
+                let new_cost = self.calculate_path_cost(&current.path, amount, &swaps, &channels_info)?;
+
+                queue.push(PathState {
+                    node: current.node,
+                    asset: quote.to_asset,
+                    cost: new_cost,
+                    capacity: converted,
+                    path: current.path.clone(),
+                    swaps,
+                });
+            }
         }
 
         if paths.is_empty() {
@@ -185,6 +347,68 @@ impl PathFinder {
         Ok(())
     }
 
+    pub async fn update_asset_capacity(
+        &self,
+        channel_id: H256,
+        asset: AssetId,
+        capacity: U256,
+    ) -> Result<(), RoutingError> {
+        let mut channels = self.channels.write().await;
+
+        if let Some(channel_info) = channels.get_mut(&channel_id) {
+            if asset.is_native() {
+                channel_info.capacity = capacity;
+            } else {
+                channel_info.token_capacity.insert(asset, capacity);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn update_channel_limits(
+        &self,
+        channel_id: H256,
+        min_htlc: U256,
+        max_in_flight: U256,
+    ) -> Result<(), RoutingError> {
+        let mut channels = self.channels.write().await;
+
+        if let Some(channel_info) = channels.get_mut(&channel_id) {
+            channel_info.min_htlc = min_htlc;
+            channel_info.max_in_flight = max_in_flight;
+        }
+
+        Ok(())
+    }
+
+    /// Makes a gateway's quote available to path finding, replacing its earlier quote for the pair.
+    /// Callers verify the signature first.
+    pub async fn add_swap_quote(&self, quote: RateQuote) -> Result<(), RoutingError> {
+        if quote.fee_rate > MAX_FEE_RATE {
+            return Err(SwapError::InvalidFeeRate(quote.fee_rate).into());
+        }
+
+        let mut quotes = self.quotes.write().await;
+        let node_quotes = quotes.entry(quote.node).or_insert_with(Vec::new);
+
+        node_quotes.retain(|existing| {
+            existing.from_asset != quote.from_asset || existing.to_asset != quote.to_asset
+        });
+        node_quotes.push(quote);
+
+        Ok(())
+    }
+
+    pub async fn prune_expired_quotes(&self, now: u64) {
+        let mut quotes = self.quotes.write().await;
+
+        for node_quotes in quotes.values_mut() {
+            node_quotes.retain(|quote| now < quote.valid_until);
+        }
+        quotes.retain(|_, node_quotes| !node_quotes.is_empty());
+    }
+
     pub async fn record_payment_result(
         &self,
         path: &[H256],
@@ -210,20 +434,29 @@ impl PathFinder {
         let mut nodes = self.nodes.write().await;
 
         for hint in hints {
+            // Assume sufficient capacity in the hinted asset only
+            let (capacity, token_capacity) = if hint.asset.is_native() {
+                (U256::max_value(), HashMap::new())
+            } else {
+                (U256::zero(), HashMap::from([(hint.asset, U256::max_value())]))
+            };
+
             channels.insert(hint.channel_id, ChannelInfo {
                 source: hint.source,
                 target: hint.target,
-                capacity: U256::max_value(), // Assume sufficient capacity
+                capacity,
                 fee_rate: hint.fee_rate,
                 timelock_delta: hint.timelock_delta,
                 reliability: 1.0,
+                min_htlc: U256::zero(),
+                max_in_flight: U256::max_value(),
+                token_capacity,
             });
 
             // Update node information
             for &address in &[hint.source, hint.target] {
                 let node = nodes.entry(address)
                     .or_insert_with(|| Node {
-                        address,
                         channels: HashSet::new(),
                     });
                 node.channels.insert(hint.channel_id);
@@ -237,28 +470,51 @@ impl PathFinder {
         &self,
         path: &[H256],
         amount: U256,
+        swaps: &[SwapStep],
         channels: &HashMap<H256, ChannelInfo>,
-    ) -> Result<u64, RoutingError> {
-        let mut total_cost = 0u64;
+    ) -> Result<U256, RoutingError> {
+        let mut total_cost = U256::zero();
+        let mut amount = amount;
+        let mut swaps = swaps.iter().peekable();
+
+        for (index, &channel_id) in path.iter().enumerate() {
+            // Conversions made before this hop change the amount it forwards
+            while let Some(swap) = swaps.next_if(|swap| swap.hop_index <= index) {
+                total_cost = total_cost.saturating_add(self.calculate_swap_cost(swap));
+                amount = swap.amount_out;
+            }
 
-        for &channel_id in path {
             if let Some(channel) = channels.get(&channel_id) {
                 // Calculate fee
-                let fee = (amount.as_u64() * channel.fee_rate as u64) / 1_000_000;
+                let fee = amount.saturating_mul(U256::from(channel.fee_rate)) / U256::from(1_000_000u64);
                 
                 // Add timelock penalty
-                let timelock_cost = channel.timelock_delta * 10;  // Weight factor for timelock
+                let timelock_cost = U256::from(channel.timelock_delta) * U256::from(10);  // Weight factor for timelock
 
                 // Add reliability factor
-                let reliability_cost = ((1.0 - channel.reliability) * 1000.0) as u64;
+                let reliability_cost = U256::from(((1.0 - channel.reliability) * 1000.0) as u64);
 
-                total_cost += fee + timelock_cost + reliability_cost;
+                total_cost = total_cost
+                    .saturating_add(fee)
+                    .saturating_add(timelock_cost)
+                    .saturating_add(reliability_cost);
             }
         }
 
+        // Conversions at the end of the path so far
+        for swap in swaps {
+            total_cost = total_cost.saturating_add(self.calculate_swap_cost(swap));
+        }
+
         Ok(total_cost)
     }
 
+    /// Conversion fee in the input asset, plus a flat penalty per conversion
+    fn calculate_swap_cost(&self, swap: &SwapStep) -> U256 {
+        let fee = swap.amount_in.saturating_mul(U256::from(swap.quote.fee_rate)) / U256::from(MAX_FEE_RATE);
+        fee.saturating_add(U256::from(SWAP_PENALTY))
+    }
+
     pub async fn get_channel_reliability(&self, channel_id: H256) -> f64 {
         let reliability = self.reliability_history.read().await;
         
@@ -278,9 +534,13 @@ impl PathFinder {
         let mut channels = self.channels.write().await;
         let mut nodes = self.nodes.write().await;
 
+        let mut reliabilities = HashMap::new();
+        for channel_id in channels.keys() {
+            reliabilities.insert(*channel_id, self.get_channel_reliability(*channel_id).await);
+        }
+
         channels.retain(|channel_id, channel_info| {
-            let reliability = self.get_channel_reliability(*channel_id).await;
-            let retain = reliability >= threshold;
+            let retain = reliabilities[channel_id] >= threshold;
 
             if !retain {
                 // Remove channel from nodes
@@ -327,6 +587,9 @@ mod tests {
                 fee_rate: 100,
                 timelock_delta: 40,
                 reliability: 1.0,
+                min_htlc: U256::zero(),
+                max_in_flight: U256::max_value(),
+                token_capacity: HashMap::new(),
             });
 
             channels.insert(channel2, ChannelInfo {
@@ -336,13 +599,15 @@ mod tests {
                 fee_rate: 100,
                 timelock_delta: 40,
                 reliability: 1.0,
+                min_htlc: U256::zero(),
+                max_in_flight: U256::max_value(),
+                token_capacity: HashMap::new(),
             });
 
             // Add nodes
             for &address in &[source, target, intermediate] {
                 let node = nodes.entry(address)
                     .or_insert_with(|| Node {
-                        address,
                         channels: HashSet::new(),
                     });
                 
@@ -375,6 +640,267 @@ mod tests {
         assert_eq!(paths[0], vec![channel1, channel2]);
     }
 
+    #[tokio::test]
+    async fn test_path_cost_of_large_amounts() {
+        let path_finder = PathFinder::new();
+        let channel_id = H256::random();
+        let channels = HashMap::from([(channel_id, ChannelInfo {
+            source: Address::random(),
+            target: Address::random(),
+            capacity: U256::max_value(),
+            fee_rate: 100,
+            timelock_delta: 40,
+            reliability: 1.0,
+            min_htlc: U256::zero(),
+            max_in_flight: U256::max_value(),
+            token_capacity: HashMap::new(),
+        })]);
+
+        // Amounts past u64 are costed in full rather than truncated
+        let amount = U256::from(u64::MAX) * 1_000_000;
+        let cost = path_finder.calculate_path_cost(&[channel_id], amount, &[], &channels).unwrap();
+        assert_eq!(cost, U256::from(u64::MAX) * 100 + 400);
+
+        let cost = path_finder.calculate_path_cost(&[channel_id], U256::max_value(), &[], &channels).unwrap();
+        assert_eq!(cost, U256::max_value() / 1_000_000 + 400);
+    }
+
+    #[tokio::test]
+    async fn test_path_finding_avoids_nodes() {
+        let path_finder = PathFinder::new();
+
+        let source = Address::random();
+        let target = Address::random();
+        let us = Address::random();
+        let other = Address::random();
+
+        // source - us - target is the cheap way round, source - other - target the detour
+        let edges = [
+            (H256::random(), source, us, 10u32),
+            (H256::random(), us, target, 10u32),
+            (H256::random(), source, other, 500u32),
+            (H256::random(), other, target, 500u32),
+        ];
+
+        {
+            let mut channels = path_finder.channels.write().await;
+            let mut nodes = path_finder.nodes.write().await;
+
+            for &(channel_id, a, b, fee_rate) in &edges {
+                channels.insert(channel_id, ChannelInfo {
+                    source: a,
+                    target: b,
+                    capacity: U256::from(1000000),
+                    fee_rate,
+                    timelock_delta: 40,
+                    reliability: 1.0,
+                    min_htlc: U256::zero(),
+                    max_in_flight: U256::max_value(),
+                    token_capacity: HashMap::new(),
+                });
+
+                for &address in &[a, b] {
+                    nodes.entry(address)
+                        .or_insert_with(|| Node {
+                            channels: HashSet::new(),
+                        })
+                        .channels.insert(channel_id);
+                }
+            }
+        }
+
+        let policy = RoutingPolicy {
+            max_hops: 3,
+            max_timelock: 144,
+            max_fee_rate: 1000,
+            min_channel_capacity: U256::from(1000),
+        };
+
+        let mut avoid = HashSet::new();
+        avoid.insert(us);
+
+        let paths = path_finder.find_paths_avoiding(
+            &HashMap::new(),
+            source,
+            target,
+            U256::from(1000),
+            None,
+            &policy,
+            &avoid,
+        ).await.unwrap();
+
+        assert_eq!(paths[0], vec![edges[2].0, edges[3].0]);
+        assert!(paths.iter().all(|path| !path.contains(&edges[0].0)));
+    }
+
+    #[tokio::test]
+    async fn test_path_finding_stays_in_asset() {
+        let path_finder = PathFinder::new();
+
+        let source = Address::random();
+        let target = Address::random();
+        let native_only = Address::random();
+        let token_node = Address::random();
+        let usdc = AssetId::token(Address::random());
+
+        // The cheap way round only holds the native asset
+        let edges = [
+            (H256::random(), source, native_only, 10u32, None),
+            (H256::random(), native_only, target, 10u32, None),
+            (H256::random(), source, token_node, 500u32, Some(usdc)),
+            (H256::random(), token_node, target, 500u32, Some(usdc)),
+        ];
+
+        {
+            let mut channels = path_finder.channels.write().await;
+            let mut nodes = path_finder.nodes.write().await;
+
+            for &(channel_id, a, b, fee_rate, token) in &edges {
+                let mut token_capacity = HashMap::new();
+                if let Some(token) = token {
+                    token_capacity.insert(token, U256::from(1000000));
+                }
+
+                channels.insert(channel_id, ChannelInfo {
+                    source: a,
+                    target: b,
+                    capacity: U256::from(1000000),
+                    fee_rate,
+                    timelock_delta: 40,
+                    reliability: 1.0,
+                    min_htlc: U256::zero(),
+                    max_in_flight: U256::max_value(),
+                    token_capacity,
+                });
+
+                for &address in &[a, b] {
+                    nodes.entry(address)
+                        .or_insert_with(|| Node {
+                            channels: HashSet::new(),
+                        })
+                        .channels.insert(channel_id);
+                }
+            }
+        }
+
+        let policy = RoutingPolicy {
+            max_hops: 3,
+            max_timelock: 144,
+            max_fee_rate: 1000,
+            min_channel_capacity: U256::from(1000),
+        };
+
+        let paths = path_finder.find_asset_paths(
+            &HashMap::new(),
+            source,
+            target,
+            usdc,
+            U256::from(1000),
+            None,
+            &policy,
+            &HashSet::new(),
+        ).await.unwrap();
+
+        assert_eq!(paths[0], vec![edges[2].0, edges[3].0]);
+        assert!(paths.iter().all(|path| !path.contains(&edges[0].0)));
+    }
+
+    #[tokio::test]
+    async fn test_path_finding_swaps_at_gateway() {
+        let path_finder = PathFinder::new();
+
+        let source = Address::random();
+        let target = Address::random();
+        let gateway = Address::random();
+        let usdc = AssetId::token(Address::random());
+
+        // source only holds the native asset with the gateway, target only USDC
+        let incoming = H256::random();
+        let outgoing = H256::random();
+
+        {
+            let mut channels = path_finder.channels.write().await;
+            let mut nodes = path_finder.nodes.write().await;
+
+            for &(channel_id, a, b, token) in &[(incoming, source, gateway, None), (outgoing, gateway, target, Some(usdc))] {
+                let (capacity, token_capacity) = match token {
+                    Some(token) => (U256::zero(), HashMap::from([(token, U256::from(10_000_000))])),
+                    None => (U256::from(1000000), HashMap::new()),
+                };
+
+                channels.insert(channel_id, ChannelInfo {
+                    source: a,
+                    target: b,
+                    capacity,
+                    fee_rate: 100,
+                    timelock_delta: 40,
+                    reliability: 1.0,
+                    min_htlc: U256::zero(),
+                    max_in_flight: U256::max_value(),
+                    token_capacity,
+                });
+
+                for &address in &[a, b] {
+                    nodes.entry(address)
+                        .or_insert_with(|| Node {
+                            channels: HashSet::new(),
+                        })
+                        .channels.insert(channel_id);
+                }
+            }
+        }
+
+        path_finder.add_swap_quote(RateQuote {
+            node: gateway,
+            from_asset: AssetId::NATIVE,
+            to_asset: usdc,
+            rate: U256::exp10(18) * 2000,
+            fee_rate: 1_000,
+            max_amount: U256::from(1000000),
+            valid_from: 100,
+            valid_until: 160,
+            signature: Vec::new(),
+        }).await.unwrap();
+
+        let policy = RoutingPolicy {
+            max_hops: 3,
+            max_timelock: 144,
+            max_fee_rate: 1000,
+            min_channel_capacity: U256::from(1000),
+        };
+
+        let paths = path_finder.find_swap_paths(
+            &HashMap::new(),
+            source,
+            target,
+            AssetId::NATIVE,
+            usdc,
+            U256::from(1000),
+            None,
+            &policy,
+            120,
+        ).await.unwrap();
+
+        assert_eq!(paths[0].channels, vec![incoming, outgoing]);
+        assert_eq!(paths[0].swaps.len(), 1);
+        assert_eq!(paths[0].swaps[0].hop_index, 1);
+        assert_eq!(paths[0].amount_received, U256::from(1_998_000));
+
+        // Once the quote lapses there is no way to deliver USDC
+        let expired = path_finder.find_swap_paths(
+            &HashMap::new(),
+            source,
+            target,
+            AssetId::NATIVE,
+            usdc,
+            U256::from(1000),
+            None,
+            &policy,
+            200,
+        ).await;
+        assert!(expired.is_err());
+    }
+
     #[tokio::test]
     async fn test_reliability_tracking() {
         let path_finder = PathFinder::new();
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::{RwLock, mpsc};
-use ethers::types::{Address, H256, U256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, RwLock};
+use ethers::types::{H256, U256};
 use serde::{Serialize, Deserialize};
+use flashchain_common::types::AssetId;
 
 use super::{Route, RoutingError};
 use crate::channel::Channel;
+use crate::channel::clock::ChainClock;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaymentInfo {
@@ -41,6 +43,7 @@ struct HtlcInfo {
     amount: U256,
     expiry: u64,
     hash: H256,
+    asset: AssetId,
 }
 
 pub struct PaymentProcessor {
@@ -48,23 +51,30 @@ pub struct PaymentProcessor {
     payment_statuses: Arc<RwLock<HashMap<H256, PaymentStatus>>>,
     htlcs: Arc<RwLock<HashMap<H256, Vec<HtlcInfo>>>>,
     results: Arc<RwLock<HashMap<H256, PaymentResult>>>,
-    status_tx: mpsc::Sender<(H256, PaymentStatus)>,
+    // Height each active payment times out at
+    expiries: Arc<RwLock<HashMap<H256, u64>>>,
+    status_tx: broadcast::Sender<(H256, PaymentStatus)>,
+    clock: Arc<dyn ChainClock>,
 }
 
 impl PaymentProcessor {
-    pub fn new() -> Self {
-        let (status_tx, _) = mpsc::channel(1000);
+    pub fn new(clock: Arc<dyn ChainClock>) -> Self {
+        let (status_tx, _) = broadcast::channel(1000);
         
         Self {
             active_payments: Arc::new(RwLock::new(HashMap::new())),
             payment_statuses: Arc::new(RwLock::new(HashMap::new())),
             htlcs: Arc::new(RwLock::new(HashMap::new())),
             results: Arc::new(RwLock::new(HashMap::new())),
+            expiries: Arc::new(RwLock::new(HashMap::new())),
             status_tx,
+            clock,
         }
     }
 
+    /// Starts tracking a payment, which times out once the chain passes the route's timelock
     pub async fn init_payment(&self, payment_info: PaymentInfo) -> Result<(), RoutingError> {
+        let current_height = self.current_height().await?;
         let mut active_payments = self.active_payments.write().await;
         let mut payment_statuses = self.payment_statuses.write().await;
         let mut htlcs = self.htlcs.write().await;
@@ -78,6 +88,8 @@ impl PaymentProcessor {
         active_payments.insert(payment_info.payment_hash, payment_info.clone());
         payment_statuses.insert(payment_info.payment_hash, PaymentStatus::Pending);
         htlcs.insert(payment_info.payment_hash, Vec::new());
+        self.expiries.write().await
+            .insert(payment_info.payment_hash, current_height + payment_info.route.total_timelock);
 
         Ok(())
     }
@@ -86,7 +98,7 @@ impl PaymentProcessor {
         &self,
         payment_hash: H256,
         hop_index: usize,
-        channel: &Channel,
+        _channel: &Channel,
     ) -> Result<(), RoutingError> {
         let mut payment_statuses = self.payment_statuses.write().await;
         let mut htlcs = self.htlcs.write().await;
@@ -99,26 +111,32 @@ impl PaymentProcessor {
             return Err(RoutingError::PaymentFailed("Invalid hop index".into()));
         }
 
-        // Create HTLC
+        // Create HTLC, timelocks are measured in blocks
+        let current_height = self.current_height().await?;
         let hop = &payment_info.route.channels[hop_index];
         let htlc = HtlcInfo {
             channel_id: hop.channel_id,
             amount: hop.amount,
-            expiry: current_timestamp() + hop.timelock,
+            expiry: current_height + hop.timelock,
             hash: payment_hash,
+            asset: hop.asset,
         };
 
         // Add HTLC to tracking
+        // The payment lasts as long as its latest expiring HTLC
+        let expiry = htlc.expiry;
         htlcs.get_mut(&payment_hash)
             .ok_or_else(|| RoutingError::PaymentFailed("Payment not found".into()))?
             .push(htlc);
+        let mut expiries = self.expiries.write().await;
+        let payment_expiry = expiries.entry(payment_hash).or_insert(expiry);
+        *payment_expiry = (*payment_expiry).max(expiry);
 
         // Update payment status
         payment_statuses.insert(payment_hash, PaymentStatus::InFlight);
 
         // Notify status change
-        self.status_tx.send((payment_hash, PaymentStatus::InFlight)).await
-            .map_err(|e| RoutingError::PaymentFailed(e.to_string()))?;
+        let _ = self.status_tx.send((payment_hash, PaymentStatus::InFlight));
 
         Ok(())
     }
@@ -133,6 +151,7 @@ impl PaymentProcessor {
 
         // Update payment status
         payment_statuses.insert(payment_hash, PaymentStatus::Success);
+        self.expiries.write().await.remove(&payment_hash);
 
         // Record result
         let payment_info = active_payments.remove(&payment_hash)
@@ -147,8 +166,7 @@ impl PaymentProcessor {
         });
 
         // Notify status change
-        self.status_tx.send((payment_hash, PaymentStatus::Success)).await
-            .map_err(|e| RoutingError::PaymentFailed(e.to_string()))?;
+        let _ = self.status_tx.send((payment_hash, PaymentStatus::Success));
 
         Ok(())
     }
@@ -164,9 +182,10 @@ impl PaymentProcessor {
 
         // Update payment status
         payment_statuses.insert(payment_hash, PaymentStatus::Failed);
+        self.expiries.write().await.remove(&payment_hash);
 
         // Record result
-        let payment_info = active_payments.remove(&payment_hash)
+        active_payments.remove(&payment_hash)
             .ok_or_else(|| RoutingError::PaymentFailed("Payment not found".into()))?;
 
         results.insert(payment_hash, PaymentResult {
@@ -178,25 +197,26 @@ impl PaymentProcessor {
         });
 
         // Notify status change
-        self.status_tx.send((payment_hash, PaymentStatus::Failed)).await
-            .map_err(|e| RoutingError::PaymentFailed(e.to_string()))?;
+        let _ = self.status_tx.send((payment_hash, PaymentStatus::Failed));
 
         Ok(())
     }
 
+    /// Times out every active payment whose expiry height the chain has reached
     pub async fn cleanup_timed_out_payments(&self) -> Result<(), RoutingError> {
+        let current_height = self.current_height().await?;
         let mut active_payments = self.active_payments.write().await;
         let mut payment_statuses = self.payment_statuses.write().await;
-        let current_time = current_timestamp();
+        let mut expiries = self.expiries.write().await;
 
-        let timed_out: Vec<H256> = active_payments.iter()
-            .filter(|(_, payment)| {
-                current_time > payment.timestamp + 300 // 5 minute timeout
-            })
-            .map(|(hash, _)| *hash)
+        let timed_out: Vec<H256> = active_payments.keys()
+            .filter(|hash| expiries.get(hash).is_some_and(|expiry| current_height >= *expiry))
+            .copied()
             .collect();
 
         for payment_hash in timed_out {
+            expiries.remove(&payment_hash);
+
             // Update status
             payment_statuses.insert(payment_hash, PaymentStatus::TimedOut);
             
@@ -204,13 +224,17 @@ impl PaymentProcessor {
             active_payments.remove(&payment_hash);
 
             // Notify status change
-            self.status_tx.send((payment_hash, PaymentStatus::TimedOut)).await
-                .map_err(|e| RoutingError::PaymentFailed(e.to_string()))?;
+            let _ = self.status_tx.send((payment_hash, PaymentStatus::TimedOut));
         }
 
         Ok(())
     }
 
+    /// Status changes of every payment from now on
+    pub fn subscribe_status(&self) -> broadcast::Receiver<(H256, PaymentStatus)> {
+        self.status_tx.subscribe()
+    }
+
     pub async fn get_payment_info(&self, payment_hash: H256) -> Result<PaymentInfo, RoutingError> {
         let active_payments = self.active_payments.read().await;
         active_payments.get(&payment_hash)
@@ -232,18 +256,23 @@ impl PaymentProcessor {
             .ok_or_else(|| RoutingError::PaymentFailed("Payment result not found".into()))
     }
 
+    /// Times out expired payments every time the chain clock advances
     pub async fn start_monitoring(&self) {
         let self_clone = self.clone();
+        let mut heights = self.clock.subscribe();
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(60));
-            loop {
-                interval.tick().await;
+            while let Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) = heights.recv().await {
                 if let Err(e) = self_clone.cleanup_timed_out_payments().await {
                     log::error!("Failed to cleanup timed out payments: {:?}", e);
                 }
             }
         });
     }
+
+    async fn current_height(&self) -> Result<u64, RoutingError> {
+        self.clock.current_height().await
+            .map_err(|e| RoutingError::Timeout(e.to_string()))
+    }
 }
 
 impl Clone for PaymentProcessor {
@@ -253,7 +282,9 @@ impl Clone for PaymentProcessor {
             payment_statuses: Arc::clone(&self.payment_statuses),
             htlcs: Arc::clone(&self.htlcs),
             results: Arc::clone(&self.results),
+            expiries: Arc::clone(&self.expiries),
             status_tx: self.status_tx.clone(),
+            clock: Arc::clone(&self.clock),
         }
     }
 }
@@ -268,9 +299,14 @@ fn current_timestamp() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::channel::clock::ManualClock;
 
     async fn setup_test_payment() -> (PaymentProcessor, PaymentInfo) {
-        let processor = PaymentProcessor::new();
+        setup_test_payment_with_clock(Arc::new(ManualClock::new(0))).await
+    }
+
+    async fn setup_test_payment_with_clock(clock: Arc<ManualClock>) -> (PaymentProcessor, PaymentInfo) {
+        let processor = PaymentProcessor::new(clock);
         let payment_info = PaymentInfo {
             route: Route {
                 path: vec![H256::random()],
@@ -278,6 +314,7 @@ mod tests {
                 total_amount: U256::from(1000),
                 total_fees: U256::from(10),
                 total_timelock: 144,
+                swaps: Vec::new(),
             },
             payment_hash: H256::random(),
             payment_secret: H256::random(),
@@ -333,15 +370,16 @@ mod tests {
 
     #[tokio::test]
     async fn test_timeout_cleanup() {
-        let (processor, payment_info) = setup_test_payment().await;
+        let clock = Arc::new(ManualClock::new(100));
+        let (processor, payment_info) = setup_test_payment_with_clock(clock.clone()).await;
 
-        // Manipulate timestamp to simulate timeout
-        let mut payments = processor.active_payments.write().await;
-        let mut payment = payments.get_mut(&payment_info.payment_hash).unwrap();
-        payment.timestamp = current_timestamp() - 600; // 10 minutes ago
+        // Wall-clock time doesn't matter, only the route's 144 block timelock
+        clock.set_height(243);
+        processor.cleanup_timed_out_payments().await.unwrap();
+        let status = processor.get_payment_status(payment_info.payment_hash).await.unwrap();
+        assert_eq!(status, PaymentStatus::Pending);
 
-        // Run cleanup
-        drop(payments);
+        clock.set_height(244);
         processor.cleanup_timed_out_payments().await.unwrap();
 
         // Check status
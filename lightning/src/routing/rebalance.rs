@@ -0,0 +1,252 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ethers::types::{Address, H256, U256};
+use serde::{Serialize, Deserialize};
+use tokio::sync::RwLock;
+
+use super::payment::PaymentStatus;
+use super::{Route, RoutingError, RoutingManager};
+use crate::channel::state::ChannelStatus;
+use crate::channel::{Channel, ChannelManager};
+
+const BPS: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RebalanceResult {
+    pub from_channel: H256,
+    pub to_channel: H256,
+    pub amount: U256,
+    pub fees_paid: U256,
+    pub route: Route,
+    pub status: PaymentStatus,
+}
+
+/// Bounds the autobalancer keeps each channel's local balance within, as a share of capacity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutobalancePolicy {
+    pub min_local_bps: u64,
+    pub target_local_bps: u64,
+    pub max_local_bps: u64,
+    /// Largest fee paid for a single rebalance, in parts per million of the amount
+    pub max_fee_ppm: u64,
+    /// Total fees we may spend per `budget_period`
+    pub fee_budget: U256,
+    pub budget_period: Duration,
+    pub min_amount: U256,
+}
+
+/// One planned circular payment from a channel with too much local balance
+/// to one with too little
+#[derive(Debug, Clone, PartialEq)]
+pub struct RebalancePlan {
+    pub from_channel: H256,
+    pub to_channel: H256,
+    pub amount: U256,
+}
+
+/// Pairs channels above the upper bound with channels below the lower bound,
+/// moving each towards the target share
+pub fn plan_rebalances(
+    channels: &[Channel],
+    node: Address,
+    policy: &AutobalancePolicy,
+) -> Vec<RebalancePlan> {
+    let mut excess = Vec::new();
+    let mut deficit = Vec::new();
+
+    for channel in channels {
+        if channel.status != ChannelStatus::Active
+            || channel.capacity.is_zero()
+            || !channel.participants.contains(&node)
+        {
+            continue;
+        }
+
+        let local = channel.state.get_participant_balance(&node);
+        let local_bps = local * U256::from(BPS) / channel.capacity;
+        let target = channel.capacity * U256::from(policy.target_local_bps) / U256::from(BPS);
+
+        if local_bps > U256::from(policy.max_local_bps) {
+            excess.push((channel.channel_id, local - target));
+        } else if local_bps < U256::from(policy.min_local_bps) {
+            deficit.push((channel.channel_id, target - local));
+        }
+    }
+
+    // Largest imbalances first
+    excess.sort_by_key(|entry| std::cmp::Reverse(entry.1));
+    deficit.sort_by_key(|entry| std::cmp::Reverse(entry.1));
+
+    let mut plans = Vec::new();
+    let mut excess = excess.into_iter().peekable();
+    let mut deficit = deficit.into_iter().peekable();
+
+    while let (Some(source), Some(sink)) = (excess.peek_mut(), deficit.peek_mut()) {
+        let amount = source.1.min(sink.1);
+        if amount >= policy.min_amount && !amount.is_zero() {
+            plans.push(RebalancePlan {
+                from_channel: source.0,
+                to_channel: sink.0,
+                amount,
+            });
+        }
+
+        source.1 -= amount;
+        sink.1 -= amount;
+        if source.1.is_zero() || source.1 < policy.min_amount {
+            excess.next();
+        }
+        if sink.1.is_zero() || sink.1 < policy.min_amount {
+            deficit.next();
+        }
+    }
+
+    plans
+}
+
+/// Periodically rebalances our channels back inside the policy bounds
+pub struct Autobalancer {
+    routing: Arc<RoutingManager>,
+    channel_manager: Arc<ChannelManager>,
+    node_address: Address,
+    policy: AutobalancePolicy,
+    spent: RwLock<(U256, Instant)>,
+}
+
+impl Autobalancer {
+    pub fn new(
+        routing: Arc<RoutingManager>,
+        channel_manager: Arc<ChannelManager>,
+        node_address: Address,
+        policy: AutobalancePolicy,
+    ) -> Self {
+        Self {
+            routing,
+            channel_manager,
+            node_address,
+            policy,
+            spent: RwLock::new((U256::zero(), Instant::now())),
+        }
+    }
+
+    pub fn start(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.run_once().await {
+                    log::error!("Autobalance run failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Executes the current rebalance plan until the fee budget runs out
+    pub async fn run_once(&self) -> Result<Vec<RebalanceResult>, RoutingError> {
+        let channels = self.channel_manager.list_channels().await
+            .map_err(|e| RoutingError::ChannelError(e.to_string()))?;
+        let plans = plan_rebalances(&channels, self.node_address, &self.policy);
+
+        let mut results = Vec::new();
+        for plan in plans {
+            let remaining = self.remaining_budget().await;
+            let max_fee = (plan.amount * U256::from(self.policy.max_fee_ppm) / U256::from(1_000_000u64))
+                .min(remaining);
+            if max_fee.is_zero() {
+                log::debug!("Autobalance fee budget exhausted");
+                break;
+            }
+
+            let result = self.routing.rebalance(
+                self.node_address,
+                plan.from_channel,
+                plan.to_channel,
+                plan.amount,
+                max_fee,
+            ).await;
+            match result {
+                Ok(result) => {
+                    self.spend(result.fees_paid).await;
+                    results.push(result);
+                }
+                // Try the next pair rather than giving up on the whole run
+                Err(e) => log::warn!(
+                    "Rebalance {} -> {} failed: {:?}",
+                    plan.from_channel, plan.to_channel, e
+                ),
+            }
+        }
+
+        Ok(results)
+    }
+
+    async fn remaining_budget(&self) -> U256 {
+        let mut spent = self.spent.write().await;
+        if spent.1.elapsed() >= self.policy.budget_period {
+            *spent = (U256::zero(), Instant::now());
+        }
+
+        self.policy.fee_budget.saturating_sub(spent.0)
+    }
+
+    async fn spend(&self, fee: U256) {
+        let mut spent = self.spent.write().await;
+        spent.0 = spent.0.saturating_add(fee);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::test_channel;
+
+    fn test_policy() -> AutobalancePolicy {
+        AutobalancePolicy {
+            min_local_bps: 2_000,
+            target_local_bps: 5_000,
+            max_local_bps: 8_000,
+            max_fee_ppm: 1_000,
+            fee_budget: U256::from(10_000),
+            budget_period: Duration::from_secs(3600),
+            min_amount: U256::from(10),
+        }
+    }
+
+    #[test]
+    fn test_plan_pairs_excess_with_deficit() {
+        let node = Address::random();
+        let full = test_channel(node, 950, Address::random(), 50);
+        let empty = test_channel(node, 50, Address::random(), 950);
+        let balanced = test_channel(node, 500, Address::random(), 500);
+
+        let plans = plan_rebalances(&[full.clone(), empty.clone(), balanced], node, &test_policy());
+
+        assert_eq!(plans, vec![RebalancePlan {
+            from_channel: full.channel_id,
+            to_channel: empty.channel_id,
+            amount: U256::from(450),
+        }]);
+    }
+
+    #[test]
+    fn test_plan_limited_by_smaller_side() {
+        let node = Address::random();
+        let full = test_channel(node, 900, Address::random(), 100);
+        let low = test_channel(node, 150, Address::random(), 850);
+        let lower = test_channel(node, 100, Address::random(), 900);
+
+        let plans = plan_rebalances(&[full.clone(), low.clone(), lower.clone()], node, &test_policy());
+
+        // 400 excess: 400 to the emptier channel, nothing left for the other
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].to_channel, lower.channel_id);
+        assert_eq!(plans[0].amount, U256::from(400));
+    }
+
+    #[test]
+    fn test_balanced_channels_need_no_plan() {
+        let node = Address::random();
+        let channels = vec![test_channel(node, 400, Address::random(), 600), test_channel(node, 600, Address::random(), 400)];
+        assert!(plan_rebalances(&channels, node, &test_policy()).is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use ethers::types::{Address, H256, U256};
+use serde::{Serialize, Deserialize};
+use sha3::{Digest, Keccak256};
+use thiserror::Error;
+use flashchain_common::encoding::u256_bytes;
+use flashchain_common::types::AssetId;
+
+use crate::crypto::CryptoManager;
+use crate::crypto::signature::{SignatureSet, SignatureVerifier};
+use crate::crypto::signer::SignRequest;
+
+/// Fixed-point scale of quoted exchange rates
+pub const RATE_DECIMALS: usize = 18;
+
+/// Fee rates are in parts per million, so no fee can exceed the whole amount
+pub const MAX_FEE_RATE: u32 = 1_000_000;
+
+#[derive(Error, Debug)]
+pub enum SwapError {
+    #[error("Pair {0:?} -> {1:?} not offered")]
+    UnsupportedPair(AssetId, AssetId),
+    #[error("Amount {0} exceeds quote limit")]
+    AmountTooLarge(U256),
+    #[error("Quote expired")]
+    Expired,
+    #[error("Invalid quote signature")]
+    InvalidSignature,
+    #[error("Fee rate {0} exceeds the converted amount")]
+    InvalidFeeRate(u32),
+    #[error("Quote names {0:?}, not the announcing gateway")]
+    GatewayMismatch(Address),
+    #[error("Slippage exceeded: expected at least {min}, got {actual}")]
+    SlippageExceeded { min: U256, actual: U256 },
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+}
+
+/// A conversion a gateway is willing to perform between its incoming and outgoing HTLC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwapOffer {
+    pub from_asset: AssetId,
+    pub to_asset: AssetId,
+    /// Conversion fee in parts per million of the converted amount
+    pub fee_rate: u32,
+    pub max_amount: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwapPolicy {
+    pub offers: Vec<SwapOffer>,
+    /// Seconds a quote stays valid after it is issued
+    pub quote_validity: u64,
+}
+
+impl Default for SwapPolicy {
+    fn default() -> Self {
+        Self {
+            offers: Vec::new(),
+            quote_validity: 60,
+        }
+    }
+}
+
+impl SwapPolicy {
+    pub fn offer(&self, from_asset: AssetId, to_asset: AssetId) -> Option<&SwapOffer> {
+        self.offers.iter()
+            .find(|offer| offer.from_asset == from_asset && offer.to_asset == to_asset)
+    }
+}
+
+/// Exchange rate a node commits to for a limited time, signed by that node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateQuote {
+    pub node: Address,
+    pub from_asset: AssetId,
+    pub to_asset: AssetId,
+    /// Units of `to_asset` per unit of `from_asset`, scaled by 10^RATE_DECIMALS
+    pub rate: U256,
+    pub fee_rate: u32,
+    pub max_amount: U256,
+    pub valid_from: u64,
+    pub valid_until: u64,
+    pub signature: Vec<u8>,
+}
+
+impl RateQuote {
+    /// Hash of every quoted term, which is what the node signs
+    pub fn digest(&self) -> H256 {
+        let mut data = Vec::new();
+        data.extend_from_slice(self.node.as_bytes());
+        data.extend_from_slice(self.from_asset.0.as_bytes());
+        data.extend_from_slice(self.to_asset.0.as_bytes());
+        data.extend_from_slice(&u256_bytes(self.rate));
+        data.extend_from_slice(&self.fee_rate.to_be_bytes());
+        data.extend_from_slice(&u256_bytes(self.max_amount));
+        data.extend_from_slice(&self.valid_from.to_be_bytes());
+        data.extend_from_slice(&self.valid_until.to_be_bytes());
+        H256::from_slice(&keccak256(&data))
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_from <= now && now < self.valid_until
+    }
+
+    /// Amount of `to_asset` paid out for `amount` of `from_asset`, after the conversion fee
+    pub fn convert(&self, amount: U256) -> Option<U256> {
+        if amount > self.max_amount {
+            return None;
+        }
+
+        let gross = amount.checked_mul(self.rate)? / U256::exp10(RATE_DECIMALS);
+        let fee = gross.checked_mul(U256::from(self.fee_rate))? / U256::from(MAX_FEE_RATE);
+        gross.checked_sub(fee)
+    }
+
+    /// Checks the quote was issued and signed by `gateway`, the identity of the node
+    /// that announced it
+    pub fn verify(&self, verifier: &SignatureVerifier, gateway: Address) -> Result<(), SwapError> {
+        if self.node != gateway {
+            return Err(SwapError::GatewayMismatch(self.node));
+        }
+        if self.fee_rate > MAX_FEE_RATE {
+            return Err(SwapError::InvalidFeeRate(self.fee_rate));
+        }
+
+        let set = SignatureSet {
+            signatures: HashMap::from([(gateway, self.signature.clone())]),
+            message_hash: self.digest(),
+            timestamp: self.valid_from,
+        };
+
+        match verifier.verify_threshold(&set, &[gateway], 1) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(SwapError::InvalidSignature),
+            Err(e) => Err(SwapError::Crypto(e.to_string())),
+        }
+    }
+}
+
+/// A conversion planned along a route, made by the node reached after `hop_index` hops
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwapStep {
+    pub hop_index: usize,
+    pub quote: RateQuote,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    /// Least the sender accepts out of this conversion
+    pub min_amount_out: U256,
+}
+
+impl SwapStep {
+    pub fn new(hop_index: usize, quote: RateQuote, amount_in: U256, amount_out: U256) -> Self {
+        Self {
+            hop_index,
+            quote,
+            amount_in,
+            amount_out,
+            min_amount_out: amount_out,
+        }
+    }
+
+    /// Lowers the accepted output to allow up to `max_slippage_bps` below the quoted one
+    pub fn with_max_slippage(mut self, max_slippage_bps: u32) -> Self {
+        let bps = U256::from(10_000u32.saturating_sub(max_slippage_bps));
+        self.min_amount_out = self.amount_out * bps / U256::from(10_000u32);
+        self
+    }
+
+    /// Output the gateway pays at forwarding time under `quote`, rejected if the
+    /// quote has expired or pays out less than the sender allowed
+    pub fn settle(&self, quote: &RateQuote, now: u64) -> Result<U256, SwapError> {
+        if quote.node != self.quote.node
+            || quote.from_asset != self.quote.from_asset
+            || quote.to_asset != self.quote.to_asset
+        {
+            return Err(SwapError::UnsupportedPair(quote.from_asset, quote.to_asset));
+        }
+
+        if !quote.is_valid_at(now) {
+            return Err(SwapError::Expired);
+        }
+
+        let actual = quote.convert(self.amount_in)
+            .ok_or(SwapError::AmountTooLarge(self.amount_in))?;
+        if actual < self.min_amount_out {
+            return Err(SwapError::SlippageExceeded { min: self.min_amount_out, actual });
+        }
+
+        Ok(actual)
+    }
+}
+
+/// Issues signed quotes for the conversions a gateway's policy offers
+pub struct SwapQuoter {
+    policy: SwapPolicy,
+    crypto: Arc<CryptoManager>,
+    node_address: Address,
+}
+
+impl SwapQuoter {
+    pub fn new(policy: SwapPolicy, crypto: Arc<CryptoManager>, node_address: Address) -> Self {
+        Self {
+            policy,
+            crypto,
+            node_address,
+        }
+    }
+
+    pub fn policy(&self) -> &SwapPolicy {
+        &self.policy
+    }
+
+    /// Quotes `rate` for an offered pair, valid from `now` for the policy's validity period
+    pub async fn quote(
+        &self,
+        from_asset: AssetId,
+        to_asset: AssetId,
+        rate: U256,
+        now: u64,
+    ) -> Result<RateQuote, SwapError> {
+        let offer = self.policy.offer(from_asset, to_asset)
+            .ok_or(SwapError::UnsupportedPair(from_asset, to_asset))?;
+
+        let mut quote = RateQuote {
+            node: self.node_address,
+            from_asset,
+            to_asset,
+            rate,
+            fee_rate: offer.fee_rate,
+            max_amount: offer.max_amount,
+            valid_from: now,
+            valid_until: now + self.policy.quote_validity,
+            signature: Vec::new(),
+        };
+        quote.signature = self.crypto.sign(&self.node_address, SignRequest::RateQuote(quote.clone())).await
+            .map_err(|e| SwapError::Crypto(e.to_string()))?;
+
+        Ok(quote)
+    }
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quoter() -> (SwapQuoter, AssetId, AssetId) {
+        let mut crypto = CryptoManager::new();
+        let node = crypto.generate_keypair().unwrap();
+        let usdc = AssetId::token(Address::random());
+
+        let policy = SwapPolicy {
+            offers: vec![SwapOffer {
+                from_asset: AssetId::NATIVE,
+                to_asset: usdc,
+                fee_rate: 1_000,
+                max_amount: U256::from(1_000_000),
+            }],
+            quote_validity: 30,
+        };
+
+        (SwapQuoter::new(policy, Arc::new(crypto), node), AssetId::NATIVE, usdc)
+    }
+
+    #[tokio::test]
+    async fn test_quote_converts_and_expires() {
+        let (quoter, eth, usdc) = quoter();
+        // 2000 USDC units per native unit
+        let quote = quoter.quote(eth, usdc, U256::exp10(RATE_DECIMALS) * 2000, 100).await.unwrap();
+
+        // 2_000_000 minus the 0.1% fee
+        assert_eq!(quote.convert(U256::from(1000)), Some(U256::from(1_998_000)));
+        assert_eq!(quote.convert(U256::from(2_000_000)), None);
+        assert!(quote.is_valid_at(100));
+        assert!(!quote.is_valid_at(130));
+        assert!(quoter.quote(usdc, eth, U256::one(), 100).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slippage_cap() {
+        let (quoter, eth, usdc) = quoter();
+        let quote = quoter.quote(eth, usdc, U256::exp10(RATE_DECIMALS) * 2000, 100).await.unwrap();
+        let amount_out = quote.convert(U256::from(1000)).unwrap();
+        let step = SwapStep::new(1, quote, U256::from(1000), amount_out).with_max_slippage(50);
+
+        // The gateway re-quotes 0.3% lower, within the 0.5% cap
+        let requote = quoter.quote(eth, usdc, U256::exp10(RATE_DECIMALS) * 1994, 120).await.unwrap();
+        assert!(step.settle(&requote, 125).is_ok());
+
+        // A 1% drop is not
+        let requote = quoter.quote(eth, usdc, U256::exp10(RATE_DECIMALS) * 1980, 120).await.unwrap();
+        assert!(matches!(step.settle(&requote, 125), Err(SwapError::SlippageExceeded { .. })));
+
+        assert!(matches!(step.settle(&requote, 200), Err(SwapError::Expired)));
+    }
+
+    #[tokio::test]
+    async fn test_quote_verified_against_gateway() {
+        let (quoter, eth, usdc) = quoter();
+        let gateway = quoter.node_address;
+        let verifier = SignatureVerifier::new(1);
+        let quote = quoter.quote(eth, usdc, U256::exp10(RATE_DECIMALS) * 2000, 100).await.unwrap();
+        assert!(quote.verify(&verifier, gateway).is_ok());
+
+        // Another node relaying the quote as its own
+        assert!(matches!(quote.verify(&verifier, Address::random()), Err(SwapError::GatewayMismatch(_))));
+
+        // Renaming the issuer does not carry the signature over
+        let mut forged = quote.clone();
+        forged.node = Address::random();
+        assert!(forged.verify(&verifier, forged.node).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fee_rate_above_whole_amount_rejected() {
+        let (quoter, eth, usdc) = quoter();
+        let mut quote = quoter.quote(eth, usdc, U256::exp10(RATE_DECIMALS) * 2000, 100).await.unwrap();
+        quote.fee_rate = MAX_FEE_RATE + 1;
+
+        assert_eq!(quote.convert(U256::from(1000)), None);
+        let verifier = SignatureVerifier::new(1);
+        assert!(matches!(quote.verify(&verifier, quoter.node_address), Err(SwapError::InvalidFeeRate(_))));
+    }
+}
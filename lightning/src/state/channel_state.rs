@@ -1,16 +1,16 @@
 use ethers::types::{Address, H256, U256};
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
+use flashchain_common::encoding::{u256_bytes, CanonicalBalance, CanonicalHtlc, CanonicalState};
+use flashchain_common::types::AssetId;
 use super::StateError;
+use crate::channel::parameters::ChannelParameters;
+use crate::channel::state_machine::{self, ChannelEvent, TransitionContext, TransitionRecord};
+use crate::channel::ChannelError;
+use crate::crypto::signature::{channel_state_message, Quorum, SignatureSet, SignatureVerifier};
+use crate::crypto::CryptoError;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum ChannelStatus {
-    Created,
-    Active,
-    Closing,
-    Closed,
-    Disputed,
-}
+pub use crate::channel::state::ChannelStatus;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Balance {
@@ -28,6 +28,8 @@ pub struct Htlc {
     pub hash_lock: H256,
     pub timeout: u64,
     pub status: HtlcStatus,
+    #[serde(default)]
+    pub asset: AssetId,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -44,7 +46,14 @@ pub struct ChannelState {
     pub participants: Vec<Address>,
     pub capacity: U256,
     pub balances: HashMap<Address, Balance>,
+    /// Balances in tokens, the native asset is kept in `balances`
+    #[serde(default)]
+    pub token_balances: HashMap<AssetId, HashMap<Address, Balance>>,
+    #[serde(default)]
+    pub token_capacity: HashMap<AssetId, U256>,
     pub htlcs: HashMap<H256, Htlc>,
+    #[serde(default)]
+    pub parameters: ChannelParameters,
     pub status: ChannelStatus,
     pub sequence: u64,
     pub dispute_timeout: u64,
@@ -67,8 +76,11 @@ impl ChannelState {
             participants,
             capacity,
             balances,
+            token_balances: HashMap::new(),
+            token_capacity: HashMap::new(),
             htlcs: HashMap::new(),
-            status: ChannelStatus::Created,
+            parameters: ChannelParameters::default(),
+            status: ChannelStatus::Initializing,
             sequence: 0,
             dispute_timeout: 144 * 7, // ~1 week in blocks
             last_update: chrono::Utc::now().timestamp() as u64,
@@ -93,6 +105,41 @@ impl ChannelState {
         Ok(())
     }
 
+    /// Adds a token to the channel with an empty balance for every participant
+    pub fn add_asset(&mut self, asset: AssetId, capacity: U256) -> Result<(), StateError> {
+        if asset.is_native() || self.token_capacity.contains_key(&asset) {
+            return Err(StateError::InvalidTransition(format!("Asset {:?} already in channel", asset)));
+        }
+
+        let balances = self.participants.iter()
+            .map(|participant| (*participant, Balance {
+                amount: U256::zero(),
+                locked: U256::zero(),
+                pending_htlcs: Vec::new(),
+            }))
+            .collect();
+
+        self.token_capacity.insert(asset, capacity);
+        self.token_balances.insert(asset, balances);
+        Ok(())
+    }
+
+    pub fn capacity_of(&self, asset: AssetId) -> Option<U256> {
+        if asset.is_native() {
+            return Some(self.capacity);
+        }
+
+        self.token_capacity.get(&asset).copied()
+    }
+
+    pub fn balance_of(&self, asset: AssetId, participant: &Address) -> Option<&Balance> {
+        if asset.is_native() {
+            return self.balances.get(participant);
+        }
+
+        self.token_balances.get(&asset).and_then(|balances| balances.get(participant))
+    }
+
     pub fn create_htlc(
         &mut self,
         sender: Address,
@@ -100,6 +147,18 @@ impl ChannelState {
         amount: U256,
         hash_lock: H256,
         timeout: u64,
+    ) -> Result<H256, StateError> {
+        self.create_asset_htlc(AssetId::NATIVE, sender, receiver, amount, hash_lock, timeout)
+    }
+
+    pub fn create_asset_htlc(
+        &mut self,
+        asset: AssetId,
+        sender: Address,
+        receiver: Address,
+        amount: U256,
+        hash_lock: H256,
+        timeout: u64,
     ) -> Result<H256, StateError> {
         // Verify participants
         if !self.participants.contains(&sender) || !self.participants.contains(&receiver) {
@@ -107,13 +166,29 @@ impl ChannelState {
         }
 
         // Verify sufficient balance
-        let sender_balance = self.balances.get(&sender)
+        let sender_balance = self.balance_of(asset, &sender)
             .ok_or_else(|| StateError::NotFound("Sender balance not found".into()))?;
 
         if sender_balance.amount < amount {
             return Err(StateError::InvalidTransition("Insufficient balance".into()));
         }
 
+        // Every asset is held to the amount limits; the reserve only applies to the native asset
+        let sender_amount = sender_balance.amount;
+        let in_flight = self.pending_htlcs()
+            .filter(|htlc| htlc.asset == asset)
+            .fold(U256::zero(), |acc, htlc| acc + htlc.amount);
+        if asset.is_native() {
+            self.parameters.check_htlc(
+                amount,
+                sender_amount,
+                self.pending_htlcs().count(),
+                in_flight,
+            )?;
+        } else {
+            self.parameters.check_htlc_amount(amount, self.pending_htlcs().count(), in_flight)?;
+        }
+
         // Create HTLC
         let htlc_id = self.generate_htlc_id(sender, receiver, amount, hash_lock);
         let htlc = Htlc {
@@ -124,101 +199,147 @@ impl ChannelState {
             hash_lock,
             timeout,
             status: HtlcStatus::Pending,
+            asset,
         };
 
         // Update balances
-        if let Some(balance) = self.balances.get_mut(&sender) {
+        if let Some(balance) = self.asset_balances_mut(asset).get_mut(&sender) {
             balance.amount -= amount;
             balance.locked += amount;
             balance.pending_htlcs.push(htlc_id);
         }
 
         self.htlcs.insert(htlc_id, htlc);
+        self.sequence += 1;
+
         Ok(htlc_id)
     }
 
     pub fn fulfill_htlc(&mut self, htlc_id: H256, preimage: H256) -> Result<(), StateError> {
-        let htlc = self.htlcs.get_mut(&htlc_id)
-            .ok_or_else(|| StateError::NotFound("HTLC not found".into()))?;
+        let hash_lock = self.htlcs.get(&htlc_id)
+            .ok_or_else(|| StateError::NotFound("HTLC not found".into()))?
+            .hash_lock;
+
+        // Verify preimage
+        if !self.verify_preimage(hash_lock, preimage) {
+            return Err(StateError::InvalidTransition("Invalid preimage".into()));
+        }
+
+        let htlc = self.htlcs.get_mut(&htlc_id).unwrap();
 
         // Verify HTLC status
         if htlc.status != HtlcStatus::Pending {
             return Err(StateError::InvalidTransition("HTLC not pending".into()));
         }
 
-        // Verify preimage
-        if !self.verify_preimage(htlc.hash_lock, preimage) {
-            return Err(StateError::InvalidTransition("Invalid preimage".into()));
-        }
-
         // Update HTLC status
         htlc.status = HtlcStatus::Fulfilled;
+        let htlc = htlc.clone();
 
         // Update balances
-        if let Some(sender_balance) = self.balances.get_mut(&htlc.sender) {
+        let balances = self.asset_balances_mut(htlc.asset);
+        if let Some(sender_balance) = balances.get_mut(&htlc.sender) {
             sender_balance.locked -= htlc.amount;
             sender_balance.pending_htlcs.retain(|&id| id != htlc_id);
         }
 
-        if let Some(receiver_balance) = self.balances.get_mut(&htlc.receiver) {
+        if let Some(receiver_balance) = balances.get_mut(&htlc.receiver) {
             receiver_balance.amount += htlc.amount;
         }
 
+        self.sequence += 1;
+
         Ok(())
     }
 
-    pub fn close(&mut self) -> Result<(), StateError> {
-        match self.status {
-            ChannelStatus::Active => {
-                self.status = ChannelStatus::Closing;
-                Ok(())
-            }
-            ChannelStatus::Closing => {
-                self.status = ChannelStatus::Closed;
-                Ok(())
-            }
-            _ => Err(StateError::InvalidTransition(
-                "Invalid state for closing".into()
-            )),
+    pub fn expire_htlc(&mut self, htlc_id: H256, current_height: u64) -> Result<Htlc, StateError> {
+        let htlc = self.htlcs.get_mut(&htlc_id)
+            .ok_or_else(|| StateError::NotFound("HTLC not found".into()))?;
+
+        // Verify HTLC status
+        if htlc.status != HtlcStatus::Pending {
+            return Err(StateError::InvalidTransition("HTLC not pending".into()));
         }
-    }
 
-    pub fn state_hash(&self) -> H256 {
-        let mut data = Vec::new();
-        data.extend_from_slice(&self.sequence.to_be_bytes());
-        
-        // Add balances
-        let mut sorted_balances: Vec<_> = self.balances.iter().collect();
-        sorted_balances.sort_by_key(|&(addr, _)| *addr);
-        
-        for (addr, balance) in sorted_balances {
-            data.extend_from_slice(addr.as_bytes());
-            data.extend_from_slice(&balance.amount.to_be_bytes());
-            data.extend_from_slice(&balance.locked.to_be_bytes());
+        if current_height < htlc.timeout {
+            return Err(StateError::InvalidTransition("HTLC not expired".into()));
         }
 
-        // Add HTLCs
-        let mut sorted_htlcs: Vec<_> = self.htlcs.values().collect();
-        sorted_htlcs.sort_by_key(|htlc| htlc.id);
+        // Update HTLC status
+        htlc.status = HtlcStatus::Expired;
+        let expired = htlc.clone();
 
-        for htlc in sorted_htlcs {
-            data.extend_from_slice(htlc.id.as_bytes());
-            data.extend_from_slice(&htlc.amount.to_be_bytes());
-            data.extend_from_slice(htlc.hash_lock.as_bytes());
+        // Refund the sender
+        if let Some(sender_balance) = self.asset_balances_mut(expired.asset).get_mut(&expired.sender) {
+            sender_balance.locked -= expired.amount;
+            sender_balance.amount += expired.amount;
+            sender_balance.pending_htlcs.retain(|&id| id != htlc_id);
         }
 
-        H256::from_slice(&keccak256(&data))
+        self.sequence += 1;
+
+        Ok(expired)
     }
 
-    pub fn verify_signature(&self, signer: &Address, state: &H256, signature: &[u8]) -> bool {
-        // Implement signature verification
-        // This would use proper cryptographic verification in production
-        true
+    pub fn pending_htlcs(&self) -> impl Iterator<Item = &Htlc> {
+        self.htlcs.values().filter(|htlc| htlc.status == HtlcStatus::Pending)
+    }
+
+    /// Takes the next closing step through the transition table: starts closing an
+    /// open channel and settles a closing or disputed one once `context` is past its timeout
+    pub fn close(&mut self, context: TransitionContext) -> Result<TransitionRecord, StateError> {
+        let event = match self.status {
+            ChannelStatus::Closing | ChannelStatus::Disputed => ChannelEvent::Settle,
+            // Never funded, so there is nothing to settle
+            ChannelStatus::Initializing => ChannelEvent::Abort,
+            _ => ChannelEvent::InitiateClose,
+        };
+
+        let record = state_machine::transition(self.channel_id, &self.status, event, context)
+            .map_err(|e| match e {
+                ChannelError::InvalidStateTransition(reason) => StateError::InvalidTransition(reason),
+                e => StateError::InvalidTransition(e.to_string()),
+            })?;
+        self.status = record.to.clone();
+
+        Ok(record)
+    }
+
+    /// Hash participants sign to agree on this state
+    pub fn state_hash(&self) -> H256 {
+        CanonicalState::from(self).hash()
+    }
+
+    /// Checks that `quorum` of the participants, and nobody else, signed `state_hash`
+    /// at `sequence` the way `BridgeCore.updateChannelState` checks it
+    pub fn verify_signatures(
+        &self,
+        verifier: &SignatureVerifier,
+        quorum: Quorum,
+        sequence: u64,
+        state_hash: H256,
+        signatures: &HashMap<Address, Vec<u8>>,
+    ) -> Result<(), CryptoError> {
+        let set = SignatureSet {
+            signatures: signatures.clone(),
+            message_hash: channel_state_message(self.channel_id, sequence, state_hash),
+            timestamp: self.last_update,
+        };
+
+        verifier.verify_quorum(&set, &self.participants, quorum)
     }
 
     // Helper methods
 
-    fn is_valid_transition(&self, update: &super::StateUpdate) -> bool {
+    fn asset_balances_mut(&mut self, asset: AssetId) -> &mut HashMap<Address, Balance> {
+        if asset.is_native() {
+            return &mut self.balances;
+        }
+
+        self.token_balances.entry(asset).or_default()
+    }
+
+    fn is_valid_transition(&self, _update: &super::StateUpdate) -> bool {
         // Implement state transition validation logic
         true
     }
@@ -233,7 +354,7 @@ impl ChannelState {
         let mut data = Vec::new();
         data.extend_from_slice(sender.as_bytes());
         data.extend_from_slice(receiver.as_bytes());
-        data.extend_from_slice(&amount.to_be_bytes());
+        data.extend_from_slice(&u256_bytes(amount));
         data.extend_from_slice(hash_lock.as_bytes());
         H256::from_slice(&keccak256(&data))
     }
@@ -243,6 +364,32 @@ impl ChannelState {
     }
 }
 
+impl From<&ChannelState> for CanonicalState {
+    fn from(state: &ChannelState) -> Self {
+        let native = state.balances.iter().map(|(address, balance)| (AssetId::NATIVE, address, balance));
+        let tokens = state.token_balances.iter()
+            .flat_map(|(&asset, balances)| balances.iter().map(move |(address, balance)| (asset, address, balance)));
+        let balances = native.chain(tokens).map(|(asset, &participant, balance)| CanonicalBalance {
+            asset,
+            participant,
+            amount: balance.amount,
+        });
+
+        // Settled HTLCs are already reflected in the balances
+        let htlcs = state.pending_htlcs().map(|htlc| CanonicalHtlc {
+            id: htlc.id,
+            asset: htlc.asset,
+            sender: htlc.sender,
+            receiver: htlc.receiver,
+            amount: htlc.amount,
+            hash_lock: htlc.hash_lock,
+            expiration: htlc.timeout,
+        });
+
+        CanonicalState::new(state.sequence, balances, htlcs, Vec::new())
+    }
+}
+
 fn keccak256(data: &[u8]) -> [u8; 32] {
     use sha3::{Digest, Keccak256};
     let mut hasher = Keccak256::new();
@@ -262,12 +409,30 @@ mod tests {
 
         let state = ChannelState::new(channel_id, participants.clone(), capacity);
 
-        assert_eq!(state.status, ChannelStatus::Created);
+        assert_eq!(state.status, ChannelStatus::Initializing);
         assert_eq!(state.sequence, 0);
         assert_eq!(state.capacity, capacity);
         assert_eq!(state.participants, participants);
     }
 
+    #[test]
+    fn test_settling_waits_for_timeout() {
+        let mut state = ChannelState::new(H256::random(), vec![Address::random(), Address::random()], U256::from(100));
+        state.status = ChannelStatus::Active;
+        let before = TransitionContext { current_height: 5, timeout_height: 10 };
+        let after = TransitionContext { current_height: 10, timeout_height: 10 };
+
+        let record = state.close(before).unwrap();
+        assert_eq!((record.from, record.to), (ChannelStatus::Active, ChannelStatus::Closing));
+
+        assert!(matches!(state.close(before), Err(StateError::InvalidTransition(_))));
+        assert_eq!(state.status, ChannelStatus::Closing);
+
+        let record = state.close(after).unwrap();
+        assert_eq!(record.event, ChannelEvent::Settle);
+        assert_eq!(state.status, ChannelStatus::Closed);
+    }
+
     #[test]
     fn test_htlc_creation_and_fulfillment() {
         let mut state = ChannelState::new(
@@ -303,4 +468,65 @@ mod tests {
         let receiver_balance = state.balances.get(&state.participants[1]).unwrap();
         assert_eq!(receiver_balance.amount, U256::from(100));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_htlc_expiry_refunds_sender() {
+        let mut state = ChannelState::new(
+            H256::random(),
+            vec![Address::random(), Address::random()],
+            U256::from(1000000),
+        );
+        let sender = state.participants[0];
+
+        if let Some(balance) = state.balances.get_mut(&sender) {
+            balance.amount = U256::from(1000);
+        }
+
+        let htlc_id = state.create_htlc(
+            sender,
+            state.participants[1],
+            U256::from(100),
+            H256::random(),
+            100,
+        ).unwrap();
+
+        // Not expired yet
+        assert!(state.expire_htlc(htlc_id, 99).is_err());
+
+        let expired = state.expire_htlc(htlc_id, 100).unwrap();
+        assert_eq!(expired.status, HtlcStatus::Expired);
+
+        let sender_balance = state.balances.get(&sender).unwrap();
+        assert_eq!(sender_balance.amount, U256::from(1000));
+        assert_eq!(sender_balance.locked, U256::zero());
+        assert!(sender_balance.pending_htlcs.is_empty());
+        assert_eq!(state.pending_htlcs().count(), 0);
+    }
+
+    #[test]
+    fn test_token_htlc_settles_in_its_asset() {
+        let mut state = ChannelState::new(
+            H256::random(),
+            vec![Address::random(), Address::random()],
+            U256::from(1000000),
+        );
+        let (sender, receiver) = (state.participants[0], state.participants[1]);
+        let usdc = AssetId::token(Address::random());
+
+        state.add_asset(usdc, U256::from(5000)).unwrap();
+        state.token_balances.get_mut(&usdc).unwrap().get_mut(&sender).unwrap().amount = U256::from(500);
+
+        // No native balance, so only the token can pay
+        let preimage = H256::random();
+        let hash_lock = H256::from_slice(&keccak256(preimage.as_bytes()));
+        assert!(state.create_htlc(sender, receiver, U256::from(100), hash_lock, 100).is_err());
+
+        let htlc_id = state.create_asset_htlc(usdc, sender, receiver, U256::from(100), hash_lock, 100).unwrap();
+        state.fulfill_htlc(htlc_id, preimage).unwrap();
+
+        assert_eq!(state.balance_of(usdc, &receiver).unwrap().amount, U256::from(100));
+        assert_eq!(state.balance_of(usdc, &sender).unwrap().locked, U256::zero());
+        assert_eq!(state.balance_of(AssetId::NATIVE, &receiver).unwrap().amount, U256::zero());
+        assert_eq!(state.capacity_of(usdc), Some(U256::from(5000)));
+    }
+}
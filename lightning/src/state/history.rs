@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use ethers::types::{Address, H256};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+use super::channel_state::ChannelState;
+use super::StateUpdate;
+use crate::crypto::signature::{Quorum, SignatureVerifier};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HistoryError {
+    #[error("No history for channel {0:?}")]
+    NotFound(H256),
+    #[error("Entry at sequence {0} has no update")]
+    MissingUpdate(u64),
+    #[error("Update {sequence} doesn't follow the state before it")]
+    BrokenChain { sequence: u64 },
+    #[error("State at sequence {sequence} hashes to {actual:?}, the update signed {expected:?}")]
+    HashMismatch { sequence: u64, expected: H256, actual: H256 },
+    #[error("Update {sequence} has invalid signatures: {reason}")]
+    InvalidSignatures { sequence: u64, reason: String },
+}
+
+/// A channel state as it was right after an update was applied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The update with its signatures; `None` for the state the channel was created with
+    pub update: Option<StateUpdate>,
+    pub state: ChannelState,
+}
+
+impl HistoryEntry {
+    pub fn channel_id(&self) -> H256 {
+        self.state.channel_id
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.state.sequence
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.update.as_ref().map_or(self.state.last_update, |update| update.timestamp)
+    }
+}
+
+/// How much history is kept per channel. Replays start from the oldest entry kept.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_entries: Option<usize>,
+    /// Entries older than this many seconds are dropped
+    pub max_age: Option<u64>,
+}
+
+/// Every state each channel went through, indexed by sequence and participant
+#[derive(Debug, Default)]
+pub struct StateHistory {
+    channels: HashMap<H256, BTreeMap<u64, HistoryEntry>>,
+    participants: HashMap<Address, HashSet<H256>>,
+}
+
+impl StateHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, entry: HistoryEntry) {
+        let channel_id = entry.channel_id();
+        for participant in &entry.state.participants {
+            self.participants.entry(*participant).or_default().insert(channel_id);
+        }
+
+        self.channels.entry(channel_id).or_default().insert(entry.sequence(), entry);
+    }
+
+    /// The channel as it was at `sequence`
+    pub fn at_sequence(&self, channel_id: H256, sequence: u64) -> Option<&HistoryEntry> {
+        self.channels.get(&channel_id)?
+            .range(..=sequence)
+            .next_back()
+            .map(|(_, entry)| entry)
+    }
+
+    /// The channel as it was at `timestamp`
+    pub fn at_time(&self, channel_id: H256, timestamp: u64) -> Option<&HistoryEntry> {
+        self.channels.get(&channel_id)?
+            .values()
+            .take_while(|entry| entry.timestamp() <= timestamp)
+            .last()
+    }
+
+    /// Entries of the channel with a timestamp in `from..=to`
+    pub fn between(&self, channel_id: H256, from: u64, to: u64) -> Vec<&HistoryEntry> {
+        self.channels.get(&channel_id)
+            .into_iter()
+            .flat_map(|entries| entries.values())
+            .filter(|entry| (from..=to).contains(&entry.timestamp()))
+            .collect()
+    }
+
+    /// Entries with a timestamp in `from..=to` of every channel the participant is in
+    pub fn for_participant(&self, participant: &Address, from: u64, to: u64) -> Vec<&HistoryEntry> {
+        let mut entries: Vec<_> = self.participants.get(participant)
+            .into_iter()
+            .flatten()
+            .flat_map(|channel_id| self.between(*channel_id, from, to))
+            .collect();
+        entries.sort_by_key(|entry| (entry.timestamp(), entry.channel_id(), entry.sequence()));
+        entries
+    }
+
+    /// Drops what the policy doesn't keep and returns the (channel id, sequence) of
+    /// each dropped entry. The latest entry of a channel is always kept.
+    pub fn prune(&mut self, policy: &RetentionPolicy, now: u64) -> Vec<(H256, u64)> {
+        let mut pruned = Vec::new();
+
+        for (channel_id, entries) in &mut self.channels {
+            let latest = entries.keys().next_back().copied();
+            let excess = policy.max_entries
+                .map_or(0, |max| entries.len().saturating_sub(max.max(1)));
+
+            let dropped: Vec<u64> = entries.iter()
+                .enumerate()
+                .filter(|(index, (sequence, entry))| {
+                    Some(**sequence) != latest && (*index < excess || policy.max_age
+                        .is_some_and(|max_age| entry.timestamp().saturating_add(max_age) < now))
+                })
+                .map(|(_, (sequence, _))| *sequence)
+                .collect();
+
+            for sequence in dropped {
+                entries.remove(&sequence);
+                pruned.push((*channel_id, sequence));
+            }
+        }
+
+        pruned
+    }
+
+    /// Walks the channel's history from its oldest entry, recomputing every state
+    /// hash and checking it against the update that led to it, and checking the
+    /// signatures of every update. Only the latest entry may be a local update that
+    /// wasn't countersigned yet. Returns the number of signed updates verified.
+    pub fn replay(
+        &self,
+        channel_id: H256,
+        verifier: &SignatureVerifier,
+        quorum: Quorum,
+    ) -> Result<usize, HistoryError> {
+        let entries = self.channels.get(&channel_id).ok_or(HistoryError::NotFound(channel_id))?;
+        let latest = entries.keys().next_back().copied();
+        let mut previous: Option<&ChannelState> = None;
+        let mut verified = 0;
+
+        for entry in entries.values() {
+            let actual = entry.state.state_hash();
+
+            match (&entry.update, previous) {
+                (Some(update), previous) => {
+                    if previous.is_some_and(|state| state.state_hash() != update.previous_state) {
+                        return Err(HistoryError::BrokenChain { sequence: entry.sequence() });
+                    }
+                    if update.new_state != actual {
+                        return Err(HistoryError::HashMismatch {
+                            sequence: entry.sequence(),
+                            expected: update.new_state,
+                            actual,
+                        });
+                    }
+
+                    if update.signatures.is_empty() {
+                        // Anything older has been built on, so it must have been signed
+                        if Some(entry.sequence()) != latest {
+                            return Err(HistoryError::InvalidSignatures {
+                                sequence: entry.sequence(),
+                                reason: "Unsigned update before the latest state".into(),
+                            });
+                        }
+                    } else {
+                        // Signed by the participants of the state it was applied to
+                        previous.unwrap_or(&entry.state)
+                            .verify_signatures(verifier, quorum, entry.state.sequence, actual, &update.signatures)
+                            .map_err(|e| HistoryError::InvalidSignatures {
+                                sequence: entry.sequence(),
+                                reason: e.to_string(),
+                            })?;
+                        verified += 1;
+                    }
+                }
+                // Only the genesis state comes without an update
+                (None, Some(_)) => return Err(HistoryError::MissingUpdate(entry.sequence())),
+                (None, None) => {}
+            }
+
+            previous = Some(&entry.state);
+        }
+
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+    use k256::ecdsa::SigningKey;
+    use crate::crypto::signature::{address_of, channel_state_message, sign_recoverable};
+
+    fn entry(state: &ChannelState, previous: Option<&ChannelState>, timestamp: u64, keys: &[SigningKey]) -> HistoryEntry {
+        let message = channel_state_message(state.channel_id, state.sequence, state.state_hash());
+        HistoryEntry {
+            update: previous.map(|previous| StateUpdate {
+                channel_id: state.channel_id,
+                sequence: state.sequence,
+                timestamp,
+                previous_state: previous.state_hash(),
+                new_state: state.state_hash(),
+                signatures: keys.iter()
+                    .map(|key| (address_of(key.verifying_key()), sign_recoverable(key, message.as_bytes()).unwrap().to_vec()))
+                    .collect(),
+            }),
+            state: state.clone(),
+        }
+    }
+
+    fn history(length: u64) -> (StateHistory, Vec<ChannelState>) {
+        let keys: Vec<SigningKey> = (0..2).map(|_| SigningKey::random(&mut rand::thread_rng())).collect();
+        let participants = keys.iter().map(|key| address_of(key.verifying_key())).collect();
+        let mut state = ChannelState::new(H256::random(), participants, U256::from(1000));
+        state.last_update = 100;
+
+        let mut history = StateHistory::new();
+        let mut states = vec![state.clone()];
+        history.record(entry(&state, None, 100, &keys));
+
+        for sequence in 1..=length {
+            let previous = state.clone();
+            state.sequence = sequence;
+            state.balances.get_mut(&state.participants[0]).unwrap().amount = U256::from(sequence);
+            // The last update is local and not countersigned yet
+            let signers = if sequence == length { &[][..] } else { &keys[..] };
+            history.record(entry(&state, Some(&previous), 100 + sequence * 10, signers));
+            states.push(state.clone());
+        }
+
+        (history, states)
+    }
+
+    fn replay(history: &StateHistory, channel_id: H256) -> Result<usize, HistoryError> {
+        history.replay(channel_id, &SignatureVerifier::new(2), Quorum::All)
+    }
+
+    #[test]
+    fn test_point_in_time_queries() {
+        let (history, states) = history(5);
+        let channel_id = states[0].channel_id;
+        let participant = states[0].participants[1];
+
+        assert_eq!(history.at_sequence(channel_id, 3).unwrap().sequence(), 3);
+        assert_eq!(history.at_time(channel_id, 125).unwrap().sequence(), 2);
+        assert!(history.at_time(channel_id, 99).is_none());
+        assert_eq!(history.between(channel_id, 110, 130).len(), 3);
+        assert_eq!(history.for_participant(&participant, 0, u64::MAX).len(), 6);
+        assert!(history.for_participant(&Address::random(), 0, u64::MAX).is_empty());
+
+        assert_eq!(replay(&history, channel_id), Ok(4));
+    }
+
+    #[test]
+    fn test_replay_detects_tampering_and_survives_pruning() {
+        let (mut history, states) = history(4);
+        let channel_id = states[0].channel_id;
+
+        let pruned = history.prune(&RetentionPolicy { max_entries: Some(3), max_age: None }, 0);
+        assert_eq!(pruned, vec![(channel_id, 0), (channel_id, 1)]);
+        assert_eq!(replay(&history, channel_id), Ok(2));
+
+        // Rehashed after tampering, but the signatures no longer match
+        let mut forged = history.at_sequence(channel_id, 3).unwrap().clone();
+        forged.state.balances.get_mut(&states[0].participants[0]).unwrap().amount = U256::from(998);
+        forged.update.as_mut().unwrap().new_state = forged.state.state_hash();
+        let mut forged_history = StateHistory::new();
+        for sequence in 2..=4 {
+            let entry = history.at_sequence(channel_id, sequence).unwrap().clone();
+            forged_history.record(if sequence == 3 { forged.clone() } else { entry });
+        }
+        assert!(matches!(replay(&forged_history, channel_id), Err(HistoryError::InvalidSignatures { sequence: 3, .. })));
+
+        // Passing it off as a local update that wasn't countersigned yet
+        forged.update.as_mut().unwrap().signatures.clear();
+        forged_history.record(forged);
+        assert!(matches!(replay(&forged_history, channel_id), Err(HistoryError::InvalidSignatures { sequence: 3, .. })));
+
+        let mut tampered = history.at_sequence(channel_id, 3).unwrap().clone();
+        tampered.state.balances.get_mut(&states[0].participants[0]).unwrap().amount = U256::from(999);
+        history.record(tampered);
+        assert!(matches!(replay(&history, channel_id), Err(HistoryError::HashMismatch { sequence: 3, .. })));
+    }
+}
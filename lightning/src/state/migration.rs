@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use flashchain_common::types::AssetId;
+use crate::channel::parameters::ChannelParameters;
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+    #[error("No migration for {kind:?} records from version {version}")]
+    MissingStep { kind: RecordKind, version: u32 },
+    #[error("{kind:?} record has version {version}, newer than the supported {supported}")]
+    TooNew { kind: RecordKind, version: u32, supported: u32 },
+    #[error("Migrating {kind:?} record from version {version} failed: {reason}")]
+    Failed { kind: RecordKind, version: u32, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RecordKind {
+    ChannelState,
+    StateUpdate,
+    NetworkState,
+    HistoryEntry,
+}
+
+/// Envelope every persisted record is written in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedRecord {
+    pub kind: RecordKind,
+    pub version: u32,
+    pub data: Value,
+}
+
+impl VersionedRecord {
+    pub fn new<T: Serialize>(kind: RecordKind, version: u32, value: &T) -> Result<Self, MigrationError> {
+        let data = serde_json::to_value(value)
+            .map_err(|e| MigrationError::Encoding(e.to_string()))?;
+
+        Ok(Self { kind, version, data })
+    }
+
+    /// Parses a stored record. Records written before envelopes existed are version 0.
+    pub fn parse(kind: RecordKind, bytes: &[u8]) -> Result<Self, MigrationError> {
+        let value: Value = serde_json::from_slice(bytes)
+            .map_err(|e| MigrationError::Encoding(e.to_string()))?;
+
+        let is_envelope = value.get("kind").is_some()
+            && value.get("version").is_some_and(Value::is_u64)
+            && value.get("data").is_some();
+        if !is_envelope {
+            return Ok(Self { kind, version: 0, data: value });
+        }
+
+        serde_json::from_value(value).map_err(|e| MigrationError::Encoding(e.to_string()))
+    }
+
+    pub fn decode<T: DeserializeOwned>(self) -> Result<T, MigrationError> {
+        serde_json::from_value(self.data).map_err(|e| MigrationError::Encoding(e.to_string()))
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, MigrationError> {
+        serde_json::to_vec(self).map_err(|e| MigrationError::Encoding(e.to_string()))
+    }
+}
+
+/// Turns the data of a record at one version into the data of the next
+pub type Upgrade = fn(Value) -> Result<Value, String>;
+
+/// Kind and field of the record embedded in records of `kind`, if any. Such
+/// records are versioned along with the embedded kind, whose steps upgrade them.
+fn embedded(kind: RecordKind) -> Option<(RecordKind, &'static str)> {
+    match kind {
+        RecordKind::HistoryEntry => Some((RecordKind::ChannelState, "state")),
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct Migration {
+    pub kind: RecordKind,
+    pub from_version: u32,
+    pub description: &'static str,
+    upgrade: Upgrade,
+}
+
+/// Steps that upgrade records of each kind one version at a time.
+///
+/// The current version of a kind is one past its last registered step, so adding
+/// a step is all it takes to bump the schema.
+#[derive(Clone)]
+pub struct MigrationRegistry {
+    steps: HashMap<(RecordKind, u32), Migration>,
+}
+
+impl MigrationRegistry {
+    /// A registry without any steps, where every kind is at version 0
+    pub fn empty() -> Self {
+        Self { steps: HashMap::new() }
+    }
+
+    pub fn register(
+        mut self,
+        kind: RecordKind,
+        from_version: u32,
+        description: &'static str,
+        upgrade: Upgrade,
+    ) -> Self {
+        self.steps.insert((kind, from_version), Migration {
+            kind,
+            from_version,
+            description,
+            upgrade,
+        });
+        self
+    }
+
+    pub fn current_version(&self, kind: RecordKind) -> u32 {
+        if let Some((embedded_kind, _)) = embedded(kind) {
+            return self.current_version(embedded_kind);
+        }
+
+        self.steps.keys()
+            .filter(|(step_kind, _)| *step_kind == kind)
+            .map(|(_, from_version)| from_version + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Brings the record to the current version of its kind, returning it with the
+    /// steps that were applied
+    pub fn upgrade(
+        &self,
+        mut record: VersionedRecord,
+    ) -> Result<(VersionedRecord, Vec<&'static str>), MigrationError> {
+        let supported = self.current_version(record.kind);
+        if record.version > supported {
+            return Err(MigrationError::TooNew {
+                kind: record.kind,
+                version: record.version,
+                supported,
+            });
+        }
+
+        let (step_kind, field) = match embedded(record.kind) {
+            Some((embedded_kind, field)) => (embedded_kind, Some(field)),
+            None => (record.kind, None),
+        };
+
+        let mut applied = Vec::new();
+        while record.version < supported {
+            let step = self.steps.get(&(step_kind, record.version))
+                .ok_or(MigrationError::MissingStep { kind: record.kind, version: record.version })?;
+
+            let failed = |reason| MigrationError::Failed {
+                kind: record.kind,
+                version: record.version,
+                reason,
+            };
+            match field {
+                Some(field) => {
+                    let data = record.data.get_mut(field)
+                        .ok_or_else(|| failed(format!("Missing {}", field)))?;
+                    *data = (step.upgrade)(data.take()).map_err(failed)?;
+                }
+                None => record.data = (step.upgrade)(record.data).map_err(failed)?,
+            }
+            record.version += 1;
+            applied.push(step.description);
+        }
+
+        Ok((record, applied))
+    }
+}
+
+impl Default for MigrationRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .register(RecordKind::ChannelState, 0, "Tag HTLCs with their asset, add token balances and rename Created to Initializing", tag_assets)
+            .register(RecordKind::StateUpdate, 0, "Wrap in a versioned envelope", Ok)
+            .register(RecordKind::NetworkState, 0, "Wrap in a versioned envelope", Ok)
+    }
+}
+
+// Channel states from before multi-asset support only held the native asset, and
+// called channels that were still opening `Created`
+fn tag_assets(mut value: Value) -> Result<Value, String> {
+    let parameters = serde_json::to_value(ChannelParameters::default()).map_err(|e| e.to_string())?;
+    let native = serde_json::to_value(AssetId::NATIVE).map_err(|e| e.to_string())?;
+
+    let state = value.as_object_mut().ok_or("Channel state is not an object")?;
+    state.entry("token_balances").or_insert_with(|| json!({}));
+    state.entry("token_capacity").or_insert_with(|| json!({}));
+    state.entry("parameters").or_insert(parameters);
+    if state.get("status").and_then(Value::as_str) == Some("Created") {
+        state.insert("status".to_string(), json!("Initializing"));
+    }
+
+    if let Some(htlcs) = state.get_mut("htlcs").and_then(Value::as_object_mut) {
+        for htlc in htlcs.values_mut() {
+            let htlc = htlc.as_object_mut().ok_or("HTLC is not an object")?;
+            htlc.entry("asset").or_insert_with(|| native.clone());
+        }
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::channel_state::ChannelStatus;
+
+    #[test]
+    fn test_legacy_channel_state_upgrades() {
+        let legacy = json!({
+            "channel_id": format!("{:?}", ethers::types::H256::random()),
+            "htlcs": { "0x01": { "amount": "0x64" } },
+            "status": "Created",
+        });
+        let record = VersionedRecord::parse(RecordKind::ChannelState, legacy.to_string().as_bytes()).unwrap();
+        assert_eq!(record.version, 0);
+
+        let registry = MigrationRegistry::default();
+        let (record, applied) = registry.upgrade(record).unwrap();
+
+        assert_eq!(record.version, registry.current_version(RecordKind::ChannelState));
+        assert_eq!(applied.len(), 1);
+        assert_eq!(record.data["htlcs"]["0x01"]["asset"], serde_json::to_value(AssetId::NATIVE).unwrap());
+        assert_eq!(record.data["token_balances"], json!({}));
+        assert_eq!(record.data["status"], serde_json::to_value(ChannelStatus::Initializing).unwrap());
+
+        // Current records come back as they are
+        let reparsed = VersionedRecord::parse(RecordKind::ChannelState, &record.encode().unwrap()).unwrap();
+        assert!(registry.upgrade(reparsed).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn test_legacy_history_entry_upgrades_its_state() {
+        let legacy = json!({
+            "update": null,
+            "state": {
+                "channel_id": format!("{:?}", ethers::types::H256::random()),
+                "htlcs": { "0x01": { "amount": "0x64" } },
+                "status": "Created",
+            },
+        });
+        let record = VersionedRecord::parse(RecordKind::HistoryEntry, legacy.to_string().as_bytes()).unwrap();
+
+        let registry = MigrationRegistry::default();
+        let (record, applied) = registry.upgrade(record).unwrap();
+
+        assert_eq!(record.version, registry.current_version(RecordKind::ChannelState));
+        assert_eq!(applied.len(), 1);
+        assert_eq!(record.data["update"], Value::Null);
+        assert_eq!(record.data["state"]["htlcs"]["0x01"]["asset"], serde_json::to_value(AssetId::NATIVE).unwrap());
+        assert_eq!(record.data["state"]["status"], serde_json::to_value(ChannelStatus::Initializing).unwrap());
+    }
+
+    #[test]
+    fn test_steps_apply_in_order_and_newer_records_are_rejected() {
+        fn add_one(mut value: Value) -> Result<Value, String> {
+            value["steps"] = json!(value["steps"].as_u64().unwrap_or(0) * 10 + 1);
+            Ok(value)
+        }
+        fn add_two(mut value: Value) -> Result<Value, String> {
+            value["steps"] = json!(value["steps"].as_u64().unwrap_or(0) * 10 + 2);
+            Ok(value)
+        }
+
+        let registry = MigrationRegistry::empty()
+            .register(RecordKind::NetworkState, 1, "second", add_two)
+            .register(RecordKind::NetworkState, 0, "first", add_one);
+
+        let record = VersionedRecord { kind: RecordKind::NetworkState, version: 0, data: json!({}) };
+        let (record, applied) = registry.upgrade(record).unwrap();
+        assert_eq!(record.data["steps"], json!(12));
+        assert_eq!(applied, vec!["first", "second"]);
+
+        let newer = VersionedRecord { kind: RecordKind::NetworkState, version: 3, data: json!({}) };
+        assert!(matches!(registry.upgrade(newer), Err(MigrationError::TooNew { supported: 2, .. })));
+    }
+}
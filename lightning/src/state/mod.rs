@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use ethers::types::{Address, H256, U256};
@@ -6,11 +7,18 @@ use serde::{Serialize, Deserialize};
 use thiserror::Error;
 
 pub mod channel_state;
+pub mod history;
+pub mod migration;
 pub mod network_state;
 pub mod persistence;
 
-use channel_state::ChannelState;
+use channel_state::{ChannelState, ChannelStatus};
+use history::{HistoryEntry, RetentionPolicy, StateHistory};
 use network_state::NetworkState;
+use crate::channel::clock::{ChainClock, TimeoutScheduler};
+use crate::channel::state_machine::{TransitionContext, TransitionRecord};
+use crate::crypto::signature::{Quorum, SignatureVerifier};
+use crate::events::{EventBus, HtlcOutcome, LightningEvent};
 
 #[derive(Error, Debug)]
 pub enum StateError {
@@ -24,6 +32,12 @@ pub enum StateError {
     PersistenceError(String),
     #[error("Concurrent modification error: {0}")]
     ConcurrentModification(String),
+    #[error("Invalid signatures: {0}")]
+    InvalidSignatures(String),
+    #[error("Chain clock error: {0}")]
+    Clock(String),
+    #[error("Channel limit: {0}")]
+    Limit(#[from] crate::channel::ChannelError),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,14 +54,33 @@ pub struct StateManager {
     channel_states: Arc<RwLock<HashMap<H256, ChannelState>>>,
     network_state: Arc<RwLock<NetworkState>>,
     persistence: Arc<persistence::StatePersistence>,
+    history: RwLock<StateHistory>,
+    transitions: RwLock<HashMap<H256, Vec<TransitionRecord>>>,
+    retention: RetentionPolicy,
+    verifier: Arc<SignatureVerifier>,
+    quorum: Quorum,
+    clock: Arc<dyn ChainClock>,
+    events: Option<Arc<EventBus>>,
+    scheduler: Option<Arc<TimeoutScheduler>>,
 }
 
 impl StateManager {
-    pub async fn new(persistence: persistence::StatePersistence) -> Result<Self, StateError> {
+    pub async fn new(
+        persistence: persistence::StatePersistence,
+        clock: Arc<dyn ChainClock>,
+    ) -> Result<Self, StateError> {
         let manager = Self {
             channel_states: Arc::new(RwLock::new(HashMap::new())),
             network_state: Arc::new(RwLock::new(NetworkState::new())),
             persistence: Arc::new(persistence),
+            history: RwLock::new(StateHistory::new()),
+            transitions: RwLock::new(HashMap::new()),
+            retention: RetentionPolicy::default(),
+            verifier: Arc::new(SignatureVerifier::new(0)),
+            quorum: Quorum::All,
+            clock,
+            events: None,
+            scheduler: None,
         };
 
         // Load persisted states
@@ -56,31 +89,102 @@ impl StateManager {
         Ok(manager)
     }
 
+    /// Publishes state and HTLC changes on `events`
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Schedules the expiry of every HTLC created from here on, for the expiry sweeper
+    pub fn with_scheduler(mut self, scheduler: Arc<TimeoutScheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// How much of each channel's history is kept, keeping everything by default
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Keys updates are checked against. The default verifier has none, so only
+    /// recoverable signatures, whose signer is recovered from them, verify.
+    pub fn with_verifier(mut self, verifier: Arc<SignatureVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    /// How many participants have to sign an update, all of them by default
+    pub fn with_quorum(mut self, quorum: Quorum) -> Self {
+        self.quorum = quorum;
+        self
+    }
+
+    /// Applies `update` if it was built on the channel's current state. An update
+    /// built on a state that has changed since is rejected with `ConcurrentModification`.
     pub async fn update_channel_state(
         &self,
         channel_id: H256,
         update: StateUpdate,
     ) -> Result<(), StateError> {
-        // Verify state transition
-        self.verify_state_update(&update).await?;
-
-        // Acquire write lock
         let mut states = self.channel_states.write().await;
-
-        // Get current state
         let current_state = states.get_mut(&channel_id)
             .ok_or_else(|| StateError::NotFound(format!("Channel {}", channel_id)))?;
 
+        // Checked under the write lock, so two updates built on the same state can't both apply
+        Self::verify_state_update(current_state, &update)?;
+
         // Apply update
-        current_state.apply_update(update.clone()).await?;
+        let mut next_state = current_state.clone();
+        next_state.apply_update(update.clone()).await?;
+        self.verify_signatures(current_state, &next_state, &update)?;
+        *current_state = next_state;
 
         // Persist update
         self.persistence.persist_state_update(&update).await
             .map_err(|e| StateError::PersistenceError(e.to_string()))?;
 
+        self.record_history(HistoryEntry {
+            update: Some(update.clone()),
+            state: current_state.clone(),
+        }).await?;
+
+        self.publish(LightningEvent::StateUpdated {
+            channel_id,
+            sequence: update.sequence,
+            state_hash: update.new_state,
+        });
+
         Ok(())
     }
 
+    /// Builds an update on the channel's latest state and applies it, building it
+    /// again on the new state whenever another update got in first
+    pub async fn update_with_retry<F, Fut>(
+        &self,
+        channel_id: H256,
+        max_attempts: usize,
+        mut build: F,
+    ) -> Result<StateUpdate, StateError>
+    where
+        F: FnMut(ChannelState) -> Fut,
+        Fut: Future<Output = Result<StateUpdate, StateError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let update = build(self.get_channel_state(channel_id).await?).await?;
+
+            match self.update_channel_state(channel_id, update.clone()).await {
+                Ok(()) => return Ok(update),
+                Err(StateError::ConcurrentModification(reason)) if attempt < max_attempts => {
+                    log::debug!("Rebasing update on channel {}: {}", channel_id, reason);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     pub async fn get_channel_state(&self, channel_id: H256) -> Result<ChannelState, StateError> {
         let states = self.channel_states.read().await;
         states.get(&channel_id)
@@ -107,35 +211,166 @@ impl StateManager {
         self.persistence.persist_channel_state(&state).await
             .map_err(|e| StateError::PersistenceError(e.to_string()))?;
 
+        self.record_history(HistoryEntry { update: None, state }).await?;
+
         Ok(())
     }
 
-    pub async fn close_channel_state(&self, channel_id: H256) -> Result<(), StateError> {
+    pub async fn create_htlc(
+        &self,
+        channel_id: H256,
+        sender: Address,
+        receiver: Address,
+        amount: U256,
+        hash_lock: H256,
+        timeout: u64,
+    ) -> Result<H256, StateError> {
+        let current_height = self.current_height().await?;
+        if timeout <= current_height {
+            return Err(StateError::InvalidTransition(
+                format!("HTLC timeout {} not after current height {}", timeout, current_height)
+            ));
+        }
+
+        let mut states = self.channel_states.write().await;
+        let state = states.get_mut(&channel_id)
+            .ok_or_else(|| StateError::NotFound(format!("Channel {}", channel_id)))?;
+
+        let previous_state = state.state_hash();
+        let htlc_id = state.create_htlc(sender, receiver, amount, hash_lock, timeout)?;
+        state.last_update = chrono::Utc::now().timestamp() as u64;
+
+        // Unsigned until the counterparty countersigns the new state
+        let update = StateUpdate {
+            channel_id,
+            sequence: state.sequence,
+            timestamp: state.last_update,
+            previous_state,
+            new_state: state.state_hash(),
+            signatures: HashMap::new(),
+        };
+
+        self.persistence.persist_channel_state(state).await
+            .map_err(|e| StateError::PersistenceError(e.to_string()))?;
+
+        self.record_history(HistoryEntry {
+            update: Some(update),
+            state: state.clone(),
+        }).await?;
+        drop(states);
+
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.track_htlc(channel_id, htlc_id, timeout).await;
+        }
+
+        self.publish(LightningEvent::HtlcAdded {
+            channel_id,
+            htlc_id,
+            amount,
+            hash_lock,
+            timeout,
+        });
+
+        Ok(htlc_id)
+    }
+
+    /// Expires a timed out HTLC and returns it with the unsigned update that refunds its sender
+    pub async fn expire_htlc(
+        &self,
+        channel_id: H256,
+        htlc_id: H256,
+    ) -> Result<(channel_state::Htlc, StateUpdate), StateError> {
+        let current_height = self.current_height().await?;
+
+        let mut states = self.channel_states.write().await;
+        let state = states.get_mut(&channel_id)
+            .ok_or_else(|| StateError::NotFound(format!("Channel {}", channel_id)))?;
+
+        let previous_state = state.state_hash();
+        let htlc = state.expire_htlc(htlc_id, current_height)?;
+        state.last_update = chrono::Utc::now().timestamp() as u64;
+
+        let update = StateUpdate {
+            channel_id,
+            sequence: state.sequence,
+            timestamp: state.last_update,
+            previous_state,
+            new_state: state.state_hash(),
+            signatures: HashMap::new(),
+        };
+
+        self.persistence.persist_channel_state(state).await
+            .map_err(|e| StateError::PersistenceError(e.to_string()))?;
+
+        self.record_history(HistoryEntry {
+            update: Some(update.clone()),
+            state: state.clone(),
+        }).await?;
+
+        self.publish(LightningEvent::HtlcResolved {
+            channel_id,
+            htlc_id,
+            outcome: HtlcOutcome::Expired,
+        });
+
+        Ok((htlc, update))
+    }
+
+    /// Lists every pending HTLC as (channel id, HTLC id, timeout height)
+    pub async fn pending_htlc_timeouts(&self) -> Vec<(H256, H256, u64)> {
+        let states = self.channel_states.read().await;
+        states.values()
+            .flat_map(|state| {
+                state.pending_htlcs().map(move |htlc| (state.channel_id, htlc.id, htlc.timeout))
+            })
+            .collect()
+    }
+
+    pub async fn current_height(&self) -> Result<u64, StateError> {
+        self.clock.current_height().await
+            .map_err(|e| StateError::Clock(e.to_string()))
+    }
+
+    /// Takes the channel's next closing step, settling only once the chain is past
+    /// `timeout_height`. The channel is dropped from the active states once closed.
+    pub async fn close_channel_state(&self, channel_id: H256, timeout_height: u64) -> Result<TransitionRecord, StateError> {
+        let current_height = self.current_height().await?;
         let mut states = self.channel_states.write().await;
         
         let state = states.get_mut(&channel_id)
             .ok_or_else(|| StateError::NotFound(format!("Channel {}", channel_id)))?;
 
-        state.close().await?;
+        let record = state.close(TransitionContext { current_height, timeout_height })?;
 
-        // Persist final state
         self.persistence.persist_channel_state(state).await
             .map_err(|e| StateError::PersistenceError(e.to_string()))?;
 
-        // Remove from active states
-        states.remove(&channel_id);
+        if record.to == ChannelStatus::Closed {
+            states.remove(&channel_id);
+        }
+        drop(states);
 
-        Ok(())
+        self.transitions.write().await
+            .entry(channel_id)
+            .or_insert_with(Vec::new)
+            .push(record.clone());
+
+        Ok(record)
     }
 
-    pub async fn update_network_state(&self, update: NetworkState) -> Result<(), StateError> {
-        let mut network = self.network_state.write().await;
-        *network = update;
+    /// Every status transition the channel went through here, oldest first
+    pub async fn transition_history(&self, channel_id: H256) -> Vec<TransitionRecord> {
+        self.transitions.read().await.get(&channel_id).cloned().unwrap_or_default()
+    }
 
+    pub async fn update_network_state(&self, update: NetworkState) -> Result<(), StateError> {
         // Persist network state
         self.persistence.persist_network_state(&update).await
             .map_err(|e| StateError::PersistenceError(e.to_string()))?;
 
+        let mut network = self.network_state.write().await;
+        *network = update;
+
         Ok(())
     }
 
@@ -143,6 +378,71 @@ impl StateManager {
         self.network_state.read().await.clone()
     }
 
+    /// The channel as it was at `sequence`, with the update that led there
+    pub async fn state_at_sequence(&self, channel_id: H256, sequence: u64) -> Result<HistoryEntry, StateError> {
+        self.history.read().await
+            .at_sequence(channel_id, sequence)
+            .cloned()
+            .ok_or_else(|| StateError::NotFound(format!("Channel {} at sequence {}", channel_id, sequence)))
+    }
+
+    /// The channel as it was at `timestamp`
+    pub async fn state_at_time(&self, channel_id: H256, timestamp: u64) -> Result<HistoryEntry, StateError> {
+        self.history.read().await
+            .at_time(channel_id, timestamp)
+            .cloned()
+            .ok_or_else(|| StateError::NotFound(format!("Channel {} at time {}", channel_id, timestamp)))
+    }
+
+    pub async fn history_between(&self, channel_id: H256, from: u64, to: u64) -> Vec<HistoryEntry> {
+        self.history.read().await
+            .between(channel_id, from, to)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    pub async fn participant_history(&self, participant: Address, from: u64, to: u64) -> Vec<HistoryEntry> {
+        self.history.read().await
+            .for_participant(&participant, from, to)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Recomputes every state hash in the channel's history and checks it and the
+    /// signatures against the updates, returning how many signed updates were verified
+    pub async fn replay_history(&self, channel_id: H256) -> Result<usize, StateError> {
+        self.history.read().await
+            .replay(channel_id, &self.verifier, self.quorum)
+            .map_err(|e| StateError::Corruption(e.to_string()))
+    }
+
+    async fn record_history(&self, entry: HistoryEntry) -> Result<(), StateError> {
+        self.persistence.persist_history_entry(&entry).await
+            .map_err(|e| StateError::PersistenceError(e.to_string()))?;
+
+        let pruned = {
+            let mut history = self.history.write().await;
+            history.record(entry);
+            history.prune(&self.retention, chrono::Utc::now().timestamp() as u64)
+        };
+
+        for (channel_id, sequence) in pruned {
+            self.persistence.remove_history_entry(channel_id, sequence).await
+                .map_err(|e| StateError::PersistenceError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Called with the channel's state still locked, so its events stay in order
+    fn publish(&self, event: LightningEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.publish(event);
+        }
+    }
+
     async fn load_persisted_states(&self) -> Result<(), StateError> {
         // Load channel states
         let channel_states = self.persistence.load_channel_states().await
@@ -158,64 +458,138 @@ impl StateManager {
         let mut network = self.network_state.write().await;
         *network = network_state;
 
+        let entries = self.persistence.load_history().await
+            .map_err(|e| StateError::PersistenceError(e.to_string()))?;
+
+        let mut history = self.history.write().await;
+        for entry in entries {
+            history.record(entry);
+        }
+
         Ok(())
     }
 
-    async fn verify_state_update(&self, update: &StateUpdate) -> Result<(), StateError> {
-        // Verify sequence number
-        let states = self.channel_states.read().await;
-        let current_state = states.get(&update.channel_id)
-            .ok_or_else(|| StateError::NotFound(format!("Channel {}", update.channel_id)))?;
+    fn verify_state_update(current_state: &ChannelState, update: &StateUpdate) -> Result<(), StateError> {
+        if update.channel_id != current_state.channel_id {
+            return Err(StateError::InvalidTransition("Update is for another channel".into()));
+        }
 
-        if update.sequence != current_state.sequence + 1 {
+        if update.sequence > current_state.sequence + 1 {
             return Err(StateError::InvalidTransition("Invalid sequence number".into()));
         }
 
-        // Verify signatures
-        if !self.verify_signatures(update).await? {
-            return Err(StateError::InvalidTransition("Invalid signatures".into()));
+        // The update must be built on exactly the state we hold
+        let current_hash = current_state.state_hash();
+        if update.sequence <= current_state.sequence || update.previous_state != current_hash {
+            return Err(StateError::ConcurrentModification(format!(
+                "Update {} built on {:?}, channel {} is at {} with state {:?}",
+                update.sequence, update.previous_state, current_state.channel_id,
+                current_state.sequence, current_hash,
+            )));
         }
 
         Ok(())
     }
 
-    async fn verify_signatures(&self, update: &StateUpdate) -> Result<bool, StateError> {
-        let states = self.channel_states.read().await;
-        let current_state = states.get(&update.channel_id)
-            .ok_or_else(|| StateError::NotFound(format!("Channel {}", update.channel_id)))?;
-
-        // Verify all required participants have signed
-        for participant in &current_state.participants {
-            if !update.signatures.contains_key(participant) {
-                return Ok(false);
-            }
-        }
-
-        // Verify each signature
-        for (address, signature) in &update.signatures {
-            if !current_state.verify_signature(address, &update.new_state, signature) {
-                return Ok(false);
-            }
+    /// Checks the update names the canonical hash of the state it leads to, and that
+    /// the participants signed that hash
+    fn verify_signatures(
+        &self,
+        current_state: &ChannelState,
+        next_state: &ChannelState,
+        update: &StateUpdate,
+    ) -> Result<(), StateError> {
+        let state_hash = next_state.state_hash();
+        if update.new_state != state_hash {
+            return Err(StateError::InvalidTransition(format!(
+                "Update names state {:?}, applying it gives {:?}", update.new_state, state_hash
+            )));
         }
 
-        Ok(true)
+        current_state.verify_signatures(&self.verifier, self.quorum, next_state.sequence, state_hash, &update.signatures)
+            .map_err(|e| StateError::InvalidSignatures(e.to_string()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use k256::ecdsa::SigningKey;
     use tokio::test;
+    use crate::channel::clock::{ManualClock, TimeoutKind};
+    use crate::crypto::signature::{address_of, channel_state_message, sign_recoverable};
+
+    struct Signers {
+        keys: Vec<(Address, SigningKey)>,
+        verifier: Arc<SignatureVerifier>,
+    }
+
+    impl Signers {
+        fn new() -> Self {
+            let keys: Vec<_> = (0..2)
+                .map(|_| {
+                    let key = SigningKey::random(&mut rand::thread_rng());
+                    (address_of(key.verifying_key()), key)
+                })
+                .collect();
+
+            // Signatures are recovered, no keys have to be registered
+            let verifier = SignatureVerifier::new(keys.len());
+            Self { keys, verifier: Arc::new(verifier) }
+        }
+
+        fn addresses(&self) -> Vec<Address> {
+            self.keys.iter().map(|(address, _)| *address).collect()
+        }
+
+        /// The next update of `state`, signed by every participant
+        fn update(&self, state: &ChannelState, timestamp: u64) -> StateUpdate {
+            let mut next_state = state.clone();
+            next_state.sequence += 1;
+            let new_state = next_state.state_hash();
+            let message = channel_state_message(state.channel_id, next_state.sequence, new_state);
+
+            StateUpdate {
+                channel_id: state.channel_id,
+                sequence: next_state.sequence,
+                timestamp,
+                previous_state: state.state_hash(),
+                new_state,
+                signatures: self.keys.iter()
+                    .map(|(address, key)| {
+                        (*address, sign_recoverable(key, message.as_bytes()).unwrap().to_vec())
+                    })
+                    .collect(),
+            }
+        }
+
+        async fn manager(&self) -> StateManager {
+            let persistence = persistence::StatePersistence::in_memory();
+            StateManager::new(persistence, Arc::new(ManualClock::new(0))).await.unwrap()
+                .with_verifier(self.verifier.clone())
+        }
+    }
+
+    async fn manager_with_channel() -> (Arc<StateManager>, Arc<Signers>, H256) {
+        let signers = Signers::new();
+        let state_manager = signers.manager().await;
+
+        let channel_id = H256::random();
+        state_manager.create_channel_state(channel_id, signers.addresses(), U256::from(1000000))
+            .await
+            .unwrap();
+
+        (Arc::new(state_manager), Arc::new(signers), channel_id)
+    }
 
     #[test]
     async fn test_state_creation_and_update() {
-        // Create mock persistence
-        let persistence = persistence::MockStatePersistence::new();
-        let state_manager = StateManager::new(persistence).await.unwrap();
+        let signers = Signers::new();
+        let state_manager = signers.manager().await;
 
         // Create channel state
         let channel_id = H256::random();
-        let participants = vec![Address::random(), Address::random()];
+        let participants = signers.addresses();
         let capacity = U256::from(1000000);
 
         state_manager.create_channel_state(channel_id, participants.clone(), capacity)
@@ -228,14 +602,7 @@ mod tests {
         assert_eq!(state.capacity, capacity);
 
         // Create state update
-        let update = StateUpdate {
-            channel_id,
-            sequence: 1,
-            timestamp: 12345,
-            previous_state: state.state_hash(),
-            new_state: H256::random(),
-            signatures: HashMap::new(),
-        };
+        let update = signers.update(&state, 12345);
 
         // Update state
         state_manager.update_channel_state(channel_id, update.clone())
@@ -247,10 +614,29 @@ mod tests {
         assert_eq!(updated_state.sequence, 1);
     }
 
+    #[test]
+    async fn test_history_keeps_every_update() {
+        let (state_manager, signers, channel_id) = manager_with_channel().await;
+        let participants = signers.addresses();
+
+        let genesis = state_manager.get_channel_state(channel_id).await.unwrap();
+        state_manager.update_channel_state(channel_id, signers.update(&genesis, genesis.last_update + 10))
+            .await
+            .unwrap();
+
+        assert!(state_manager.state_at_sequence(channel_id, 0).await.unwrap().update.is_none());
+        let entry = state_manager.state_at_sequence(channel_id, 1).await.unwrap();
+        assert_eq!(entry.update.unwrap().signatures.len(), 2);
+        assert_eq!(state_manager.state_at_time(channel_id, genesis.last_update).await.unwrap().sequence(), 0);
+        assert_eq!(state_manager.participant_history(participants[1], 0, u64::MAX).await.len(), 2);
+
+        assert_eq!(state_manager.replay_history(channel_id).await.unwrap(), 1);
+    }
+
     #[test]
     async fn test_channel_closing() {
-        let persistence = persistence::MockStatePersistence::new();
-        let state_manager = StateManager::new(persistence).await.unwrap();
+        let persistence = persistence::StatePersistence::in_memory();
+        let state_manager = StateManager::new(persistence, Arc::new(ManualClock::new(0))).await.unwrap();
 
         // Create and close channel
         let channel_id = H256::random();
@@ -261,10 +647,126 @@ mod tests {
             .await
             .unwrap();
 
-        state_manager.close_channel_state(channel_id).await.unwrap();
+        state_manager.close_channel_state(channel_id, 0).await.unwrap();
 
         // Verify channel is closed
         let result = state_manager.get_channel_state(channel_id).await;
         assert!(result.is_err());
+        assert_eq!(state_manager.transition_history(channel_id).await[0].to, ChannelStatus::Closed);
+    }
+
+    #[test]
+    async fn test_created_htlc_is_scheduled() {
+        let clock = Arc::new(ManualClock::new(0));
+        let scheduler = Arc::new(TimeoutScheduler::new(clock.clone()));
+        let state_manager = StateManager::new(persistence::StatePersistence::in_memory(), clock).await.unwrap()
+            .with_scheduler(scheduler.clone());
+
+        let channel_id = H256::random();
+        let participants = vec![Address::random(), Address::random()];
+        state_manager.create_channel_state(channel_id, participants.clone(), U256::from(1000000))
+            .await
+            .unwrap();
+        state_manager.channel_states.write().await
+            .get_mut(&channel_id).unwrap()
+            .balances.get_mut(&participants[0]).unwrap()
+            .amount = U256::from(100);
+
+        let mut events = scheduler.subscribe();
+        let htlc_id = state_manager
+            .create_htlc(channel_id, participants[0], participants[1], U256::from(10), H256::random(), 50)
+            .await
+            .unwrap();
+
+        scheduler.fire_until(50).await;
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.kind, TimeoutKind::Htlc { channel_id, htlc_id });
+    }
+
+    #[test]
+    async fn test_stale_update_is_rejected() {
+        let (state_manager, signers, channel_id) = manager_with_channel().await;
+        let state = state_manager.get_channel_state(channel_id).await.unwrap();
+
+        let first = signers.update(&state, 1);
+        let second = signers.update(&state, 2);
+        state_manager.update_channel_state(channel_id, first).await.unwrap();
+
+        let result = state_manager.update_channel_state(channel_id, second).await;
+        assert!(matches!(result, Err(StateError::ConcurrentModification(_))));
     }
-}
\ No newline at end of file
+
+    #[test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_updates_are_never_lost() {
+        let (state_manager, signers, channel_id) = manager_with_channel().await;
+        let writers = 32;
+
+        let handles: Vec<_> = (0..writers)
+            .map(|_| {
+                let (state_manager, signers) = (state_manager.clone(), signers.clone());
+                tokio::spawn(async move {
+                    state_manager.update_with_retry(channel_id, writers, |state| {
+                        let update = signers.update(&state, state.sequence + 1);
+                        async move {
+                            tokio::task::yield_now().await;
+                            Ok(update)
+                        }
+                    }).await
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        let state = state_manager.get_channel_state(channel_id).await.unwrap();
+        assert_eq!(state.sequence, writers as u64);
+
+        // Every update was applied on top of the one before it
+        for sequence in 1..=writers as u64 {
+            let previous = state_manager.state_at_sequence(channel_id, sequence - 1).await.unwrap();
+            let entry = state_manager.state_at_sequence(channel_id, sequence).await.unwrap();
+            assert_eq!(entry.sequence(), sequence);
+            assert_eq!(entry.update.unwrap().previous_state, previous.state.state_hash());
+        }
+    }
+
+    #[test]
+    async fn test_forged_updates_are_rejected() {
+        let (state_manager, signers, channel_id) = manager_with_channel().await;
+        let state = state_manager.get_channel_state(channel_id).await.unwrap();
+        let update = signers.update(&state, 1);
+
+        // Missing a participant
+        let mut forged = update.clone();
+        forged.signatures.remove(&signers.keys[1].0);
+        let result = state_manager.update_channel_state(channel_id, forged).await;
+        assert!(matches!(result, Err(StateError::InvalidSignatures(_))));
+
+        // Signed by someone outside the channel
+        let mut forged = update.clone();
+        let outsider = SigningKey::random(&mut rand::thread_rng());
+        let message = channel_state_message(channel_id, update.sequence, update.new_state);
+        let signature = sign_recoverable(&outsider, message.as_bytes()).unwrap();
+        forged.signatures.insert(address_of(outsider.verifying_key()), signature.to_vec());
+        let result = state_manager.update_channel_state(channel_id, forged).await;
+        assert!(matches!(result, Err(StateError::InvalidSignatures(_))));
+
+        // Signed over the bare state hash instead of the bridge message
+        let mut forged = update.clone();
+        let (address, key) = &signers.keys[0];
+        let signature = sign_recoverable(key, update.new_state.as_bytes()).unwrap();
+        forged.signatures.insert(*address, signature.to_vec());
+        let result = state_manager.update_channel_state(channel_id, forged).await;
+        assert!(matches!(result, Err(StateError::InvalidSignatures(_))));
+
+        // Names a state the update doesn't lead to
+        let mut forged = update.clone();
+        forged.new_state = H256::random();
+        let result = state_manager.update_channel_state(channel_id, forged).await;
+        assert!(matches!(result, Err(StateError::InvalidTransition(_))));
+
+        state_manager.update_channel_state(channel_id, update).await.unwrap();
+    }
+}
@@ -19,6 +19,12 @@ pub struct NetworkState {
     pub last_update: u64,
 }
 
+impl Default for NetworkState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NetworkState {
     pub fn new() -> Self {
         Self {
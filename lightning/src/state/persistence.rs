@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use ethers::types::H256;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use super::channel_state::ChannelState;
+use super::history::HistoryEntry;
+use super::migration::{MigrationError, MigrationRegistry, RecordKind, VersionedRecord};
+use super::network_state::NetworkState;
+use super::StateUpdate;
+
+#[derive(Error, Debug)]
+pub enum PersistenceError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Migration error in {path:?}: {source}")]
+    Migration { path: PathBuf, source: MigrationError },
+}
+
+const CHANNELS_DIR: &str = "channels";
+const UPDATES_DIR: &str = "updates";
+const HISTORY_DIR: &str = "history";
+const NETWORK_FILE: &str = "network.json";
+
+enum Backend {
+    Directory(PathBuf),
+    Memory(Mutex<HashMap<PathBuf, Vec<u8>>>),
+}
+
+/// What migrating one record did, or would do in a dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordMigration {
+    pub path: PathBuf,
+    pub kind: RecordKind,
+    pub from_version: u32,
+    pub to_version: u32,
+    pub steps: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    /// Records that were, or would be, rewritten
+    pub migrated: Vec<RecordMigration>,
+    /// Records already at the current version
+    pub up_to_date: usize,
+}
+
+/// Store of channel states, their updates and history, and the network state,
+/// one versioned record per file. Old records are upgraded through the migration registry as
+/// they are loaded.
+pub struct StatePersistence {
+    backend: Backend,
+    registry: MigrationRegistry,
+}
+
+impl StatePersistence {
+    pub fn open(root: impl Into<PathBuf>) -> Self {
+        Self {
+            backend: Backend::Directory(root.into()),
+            registry: MigrationRegistry::default(),
+        }
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            backend: Backend::Memory(Mutex::new(HashMap::new())),
+            registry: MigrationRegistry::default(),
+        }
+    }
+
+    pub fn with_registry(mut self, registry: MigrationRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub async fn persist_channel_state(&self, state: &ChannelState) -> Result<(), PersistenceError> {
+        let path = Path::new(CHANNELS_DIR).join(format!("{:x}.json", state.channel_id));
+        self.write_record(&path, RecordKind::ChannelState, state).await
+    }
+
+    pub async fn persist_state_update(&self, update: &StateUpdate) -> Result<(), PersistenceError> {
+        let path = Path::new(UPDATES_DIR)
+            .join(format!("{:x}", update.channel_id))
+            .join(format!("{:020}.json", update.sequence));
+        self.write_record(&path, RecordKind::StateUpdate, update).await
+    }
+
+    pub async fn persist_network_state(&self, state: &NetworkState) -> Result<(), PersistenceError> {
+        self.write_record(Path::new(NETWORK_FILE), RecordKind::NetworkState, state).await
+    }
+
+    pub async fn load_channel_states(&self) -> Result<HashMap<H256, ChannelState>, PersistenceError> {
+        let mut states = HashMap::new();
+        for path in self.list(Path::new(CHANNELS_DIR)).await? {
+            let state: ChannelState = self.load_record(&path, RecordKind::ChannelState).await?;
+            states.insert(state.channel_id, state);
+        }
+
+        Ok(states)
+    }
+
+    /// Updates of a channel, oldest first
+    pub async fn load_state_updates(&self, channel_id: H256) -> Result<Vec<StateUpdate>, PersistenceError> {
+        let dir = Path::new(UPDATES_DIR).join(format!("{:x}", channel_id));
+        let mut updates = Vec::new();
+        for path in self.list(&dir).await? {
+            updates.push(self.load_record::<StateUpdate>(&path, RecordKind::StateUpdate).await?);
+        }
+        updates.sort_by_key(|update| update.sequence);
+
+        Ok(updates)
+    }
+
+    pub async fn persist_history_entry(&self, entry: &HistoryEntry) -> Result<(), PersistenceError> {
+        self.write_record(&history_path(entry.channel_id(), entry.sequence()), RecordKind::HistoryEntry, entry).await
+    }
+
+    pub async fn remove_history_entry(&self, channel_id: H256, sequence: u64) -> Result<(), PersistenceError> {
+        self.remove(&history_path(channel_id, sequence)).await
+    }
+
+    pub async fn load_history(&self) -> Result<Vec<HistoryEntry>, PersistenceError> {
+        let mut entries = Vec::new();
+        for dir in self.list_dirs(Path::new(HISTORY_DIR)).await? {
+            for path in self.list(&dir).await? {
+                entries.push(self.load_record(&path, RecordKind::HistoryEntry).await?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// The persisted network state, or an empty one if none was saved yet
+    pub async fn load_network_state(&self) -> Result<NetworkState, PersistenceError> {
+        let path = Path::new(NETWORK_FILE);
+        if self.read(path).await?.is_none() {
+            return Ok(NetworkState::new());
+        }
+
+        self.load_record(path, RecordKind::NetworkState).await
+    }
+
+    /// Rewrites every record older than the current schema. A dry run only
+    /// reports what would change and leaves the store untouched.
+    pub async fn migrate(&self, dry_run: bool) -> Result<MigrationReport, PersistenceError> {
+        let mut report = MigrationReport {
+            dry_run,
+            migrated: Vec::new(),
+            up_to_date: 0,
+        };
+
+        for (path, kind) in self.records().await? {
+            let Some(bytes) = self.read(&path).await? else { continue };
+            let record = VersionedRecord::parse(kind, &bytes)
+                .map_err(|source| PersistenceError::Migration { path: path.clone(), source })?;
+            let from_version = record.version;
+
+            let (record, steps) = self.registry.upgrade(record)
+                .map_err(|source| PersistenceError::Migration { path: path.clone(), source })?;
+            if steps.is_empty() {
+                report.up_to_date += 1;
+                continue;
+            }
+
+            if !dry_run {
+                let bytes = record.encode()
+                    .map_err(|source| PersistenceError::Migration { path: path.clone(), source })?;
+                self.write(&path, bytes).await?;
+            }
+
+            report.migrated.push(RecordMigration {
+                path,
+                kind,
+                from_version,
+                to_version: record.version,
+                steps,
+            });
+        }
+
+        Ok(report)
+    }
+
+    async fn write_record<T: Serialize>(
+        &self,
+        path: &Path,
+        kind: RecordKind,
+        value: &T,
+    ) -> Result<(), PersistenceError> {
+        let bytes = VersionedRecord::new(kind, self.registry.current_version(kind), value)
+            .and_then(|record| record.encode())
+            .map_err(|source| PersistenceError::Migration { path: path.to_path_buf(), source })?;
+
+        self.write(path, bytes).await
+    }
+
+    async fn load_record<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &Path,
+        kind: RecordKind,
+    ) -> Result<T, PersistenceError> {
+        let bytes = self.read(path).await?
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{:?}", path)))?;
+
+        VersionedRecord::parse(kind, &bytes)
+            .and_then(|record| self.registry.upgrade(record))
+            .and_then(|(record, _)| record.decode())
+            .map_err(|source| PersistenceError::Migration { path: path.to_path_buf(), source })
+    }
+
+    /// Every record in the store with its kind, as told by where it's kept
+    async fn records(&self) -> Result<Vec<(PathBuf, RecordKind)>, PersistenceError> {
+        let mut records: Vec<_> = self.list(Path::new(CHANNELS_DIR)).await?
+            .into_iter()
+            .map(|path| (path, RecordKind::ChannelState))
+            .collect();
+
+        for dir in self.list_dirs(Path::new(UPDATES_DIR)).await? {
+            records.extend(self.list(&dir).await?.into_iter().map(|path| (path, RecordKind::StateUpdate)));
+        }
+
+        for dir in self.list_dirs(Path::new(HISTORY_DIR)).await? {
+            records.extend(self.list(&dir).await?.into_iter().map(|path| (path, RecordKind::HistoryEntry)));
+        }
+
+        if self.read(Path::new(NETWORK_FILE)).await?.is_some() {
+            records.push((PathBuf::from(NETWORK_FILE), RecordKind::NetworkState));
+        }
+
+        Ok(records)
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>, PersistenceError> {
+        match &self.backend {
+            Backend::Directory(root) => match tokio::fs::read(root.join(path)).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Backend::Memory(records) => Ok(records.lock().await.get(path).cloned()),
+        }
+    }
+
+    /// Writes next to the final path first so a crash never leaves a torn record
+    async fn write(&self, path: &Path, bytes: Vec<u8>) -> Result<(), PersistenceError> {
+        match &self.backend {
+            Backend::Directory(root) => {
+                let path = root.join(path);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                let tmp_path = path.with_extension("tmp");
+                tokio::fs::write(&tmp_path, &bytes).await?;
+                tokio::fs::rename(&tmp_path, &path).await?;
+            }
+            Backend::Memory(records) => {
+                records.lock().await.insert(path.to_path_buf(), bytes);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> Result<(), PersistenceError> {
+        match &self.backend {
+            Backend::Directory(root) => match tokio::fs::remove_file(root.join(path)).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            Backend::Memory(records) => {
+                records.lock().await.remove(path);
+                Ok(())
+            }
+        }
+    }
+
+    /// Record files directly inside `dir`, relative to the store root
+    async fn list(&self, dir: &Path) -> Result<Vec<PathBuf>, PersistenceError> {
+        let mut paths = match &self.backend {
+            Backend::Directory(root) => {
+                let mut paths = Vec::new();
+                let mut entries = match tokio::fs::read_dir(root.join(dir)).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(paths),
+                    Err(e) => return Err(e.into()),
+                };
+
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = PathBuf::from(entry.file_name());
+                    if entry.file_type().await?.is_file() && name.extension().is_some_and(|ext| ext == "json") {
+                        paths.push(dir.join(name));
+                    }
+                }
+                paths
+            }
+            Backend::Memory(records) => records.lock().await.keys()
+                .filter(|path| path.parent() == Some(dir))
+                .cloned()
+                .collect(),
+        };
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    async fn list_dirs(&self, dir: &Path) -> Result<Vec<PathBuf>, PersistenceError> {
+        let mut dirs = match &self.backend {
+            Backend::Directory(root) => {
+                let mut dirs = Vec::new();
+                let mut entries = match tokio::fs::read_dir(root.join(dir)).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dirs),
+                    Err(e) => return Err(e.into()),
+                };
+
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_dir() {
+                        dirs.push(dir.join(entry.file_name()));
+                    }
+                }
+                dirs
+            }
+            Backend::Memory(records) => records.lock().await.keys()
+                .filter_map(|path| path.parent())
+                .filter(|parent| parent.parent() == Some(dir))
+                .map(Path::to_path_buf)
+                .collect(),
+        };
+        dirs.sort();
+        dirs.dedup();
+
+        Ok(dirs)
+    }
+}
+
+fn history_path(channel_id: H256, sequence: u64) -> PathBuf {
+    Path::new(HISTORY_DIR)
+        .join(format!("{:x}", channel_id))
+        .join(format!("{:020}.json", sequence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Address, U256};
+
+    #[tokio::test]
+    async fn test_migrate_dry_run_then_rewrite() {
+        let root = std::env::temp_dir().join(format!("state-{:x}", H256::random()));
+        let persistence = StatePersistence::open(&root);
+
+        // A channel state written before records were versioned
+        let state = ChannelState::new(H256::random(), vec![Address::random(), Address::random()], U256::from(1000));
+        let legacy_path = Path::new(CHANNELS_DIR).join(format!("{:x}.json", state.channel_id));
+        tokio::fs::create_dir_all(root.join(CHANNELS_DIR)).await.unwrap();
+        tokio::fs::write(root.join(&legacy_path), serde_json::to_vec(&state).unwrap()).await.unwrap();
+        persistence.persist_network_state(&NetworkState::new()).await.unwrap();
+
+        let report = persistence.migrate(true).await.unwrap();
+        assert_eq!(report.migrated.len(), 1);
+        assert_eq!(report.migrated[0].path, legacy_path);
+        assert_eq!(report.migrated[0].from_version, 0);
+        assert_eq!(report.up_to_date, 1);
+
+        // Nothing was written, and loading still upgrades on the fly
+        assert_eq!(persistence.migrate(true).await.unwrap().migrated.len(), 1);
+        assert!(persistence.load_channel_states().await.unwrap().contains_key(&state.channel_id));
+
+        persistence.migrate(false).await.unwrap();
+        let report = persistence.migrate(true).await.unwrap();
+        assert!(report.migrated.is_empty());
+        assert_eq!(report.up_to_date, 2);
+
+        let _ = tokio::fs::remove_dir_all(root).await;
+    }
+
+    #[tokio::test]
+    async fn test_updates_load_in_sequence_order() {
+        let persistence = StatePersistence::in_memory();
+        let channel_id = H256::random();
+
+        for sequence in [3, 1, 2] {
+            persistence.persist_state_update(&StateUpdate {
+                channel_id,
+                sequence,
+                timestamp: 0,
+                previous_state: H256::zero(),
+                new_state: H256::random(),
+                signatures: HashMap::new(),
+            }).await.unwrap();
+        }
+
+        let updates = persistence.load_state_updates(channel_id).await.unwrap();
+        assert_eq!(updates.iter().map(|update| update.sequence).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(persistence.migrate(true).await.unwrap().up_to_date, 3);
+    }
+}